@@ -1,3 +1,4 @@
+use domain::{DiskFullPolicy, EncryptionPolicy, Preallocation};
 use std::env;
 
 #[derive(Debug, Clone)]
@@ -7,6 +8,14 @@ pub struct Config {
     pub api_port: u16,
     pub download_dir: String,
     pub streaming_buffer_size_mb: usize,
+    pub memory_storage_mb: usize,
+    pub peer_listen_port: u16,
+    pub upload_slots_per_torrent: usize,
+    pub global_upload_slots: usize,
+    pub peer_encryption: EncryptionPolicy,
+    pub preallocation: Preallocation,
+    pub disk_full_policy: DiskFullPolicy,
+    pub min_free_space_mb: u64,
 }
 
 impl Config {
@@ -33,6 +42,46 @@ impl Config {
                 .unwrap_or_else(|_| "64".to_string())
                 .parse()
                 .unwrap_or(64),
+            
+            memory_storage_mb: env::var("MEMORY_STORAGE_MB")
+                .unwrap_or_else(|_| "512".to_string())
+                .parse()
+                .unwrap_or(512),
+            
+            peer_listen_port: env::var("PEER_LISTEN_PORT")
+                .unwrap_or_else(|_| "6881".to_string())
+                .parse()
+                .unwrap_or(6881),
+            
+            upload_slots_per_torrent: env::var("UPLOAD_SLOTS_PER_TORRENT")
+                .unwrap_or_else(|_| "4".to_string())
+                .parse()
+                .unwrap_or(4),
+            
+            global_upload_slots: env::var("GLOBAL_UPLOAD_SLOTS")
+                .unwrap_or_else(|_| "20".to_string())
+                .parse()
+                .unwrap_or(20),
+            
+            peer_encryption: env::var("PEER_ENCRYPTION")
+                .unwrap_or_else(|_| "enabled".to_string())
+                .parse()
+                .unwrap_or_default(),
+            
+            preallocation: env::var("PREALLOCATION")
+                .unwrap_or_else(|_| "sparse".to_string())
+                .parse()
+                .unwrap_or_default(),
+            
+            disk_full_policy: env::var("DISK_FULL_POLICY")
+                .unwrap_or_else(|_| "refuse".to_string())
+                .parse()
+                .unwrap_or_default(),
+            
+            min_free_space_mb: env::var("MIN_FREE_SPACE_MB")
+                .unwrap_or_else(|_| "1024".to_string())
+                .parse()
+                .unwrap_or(1024),
         }
     }
 }
@@ -1,15 +1,17 @@
-use application::TorrentApp;
+use application::{AppConfig, TorrentApp};
 use domain::StreamingService;
 use axum::{
     extract::{Path, State},
     response::{IntoResponse, Json},
-    routing::{get, post},
+    routing::{get, patch, post},
     Router,
     http::{StatusCode, HeaderMap, header},
     body::Body,
 };
-use domain::entities::{Torrent, TorrentStatus};
+use domain::entities::{FilePriority, FileSelection, StorageKind, Torrent, TorrentStatus};
+use domain::{parse_select_only, DomainError, MagnetLink};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::sync::Arc;
 use tower_http::cors::CorsLayer;
 use tower_http::trace::TraceLayer;
@@ -26,7 +28,28 @@ struct AppState {
 
 #[derive(Debug, Serialize, Deserialize)]
 struct AddTorrentRequest {
+    /// A .torrent URL, or a magnet link with an `xs` source for the .torrent
     pub url: String,
+    /// Files to download, e.g. `0,2,4-6` (BEP 53); overrides a magnet's `so=`
+    #[serde(default)]
+    pub select_only: Option<String>,
+    /// Priorities of individual files by index
+    #[serde(default)]
+    pub file_priorities: HashMap<usize, FilePriority>,
+    /// `memory` to stream without writing anything to disk
+    #[serde(default)]
+    pub storage: StorageKind,
+}
+
+#[derive(Debug, Deserialize)]
+struct UpdateFileRequest {
+    pub priority: FilePriority,
+}
+
+#[derive(Debug, Deserialize)]
+struct UpdateStreamRequest {
+    /// Share of the torrent's bandwidth relative to its other streams
+    pub weight: u32,
 }
 
 #[derive(Debug, Serialize)]
@@ -39,6 +62,7 @@ struct TorrentInfo {
     piece_count: i32,
     status: TorrentStatus,
     progress: f32,
+    storage: StorageKind,
 }
 
 impl From<Torrent> for TorrentInfo {
@@ -52,6 +76,7 @@ impl From<Torrent> for TorrentInfo {
             piece_count: torrent.piece_count,
             status: torrent.status,
             progress: torrent.progress,
+            storage: torrent.storage,
         }
     }
 }
@@ -79,11 +104,35 @@ async fn main() -> anyhow::Result<()> {
     info!("🌐 API server will bind to: {}:{}", config.api_host, config.api_port);
 
     // Initialize the torrent application with configuration
-    let torrent_app = Arc::new(TorrentApp::new_with_config(
-        &config.database_path,
-        &config.download_dir,
-        config.streaming_buffer_size_mb,
-    ));
+    let torrent_app = Arc::new(TorrentApp::from_config(AppConfig {
+        database_path: config.database_path.clone(),
+        download_dir: config.download_dir.clone(),
+        buffer_size_mb: config.streaming_buffer_size_mb,
+        memory_storage_mb: config.memory_storage_mb,
+        listen_port: config.peer_listen_port,
+        upload_slots_per_torrent: config.upload_slots_per_torrent,
+        global_upload_slots: config.global_upload_slots,
+        encryption: config.peer_encryption,
+        preallocation: config.preallocation,
+        when_disk_full: config.disk_full_policy,
+        min_free_space_mb: config.min_free_space_mb,
+    }));
+
+    // Accept inbound peers so we can seed and reach peers behind NAT
+    let peer_address = torrent_app.start_peer_listener().await?;
+    info!("🧲 Accepting peer connections on port {}", peer_address.port());
+    torrent_app.start_choker();
+    torrent_app.start_checkpoints();
+    torrent_app.start_disk_monitor();
+
+    // Pick up the torrents of the last run without holding up the API
+    let resuming_app = torrent_app.clone();
+    tokio::spawn(async move {
+        match resuming_app.resume_torrents().await {
+            Ok(resumed) => info!("▶️  Resumed {} torrents", resumed),
+            Err(e) => tracing::error!("Failed to resume torrents: {}", e),
+        }
+    });
     let app_state = AppState { torrent_app };
 
     // Build our application with routes
@@ -91,11 +140,13 @@ async fn main() -> anyhow::Result<()> {
         // Basic torrent management endpoints
         .route("/api/torrents", get(list_torrents).post(add_torrent))
         .route("/api/torrents/:id", get(get_torrent))
+        .route("/api/torrents/:id/recheck", post(recheck_torrent))
         
         // Streaming endpoints
         .route("/api/torrents/:id/files", get(get_streamable_files))
+        .route("/api/torrents/:id/files/:index", patch(update_file))
         .route("/api/torrents/:id/stream/:file_index", post(create_stream_session))
-        .route("/api/stream/:session_id", get(stream_content))
+        .route("/api/stream/:session_id", get(stream_content).patch(update_stream_session))
         .route("/api/streams", get(list_active_streams))
         
         // System info endpoints
@@ -115,9 +166,12 @@ async fn main() -> anyhow::Result<()> {
     info!("   GET  /api/torrents          - List all torrents");
     info!("   POST /api/torrents          - Add torrent by URL");
     info!("   GET  /api/torrents/:id      - Get torrent details");
+    info!("   POST /api/torrents/:id/recheck - Re-hash stored pieces (status is checking meanwhile)");
     info!("   GET  /api/torrents/:id/files - Get streamable files");
+    info!("   PATCH /api/torrents/:id/files/:index - Set file priority (skip, low, normal, high)");
     info!("   POST /api/torrents/:id/stream/:file_index - Create stream session");
     info!("   GET  /api/stream/:session_id - Stream content (supports range requests)");
+    info!("   PATCH /api/stream/:session_id - Set a stream's bandwidth weight");
     info!("   GET  /api/streams            - List active streams");
     info!("   GET  /api/status             - System status");
     info!("   GET  /health                 - Health check");
@@ -145,9 +199,38 @@ async fn add_torrent(
     Json(payload): Json<AddTorrentRequest>,
 ) -> impl IntoResponse {
     info!("📥 Adding torrent from URL: {}", payload.url);
-    
+
+    // Magnet links are only usable when they say where the .torrent file is
+    let mut torrent_url = payload.url.clone();
+    let mut magnet = None;
+    if MagnetLink::is_magnet(&payload.url) {
+        let link = match MagnetLink::parse(&payload.url) {
+            Ok(link) => link,
+            Err(e) => return (StatusCode::BAD_REQUEST, format!("Failed to parse magnet link: {}", e)).into_response(),
+        };
+        match &link.exact_source {
+            Some(source) => torrent_url = source.clone(),
+            None => {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    "Magnet links need an xs= .torrent source; fetching metadata from peers is not supported".to_string(),
+                ).into_response();
+            }
+        }
+        magnet = Some(link);
+    }
+
+    let only = match &payload.select_only {
+        Some(select_only) => match parse_select_only(select_only) {
+            Ok(only) => Some(only),
+            Err(e) => return (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+        },
+        None => magnet.as_ref().and_then(|link| link.select_only.clone()),
+    };
+    let selection = FileSelection { only, priorities: payload.file_priorities };
+
     // Fetch the torrent file from URL
-    let torrent_data = match fetch_torrent_from_url(&payload.url).await {
+    let torrent_data = match fetch_torrent_from_url(&torrent_url).await {
         Ok(data) => data,
         Err(e) => {
             return (StatusCode::BAD_REQUEST, format!("Failed to fetch torrent: {}", e)).into_response();
@@ -162,13 +245,33 @@ async fn add_torrent(
         }
     };
     
+    // The .torrent must describe the torrent the magnet link names
+    if let Some(expected) = magnet.as_ref().and_then(|link| link.info_hash.as_ref()) {
+        match state.torrent_app.torrent_service.parse_torrent_file(torrent_data.clone()).await {
+            Ok(torrent) if &torrent.info_hash == expected => {}
+            Ok(torrent) => {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    format!("Torrent source has info hash {}, magnet link names {}", torrent.info_hash, expected),
+                ).into_response();
+            }
+            Err(e) => return (StatusCode::BAD_REQUEST, format!("Failed to parse torrent: {}", e)).into_response(),
+        }
+    }
+
     // Add to database via torrent service
-    match state.torrent_app.torrent_service.add_torrent_from_file(torrent_data).await {
+    match state.torrent_app.torrent_service.add_torrent_from_file_with(torrent_data, &selection, payload.storage).await {
         Ok(torrent) => {
             info!("✅ Successfully added torrent: {}", torrent.name);
             let torrent_info: TorrentInfo = torrent.into();
             (StatusCode::CREATED, Json(torrent_info)).into_response()
         }
+        Err(e @ DomainError::ValidationError(_)) => {
+            (StatusCode::BAD_REQUEST, format!("Failed to add torrent: {}", e)).into_response()
+        }
+        Err(e @ DomainError::InsufficientSpace(_)) => {
+            (StatusCode::INSUFFICIENT_STORAGE, format!("Failed to add torrent: {}", e)).into_response()
+        }
         Err(e) => {
             (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to add torrent to database: {}", e)).into_response()
         }
@@ -255,6 +358,43 @@ async fn get_streamable_files(
     }
 }
 
+async fn recheck_torrent(
+    State(state): State<AppState>,
+    Path(id): Path<i32>,
+) -> impl IntoResponse {
+    match state.torrent_app.recheck_torrent(id).await {
+        Ok(torrent) => {
+            info!("🔍 Rechecking torrent {}", id);
+            let torrent_info: TorrentInfo = torrent.into();
+            (StatusCode::ACCEPTED, Json(torrent_info)).into_response()
+        }
+        Err(e @ DomainError::TorrentNotFound(_)) => {
+            (StatusCode::NOT_FOUND, format!("Failed to recheck torrent: {}", e)).into_response()
+        }
+        Err(e @ DomainError::ValidationError(_)) => {
+            (StatusCode::CONFLICT, format!("Failed to recheck torrent: {}", e)).into_response()
+        }
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to recheck torrent: {}", e)).into_response()
+    }
+}
+
+async fn update_file(
+    State(state): State<AppState>,
+    Path((torrent_id, file_index)): Path<(i32, i32)>,
+    Json(payload): Json<UpdateFileRequest>,
+) -> impl IntoResponse {
+    match state.torrent_app.torrent_service.set_file_priority(torrent_id, file_index, payload.priority).await {
+        Ok(file) => {
+            info!("🎚️  Torrent {} file {} priority set to {}", torrent_id, file_index, file.priority.as_str());
+            Json(file).into_response()
+        }
+        Err(e @ (DomainError::NotFound(_) | DomainError::TorrentNotFound(_))) => {
+            (StatusCode::NOT_FOUND, format!("Failed to update file: {}", e)).into_response()
+        }
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to update file: {}", e)).into_response()
+    }
+}
+
 async fn create_stream_session(
     State(state): State<AppState>,
     Path((torrent_id, file_index)): Path<(i32, usize)>,
@@ -290,7 +430,7 @@ async fn stream_content(
             response_headers.insert(header::CONTENT_TYPE, "application/octet-stream".parse().unwrap());
             response_headers.insert(header::ACCEPT_RANGES, "bytes".parse().unwrap());
             
-            if data.len() > 0 {
+            if !data.is_empty() {
                 (StatusCode::OK, response_headers, Body::from(data)).into_response()
             } else {
                 (StatusCode::NO_CONTENT, response_headers, Body::empty()).into_response()
@@ -300,6 +440,26 @@ async fn stream_content(
     }
 }
 
+async fn update_stream_session(
+    State(state): State<AppState>,
+    Path(session_id): Path<String>,
+    Json(payload): Json<UpdateStreamRequest>,
+) -> impl IntoResponse {
+    match state.torrent_app.streaming_service.set_session_weight(&session_id, payload.weight).await {
+        Ok(session) => {
+            info!("⚖️  Stream {} weight set to {}", session_id, session.weight);
+            Json(session).into_response()
+        }
+        Err(e @ DomainError::NotFound(_)) => {
+            (StatusCode::NOT_FOUND, format!("Failed to update stream: {}", e)).into_response()
+        }
+        Err(e @ DomainError::ValidationError(_)) => {
+            (StatusCode::BAD_REQUEST, format!("Failed to update stream: {}", e)).into_response()
+        }
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to update stream: {}", e)).into_response()
+    }
+}
+
 async fn list_active_streams(State(state): State<AppState>) -> impl IntoResponse {
     match state.torrent_app.streaming_service.get_active_sessions().await {
         Ok(sessions) => Json(sessions).into_response(),
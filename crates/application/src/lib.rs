@@ -1,6 +1,57 @@
+mod peer_listener;
+
+pub use peer_listener::PeerListener;
+
 use domain::*;
 use infrastructure::*;
+use std::net::{Ipv4Addr, SocketAddr};
 use std::sync::Arc;
+use std::time::Duration;
+
+/// How often free disk space is checked
+const DISK_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Settings the application is built from
+#[derive(Debug, Clone)]
+pub struct AppConfig {
+    pub database_path: String,
+    pub download_dir: String,
+    pub buffer_size_mb: usize,
+    /// Memory budget of torrents kept in memory instead of on disk
+    pub memory_storage_mb: usize,
+    /// TCP port for inbound peer connections (0 picks a free port)
+    pub listen_port: u16,
+    /// Regular unchoke slots per torrent
+    pub upload_slots_per_torrent: usize,
+    /// Regular unchoke slots across all torrents
+    pub global_upload_slots: usize,
+    /// Message Stream Encryption for peer connections
+    pub encryption: EncryptionPolicy,
+    /// How the files of torrents added to disk are allocated
+    pub preallocation: Preallocation,
+    /// Whether torrents that do not fit on disk are refused or queued
+    pub when_disk_full: DiskFullPolicy,
+    /// Free disk space to keep; downloads pause below it
+    pub min_free_space_mb: u64,
+}
+
+impl Default for AppConfig {
+    fn default() -> Self {
+        Self {
+            database_path: "stremio.db".to_string(),
+            download_dir: "downloads".to_string(),
+            buffer_size_mb: 64,
+            memory_storage_mb: DEFAULT_MEMORY_CAPACITY / (1024 * 1024),
+            listen_port: DEFAULT_LISTEN_PORT,
+            upload_slots_per_torrent: DEFAULT_UPLOAD_SLOTS_PER_TORRENT,
+            global_upload_slots: DEFAULT_GLOBAL_UPLOAD_SLOTS,
+            encryption: EncryptionPolicy::default(),
+            preallocation: Preallocation::default(),
+            when_disk_full: DiskFullPolicy::default(),
+            min_free_space_mb: DEFAULT_MIN_FREE_SPACE / (1024 * 1024),
+        }
+    }
+}
 
 /// Torrent Application - orchestrates the complete flow
 pub struct TorrentApp {
@@ -9,6 +60,14 @@ pub struct TorrentApp {
     pub tracker_service: TrackerService,
     pub peer_service: PeerService,
     pub streaming_service: StreamingServiceImpl,
+    config: AppConfig,
+    torrent_repository: Arc<dyn TorrentRepository>,
+    connection_manager: Arc<ConnectionManager>,
+    piece_manager: Arc<PieceManager>,
+    piece_picker: PiecePicker,
+    piece_downloader: Arc<PieceDownloader>,
+    resume_validator: ResumeValidator,
+    choker: Arc<Choker>,
 }
 
 impl TorrentApp {
@@ -19,8 +78,21 @@ impl TorrentApp {
 
     /// Creates a new TorrentApp with custom configuration parameters
     pub fn new_with_config(database_path: &str, download_dir: &str, buffer_size_mb: usize) -> Self {
+        Self::from_config(AppConfig {
+            database_path: database_path.to_string(),
+            download_dir: download_dir.to_string(),
+            buffer_size_mb,
+            ..AppConfig::default()
+        })
+    }
+
+    /// Creates a new TorrentApp from a full `AppConfig`
+    pub fn from_config(config: AppConfig) -> Self {
+        let download_dir = config.download_dir.as_str();
+        let buffer_size_mb = config.buffer_size_mb;
+
         // Infrastructure layer - database setup
-        let database = Database::new(database_path);
+        let database = Database::new(&config.database_path);
         let pool = database.get_pool().clone();
 
         // Create repository implementations
@@ -32,37 +104,89 @@ impl TorrentApp {
             Arc::new(SqliteTrackerRepository::new(pool.clone()));
         let peer_repository: Arc<dyn PeerRepository> =
             Arc::new(SqlitePeerRepository::new(pool.clone()));
+        let torrent_file_repository: Arc<dyn TorrentFileRepository> =
+            Arc::new(SqliteTorrentFileRepository::new(pool.clone()));
+        let file_snapshot_repository: Arc<dyn FileSnapshotRepository> =
+            Arc::new(SqliteFileSnapshotRepository::new(pool.clone()));
+
+        // Downloaded data of every torrent, laid out over its files on disk
+        // or, for torrents that are only streamed, in memory
+        let memory_backend = Arc::new(MemoryBackend::new(config.memory_storage_mb * 1024 * 1024));
+        let storage = Arc::new(
+            TorrentStorage::new(torrent_repository.clone(), download_dir)
+                .with_file_repository(torrent_file_repository.clone())
+                .with_snapshot_repository(file_snapshot_repository)
+                .with_backend(StorageKind::Memory, memory_backend),
+        );
+        let resume_validator = ResumeValidator::new(piece_repository.clone(), storage.clone());
 
         // Domain services
+        let download_service =
+            DownloadService::new(piece_repository.clone(), torrent_repository.clone(), storage.clone());
+
+        // Create piece manager
+        let piece_manager = Arc::new(
+            PieceManager::new(piece_repository.clone(), torrent_repository.clone(), download_dir.to_string())
+                .with_storage(storage.clone()),
+        );
+
+        // Live peer connections are shared by the peer service and the piece downloader.
+        // Every connection also serves our verified pieces back to the swarm.
+        let seeder = Arc::new(Seeder::new(piece_manager.clone(), torrent_repository.clone()));
+        let connection_manager = Arc::new(
+            ConnectionManager::new(peer_repository.clone(), torrent_repository.clone())
+                .with_seeder(seeder)
+                .with_encryption(config.encryption),
+        );
+
+        let tracker_service = TrackerService::new(
+            tracker_repository.clone(),
+            peer_repository.clone(),
+            torrent_repository.clone(),
+        )
+        .with_peer_id(connection_manager.local_peer_id());
+        tracker_service.set_listen_port(config.listen_port);
+
         let torrent_service = TorrentService::new(
             torrent_repository.clone(),
             piece_repository.clone(),
             tracker_repository.clone(),
-        );
+            torrent_file_repository.clone(),
+            storage.clone(),
+        )
+        .with_connection_manager(connection_manager.clone())
+        .with_disk_policy(DiskPolicy {
+            preallocation: config.preallocation,
+            when_full: config.when_disk_full,
+            min_free_space: config.min_free_space_mb * 1024 * 1024,
+        });
 
-        let download_service =
-            DownloadService::new(piece_repository.clone(), torrent_repository.clone());
+        let choker = Arc::new(
+            Choker::new(connection_manager.clone(), torrent_repository.clone())
+                .with_upload_slots(config.upload_slots_per_torrent, config.global_upload_slots),
+        );
 
-        let tracker_service = TrackerService::new(
-            tracker_repository,
+        let peer_service = PeerService::new(
             peer_repository.clone(),
             torrent_repository.clone(),
+            connection_manager.clone(),
         );
-
-        let peer_service = PeerService::new(peer_repository.clone(), torrent_repository.clone());
         
-        // Create piece manager
-        let piece_manager = Arc::new(PieceManager::new(
+
+        let piece_picker = PiecePicker::new(
             piece_repository.clone(),
             torrent_repository.clone(),
-            download_dir.to_string(),
-        ));
+            torrent_file_repository.clone(),
+            piece_manager.clone(),
+            connection_manager.clone(),
+        );
 
         // Create piece downloader for production downloading
         let piece_downloader = Arc::new(PieceDownloader::new(
             piece_repository.clone(),
-            peer_repository.clone(),
             torrent_repository.clone(),
+            torrent_file_repository,
+            connection_manager.clone(),
             piece_manager.clone(),
             download_dir.to_string(),
         ));
@@ -77,7 +201,7 @@ impl TorrentApp {
         
         let streaming_service = StreamingServiceImpl::new(
             torrent_repository.clone(),
-            piece_manager,
+            piece_manager.clone(),
             streaming_buffer,
             download_dir.to_string(),
         );
@@ -88,7 +212,164 @@ impl TorrentApp {
             tracker_service,
             peer_service,
             streaming_service,
+            config,
+            torrent_repository,
+            connection_manager,
+            piece_manager,
+            piece_picker,
+            piece_downloader,
+            resume_validator,
+            choker,
+        }
+    }
+
+    /// Start accepting inbound peer connections (TCP and uTP) on the configured port.
+    /// Returns the bound address; trackers are told about its port from now on,
+    /// and outbound connections try uTP from the same port first.
+    pub async fn start_peer_listener(&self) -> Result<SocketAddr, DomainError> {
+        let address = SocketAddr::from((Ipv4Addr::UNSPECIFIED, self.config.listen_port));
+        let listener = PeerListener::bind(
+            address,
+            self.torrent_repository.clone(),
+            self.connection_manager.clone(),
+        ).await?;
+
+        let local_addr = listener.local_addr()?;
+        self.tracker_service.set_listen_port(local_addr.port());
+        if let Some(utp) = listener.utp_socket() {
+            self.connection_manager.set_utp_socket(utp);
+        }
+        tokio::spawn(listener.run());
+
+        Ok(local_addr)
+    }
+
+    /// Start the periodic choker that hands out upload slots
+    pub fn start_choker(&self) {
+        self.choker.spawn();
+    }
+
+    /// Periodically save the blocks of unfinished pieces so a restart resumes them
+    pub fn start_checkpoints(&self) {
+        self.piece_manager.spawn_checkpoints();
+    }
+
+    /// Check free disk space periodically: stop downloads writing to disk
+    /// when it drops below the minimum, and start queued torrents once they
+    /// fit
+    pub fn start_disk_monitor(self: &Arc<Self>) {
+        let app = Arc::clone(self);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(DISK_CHECK_INTERVAL);
+            loop {
+                interval.tick().await;
+                if let Err(e) = app.check_disk_space().await {
+                    eprintln!("❌ Failed to check disk space: {}", e);
+                }
+            }
+        });
+    }
+
+    async fn check_disk_space(&self) -> Result<(), DomainError> {
+        self.torrent_service.pause_if_low_on_space().await?;
+        for torrent_id in self.torrent_service.start_queued().await? {
+            if let Err(e) = self.resume_torrent(torrent_id).await {
+                eprintln!("❌ Failed to start queued torrent {}: {}", torrent_id, e);
+            }
         }
+        Ok(())
+    }
+
+    /// Pick up every torrent that was not paused when the last run stopped.
+    /// The saved piece state is checked against the stored files first:
+    /// quickly when their sizes and modification times are unchanged, by
+    /// hashing every stored piece otherwise. Torrents then announce and go
+    /// back to downloading, or to seeding once complete. Incomplete torrents
+    /// on disk stay stopped while free space is below the minimum. Returns
+    /// how many torrents were resumed.
+    pub async fn resume_torrents(&self) -> Result<usize, DomainError> {
+        let low_on_space = self.torrent_service.is_low_on_space().await?;
+        let mut resumed = 0;
+        for torrent in self.torrent_service.get_all_torrents().await? {
+            let Some(torrent_id) = torrent.id else {
+                continue;
+            };
+            // Queued torrents wait for the disk monitor
+            if matches!(torrent.status, TorrentStatus::Paused | TorrentStatus::Queued) {
+                continue;
+            }
+            // Still no room to write, e.g. for torrents stopped for lack of space
+            if low_on_space && torrent.storage == StorageKind::Disk && !torrent.is_complete() {
+                println!("💾 Not resuming {}: free disk space is below the minimum", torrent.name);
+                continue;
+            }
+
+            match self.resume_validator.validate(&torrent).await {
+                Ok(ResumeCheck::Quick) => println!("⚡ {} is unchanged since the last run", torrent.name),
+                Ok(ResumeCheck::Full { invalid }) => {
+                    println!("🔍 Checked {}: {} stored pieces need downloading again", torrent.name, invalid);
+                }
+                Ok(ResumeCheck::Cleared { invalid }) => {
+                    println!("🧹 {} was kept in memory: {} pieces need downloading again", torrent.name, invalid);
+                }
+                Err(e) => {
+                    eprintln!("❌ Failed to check {} before resuming: {}", torrent.name, e);
+                    continue;
+                }
+            }
+
+            if let Err(e) = self.resume_torrent(torrent_id).await {
+                eprintln!("❌ Failed to resume {}: {}", torrent.name, e);
+                continue;
+            }
+            resumed += 1;
+        }
+        Ok(resumed)
+    }
+
+    /// Announce a torrent and start downloading it in the background, or
+    /// connect to peers to seed it when it is complete
+    async fn resume_torrent(&self, torrent_id: i32) -> Result<(), DomainError> {
+        let torrent = self.torrent_service.update_progress(torrent_id).await?;
+        let peers = self.tracker_service.announce_to_trackers(torrent_id, &torrent.info_hash).await?;
+        println!("▶️  Resuming {} ({:.1}%, {} peers)", torrent.name, torrent.progress * 100.0, peers.len());
+
+        if torrent.is_complete() {
+            self.connection_manager.connect_to_peers(torrent_id).await?;
+            return Ok(());
+        }
+
+        self.torrent_service.start_download(torrent_id).await?;
+        let piece_downloader = self.piece_downloader.clone();
+        tokio::spawn(async move {
+            if let Err(e) = piece_downloader.start_downloading(torrent_id).await {
+                eprintln!("❌ Resumed download of torrent {} stopped: {}", torrent_id, e);
+            }
+        });
+        Ok(())
+    }
+
+    /// Hash every piece of a torrent from storage again in the background.
+    /// Returns the torrent already `Checking`, with its progress the share
+    /// checked until the check ends; then transfers pick up again unless the
+    /// torrent was stopped.
+    pub async fn recheck_torrent(self: &Arc<Self>, torrent_id: i32) -> Result<Torrent, DomainError> {
+        let recheck = self.torrent_service.begin_recheck(torrent_id).await?;
+        let torrent = recheck.torrent.clone();
+
+        let app = Arc::clone(self);
+        tokio::spawn(async move {
+            match app.torrent_service.finish_recheck(recheck).await {
+                Ok(torrent) if !torrent.is_stopped() => {
+                    if let Err(e) = app.resume_torrent(torrent_id).await {
+                        eprintln!("❌ Failed to resume {} after its recheck: {}", torrent.name, e);
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => eprintln!("❌ Recheck of torrent {} failed: {}", torrent_id, e),
+            }
+        });
+        Ok(torrent)
     }
 
     /// Complete torrent download flow as per your requirements
@@ -125,11 +406,8 @@ impl TorrentApp {
         let connected_peers = self.peer_service.connect_to_peers(torrent_id).await?;
         println!("🤝 Connected to {} peers", connected_peers.len());
 
-        // Step 6: Start downloading first N pieces
-        let pieces_to_download = self
-            .download_service
-            .get_next_pieces_to_download(torrent_id, 10)
-            .await?;
+        // Step 6: Start downloading the first N pieces, rarest first
+        let pieces_to_download = self.piece_picker.next_pieces(torrent_id, 10).await?;
         println!(
             "⬇️  Starting download of {} pieces",
             pieces_to_download.len()
@@ -137,11 +415,12 @@ impl TorrentApp {
 
         for piece in pieces_to_download {
             // Request piece from peers
-            self.peer_service.request_piece(torrent_id, &piece).await?;
-        }
+            let data = self.peer_service.request_piece(torrent_id, &piece).await?;
 
-        // Step 7: Verify SHA1 hash of each piece (handled in complete_piece)
-        // Step 8: Write to local file or stream buffer (handled in complete_piece)
+            // Step 7: Verify SHA1 hash of each piece (handled in complete_piece)
+            // Step 8: Write to local file or stream buffer (handled in complete_piece)
+            self.handle_piece_data(torrent_id, piece.piece_index, data).await?;
+        }
 
         // Step 9: Prepare for streaming and check readiness
         let is_ready = self
@@ -174,6 +453,9 @@ impl TorrentApp {
             .complete_piece(torrent_id, piece_index, data)
             .await?;
 
+        // Let connected peers know they can request it from us
+        self.connection_manager.announce_piece(torrent_id, piece_index as u32);
+
         // Update overall torrent progress
         self.torrent_service.update_progress(torrent_id).await?;
 
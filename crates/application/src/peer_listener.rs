@@ -0,0 +1,219 @@
+use domain::encryption;
+use domain::*;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::TcpListener;
+use tokio::sync::Semaphore;
+
+/// How long an inbound peer gets to send its handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Inbound connections that may be handshaking at once; more are dropped
+const MAX_PENDING_HANDSHAKES: usize = 32;
+
+/// How long the info hashes encrypted peers are matched against are reused
+/// before they are read from the repository again
+const INFO_HASH_REFRESH: Duration = Duration::from_secs(5);
+
+/// Accepts inbound peer connections over TCP and uTP on the same port and
+/// hands them to the connection manager, so peers behind NAT can reach us and
+/// we can seed.
+pub struct PeerListener {
+    listener: TcpListener,
+    utp: Option<Arc<UtpSocket>>,
+    torrent_repository: Arc<dyn TorrentRepository>,
+    connection_manager: Arc<ConnectionManager>,
+    handshakes: Arc<Semaphore>,
+    info_hashes: Arc<InfoHashCache>,
+}
+
+impl PeerListener {
+    /// Bind the listening sockets. Port 0 picks a free port; see `local_addr`.
+    /// uTP is optional: if its UDP port cannot be bound we accept TCP only.
+    pub async fn bind(
+        address: SocketAddr,
+        torrent_repository: Arc<dyn TorrentRepository>,
+        connection_manager: Arc<ConnectionManager>,
+    ) -> Result<Self, DomainError> {
+        let listener = TcpListener::bind(address).await
+            .map_err(|e| DomainError::NetworkError(format!("Failed to bind peer listener on {}: {}", address, e)))?;
+
+        let mut utp_address = address;
+        if let Ok(tcp_address) = listener.local_addr() {
+            utp_address.set_port(tcp_address.port());
+        }
+        let utp = match UtpSocket::bind(utp_address).await {
+            Ok(socket) => Some(Arc::new(socket)),
+            Err(e) => {
+                eprintln!("uTP disabled: {}", e);
+                None
+            }
+        };
+
+        Ok(Self {
+            listener,
+            utp,
+            info_hashes: Arc::new(InfoHashCache::new(torrent_repository.clone())),
+            torrent_repository,
+            connection_manager,
+            handshakes: Arc::new(Semaphore::new(MAX_PENDING_HANDSHAKES)),
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, DomainError> {
+        self.listener.local_addr()
+            .map_err(|e| DomainError::NetworkError(format!("Failed to read listener address: {}", e)))
+    }
+
+    /// The uTP socket inbound connections arrive on; outbound uTP should use it too
+    pub fn utp_socket(&self) -> Option<Arc<UtpSocket>> {
+        self.utp.clone()
+    }
+
+    /// Accept connections until the task is dropped
+    pub async fn run(self) {
+        loop {
+            let accepted = tokio::select! {
+                accepted = self.listener.accept() => {
+                    accepted.map(|(stream, address)| (PeerStream::Tcp(stream), address))
+                        .map_err(|e| e.to_string())
+                }
+                accepted = accept_utp(&self.utp) => {
+                    accepted.map(|stream| {
+                        let address = stream.peer_addr();
+                        (PeerStream::Utp(stream), address)
+                    })
+                    .map_err(|e| e.to_string())
+                }
+            };
+
+            let (stream, address) = match accepted {
+                Ok(connection) => connection,
+                Err(e) => {
+                    eprintln!("Failed to accept peer connection: {}", e);
+                    continue;
+                }
+            };
+
+            // Dropping the stream closes it, so a flood costs no task or query
+            let Ok(permit) = Arc::clone(&self.handshakes).try_acquire_owned() else {
+                eprintln!("Rejected inbound peer {}: too many pending handshakes", address);
+                continue;
+            };
+
+            let torrent_repository = Arc::clone(&self.torrent_repository);
+            let info_hashes = Arc::clone(&self.info_hashes);
+            let connection_manager = Arc::clone(&self.connection_manager);
+            tokio::spawn(async move {
+                let accepted = accept_peer(stream, address, torrent_repository, &info_hashes, connection_manager).await;
+                drop(permit);
+                if let Err(e) = accepted {
+                    eprintln!("Rejected inbound peer {}: {}", address, e);
+                }
+            });
+        }
+    }
+}
+
+type InfoHashes = Arc<Vec<[u8; 20]>>;
+
+/// Info hashes of every torrent, read from the repository at most once per
+/// `INFO_HASH_REFRESH`
+struct InfoHashCache {
+    torrent_repository: Arc<dyn TorrentRepository>,
+    cached: Mutex<Option<(Instant, InfoHashes)>>,
+}
+
+impl InfoHashCache {
+    fn new(torrent_repository: Arc<dyn TorrentRepository>) -> Self {
+        Self { torrent_repository, cached: Mutex::new(None) }
+    }
+
+    async fn get(&self) -> Result<InfoHashes, DomainError> {
+        if let Some((read_at, info_hashes)) = self.cached.lock().unwrap().as_ref() {
+            if read_at.elapsed() < INFO_HASH_REFRESH {
+                return Ok(Arc::clone(info_hashes));
+            }
+        }
+
+        let info_hashes: InfoHashes = Arc::new(
+            self.torrent_repository.find_all().await?
+                .iter()
+                .filter_map(|torrent| info_hash_bytes(&torrent.info_hash).ok())
+                .collect(),
+        );
+        *self.cached.lock().unwrap() = Some((Instant::now(), Arc::clone(&info_hashes)));
+        Ok(info_hashes)
+    }
+}
+
+async fn accept_utp(utp: &Option<Arc<UtpSocket>>) -> Result<UtpStream, DomainError> {
+    match utp {
+        Some(utp) => utp.accept().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Negotiate encryption, read the remote handshake, route it to its torrent by
+/// info hash and answer it
+async fn accept_peer(
+    stream: PeerStream,
+    address: SocketAddr,
+    torrent_repository: Arc<dyn TorrentRepository>,
+    info_hashes: &InfoHashCache,
+    connection_manager: Arc<ConnectionManager>,
+) -> Result<PeerHandle, DomainError> {
+    // Encrypted peers name their torrent by hash, so the candidates are needed up front
+    let info_hashes = info_hashes.get().await?;
+
+    let exchange = async {
+        let (mut stream, encrypted_for) =
+            encryption::accept(stream, &info_hashes, connection_manager.encryption_policy()).await?;
+        let remote = Handshake::read_from(&mut stream).await?;
+        Ok::<_, DomainError>((stream, encrypted_for, remote))
+    };
+    let (mut stream, encrypted_for, remote) = tokio::time::timeout(HANDSHAKE_TIMEOUT, exchange).await
+        .map_err(|_| DomainError::PeerConnectionError("Handshake timeout".to_string()))??;
+
+    if encrypted_for.is_some_and(|info_hash| info_hash != remote.info_hash) {
+        return Err(DomainError::PeerConnectionError("Info hash mismatch in handshake".to_string()));
+    }
+
+    let info_hash = hex::encode(remote.info_hash);
+    let torrent = torrent_repository.find_by_info_hash(&info_hash).await?
+        .ok_or_else(|| DomainError::PeerConnectionError(format!("Unknown info hash {}", info_hash)))?;
+
+    if torrent.is_stopped() {
+        return Err(DomainError::PeerConnectionError(format!(
+            "Torrent {} is not active",
+            torrent.name
+        )));
+    }
+
+    let torrent_id = torrent.id.unwrap();
+    if !connection_manager.has_room(torrent_id) {
+        return Err(DomainError::PeerConnectionError(format!(
+            "Torrent {} has no room for more connections",
+            torrent.name
+        )));
+    }
+
+    connection_manager.local_handshake(remote.info_hash)
+        .write_to(&mut stream)
+        .await?;
+
+    let encrypted = if stream.is_encrypted() { ", encrypted" } else { "" };
+    println!(
+        "📥 Accepted inbound peer {} for torrent {} ({}{})",
+        address,
+        torrent.name,
+        stream.get_ref().transport_name(),
+        encrypted
+    );
+
+    let mut peer = Peer::new(torrent_id, address.ip().to_string(), address.port());
+    // uTP arrives from the peer's listen port, TCP from a throwaway one
+    peer.inbound = matches!(stream.get_ref(), PeerStream::Tcp(_));
+    connection_manager.register(stream, peer, remote).await
+}
@@ -0,0 +1,111 @@
+mod common;
+
+use common::{torrent, MemoryPeerRepository, MemoryTorrentRepository};
+use domain::*;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::DuplexStream;
+
+struct Swarm {
+    manager: Arc<ConnectionManager>,
+    torrents: Arc<dyn TorrentRepository>,
+    remotes: Vec<DuplexStream>,
+}
+
+impl Swarm {
+    fn new() -> Self {
+        let torrents: Arc<dyn TorrentRepository> = Arc::new(MemoryTorrentRepository::with(vec![
+            torrent(1, [1u8; 20], TorrentStatus::Downloading),
+            torrent(2, [2u8; 20], TorrentStatus::Downloading),
+        ]));
+        let peers: Arc<dyn PeerRepository> = Arc::new(MemoryPeerRepository::default());
+        let manager = Arc::new(ConnectionManager::new(peers, torrents.clone()));
+        Self { manager, torrents, remotes: Vec::new() }
+    }
+
+    /// Register a peer on `torrent_id` that tells us it is interested, optionally uploading a block first
+    async fn interested_peer(&mut self, torrent_id: i32, port: u16, uploads: bool) -> PeerHandle {
+        let (local, mut remote) = tokio::io::duplex(1 << 20);
+        let peer = Peer::new(torrent_id, "10.0.0.1".to_string(), port);
+        let handle = self.manager
+            .register(local, peer, Handshake::new([torrent_id as u8; 20], [port as u8; 20]))
+            .await
+            .unwrap();
+
+        let codec = MessageCodec::default();
+        if uploads {
+            let block = PeerMessage::Piece { piece_index: 0, begin: 0, data: vec![0u8; 16384] };
+            codec.write_message(&mut remote, &block).await.unwrap();
+        }
+        codec.write_message(&mut remote, &PeerMessage::Interested).await.unwrap();
+
+        let interested = async {
+            while !handle.stats().peer_interested {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), interested).await.unwrap();
+
+        self.remotes.push(remote);
+        handle
+    }
+
+    fn unchoked(&self, torrent_id: i32) -> usize {
+        self.manager.handles(torrent_id).iter().filter(|handle| !handle.stats().am_choking).count()
+    }
+}
+
+#[tokio::test]
+async fn respects_per_torrent_and_global_slots() {
+    let mut swarm = Swarm::new();
+    for port in 0..6 {
+        swarm.interested_peer(1, 7000 + port, false).await;
+    }
+    swarm.interested_peer(2, 8000, false).await;
+
+    // 3 regular slots overall, at most 2 per torrent, plus one optimistic unchoke
+    let choker = Choker::new(swarm.manager.clone(), swarm.torrents.clone()).with_upload_slots(2, 4);
+    choker.rechoke().await;
+
+    assert_eq!(swarm.unchoked(1), 3);
+    assert_eq!(swarm.unchoked(2), 1);
+}
+
+#[tokio::test]
+async fn unchokes_peers_that_upload_to_us() {
+    let mut swarm = Swarm::new();
+    swarm.interested_peer(1, 7000, false).await;
+    let fast = swarm.interested_peer(1, 7001, true).await;
+    swarm.interested_peer(1, 7002, false).await;
+
+    let choker = Choker::new(swarm.manager.clone(), swarm.torrents.clone()).with_upload_slots(1, 2);
+    choker.rechoke().await;
+
+    assert!(!fast.stats().am_choking);
+    assert_eq!(swarm.unchoked(1), 2);
+
+    // The optimistic slot stays put between rotations
+    let optimistic: Vec<_> = swarm.manager.handles(1).into_iter()
+        .filter(|handle| !handle.stats().am_choking && !handle.same_connection(&fast))
+        .collect();
+    choker.rechoke().await;
+    assert!(!optimistic[0].stats().am_choking);
+    assert_eq!(swarm.unchoked(1), 2);
+}
+
+#[tokio::test]
+async fn never_unchokes_uninterested_peers() {
+    let mut swarm = Swarm::new();
+    let (local, _remote) = tokio::io::duplex(1 << 16);
+    let idle = swarm.manager
+        .register(local, Peer::new(1, "10.0.0.2".to_string(), 9000), Handshake::new([1u8; 20], [3u8; 20]))
+        .await
+        .unwrap();
+    let interested = swarm.interested_peer(1, 7000, false).await;
+
+    let choker = Choker::new(swarm.manager.clone(), swarm.torrents.clone());
+    choker.rechoke().await;
+
+    assert!(idle.stats().am_choking);
+    assert!(!interested.stats().am_choking);
+}
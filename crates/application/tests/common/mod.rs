@@ -0,0 +1,289 @@
+//! In-memory repositories for exercising application wiring without SQLite
+#![allow(dead_code)]
+
+use async_trait::async_trait;
+use domain::*;
+use std::sync::Mutex;
+
+#[derive(Default)]
+pub struct MemoryTorrentRepository {
+    torrents: Mutex<Vec<Torrent>>,
+}
+
+impl MemoryTorrentRepository {
+    pub fn with(torrents: Vec<Torrent>) -> Self {
+        Self { torrents: Mutex::new(torrents) }
+    }
+}
+
+#[async_trait]
+impl TorrentRepository for MemoryTorrentRepository {
+    async fn find_by_id(&self, id: i32) -> Result<Option<Torrent>, DomainError> {
+        Ok(self.torrents.lock().unwrap().iter().find(|t| t.id == Some(id)).cloned())
+    }
+
+    async fn find_by_info_hash(&self, info_hash: &str) -> Result<Option<Torrent>, DomainError> {
+        Ok(self.torrents.lock().unwrap().iter().find(|t| t.info_hash == info_hash).cloned())
+    }
+
+    async fn save(&self, torrent: &Torrent) -> Result<Torrent, DomainError> {
+        let mut torrents = self.torrents.lock().unwrap();
+        let mut saved = torrent.clone();
+        saved.id = Some(torrents.len() as i32 + 1);
+        torrents.push(saved.clone());
+        Ok(saved)
+    }
+
+    async fn update(&self, torrent: &Torrent) -> Result<Torrent, DomainError> {
+        let mut torrents = self.torrents.lock().unwrap();
+        let existing = torrents.iter_mut().find(|t| t.id == torrent.id)
+            .ok_or_else(|| DomainError::NotFound("torrent".to_string()))?;
+        *existing = torrent.clone();
+        Ok(torrent.clone())
+    }
+
+    async fn delete(&self, id: i32) -> Result<(), DomainError> {
+        self.torrents.lock().unwrap().retain(|t| t.id != Some(id));
+        Ok(())
+    }
+
+    async fn find_all(&self) -> Result<Vec<Torrent>, DomainError> {
+        Ok(self.torrents.lock().unwrap().clone())
+    }
+
+    async fn find_active(&self) -> Result<Vec<Torrent>, DomainError> {
+        Ok(self.torrents.lock().unwrap().iter()
+            .filter(|t| !matches!(t.status, TorrentStatus::Paused | TorrentStatus::Queued | TorrentStatus::Completed))
+            .cloned()
+            .collect())
+    }
+}
+
+#[derive(Default)]
+pub struct MemoryPeerRepository {
+    peers: Mutex<Vec<Peer>>,
+}
+
+#[async_trait]
+impl PeerRepository for MemoryPeerRepository {
+    async fn find_by_torrent_id(&self, torrent_id: i32) -> Result<Vec<Peer>, DomainError> {
+        Ok(self.peers.lock().unwrap().iter().filter(|p| p.torrent_id == torrent_id).cloned().collect())
+    }
+
+    async fn find_connected(&self, torrent_id: i32) -> Result<Vec<Peer>, DomainError> {
+        Ok(self.peers.lock().unwrap().iter()
+            .filter(|p| p.torrent_id == torrent_id && p.is_connected())
+            .cloned()
+            .collect())
+    }
+
+    async fn save(&self, peer: &Peer) -> Result<Peer, DomainError> {
+        let mut peers = self.peers.lock().unwrap();
+        // Like the UNIQUE(torrent_id, ip, port) constraint of the peers table
+        if peers.iter().any(|p| p.torrent_id == peer.torrent_id && p.ip == peer.ip && p.port == peer.port) {
+            return Err(DomainError::RepositoryError(format!("Peer {} already saved", peer.socket_addr())));
+        }
+        let mut saved = peer.clone();
+        saved.id = Some(peers.len() as i32 + 1);
+        peers.push(saved.clone());
+        Ok(saved)
+    }
+
+    async fn update(&self, peer: &Peer) -> Result<Peer, DomainError> {
+        let mut peers = self.peers.lock().unwrap();
+        if let Some(existing) = peers.iter_mut().find(|p| p.id == peer.id) {
+            *existing = peer.clone();
+        }
+        Ok(peer.clone())
+    }
+
+    async fn save_batch(&self, peers: &[Peer]) -> Result<Vec<Peer>, DomainError> {
+        let mut saved = Vec::new();
+        for peer in peers {
+            saved.push(self.save(peer).await?);
+        }
+        Ok(saved)
+    }
+
+    async fn delete_old(&self, _torrent_id: i32, _hours: u32) -> Result<(), DomainError> {
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub struct MemoryPieceRepository {
+    pieces: Mutex<Vec<Piece>>,
+}
+
+impl MemoryPieceRepository {
+    pub fn with(pieces: Vec<Piece>) -> Self {
+        Self { pieces: Mutex::new(pieces) }
+    }
+}
+
+#[async_trait]
+impl PieceRepository for MemoryPieceRepository {
+    async fn find_by_torrent_id(&self, torrent_id: i32) -> Result<Vec<Piece>, DomainError> {
+        Ok(self.pieces.lock().unwrap().iter().filter(|p| p.torrent_id == torrent_id).cloned().collect())
+    }
+
+    async fn find_by_torrent_and_index(&self, torrent_id: i32, piece_index: i32) -> Result<Option<Piece>, DomainError> {
+        Ok(self.pieces.lock().unwrap().iter()
+            .find(|p| p.torrent_id == torrent_id && p.piece_index == piece_index)
+            .cloned())
+    }
+
+    async fn save(&self, piece: &Piece) -> Result<Piece, DomainError> {
+        let mut pieces = self.pieces.lock().unwrap();
+        let mut saved = piece.clone();
+        saved.id = Some(pieces.len() as i32 + 1);
+        pieces.push(saved.clone());
+        Ok(saved)
+    }
+
+    async fn update(&self, piece: &Piece) -> Result<Piece, DomainError> {
+        let mut pieces = self.pieces.lock().unwrap();
+        if let Some(existing) = pieces.iter_mut()
+            .find(|p| p.torrent_id == piece.torrent_id && p.piece_index == piece.piece_index)
+        {
+            *existing = piece.clone();
+        }
+        Ok(piece.clone())
+    }
+
+    async fn save_batch(&self, pieces: &[Piece]) -> Result<Vec<Piece>, DomainError> {
+        let mut saved = Vec::new();
+        for piece in pieces {
+            saved.push(self.save(piece).await?);
+        }
+        Ok(saved)
+    }
+
+    async fn update_batch(&self, pieces: &[Piece]) -> Result<(), DomainError> {
+        for piece in pieces {
+            self.update(piece).await?;
+        }
+        Ok(())
+    }
+
+    async fn count_downloaded(&self, torrent_id: i32) -> Result<i32, DomainError> {
+        Ok(self.pieces.lock().unwrap().iter().filter(|p| p.torrent_id == torrent_id && p.downloaded).count() as i32)
+    }
+
+    async fn find_next_needed(&self, torrent_id: i32, limit: i32) -> Result<Vec<Piece>, DomainError> {
+        Ok(self.pieces.lock().unwrap().iter()
+            .filter(|p| p.torrent_id == torrent_id && !p.downloaded)
+            .take(limit as usize)
+            .cloned()
+            .collect())
+    }
+}
+
+#[derive(Default)]
+pub struct MemoryTorrentFileRepository {
+    files: Mutex<Vec<TorrentFile>>,
+}
+
+impl MemoryTorrentFileRepository {
+    pub fn with(files: Vec<TorrentFile>) -> Self {
+        Self { files: Mutex::new(files) }
+    }
+}
+
+#[async_trait]
+impl TorrentFileRepository for MemoryTorrentFileRepository {
+    async fn find_by_torrent_id(&self, torrent_id: i32) -> Result<Vec<TorrentFile>, DomainError> {
+        let mut files: Vec<TorrentFile> = self.files.lock().unwrap().iter().filter(|f| f.torrent_id == torrent_id).cloned().collect();
+        files.sort_by_key(|f| f.file_index);
+        Ok(files)
+    }
+
+    async fn save_batch(&self, files: &[TorrentFile]) -> Result<Vec<TorrentFile>, DomainError> {
+        self.files.lock().unwrap().extend_from_slice(files);
+        Ok(files.to_vec())
+    }
+
+    async fn update(&self, file: &TorrentFile) -> Result<TorrentFile, DomainError> {
+        let mut files = self.files.lock().unwrap();
+        if let Some(existing) = files.iter_mut()
+            .find(|f| f.torrent_id == file.torrent_id && f.file_index == file.file_index)
+        {
+            *existing = file.clone();
+        }
+        Ok(file.clone())
+    }
+}
+
+#[derive(Default)]
+pub struct MemoryTrackerRepository {
+    trackers: Mutex<Vec<Tracker>>,
+}
+
+#[async_trait]
+impl TrackerRepository for MemoryTrackerRepository {
+    async fn find_by_torrent_id(&self, torrent_id: i32) -> Result<Vec<Tracker>, DomainError> {
+        Ok(self.trackers.lock().unwrap().iter().filter(|t| t.torrent_id == torrent_id).cloned().collect())
+    }
+
+    async fn find_active(&self, torrent_id: i32) -> Result<Vec<Tracker>, DomainError> {
+        self.find_by_torrent_id(torrent_id).await
+    }
+
+    async fn save(&self, tracker: &Tracker) -> Result<Tracker, DomainError> {
+        self.trackers.lock().unwrap().push(tracker.clone());
+        Ok(tracker.clone())
+    }
+
+    async fn update(&self, tracker: &Tracker) -> Result<Tracker, DomainError> {
+        Ok(tracker.clone())
+    }
+
+    async fn save_batch(&self, trackers: &[Tracker]) -> Result<Vec<Tracker>, DomainError> {
+        self.trackers.lock().unwrap().extend_from_slice(trackers);
+        Ok(trackers.to_vec())
+    }
+}
+
+#[derive(Default)]
+pub struct MemoryFileSnapshotRepository {
+    snapshots: Mutex<Vec<FileSnapshot>>,
+}
+
+#[async_trait]
+impl FileSnapshotRepository for MemoryFileSnapshotRepository {
+    async fn find_by_torrent_id(&self, torrent_id: i32) -> Result<Vec<FileSnapshot>, DomainError> {
+        Ok(self.snapshots.lock().unwrap().iter().filter(|s| s.torrent_id == torrent_id).cloned().collect())
+    }
+
+    async fn replace_for_torrent(&self, torrent_id: i32, snapshots: &[FileSnapshot]) -> Result<(), DomainError> {
+        let mut saved = self.snapshots.lock().unwrap();
+        saved.retain(|s| s.torrent_id != torrent_id);
+        saved.extend_from_slice(snapshots);
+        Ok(())
+    }
+}
+
+/// A fresh directory under the system temp dir
+pub fn temp_dir(name: &str) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("{}-{}-{}", name, std::process::id(), rand_suffix()));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn rand_suffix() -> u64 {
+    use std::time::{SystemTime, UNIX_EPOCH};
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos() as u64
+}
+
+pub fn torrent(id: i32, info_hash: [u8; 20], status: TorrentStatus) -> Torrent {
+    let mut torrent = Torrent::new(
+        hex::encode(info_hash),
+        format!("torrent-{}", id),
+        4 * 16384,
+        16384,
+        4,
+    );
+    torrent.id = Some(id);
+    torrent.status = status;
+    torrent
+}
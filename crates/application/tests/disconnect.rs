@@ -0,0 +1,49 @@
+mod common;
+
+use common::{
+    temp_dir, torrent, MemoryPeerRepository, MemoryPieceRepository, MemoryTorrentFileRepository,
+    MemoryTorrentRepository, MemoryTrackerRepository,
+};
+use domain::*;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::DuplexStream;
+
+async fn connect(manager: &ConnectionManager, torrent_id: i32, port: u16) -> (PeerHandle, DuplexStream) {
+    let (local, remote) = tokio::io::duplex(1 << 16);
+    let peer = Peer::new(torrent_id, "10.0.0.1".to_string(), port);
+    let handle = manager
+        .register(local, peer, Handshake::new([torrent_id as u8; 20], [port as u8; 20]))
+        .await
+        .unwrap();
+    (handle, remote)
+}
+
+#[tokio::test]
+async fn pausing_or_removing_a_torrent_closes_its_connections() {
+    let torrents: Arc<dyn TorrentRepository> = Arc::new(MemoryTorrentRepository::with(vec![
+        torrent(1, [1u8; 20], TorrentStatus::Downloading),
+        torrent(2, [2u8; 20], TorrentStatus::Downloading),
+    ]));
+    let manager = Arc::new(ConnectionManager::new(Arc::new(MemoryPeerRepository::default()), torrents.clone()));
+    let service = TorrentService::new(
+        torrents.clone(),
+        Arc::new(MemoryPieceRepository::with(Vec::new())),
+        Arc::new(MemoryTrackerRepository::default()),
+        Arc::new(MemoryTorrentFileRepository::with(Vec::new())),
+        Arc::new(TorrentStorage::new(torrents, temp_dir("disconnect"))),
+    )
+    .with_connection_manager(manager.clone());
+
+    let (paused, _paused_remote) = connect(&manager, 1, 7001).await;
+    let (removed, _removed_remote) = connect(&manager, 2, 7002).await;
+
+    service.pause_torrent(1).await.unwrap();
+    tokio::time::timeout(Duration::from_secs(5), paused.closed()).await.unwrap();
+    assert_eq!(manager.connection_count(1), 0);
+    assert!(!removed.is_closed());
+
+    service.remove_torrent(2, false).await.unwrap();
+    tokio::time::timeout(Duration::from_secs(5), removed.closed()).await.unwrap();
+    assert_eq!(manager.connection_count(2), 0);
+}
@@ -0,0 +1,176 @@
+mod common;
+
+use common::{
+    temp_dir, torrent, MemoryPeerRepository, MemoryPieceRepository, MemoryTorrentFileRepository,
+    MemoryTorrentRepository, MemoryTrackerRepository,
+};
+use domain::*;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Every torrent from `common::torrent` is this large
+const TORRENT_SIZE: usize = 4 * 16384;
+
+fn service(
+    torrents: Vec<Torrent>,
+    files: Vec<TorrentFile>,
+    download_dir: &Path,
+    disk: Option<Arc<MemoryBackend>>,
+    disk_policy: DiskPolicy,
+) -> (TorrentService, Arc<dyn TorrentRepository>, Arc<TorrentStorage>) {
+    let torrents: Arc<dyn TorrentRepository> = Arc::new(MemoryTorrentRepository::with(torrents));
+    let mut storage = TorrentStorage::new(torrents.clone(), download_dir)
+        .with_file_repository(Arc::new(MemoryTorrentFileRepository::with(files.clone())));
+    // Stands in for a disk with exactly its capacity free
+    if let Some(disk) = disk {
+        storage = storage.with_backend(StorageKind::Disk, disk);
+    }
+    let storage = Arc::new(storage);
+    let service = TorrentService::new(
+        torrents.clone(),
+        Arc::new(MemoryPieceRepository::with(Vec::new())),
+        Arc::new(MemoryTrackerRepository::default()),
+        Arc::new(MemoryTorrentFileRepository::with(files)),
+        storage.clone(),
+    )
+    .with_disk_policy(disk_policy);
+    (service, torrents, storage)
+}
+
+#[tokio::test]
+async fn sparse_preallocation_creates_wanted_files_at_full_length() {
+    let download_dir = temp_dir("preallocate");
+    let mut skipped = TorrentFile::new(1, 1, "extras/sample.mkv".to_string(), 16384, 49152);
+    skipped.priority = FilePriority::Skip;
+    let files = vec![TorrentFile::new(1, 0, "movie.mkv".to_string(), 49152, 0), skipped];
+    let (_, _, storage) = service(
+        vec![torrent(1, [1u8; 20], TorrentStatus::Parsing)],
+        files,
+        &download_dir,
+        None,
+        DiskPolicy::default(),
+    );
+
+    storage.preallocate(1, Preallocation::Sparse).await.unwrap();
+
+    let movie = download_dir.join("torrent-1").join("movie.mkv");
+    assert_eq!(std::fs::metadata(movie).unwrap().len(), 49152);
+    assert!(!download_dir.join("torrent-1").join("extras").exists());
+}
+
+#[tokio::test]
+async fn downloads_stop_with_an_error_when_space_runs_low() {
+    let disk = Arc::new(MemoryBackend::new(10 * 1024 * 1024));
+    let mut streamed = torrent(3, [3u8; 20], TorrentStatus::Downloading);
+    streamed.storage = StorageKind::Memory;
+    let (service, torrents, _) = service(
+        vec![
+            torrent(1, [1u8; 20], TorrentStatus::Downloading),
+            torrent(2, [2u8; 20], TorrentStatus::Paused),
+            streamed,
+        ],
+        Vec::new(),
+        &temp_dir("low-space"),
+        Some(disk),
+        DiskPolicy { min_free_space: 64 * 1024 * 1024, ..DiskPolicy::default() },
+    );
+    let manager = Arc::new(ConnectionManager::new(Arc::new(MemoryPeerRepository::default()), torrents.clone()));
+    let service = service.with_connection_manager(manager.clone());
+    let (local, _remote) = tokio::io::duplex(1 << 16);
+    let peer = manager
+        .register(local, Peer::new(1, "10.0.0.1".to_string(), 7001), Handshake::new([1u8; 20], [9u8; 20]))
+        .await
+        .unwrap();
+
+    assert!(service.is_low_on_space().await.unwrap());
+    assert_eq!(service.pause_if_low_on_space().await.unwrap(), [1]);
+    tokio::time::timeout(Duration::from_secs(5), peer.closed()).await.unwrap();
+    assert_eq!(manager.connection_count(1), 0);
+
+    let stopped = torrents.find_by_id(1).await.unwrap().unwrap();
+    assert_eq!(
+        stopped.status,
+        TorrentStatus::Error("Paused: only 10 MiB free on disk, below the 64 MiB minimum".to_string())
+    );
+    assert!(stopped.is_stopped());
+    assert_eq!(torrents.find_by_id(2).await.unwrap().unwrap().status, TorrentStatus::Paused);
+    assert_eq!(torrents.find_by_id(3).await.unwrap().unwrap().status, TorrentStatus::Downloading);
+}
+
+#[tokio::test]
+async fn queued_torrents_start_once_they_fit() {
+    let disk = Arc::new(MemoryBackend::new(4 * TORRENT_SIZE));
+    let policy = DiskPolicy {
+        preallocation: Preallocation::Full,
+        when_full: DiskFullPolicy::Queue,
+        min_free_space: 2 * TORRENT_SIZE as u64,
+    };
+    let (service, torrents, _) = service(
+        vec![torrent(1, [1u8; 20], TorrentStatus::Queued)],
+        Vec::new(),
+        &temp_dir("queued"),
+        Some(disk.clone()),
+        policy,
+    );
+
+    // Another download holds half the disk
+    let other = Path::new("other.bin");
+    disk.write_span(other, 0, &vec![0u8; 2 * TORRENT_SIZE]).await.unwrap();
+    assert!(service.start_queued().await.unwrap().is_empty());
+    assert_eq!(torrents.find_by_id(1).await.unwrap().unwrap().status, TorrentStatus::Queued);
+
+    disk.delete(other).await.unwrap();
+    assert_eq!(service.start_queued().await.unwrap(), [1]);
+    assert_eq!(torrents.find_by_id(1).await.unwrap().unwrap().status, TorrentStatus::Connecting);
+    // Files are allocated when the download starts, not while unqueuing
+    assert_eq!(disk.used(), 0);
+    service.preallocate(1).await.unwrap();
+    assert_eq!(disk.used(), TORRENT_SIZE);
+}
+
+#[tokio::test]
+async fn space_other_downloads_still_need_is_not_free() {
+    // Room for one and a half torrents, nothing written yet
+    let disk = Arc::new(MemoryBackend::new(TORRENT_SIZE * 3 / 2));
+    let policy = DiskPolicy { when_full: DiskFullPolicy::Queue, min_free_space: 0, ..DiskPolicy::default() };
+    let (service, torrents, _) = service(
+        vec![
+            torrent(1, [1u8; 20], TorrentStatus::Downloading),
+            torrent(2, [2u8; 20], TorrentStatus::Queued),
+        ],
+        Vec::new(),
+        &temp_dir("committed-space"),
+        Some(disk),
+        policy,
+    );
+
+    // The sparse files of torrent 1 take no space yet but will
+    assert!(service.start_queued().await.unwrap().is_empty());
+
+    // Half done, half of it is still to come
+    let mut downloading = torrents.find_by_id(1).await.unwrap().unwrap();
+    downloading.progress = 0.5;
+    torrents.update(&downloading).await.unwrap();
+    assert_eq!(service.start_queued().await.unwrap(), [2]);
+}
+
+#[tokio::test]
+async fn failed_preallocation_stops_the_torrent() {
+    // Files cannot be created below a regular file
+    let download_dir = temp_dir("preallocation-failure").join("not-a-directory");
+    std::fs::write(&download_dir, b"").unwrap();
+    let (service, torrents, _) = service(
+        vec![torrent(1, [1u8; 20], TorrentStatus::Connecting)],
+        Vec::new(),
+        &download_dir,
+        None,
+        DiskPolicy { min_free_space: 0, ..DiskPolicy::default() },
+    );
+
+    assert!(service.preallocate(1).await.is_err());
+    match torrents.find_by_id(1).await.unwrap().unwrap().status {
+        TorrentStatus::Error(message) => assert!(message.starts_with("Preallocation failed"), "{}", message),
+        status => panic!("status {:?}", status),
+    }
+}
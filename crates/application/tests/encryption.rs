@@ -0,0 +1,74 @@
+mod common;
+
+use application::PeerListener;
+use common::{torrent, MemoryPeerRepository, MemoryTorrentRepository};
+use domain::*;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpListener;
+
+const INFO_HASH: [u8; 20] = [4u8; 20];
+
+fn manager(policy: EncryptionPolicy) -> Arc<ConnectionManager> {
+    let torrents: Arc<dyn TorrentRepository> = Arc::new(MemoryTorrentRepository::with(vec![torrent(
+        1,
+        INFO_HASH,
+        TorrentStatus::Downloading,
+    )]));
+    let peers: Arc<dyn PeerRepository> = Arc::new(MemoryPeerRepository::default());
+    Arc::new(ConnectionManager::new(peers, torrents).with_encryption(policy))
+}
+
+#[tokio::test]
+async fn forced_encryption_connects_outbound_to_inbound() {
+    let torrents: Arc<dyn TorrentRepository> = Arc::new(MemoryTorrentRepository::with(vec![torrent(
+        1,
+        INFO_HASH,
+        TorrentStatus::Downloading,
+    )]));
+    let peers: Arc<dyn PeerRepository> = Arc::new(MemoryPeerRepository::default());
+    let server = Arc::new(ConnectionManager::new(peers, torrents.clone()).with_encryption(EncryptionPolicy::Forced));
+    let listener = PeerListener::bind("127.0.0.1:0".parse().unwrap(), torrents, server.clone())
+        .await
+        .unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(listener.run());
+
+    let client = manager(EncryptionPolicy::Forced);
+    let peer = Peer::new(1, "127.0.0.1".to_string(), address.port());
+    let handle = client.connect(peer, &hex::encode(INFO_HASH)).await.unwrap();
+    assert_eq!(handle.remote_peer_id(), server.local_peer_id());
+
+    let registered = async {
+        while server.connection_count(1) == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(5), registered).await.unwrap();
+    assert_eq!(server.handles(1)[0].remote_peer_id(), client.local_peer_id());
+}
+
+#[tokio::test]
+async fn enabled_encryption_falls_back_to_plaintext() {
+    // A peer that only understands plaintext handshakes
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut start = [0u8; 68];
+        stream.read_exact(&mut start).await.unwrap();
+        assert!(Handshake::from_bytes(&start).is_err(), "first attempt should be encrypted");
+        drop(stream);
+
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let remote = Handshake::read_from(&mut stream).await.unwrap();
+        Handshake::new(remote.info_hash, [8u8; 20]).write_to(&mut stream).await.unwrap();
+        tokio::time::sleep(Duration::from_secs(5)).await;
+    });
+
+    let client = manager(EncryptionPolicy::Enabled);
+    let peer = Peer::new(1, "127.0.0.1".to_string(), address.port());
+    let handle = client.connect(peer, &hex::encode(INFO_HASH)).await.unwrap();
+    assert_eq!(handle.remote_peer_id(), [8u8; 20]);
+}
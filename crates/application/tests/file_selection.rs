@@ -0,0 +1,57 @@
+mod common;
+
+use common::{temp_dir, MemoryPieceRepository, MemoryTorrentFileRepository, MemoryTorrentRepository, MemoryTrackerRepository};
+use domain::*;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// A .torrent with two 100-byte files in one piece
+fn two_file_torrent() -> Vec<u8> {
+    let mut data = b"d8:announce23:http://tracker/announce4:infod5:filesl".to_vec();
+    data.extend_from_slice(b"d6:lengthi100e4:pathl5:a.txteed6:lengthi100e4:pathl5:b.txteee");
+    data.extend_from_slice(b"4:name6:folder12:piece lengthi16384e6:pieces20:");
+    data.extend_from_slice(&[7u8; 20]);
+    data.extend_from_slice(b"ee");
+    data
+}
+
+fn service() -> TorrentService {
+    let torrents: Arc<dyn TorrentRepository> = Arc::new(MemoryTorrentRepository::with(Vec::new()));
+    let files: Arc<dyn TorrentFileRepository> = Arc::new(MemoryTorrentFileRepository::with(Vec::new()));
+    let storage = TorrentStorage::new(torrents.clone(), temp_dir("file-selection")).with_file_repository(files.clone());
+    TorrentService::new(
+        torrents,
+        Arc::new(MemoryPieceRepository::with(Vec::new())),
+        Arc::new(MemoryTrackerRepository::default()),
+        files,
+        Arc::new(storage),
+    )
+    // Independent of how full the disk running the test is
+    .with_disk_policy(DiskPolicy { min_free_space: 0, ..DiskPolicy::default() })
+}
+
+#[tokio::test]
+async fn selections_of_files_the_torrent_lacks_are_rejected() {
+    let service = service();
+    let selections = [
+        FileSelection { only: Some(vec![0, 2]), priorities: HashMap::new() },
+        FileSelection { only: None, priorities: HashMap::from([(5, FilePriority::High)]) },
+    ];
+
+    for selection in selections {
+        let result = service.add_torrent_from_file_with(two_file_torrent(), &selection, StorageKind::Disk).await;
+        match result {
+            Err(DomainError::ValidationError(message)) => assert!(message.contains("no file"), "{}", message),
+            other => panic!("{:?} gave {:?}", selection, other),
+        }
+    }
+    assert!(service.get_all_torrents().await.unwrap().is_empty());
+
+    let selection = FileSelection { only: Some(vec![1]), priorities: HashMap::new() };
+    let torrent = service.add_torrent_from_file_with(two_file_torrent(), &selection, StorageKind::Disk).await.unwrap();
+    let priorities: Vec<FilePriority> = service.get_files(torrent.id.unwrap()).await.unwrap()
+        .iter()
+        .map(|file| file.priority)
+        .collect();
+    assert_eq!(priorities, [FilePriority::Skip, FilePriority::Normal]);
+}
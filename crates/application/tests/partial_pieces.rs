@@ -0,0 +1,92 @@
+mod common;
+
+use common::{temp_dir, MemoryPeerRepository, MemoryPieceRepository, MemoryTorrentFileRepository, MemoryTorrentRepository};
+use domain::*;
+use sha1::Digest;
+use std::sync::Arc;
+
+const PIECE_LENGTH: u32 = 4 * BLOCK_SIZE;
+
+fn content() -> Vec<u8> {
+    (0..PIECE_LENGTH).map(|i| (i % 251) as u8).collect()
+}
+
+fn block(data: &[u8], block_index: u32) -> &[u8] {
+    &data[(block_index * BLOCK_SIZE) as usize..((block_index + 1) * BLOCK_SIZE) as usize]
+}
+
+#[tokio::test]
+async fn unfinished_pieces_resume_from_stored_blocks() {
+    let download_dir = temp_dir("partial-pieces").to_string_lossy().to_string();
+    let data = content();
+
+    let mut torrent = Torrent::new(hex::encode([3u8; 20]), "torrent-1".to_string(), PIECE_LENGTH as i64, PIECE_LENGTH as i32, 1);
+    torrent.id = Some(1);
+    let torrents: Arc<dyn TorrentRepository> = Arc::new(MemoryTorrentRepository::with(vec![torrent]));
+    let pieces: Arc<dyn PieceRepository> = Arc::new(MemoryPieceRepository::with(vec![
+        Piece::new(1, 0, hex::encode(sha1::Sha1::digest(&data))),
+    ]));
+
+    // Blocks 0 and 2 arrive, then the server stops
+    let before = PieceManager::new(pieces.clone(), torrents.clone(), download_dir.clone());
+    let download = before.begin_download(1, 0, PIECE_LENGTH).await.unwrap();
+    download.add_block(0, block(&data, 0)).unwrap();
+    download.add_block(2 * BLOCK_SIZE, block(&data, 2)).unwrap();
+    assert_eq!(before.checkpoint_partial_pieces().await.unwrap(), 1);
+    assert_eq!(before.checkpoint_partial_pieces().await.unwrap(), 0, "nothing new to save");
+
+    let stored = pieces.find_by_torrent_and_index(1, 0).await.unwrap().unwrap();
+    assert_eq!(stored.received_blocks, Some(vec![0b1010_0000]));
+
+    // After the restart only the other two blocks are requested
+    let after = PieceManager::new(pieces.clone(), torrents, download_dir);
+    let download = after.begin_download(1, 0, PIECE_LENGTH).await.unwrap();
+    let missing: Vec<u32> = download.missing_blocks().iter().map(|request| request.begin).collect();
+    assert_eq!(missing, vec![BLOCK_SIZE, 3 * BLOCK_SIZE]);
+
+    download.add_block(BLOCK_SIZE, block(&data, 1)).unwrap();
+    download.add_block(3 * BLOCK_SIZE, block(&data, 3)).unwrap();
+    let assembled = download.take_data().unwrap();
+    assert_eq!(assembled, data);
+
+    after.mark_piece_completed(1, 0, assembled).await.unwrap();
+    let completed = pieces.find_by_torrent_and_index(1, 0).await.unwrap().unwrap();
+    assert!(completed.is_complete());
+    assert_eq!(completed.received_blocks, None);
+}
+
+#[tokio::test]
+async fn pieces_with_stored_blocks_are_picked_first_after_a_restart() {
+    let mut torrent = Torrent::new(hex::encode([4u8; 20]), "torrent-1".to_string(), 8 * PIECE_LENGTH as i64, PIECE_LENGTH as i32, 8);
+    torrent.id = Some(1);
+    let torrents: Arc<dyn TorrentRepository> = Arc::new(MemoryTorrentRepository::with(vec![torrent]));
+    let pieces: Arc<dyn PieceRepository> = Arc::new(MemoryPieceRepository::with(
+        (0..8)
+            .map(|index| {
+                let mut piece = Piece::new(1, index, hex::encode([index as u8; 20]));
+                piece.id = Some(index + 1);
+                if index == 5 {
+                    piece.received_blocks = Some(vec![0b1000_0000]);
+                }
+                piece
+            })
+            .collect(),
+    ));
+
+    let piece_manager = Arc::new(PieceManager::new(
+        pieces.clone(),
+        torrents.clone(),
+        temp_dir("partial-picks").to_string_lossy().to_string(),
+    ));
+    let picker = PiecePicker::new(
+        pieces,
+        torrents.clone(),
+        Arc::new(MemoryTorrentFileRepository::with(Vec::new())),
+        piece_manager.clone(),
+        Arc::new(ConnectionManager::new(Arc::new(MemoryPeerRepository::default()), torrents)),
+    );
+
+    let picked: Vec<i32> = picker.next_pieces(1, 1).await.unwrap().iter().map(|piece| piece.piece_index).collect();
+    assert_eq!(picked, [5]);
+    assert_eq!(piece_manager.partial_pieces(1).into_iter().collect::<Vec<_>>(), [5]);
+}
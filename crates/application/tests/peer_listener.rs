@@ -0,0 +1,140 @@
+mod common;
+
+use application::PeerListener;
+use common::{torrent, MemoryPeerRepository, MemoryTorrentRepository};
+use domain::*;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpStream;
+
+const ACTIVE: [u8; 20] = [1u8; 20];
+const PAUSED: [u8; 20] = [2u8; 20];
+const UNKNOWN: [u8; 20] = [3u8; 20];
+
+async fn start_listener() -> (SocketAddr, Arc<ConnectionManager>) {
+    start_listener_with_cap(domain::services::connection_manager::DEFAULT_MAX_CONNECTIONS_PER_TORRENT).await
+}
+
+async fn start_listener_with_cap(max_connections: usize) -> (SocketAddr, Arc<ConnectionManager>) {
+    let torrents: Arc<dyn TorrentRepository> = Arc::new(MemoryTorrentRepository::with(vec![
+        torrent(1, ACTIVE, TorrentStatus::Downloading),
+        torrent(2, PAUSED, TorrentStatus::Paused),
+    ]));
+    let peers: Arc<dyn PeerRepository> = Arc::new(MemoryPeerRepository::default());
+    let manager = Arc::new(
+        ConnectionManager::new(peers, torrents.clone()).with_max_connections_per_torrent(max_connections),
+    );
+
+    let listener = PeerListener::bind("127.0.0.1:0".parse().unwrap(), torrents, manager.clone())
+        .await
+        .unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(listener.run());
+    (address, manager)
+}
+
+async fn handshake(address: SocketAddr, info_hash: [u8; 20]) -> (TcpStream, Option<Handshake>) {
+    let mut stream = TcpStream::connect(address).await.unwrap();
+    Handshake::new(info_hash, [9u8; 20]).write_to(&mut stream).await.unwrap();
+    let reply = tokio::time::timeout(Duration::from_secs(5), Handshake::read_from(&mut stream))
+        .await
+        .unwrap()
+        .ok();
+    (stream, reply)
+}
+
+#[tokio::test]
+async fn accepts_peers_for_active_torrents() {
+    let (address, manager) = start_listener().await;
+
+    let (_stream, reply) = handshake(address, ACTIVE).await;
+    let reply = reply.expect("listener should answer the handshake");
+    assert_eq!(reply.info_hash, ACTIVE);
+    assert_eq!(reply.peer_id, manager.local_peer_id());
+
+    // The connection is handed to the same connection manager as outbound peers
+    let registered = async {
+        while manager.connection_count(1) == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(5), registered).await.unwrap();
+    assert_eq!(manager.handles(1)[0].remote_peer_id(), [9u8; 20]);
+}
+
+#[tokio::test]
+async fn rejects_unknown_and_paused_torrents() {
+    let (address, manager) = start_listener().await;
+
+    for info_hash in [UNKNOWN, PAUSED] {
+        let (mut stream, reply) = handshake(address, info_hash).await;
+        assert!(reply.is_none());
+        let mut buf = [0u8; 1];
+        assert_eq!(stream.read(&mut buf).await.unwrap_or(0), 0);
+    }
+
+    assert_eq!(manager.connection_count(2), 0);
+}
+
+#[tokio::test]
+async fn rejects_peers_beyond_the_connection_cap() {
+    let (address, manager) = start_listener_with_cap(1).await;
+
+    let (_first, reply) = handshake(address, ACTIVE).await;
+    assert!(reply.is_some());
+    let registered = async {
+        while manager.connection_count(1) == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(5), registered).await.unwrap();
+
+    let (_second, reply) = handshake(address, ACTIVE).await;
+    assert!(reply.is_none());
+    assert_eq!(manager.connection_count(1), 1);
+}
+
+#[tokio::test]
+async fn inbound_peers_reuse_known_rows_and_are_not_dialed_back() {
+    let torrents: Arc<dyn TorrentRepository> =
+        Arc::new(MemoryTorrentRepository::with(vec![torrent(1, ACTIVE, TorrentStatus::Downloading)]));
+    let peers = Arc::new(MemoryPeerRepository::default());
+    let manager = Arc::new(ConnectionManager::new(peers.clone(), torrents.clone()));
+    let listener = PeerListener::bind("127.0.0.1:0".parse().unwrap(), torrents, manager.clone())
+        .await
+        .unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(listener.run());
+    let connected = |count: usize| {
+        let manager = manager.clone();
+        async move {
+            while manager.connection_count(1) < count {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }
+    };
+
+    // A tracker already returned the address this peer connects from
+    let socket = tokio::net::TcpSocket::new_v4().unwrap();
+    socket.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+    let known = socket.local_addr().unwrap();
+    peers.save(&Peer::new(1, "127.0.0.1".to_string(), known.port())).await.unwrap();
+    let mut stream = socket.connect(address).await.unwrap();
+    Handshake::new(ACTIVE, [9u8; 20]).write_to(&mut stream).await.unwrap();
+    Handshake::read_from(&mut stream).await.unwrap();
+    tokio::time::timeout(Duration::from_secs(5), connected(1)).await.unwrap();
+
+    let saved = peers.find_by_torrent_id(1).await.unwrap();
+    assert_eq!(saved.len(), 1);
+    assert!(saved[0].is_connected() && !saved[0].inbound);
+
+    // An unknown peer gets a row of its own that is never dialed
+    let (_other, reply) = handshake(address, ACTIVE).await;
+    assert!(reply.is_some());
+    tokio::time::timeout(Duration::from_secs(5), connected(2)).await.unwrap();
+    let inbound: Vec<Peer> = peers.find_by_torrent_id(1).await.unwrap().into_iter().filter(|peer| peer.inbound).collect();
+    assert_eq!(inbound.len(), 1);
+    assert_ne!(inbound[0].port, known.port());
+}
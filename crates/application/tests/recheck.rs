@@ -0,0 +1,123 @@
+mod common;
+
+use common::{
+    temp_dir, MemoryPieceRepository, MemoryTorrentFileRepository, MemoryTorrentRepository, MemoryTrackerRepository,
+};
+use domain::*;
+use sha1::Digest;
+use std::sync::Arc;
+
+const PIECE_LENGTH: i32 = 64;
+
+/// A 200-byte torrent whose file on disk holds every piece but the third,
+/// while the database believes only the third is there
+fn stale_torrent(status: TorrentStatus) -> (TorrentService, Arc<MemoryPieceRepository>, std::path::PathBuf) {
+    let download_dir = temp_dir("recheck");
+    let data: Vec<u8> = (0..200).map(|i| (i * 13 % 256) as u8).collect();
+    let mut on_disk = data.clone();
+    on_disk[130] ^= 0xff;
+    std::fs::write(download_dir.join("album.flac"), &on_disk).unwrap();
+
+    let mut torrent = Torrent::new(hex::encode([3u8; 20]), "album.flac".to_string(), 200, PIECE_LENGTH, 4);
+    torrent.id = Some(1);
+    torrent.status = status;
+    let pieces: Vec<Piece> = data
+        .chunks(PIECE_LENGTH as usize)
+        .enumerate()
+        .map(|(index, chunk)| {
+            let mut piece = Piece::new(1, index as i32, hex::encode(sha1::Sha1::digest(chunk)));
+            piece.id = Some(index as i32 + 1);
+            if index == 2 {
+                piece.mark_downloaded();
+                piece.mark_verified(true);
+            }
+            piece
+        })
+        .collect();
+
+    let torrents: Arc<dyn TorrentRepository> = Arc::new(MemoryTorrentRepository::with(vec![torrent]));
+    let pieces = Arc::new(MemoryPieceRepository::with(pieces));
+    let files: Arc<dyn TorrentFileRepository> = Arc::new(MemoryTorrentFileRepository::with(Vec::new()));
+    let storage = Arc::new(TorrentStorage::new(torrents.clone(), &download_dir));
+    let service = TorrentService::new(
+        torrents,
+        pieces.clone(),
+        Arc::new(MemoryTrackerRepository::default()),
+        files,
+        storage,
+    );
+    (service, pieces, download_dir)
+}
+
+#[tokio::test]
+async fn recheck_rebuilds_piece_state_from_disk() {
+    let (service, pieces, _) = stale_torrent(TorrentStatus::Downloading);
+
+    let torrent = service.recheck(1).await.unwrap();
+    assert_eq!(torrent.status, TorrentStatus::Downloading);
+    assert_eq!(torrent.progress, 0.75);
+
+    let complete: Vec<i32> = pieces.find_by_torrent_id(1).await.unwrap()
+        .iter()
+        .filter(|piece| piece.is_complete())
+        .map(|piece| piece.piece_index)
+        .collect();
+    assert_eq!(complete, [0, 1, 3]);
+}
+
+#[tokio::test]
+async fn recheck_keeps_paused_torrents_paused() {
+    let (service, _, download_dir) = stale_torrent(TorrentStatus::Paused);
+    std::fs::remove_file(download_dir.join("album.flac")).unwrap();
+
+    let torrent = service.recheck(1).await.unwrap();
+    assert_eq!(torrent.status, TorrentStatus::Paused);
+    assert_eq!(torrent.progress, 0.0);
+}
+
+#[tokio::test]
+async fn recheck_only_moves_running_torrents_between_downloading_and_complete() {
+    // Everything intact: a seeding torrent keeps seeding
+    let (service, _, download_dir) = stale_torrent(TorrentStatus::Seeding);
+    let data: Vec<u8> = (0..200).map(|i| (i * 13 % 256) as u8).collect();
+    std::fs::write(download_dir.join("album.flac"), &data).unwrap();
+    let torrent = service.recheck(1).await.unwrap();
+    assert_eq!((torrent.status, torrent.progress), (TorrentStatus::Seeding, 1.0));
+
+    // A completed torrent missing a piece has to download it again
+    let (service, _, _) = stale_torrent(TorrentStatus::Completed);
+    assert_eq!(service.recheck(1).await.unwrap().status, TorrentStatus::Downloading);
+
+    for status in [TorrentStatus::Queued, TorrentStatus::Error("disk full".to_string())] {
+        let (service, _, _) = stale_torrent(status.clone());
+        let torrent = service.recheck(1).await.unwrap();
+        assert_eq!(torrent.status, status);
+        assert_eq!(torrent.progress, 0.75);
+    }
+}
+
+#[tokio::test]
+async fn torrents_are_checking_as_soon_as_a_recheck_is_accepted() {
+    let (service, _, _) = stale_torrent(TorrentStatus::Downloading);
+
+    let recheck = service.begin_recheck(1).await.unwrap();
+    assert_eq!(recheck.torrent.status, TorrentStatus::Checking);
+    let stored = service.get_torrent(1).await.unwrap();
+    assert_eq!((stored.status.clone(), stored.progress), (TorrentStatus::Checking, 0.0));
+    assert!(stored.is_stopped());
+    assert!(matches!(service.begin_recheck(1).await, Err(DomainError::ValidationError(_))));
+
+    let torrent = service.finish_recheck(recheck).await.unwrap();
+    assert_eq!(torrent.status, TorrentStatus::Downloading);
+}
+
+#[tokio::test]
+async fn pausing_during_a_recheck_sticks() {
+    let (service, _, _) = stale_torrent(TorrentStatus::Downloading);
+
+    let recheck = service.begin_recheck(1).await.unwrap();
+    service.pause_torrent(1).await.unwrap();
+    let torrent = service.finish_recheck(recheck).await.unwrap();
+    assert_eq!(torrent.status, TorrentStatus::Paused);
+    assert_eq!(torrent.progress, 0.75);
+}
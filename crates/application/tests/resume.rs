@@ -0,0 +1,85 @@
+mod common;
+
+use common::{temp_dir, MemoryFileSnapshotRepository, MemoryPieceRepository, MemoryTorrentRepository};
+use domain::*;
+use sha1::Digest;
+use std::sync::Arc;
+
+const PIECE_LENGTH: i32 = 64;
+
+struct Fixture {
+    download_dir: std::path::PathBuf,
+    pieces: Arc<MemoryPieceRepository>,
+    piece_manager: PieceManager,
+    storage: Arc<TorrentStorage>,
+    validator: ResumeValidator,
+    torrent: Torrent,
+}
+
+/// A 200-byte single-file torrent of four pieces, every piece downloaded
+async fn downloaded(storage_kind: StorageKind) -> (Fixture, Vec<u8>) {
+    let download_dir = temp_dir("resume");
+    let data: Vec<u8> = (0..200).map(|i| (i * 7 % 256) as u8).collect();
+    let mut torrent = Torrent::new(hex::encode([9u8; 20]), "movie.mkv".to_string(), 200, PIECE_LENGTH, 4);
+    torrent.id = Some(1);
+    torrent.storage = storage_kind;
+    let pieces = Arc::new(MemoryPieceRepository::with(
+        data.chunks(PIECE_LENGTH as usize)
+            .enumerate()
+            .map(|(index, chunk)| Piece::new(1, index as i32, hex::encode(sha1::Sha1::digest(chunk))))
+            .collect(),
+    ));
+    let torrents: Arc<dyn TorrentRepository> = Arc::new(MemoryTorrentRepository::with(vec![torrent.clone()]));
+    let storage = Arc::new(
+        TorrentStorage::new(torrents.clone(), &download_dir)
+            .with_snapshot_repository(Arc::new(MemoryFileSnapshotRepository::default())),
+    );
+    let piece_manager = PieceManager::new(pieces.clone(), torrents, download_dir.to_string_lossy().to_string())
+        .with_storage(storage.clone());
+    for (index, chunk) in data.chunks(PIECE_LENGTH as usize).enumerate() {
+        piece_manager.mark_piece_completed(1, index, chunk.to_vec()).await.unwrap();
+    }
+    let validator = ResumeValidator::new(pieces.clone(), storage.clone());
+    (Fixture { download_dir, pieces, piece_manager, storage, validator, torrent }, data)
+}
+
+async fn complete_pieces(pieces: &MemoryPieceRepository) -> usize {
+    pieces.find_by_torrent_id(1).await.unwrap().iter().filter(|piece| piece.is_complete()).count()
+}
+
+#[tokio::test]
+async fn unchanged_files_resume_without_hashing() {
+    let (fixture, _) = downloaded(StorageKind::Disk).await;
+    assert_eq!(fixture.storage.save_dirty_snapshots().await.unwrap(), 1);
+
+    assert_eq!(fixture.validator.validate(&fixture.torrent).await.unwrap(), ResumeCheck::Quick);
+    assert_eq!(complete_pieces(&fixture.pieces).await, 4);
+}
+
+#[tokio::test]
+async fn changed_files_are_hashed_again() {
+    let (fixture, data) = downloaded(StorageKind::Disk).await;
+    fixture.storage.save_snapshot(1).await.unwrap();
+
+    // Cut short behind our back: the last two pieces are lost
+    let file = fixture.download_dir.join("movie.mkv");
+    std::fs::write(&file, &data[..150]).unwrap();
+
+    let check = fixture.validator.validate(&fixture.torrent).await.unwrap();
+    assert_eq!(check, ResumeCheck::Full { invalid: 2 });
+    assert_eq!(complete_pieces(&fixture.pieces).await, 2);
+    assert!(fixture.piece_manager.read_piece_data(1, 1).await.is_ok());
+
+    // The check recorded the files as they are now
+    assert_eq!(fixture.validator.validate(&fixture.torrent).await.unwrap(), ResumeCheck::Quick);
+}
+
+#[tokio::test]
+async fn memory_torrents_start_over() {
+    let (fixture, _) = downloaded(StorageKind::Memory).await;
+    assert!(!fixture.download_dir.join("movie.mkv").exists());
+
+    let check = fixture.validator.validate(&fixture.torrent).await.unwrap();
+    assert_eq!(check, ResumeCheck::Cleared { invalid: 4 });
+    assert_eq!(complete_pieces(&fixture.pieces).await, 0);
+}
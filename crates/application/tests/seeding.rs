@@ -0,0 +1,186 @@
+mod common;
+
+use application::PeerListener;
+use common::{temp_dir, torrent, MemoryPeerRepository, MemoryPieceRepository, MemoryTorrentRepository};
+use domain::*;
+use sha1::Digest;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpStream;
+
+const INFO_HASH: [u8; 20] = [5u8; 20];
+const PIECE_LENGTH: usize = 16384;
+
+fn content() -> Vec<u8> {
+    (0..4 * PIECE_LENGTH).map(|i| (i % 251) as u8).collect()
+}
+
+async fn next_message(stream: &mut TcpStream) -> PeerMessage {
+    let codec = MessageCodec::default();
+    loop {
+        let read = codec.read_message(stream);
+        match tokio::time::timeout(Duration::from_secs(5), read).await.unwrap().unwrap() {
+            PeerMessage::KeepAlive => continue,
+            message => return message,
+        }
+    }
+}
+
+#[tokio::test]
+async fn serves_verified_pieces_and_announces_new_ones() {
+    let download_dir = temp_dir("seeding");
+    let data = content();
+    std::fs::write(download_dir.join("torrent-1"), &data).unwrap();
+
+    // Pieces 0 and 2 are verified, 1 and 3 are still missing
+    let pieces = (0..4)
+        .map(|index| {
+            let chunk = &data[index * PIECE_LENGTH..(index + 1) * PIECE_LENGTH];
+            let mut piece = Piece::new(1, index as i32, hex::encode(sha1::Sha1::digest(chunk)));
+            if index % 2 == 0 {
+                piece.mark_downloaded();
+                piece.mark_verified(true);
+            }
+            piece
+        })
+        .collect();
+
+    let torrents: Arc<dyn TorrentRepository> = Arc::new(MemoryTorrentRepository::with(vec![
+        torrent(1, INFO_HASH, TorrentStatus::Seeding),
+    ]));
+    let piece_repository: Arc<dyn PieceRepository> = Arc::new(MemoryPieceRepository::with(pieces));
+    let peers: Arc<dyn PeerRepository> = Arc::new(MemoryPeerRepository::default());
+    let piece_manager = Arc::new(PieceManager::new(
+        piece_repository,
+        torrents.clone(),
+        download_dir.to_string_lossy().to_string(),
+    ));
+    let manager = Arc::new(
+        ConnectionManager::new(peers, torrents.clone())
+            .with_seeder(Arc::new(Seeder::new(piece_manager.clone(), torrents.clone()))),
+    );
+
+    let listener = PeerListener::bind("127.0.0.1:0".parse().unwrap(), torrents.clone(), manager.clone())
+        .await
+        .unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(listener.run());
+
+    let codec = MessageCodec::default();
+    let mut stream = TcpStream::connect(address).await.unwrap();
+    Handshake::new(INFO_HASH, [9u8; 20]).write_to(&mut stream).await.unwrap();
+    Handshake::read_from(&mut stream).await.unwrap();
+
+    assert_eq!(next_message(&mut stream).await, PeerMessage::Bitfield(vec![0b1010_0000]));
+
+    // Interested peers are unchoked by the choker
+    codec.write_message(&mut stream, &PeerMessage::Interested).await.unwrap();
+    let interested = async {
+        while !manager.handles(1).iter().any(|handle| handle.stats().peer_interested) {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(5), interested).await.unwrap();
+    Choker::new(manager.clone(), torrents.clone()).rechoke().await;
+    assert_eq!(next_message(&mut stream).await, PeerMessage::Unchoke);
+
+    // A missing piece is never served; the following valid request is
+    for request in [BlockRequest::new(1, 0, 16384), BlockRequest::new(2, 1000, 500)] {
+        codec.write_message(&mut stream, &PeerMessage::Request(request)).await.unwrap();
+    }
+    let start = 2 * PIECE_LENGTH + 1000;
+    assert_eq!(
+        next_message(&mut stream).await,
+        PeerMessage::Piece { piece_index: 2, begin: 1000, data: data[start..start + 500].to_vec() }
+    );
+
+    // Completing a piece is announced to connected peers
+    let piece_three = data[3 * PIECE_LENGTH..].to_vec();
+    piece_manager.mark_piece_completed(1, 3, piece_three).await.unwrap();
+    manager.announce_piece(1, 3);
+    assert_eq!(next_message(&mut stream).await, PeerMessage::Have { piece_index: 3 });
+
+    codec.write_message(&mut stream, &PeerMessage::Request(BlockRequest::new(3, 0, 16384))).await.unwrap();
+    assert_eq!(
+        next_message(&mut stream).await,
+        PeerMessage::Piece { piece_index: 3, begin: 0, data: data[3 * PIECE_LENGTH..].to_vec() }
+    );
+
+    let _ = std::fs::remove_dir_all(download_dir);
+}
+
+#[tokio::test]
+async fn slow_readers_hold_up_their_own_requests() {
+    use domain::services::seeder::MAX_UNSENT_BLOCKS;
+    use tokio::io::AsyncReadExt;
+
+    let download_dir = temp_dir("slow-reader");
+    let data = content();
+    std::fs::write(download_dir.join("torrent-1"), &data).unwrap();
+    let pieces = (0..4)
+        .map(|index| {
+            let chunk = &data[index * PIECE_LENGTH..(index + 1) * PIECE_LENGTH];
+            let mut piece = Piece::new(1, index as i32, hex::encode(sha1::Sha1::digest(chunk)));
+            piece.mark_downloaded();
+            piece.mark_verified(true);
+            piece
+        })
+        .collect();
+    let torrents: Arc<dyn TorrentRepository> = Arc::new(MemoryTorrentRepository::with(vec![
+        torrent(1, INFO_HASH, TorrentStatus::Seeding),
+    ]));
+    let piece_manager = Arc::new(PieceManager::new(
+        Arc::new(MemoryPieceRepository::with(pieces)),
+        torrents.clone(),
+        download_dir.to_string_lossy().to_string(),
+    ));
+    let manager = Arc::new(
+        ConnectionManager::new(Arc::new(MemoryPeerRepository::default()), torrents.clone())
+            .with_seeder(Arc::new(Seeder::new(piece_manager, torrents.clone()))),
+    );
+
+    // Room for little more than one block in flight
+    let (local, mut remote) = tokio::io::duplex(20 * 1024);
+    let handle = manager
+        .register(local, Peer::new(1, "10.0.0.1".to_string(), 7001), Handshake::new(INFO_HASH, [9u8; 20]))
+        .await
+        .unwrap();
+    handle.send(PeerMessage::Unchoke).unwrap();
+
+    let codec = MessageCodec::default();
+    let requests: Vec<BlockRequest> = (0..4)
+        .flat_map(|piece_index| [BlockRequest::new(piece_index, 0, 8192), BlockRequest::new(piece_index, 8192, 8192)])
+        .collect();
+    for request in &requests {
+        codec.write_message(&mut remote, &PeerMessage::Request(*request)).await.unwrap();
+    }
+
+    // The peer reads nothing, so the seeder stops handing over blocks
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert!(handle.queued_uploads() <= MAX_UNSENT_BLOCKS);
+
+    // Everything not handed over yet can still be cancelled
+    for request in &requests {
+        codec.write_message(&mut remote, &PeerMessage::Cancel(*request)).await.unwrap();
+    }
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let mut received = Vec::new();
+    let mut buffer = [0u8; 4096];
+    while let Ok(Ok(read)) = tokio::time::timeout(Duration::from_millis(300), remote.read(&mut buffer)).await {
+        if read == 0 {
+            break;
+        }
+        received.extend_from_slice(&buffer[..read]);
+    }
+    // Bitfield and unchoke, then at most the blocks already handed over
+    let mut served = 0;
+    let mut reader = received.as_slice();
+    while let Ok(message) = codec.read_message(&mut reader).await {
+        if matches!(message, PeerMessage::Piece { .. }) {
+            served += 1;
+        }
+    }
+    assert!(served > 0 && served < requests.len(), "{} blocks served", served);
+
+    let _ = std::fs::remove_dir_all(download_dir);
+}
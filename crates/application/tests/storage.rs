@@ -0,0 +1,155 @@
+mod common;
+
+use common::{temp_dir, MemoryPieceRepository, MemoryTorrentFileRepository, MemoryTorrentRepository};
+use domain::*;
+use sha1::Digest;
+use std::sync::Arc;
+
+const PIECE_LENGTH: i32 = 128;
+
+fn content(length: usize) -> Vec<u8> {
+    (0..length).map(|i| (i % 251) as u8).collect()
+}
+
+/// A 500-byte pack over four pieces: a 100-byte file, an empty one, and
+/// two in a subdirectory, one of them trying to escape the download dir
+fn season_pack() -> (Torrent, Vec<TorrentFile>) {
+    let mut torrent = Torrent::new(hex::encode([5u8; 20]), "Season 1".to_string(), 500, PIECE_LENGTH, 4);
+    torrent.id = Some(1);
+    let files = vec![
+        TorrentFile::new(1, 0, "readme.txt".to_string(), 100, 0),
+        TorrentFile::new(1, 1, "empty.nfo".to_string(), 0, 100),
+        TorrentFile::new(1, 2, "video/e01.mkv".to_string(), 250, 100),
+        TorrentFile::new(1, 3, "../../e02.mkv".to_string(), 150, 350),
+    ];
+    (torrent, files)
+}
+
+fn storage(download_dir: &std::path::Path, torrent: Torrent, files: Vec<TorrentFile>) -> (Arc<TorrentStorage>, Arc<dyn TorrentRepository>) {
+    let torrents: Arc<dyn TorrentRepository> = Arc::new(MemoryTorrentRepository::with(vec![torrent]));
+    let storage = TorrentStorage::new(torrents.clone(), download_dir)
+        .with_file_repository(Arc::new(MemoryTorrentFileRepository::with(files)));
+    (Arc::new(storage), torrents)
+}
+
+#[test]
+fn maps_byte_ranges_to_file_spans() {
+    let (torrent, files) = season_pack();
+    let layout = StorageLayout::new(std::path::Path::new("downloads"), &torrent, &files);
+    assert_eq!(layout.root, std::path::Path::new("downloads/Season 1"));
+    assert_eq!(layout.files[3].path, std::path::Path::new("downloads/Season 1/e02.mkv"));
+
+    // Piece 0 covers the first file and the start of the third
+    let spans = layout.spans(0, 128).unwrap();
+    assert_eq!(spans.len(), 2);
+    assert_eq!((spans[0].file_offset, spans[0].range_offset, spans[0].length), (0, 0, 100));
+    assert_eq!(spans[1].path, std::path::Path::new("downloads/Season 1/video/e01.mkv"));
+    assert_eq!((spans[1].file_offset, spans[1].range_offset, spans[1].length), (0, 100, 28));
+
+    assert!(layout.spans(400, 101).is_err());
+
+    // A single file is stored under its own name
+    let layout = StorageLayout::new(std::path::Path::new("downloads"), &torrent, &files[2..3]);
+    assert_eq!(layout.root, std::path::Path::new("downloads/video/e01.mkv"));
+}
+
+#[tokio::test]
+async fn pieces_land_in_the_torrent_directory_tree() {
+    let download_dir = temp_dir("storage");
+    let (torrent, files) = season_pack();
+    let data = content(500);
+    let pieces = (0..4)
+        .map(|index| {
+            let start = index * PIECE_LENGTH as usize;
+            let end = (start + PIECE_LENGTH as usize).min(data.len());
+            Piece::new(1, index as i32, hex::encode(sha1::Sha1::digest(&data[start..end])))
+        })
+        .collect();
+    let (storage, torrents) = storage(&download_dir, torrent, files);
+    let piece_manager = PieceManager::new(
+        Arc::new(MemoryPieceRepository::with(pieces)),
+        torrents,
+        download_dir.to_string_lossy().to_string(),
+    )
+    .with_storage(storage.clone());
+
+    for index in 0..4 {
+        let start = index * PIECE_LENGTH as usize;
+        let end = (start + PIECE_LENGTH as usize).min(data.len());
+        piece_manager.mark_piece_completed(1, index, data[start..end].to_vec()).await.unwrap();
+    }
+
+    let root = download_dir.join("Season 1");
+    assert_eq!(std::fs::read(root.join("readme.txt")).unwrap(), &data[..100]);
+    assert_eq!(std::fs::read(root.join("video/e01.mkv")).unwrap(), &data[100..350]);
+    assert_eq!(std::fs::read(root.join("e02.mkv")).unwrap(), &data[350..]);
+
+    // Reads go through the same mapping
+    assert_eq!(piece_manager.read_piece_data(1, 2).await.unwrap(), &data[256..384]);
+    assert_eq!(storage.read(1, 90, 20).await.unwrap(), &data[90..110]);
+
+    storage.delete(1).await.unwrap();
+    assert!(!root.exists());
+}
+
+#[tokio::test]
+async fn deleting_a_partial_download_removes_its_directory() {
+    let download_dir = temp_dir("partial-delete");
+    let (torrent, files) = season_pack();
+    let (storage, _) = storage(&download_dir, torrent, files);
+    // Only the last file was written, the video directory never existed
+    storage.write(1, 350, &content(150)).await.unwrap();
+    storage.flush(1).await.unwrap();
+
+    let root = download_dir.join("Season 1");
+    assert!(root.join("e02.mkv").exists() && !root.join("video").exists());
+    storage.delete(1).await.unwrap();
+    assert!(!root.exists());
+    assert!(download_dir.exists());
+
+    // Files the torrent does not own keep the directory around
+    storage.write(1, 0, &content(100)).await.unwrap();
+    storage.flush(1).await.unwrap();
+    std::fs::write(root.join("notes.txt"), b"mine").unwrap();
+    storage.delete(1).await.unwrap();
+    assert!(!root.join("readme.txt").exists());
+    assert!(root.join("notes.txt").exists());
+}
+
+#[tokio::test]
+async fn memory_torrents_never_touch_the_disk() {
+    let download_dir = temp_dir("memory-storage");
+    let (mut torrent, files) = season_pack();
+    torrent.storage = StorageKind::Memory;
+    let data = content(500);
+    let pieces: Vec<Piece> = (0..4)
+        .map(|index| {
+            let start = index * PIECE_LENGTH as usize;
+            let end = (start + PIECE_LENGTH as usize).min(data.len());
+            Piece::new(1, index as i32, hex::encode(sha1::Sha1::digest(&data[start..end])))
+        })
+        .collect();
+    let torrents: Arc<dyn TorrentRepository> = Arc::new(MemoryTorrentRepository::with(vec![torrent]));
+    // Room for two pieces
+    let storage = Arc::new(
+        TorrentStorage::new(torrents.clone(), &download_dir)
+            .with_file_repository(Arc::new(MemoryTorrentFileRepository::with(files)))
+            .with_backend(StorageKind::Memory, Arc::new(MemoryBackend::new(2 * PIECE_LENGTH as usize))),
+    );
+    let piece_repository = Arc::new(MemoryPieceRepository::with(pieces));
+    let piece_manager = PieceManager::new(piece_repository.clone(), torrents, download_dir.to_string_lossy().to_string())
+        .with_storage(storage.clone());
+
+    for index in 0..4 {
+        let start = index * PIECE_LENGTH as usize;
+        let end = (start + PIECE_LENGTH as usize).min(data.len());
+        piece_manager.mark_piece_completed(1, index, data[start..end].to_vec()).await.unwrap();
+    }
+    assert!(!download_dir.join("Season 1").exists());
+    assert_eq!(piece_manager.read_piece_data(1, 3).await.unwrap(), &data[384..]);
+
+    // The first piece was evicted, so it has to be downloaded again
+    assert!(matches!(piece_manager.read_piece_data(1, 0).await, Err(DomainError::NotFound(_))));
+    let piece = piece_repository.find_by_torrent_and_index(1, 0).await.unwrap().unwrap();
+    assert!(!piece.is_complete());
+}
@@ -0,0 +1,106 @@
+mod common;
+
+use application::PeerListener;
+use common::{torrent, MemoryPeerRepository, MemoryTorrentRepository};
+use domain::*;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+
+const INFO_HASH: [u8; 20] = [6u8; 20];
+
+fn manager(policy: EncryptionPolicy) -> Arc<ConnectionManager> {
+    let torrents: Arc<dyn TorrentRepository> = Arc::new(MemoryTorrentRepository::with(vec![torrent(
+        1,
+        INFO_HASH,
+        TorrentStatus::Downloading,
+    )]));
+    let peers: Arc<dyn PeerRepository> = Arc::new(MemoryPeerRepository::default());
+    Arc::new(ConnectionManager::new(peers, torrents).with_encryption(policy))
+}
+
+#[tokio::test]
+async fn outbound_connections_prefer_utp() {
+    let torrents: Arc<dyn TorrentRepository> = Arc::new(MemoryTorrentRepository::with(vec![torrent(
+        1,
+        INFO_HASH,
+        TorrentStatus::Downloading,
+    )]));
+    let peers: Arc<dyn PeerRepository> = Arc::new(MemoryPeerRepository::default());
+    let server = Arc::new(ConnectionManager::new(peers, torrents.clone()));
+    let listener = PeerListener::bind("127.0.0.1:0".parse().unwrap(), torrents, server.clone())
+        .await
+        .unwrap();
+    let address = listener.local_addr().unwrap();
+    let server_utp = listener.utp_socket().expect("listener should accept uTP");
+    assert_eq!(server_utp.local_addr().unwrap().port(), address.port());
+    tokio::spawn(listener.run());
+
+    let client = manager(EncryptionPolicy::Enabled);
+    client.set_utp_socket(Arc::new(UtpSocket::bind("127.0.0.1:0".parse().unwrap()).await.unwrap()));
+    let peer = Peer::new(1, "127.0.0.1".to_string(), address.port());
+    let handle = tokio::time::timeout(Duration::from_secs(10), client.connect(peer, &hex::encode(INFO_HASH)))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(handle.remote_peer_id(), server.local_peer_id());
+
+    let registered = async {
+        while server.connection_count(1) == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(5), registered).await.unwrap();
+    assert_eq!(server_utp.connection_count(), 1, "the connection should run over uTP");
+}
+
+#[tokio::test]
+async fn falls_back_to_tcp_when_utp_is_unanswered() {
+    // A TCP-only peer; nothing answers on its UDP port
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let remote = Handshake::read_from(&mut stream).await.unwrap();
+        Handshake::new(remote.info_hash, [8u8; 20]).write_to(&mut stream).await.unwrap();
+        tokio::time::sleep(Duration::from_secs(5)).await;
+    });
+
+    let client = manager(EncryptionPolicy::Disabled);
+    client.set_utp_socket(Arc::new(UtpSocket::bind("127.0.0.1:0".parse().unwrap()).await.unwrap()));
+
+    let peer = Peer::new(1, "127.0.0.1".to_string(), address.port());
+    let handle = tokio::time::timeout(Duration::from_secs(20), client.connect(peer, &hex::encode(INFO_HASH)))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(handle.remote_peer_id(), [8u8; 20]);
+}
+
+#[tokio::test]
+async fn concurrent_connects_share_one_dial() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let accepted = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let counter = accepted.clone();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            tokio::spawn(async move {
+                let remote = Handshake::read_from(&mut stream).await.unwrap();
+                Handshake::new(remote.info_hash, [8u8; 20]).write_to(&mut stream).await.unwrap();
+                tokio::time::sleep(Duration::from_secs(5)).await;
+            });
+        }
+    });
+
+    let client = manager(EncryptionPolicy::Disabled);
+    let peer = Peer::new(1, "127.0.0.1".to_string(), address.port());
+    let info_hash = hex::encode(INFO_HASH);
+    let (first, second) = tokio::join!(client.connect(peer.clone(), &info_hash), client.connect(peer, &info_hash));
+
+    assert!(first.unwrap().same_connection(&second.unwrap()));
+    assert_eq!(accepted.load(std::sync::atomic::Ordering::SeqCst), 1);
+    assert_eq!(client.connection_count(1), 1);
+}
@@ -17,7 +17,7 @@ impl Config {
     pub fn from_env(env_path: Option<&str>) -> Self {
         // Load the specified `.env` file or default to the root `.env` file
         if let Some(path) = env_path {
-            from_path(path).expect(&format!("Failed to load .env file from path: {}", path));
+            from_path(path).unwrap_or_else(|_| panic!("Failed to load .env file from path: {}", path));
         } else {
             // Default to `.env` in the root directory
             dotenv().ok();
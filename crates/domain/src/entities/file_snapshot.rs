@@ -0,0 +1,26 @@
+use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Size and modification time of a stored file, recorded after the piece
+/// state of its torrent was saved. A restart finding every file unchanged
+/// can trust the saved piece state without hashing anything.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FileSnapshot {
+    pub torrent_id: i32,
+    pub path: String,
+    pub size: i64,
+    pub modified_at: SystemTime,
+}
+
+impl FileSnapshot {
+    pub fn new(torrent_id: i32, path: String, size: i64, modified_at: SystemTime) -> Self {
+        Self { torrent_id, path, size, modified_at }
+    }
+
+    /// Same file, size and modification time; times are compared to the
+    /// second since that is all the database keeps
+    pub fn matches(&self, other: &FileSnapshot) -> bool {
+        let seconds = |time: SystemTime| time.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs());
+        self.path == other.path && self.size == other.size && seconds(self.modified_at) == seconds(other.modified_at)
+    }
+}
@@ -1,3 +1,4 @@
+pub mod file_snapshot;
 pub mod peer;
 pub mod piece;
 pub mod stream;
@@ -5,6 +6,7 @@ pub mod torrent;
 pub mod torrent_file;
 pub mod tracker;
 
+pub use file_snapshot::*;
 pub use peer::*;
 pub use piece::*;
 pub use stream::*;
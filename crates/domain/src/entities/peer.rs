@@ -18,6 +18,8 @@ pub struct Peer {
     pub peer_id: Option<String>,
     pub last_seen: SystemTime,
     pub status: PeerStatus,
+    /// Reached us from a port we cannot dial back, e.g. a TCP source port
+    pub inbound: bool,
 }
 
 impl Peer {
@@ -30,6 +32,7 @@ impl Peer {
             peer_id: None,
             last_seen: SystemTime::now(),
             status: PeerStatus::Disconnected,
+            inbound: false,
         }
     }
 
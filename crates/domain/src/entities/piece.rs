@@ -8,6 +8,8 @@ pub struct Piece {
     pub hash: String,           // SHA1 hash as hex string
     pub downloaded: bool,
     pub verified: bool,
+    /// Blocks of an unfinished piece already stored on disk, one bit per block
+    pub received_blocks: Option<Vec<u8>>,
 }
 
 impl Piece {
@@ -19,6 +21,7 @@ impl Piece {
             hash,
             downloaded: false,
             verified: false,
+            received_blocks: None,
         }
     }
 
@@ -32,9 +35,18 @@ impl Piece {
             // If verification failed, mark as not downloaded
             self.downloaded = false;
         }
+        // Either way the stored blocks are no longer needed to resume
+        self.received_blocks = None;
     }
 
     pub fn is_complete(&self) -> bool {
         self.downloaded && self.verified
     }
+
+    /// Whether block `block_index` is stored from an earlier session
+    pub fn has_received_block(&self, block_index: usize) -> bool {
+        self.received_blocks.as_ref().is_some_and(|bitmap| {
+            bitmap.get(block_index / 8).is_some_and(|byte| byte & (0x80 >> (block_index % 8)) != 0)
+        })
+    }
 }
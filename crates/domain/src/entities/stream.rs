@@ -12,6 +12,8 @@ pub struct StreamSession {
     pub started_at: SystemTime,
     pub last_accessed: SystemTime,
     pub bytes_served: i64,
+    /// Share of the torrent's bandwidth relative to other streams of it
+    pub weight: u32,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -4,6 +4,10 @@ use std::time::SystemTime;
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum TorrentStatus {
     Parsing,
+    /// Waiting for enough free disk space to start
+    Queued,
+    /// Stored pieces are being hashed again; `progress` is the share checked
+    Checking,
     Connecting,
     Downloading,
     Seeding,
@@ -12,6 +16,34 @@ pub enum TorrentStatus {
     Error(String),
 }
 
+/// Where the downloaded data of a torrent is kept
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageKind {
+    /// Files in the download directory
+    #[default]
+    Disk,
+    /// Memory only, for torrents that are streamed and never kept
+    Memory,
+}
+
+impl StorageKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            StorageKind::Disk => "disk",
+            StorageKind::Memory => "memory",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "disk" => Some(StorageKind::Disk),
+            "memory" => Some(StorageKind::Memory),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Torrent {
     pub id: Option<i32>,
@@ -21,6 +53,7 @@ pub struct Torrent {
     pub piece_length: i32,
     pub piece_count: i32,
     pub file_path: Option<String>,
+    pub storage: StorageKind,
     pub status: TorrentStatus,
     pub progress: f32,             // 0.0 to 1.0
     pub created_at: SystemTime,
@@ -44,6 +77,7 @@ impl Torrent {
             piece_length,
             piece_count,
             file_path: None,
+            storage: StorageKind::Disk,
             status: TorrentStatus::Parsing,
             progress: 0.0,
             created_at: now,
@@ -51,6 +85,7 @@ impl Torrent {
         }
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn with_id(
         id: i32,
         info_hash: String,
@@ -59,6 +94,7 @@ impl Torrent {
         piece_length: i32,
         piece_count: i32,
         file_path: Option<String>,
+        storage: StorageKind,
         status: TorrentStatus,
         progress: f32,
         created_at: SystemTime,
@@ -72,6 +108,7 @@ impl Torrent {
             piece_length,
             piece_count,
             file_path,
+            storage,
             status,
             progress,
             created_at,
@@ -79,12 +116,20 @@ impl Torrent {
         }
     }
 
-    pub fn update_progress(&mut self, downloaded_pieces: i32) {
-        self.progress = (downloaded_pieces as f32) / (self.piece_count as f32);
+    /// Progress over the pieces of the files we want; skipped files do not count
+    pub fn update_progress(&mut self, downloaded_pieces: i32, wanted_pieces: i32) {
+        self.progress = if wanted_pieces > 0 {
+            ((downloaded_pieces as f32) / (wanted_pieces as f32)).min(1.0)
+        } else {
+            1.0
+        };
         self.updated_at = SystemTime::now();
-        
+
         if self.progress >= 1.0 {
             self.status = TorrentStatus::Completed;
+        } else if self.status == TorrentStatus::Completed {
+            // More files were selected after everything wanted had arrived
+            self.status = TorrentStatus::Downloading;
         }
     }
 
@@ -96,4 +141,22 @@ impl Torrent {
     pub fn is_complete(&self) -> bool {
         matches!(self.status, TorrentStatus::Completed) || self.progress >= 1.0
     }
+
+    /// Whether the torrent must not exchange pieces: paused by the user,
+    /// waiting for disk space, being rechecked or stopped by an error
+    pub fn is_stopped(&self) -> bool {
+        matches!(
+            self.status,
+            TorrentStatus::Paused | TorrentStatus::Queued | TorrentStatus::Checking | TorrentStatus::Error(_)
+        )
+    }
+
+    /// Length of a piece; only the last piece may be shorter than `piece_length`
+    pub fn piece_size(&self, piece_index: i32) -> u32 {
+        if piece_index == self.piece_count - 1 {
+            (self.total_size - (self.piece_count as i64 - 1) * self.piece_length as i64) as u32
+        } else {
+            self.piece_length as u32
+        }
+    }
 }
@@ -1,22 +1,85 @@
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// How much we want a file of a torrent
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FilePriority {
+    /// Not downloaded at all
+    Skip,
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl FilePriority {
+    pub fn is_wanted(&self) -> bool {
+        *self != FilePriority::Skip
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FilePriority::Skip => "skip",
+            FilePriority::Low => "low",
+            FilePriority::Normal => "normal",
+            FilePriority::High => "high",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "skip" => Some(FilePriority::Skip),
+            "low" => Some(FilePriority::Low),
+            "normal" => Some(FilePriority::Normal),
+            "high" => Some(FilePriority::High),
+            _ => None,
+        }
+    }
+}
+
+/// Which files to download when a torrent is added
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct FileSelection {
+    /// Download only these files (BEP 53 `so=`); every file when `None`
+    pub only: Option<Vec<usize>>,
+    /// Priorities of individual files, taking precedence over `only`
+    pub priorities: HashMap<usize, FilePriority>,
+}
+
+impl FileSelection {
+    pub fn priority_for(&self, file_index: usize) -> FilePriority {
+        if let Some(priority) = self.priorities.get(&file_index) {
+            return *priority;
+        }
+        match &self.only {
+            Some(only) if !only.contains(&file_index) => FilePriority::Skip,
+            _ => FilePriority::Normal,
+        }
+    }
+}
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct TorrentFile {
     pub id: Option<i32>,
     pub torrent_id: i32,
+    pub file_index: i32,   // Position in the torrent's file list
     pub path: String,      // File path within the torrent
     pub length: i64,       // File size in bytes
     pub offset: i64,       // Byte offset within the torrent
+    pub priority: FilePriority,
 }
 
 impl TorrentFile {
-    pub fn new(torrent_id: i32, path: String, length: i64, offset: i64) -> Self {
+    pub fn new(torrent_id: i32, file_index: i32, path: String, length: i64, offset: i64) -> Self {
         Self {
             id: None,
             torrent_id,
+            file_index,
             path,
             length,
             offset,
+            priority: FilePriority::Normal,
         }
     }
 
@@ -29,6 +92,16 @@ impl TorrentFile {
     }
 
     pub fn file_name(&self) -> Option<&str> {
-        self.path.split('/').last()
+        self.path.split('/').next_back()
+    }
+
+    /// Indices of the pieces holding part of this file; empty for empty files
+    pub fn piece_range(&self, piece_length: i64) -> std::ops::Range<usize> {
+        if self.length <= 0 || piece_length <= 0 {
+            return 0..0;
+        }
+        let first = self.offset / piece_length;
+        let last = (self.end_offset() - 1) / piece_length;
+        first as usize..last as usize + 1
     }
 }
@@ -35,6 +35,9 @@ pub enum DomainError {
     #[error("Parsing error: {0}")]
     ParsingError(String),
 
+    #[error("Not enough disk space: {0}")]
+    InsufficientSpace(String),
+
     #[error("Not found: {0}")]
     NotFound(String),
 
@@ -1,9 +1,11 @@
 pub mod entities;
 pub mod errors;
+pub mod protocol;
 pub mod repositories;
 pub mod services;
 
 pub use entities::*;
 pub use errors::*;
+pub use protocol::*;
 pub use repositories::*;
 pub use services::*;
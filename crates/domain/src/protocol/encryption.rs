@@ -0,0 +1,493 @@
+use crate::errors::DomainError;
+use crate::protocol::handshake::PROTOCOL_NAME;
+use num_bigint::BigUint;
+use sha1::{Digest, Sha1};
+use std::fmt;
+use std::io;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+/// The 768-bit MSE prime; the generator is 2
+const PRIME: &str = "FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F14374FE1356D6D51C245E485B576625E7EC6F44C42E9A63A36210000000000090563";
+const GENERATOR: u32 = 2;
+
+/// Length of a public key and of the shared secret on the wire
+pub const KEY_LENGTH: usize = 96;
+
+/// Upper bound on the random padding after each public key
+const MAX_PADDING: usize = 512;
+
+/// Verification constant, sent encrypted so each side can find the start of the stream
+const VC: [u8; 8] = [0u8; 8];
+
+/// `crypto_provide` / `crypto_select` bits
+pub const CRYPTO_PLAINTEXT: u32 = 0x01;
+pub const CRYPTO_RC4: u32 = 0x02;
+
+/// The first RC4 keystream bytes are weak and dropped by both sides
+const RC4_DISCARD: usize = 1024;
+
+/// Whether peer connections use Message Stream Encryption
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EncryptionPolicy {
+    /// Plaintext only; encrypted inbound connections are refused
+    Disabled,
+    /// Try MSE first and fall back to plaintext; accept both inbound
+    #[default]
+    Enabled,
+    /// RC4-encrypted connections only
+    Forced,
+}
+
+impl EncryptionPolicy {
+    /// Methods we offer as the initiator
+    pub fn crypto_provide(&self) -> u32 {
+        match self {
+            EncryptionPolicy::Disabled => CRYPTO_PLAINTEXT,
+            EncryptionPolicy::Enabled => CRYPTO_PLAINTEXT | CRYPTO_RC4,
+            EncryptionPolicy::Forced => CRYPTO_RC4,
+        }
+    }
+
+    /// Method we pick from what an initiator offers; RC4 wins when both are possible
+    fn crypto_select(&self, provided: u32) -> Option<u32> {
+        let allowed = self.crypto_provide() & provided;
+        if allowed & CRYPTO_RC4 != 0 {
+            Some(CRYPTO_RC4)
+        } else if allowed & CRYPTO_PLAINTEXT != 0 {
+            Some(CRYPTO_PLAINTEXT)
+        } else {
+            None
+        }
+    }
+}
+
+impl FromStr for EncryptionPolicy {
+    type Err = DomainError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "disabled" => Ok(EncryptionPolicy::Disabled),
+            "enabled" => Ok(EncryptionPolicy::Enabled),
+            "forced" => Ok(EncryptionPolicy::Forced),
+            other => Err(DomainError::ValidationError(format!(
+                "Unknown encryption policy '{}', expected disabled, enabled or forced",
+                other
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for EncryptionPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EncryptionPolicy::Disabled => write!(f, "disabled"),
+            EncryptionPolicy::Enabled => write!(f, "enabled"),
+            EncryptionPolicy::Forced => write!(f, "forced"),
+        }
+    }
+}
+
+/// RC4 keystream generator
+#[derive(Clone)]
+pub struct Rc4 {
+    state: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+impl Rc4 {
+    pub fn new(key: &[u8]) -> Self {
+        let mut state = [0u8; 256];
+        for (index, value) in state.iter_mut().enumerate() {
+            *value = index as u8;
+        }
+
+        let mut j = 0u8;
+        for i in 0..256 {
+            j = j.wrapping_add(state[i]).wrapping_add(key[i % key.len()]);
+            state.swap(i, j as usize);
+        }
+
+        Self { state, i: 0, j: 0 }
+    }
+
+    /// Encrypt or decrypt `data` in place
+    pub fn apply(&mut self, data: &mut [u8]) {
+        for byte in data {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.state[self.i as usize]);
+            self.state.swap(self.i as usize, self.j as usize);
+            let index = self.state[self.i as usize].wrapping_add(self.state[self.j as usize]);
+            *byte ^= self.state[index as usize];
+        }
+    }
+
+    pub fn discard(&mut self, count: usize) {
+        self.apply(&mut vec![0u8; count]);
+    }
+}
+
+/// A peer stream after the MSE negotiation.
+///
+/// With RC4 selected every byte is encrypted in both directions. Bytes that were
+/// read off the wire during negotiation but belong to the BitTorrent stream (a
+/// plaintext handshake, or MSE initial payload) are replayed first.
+pub struct CryptoStream<S> {
+    inner: S,
+    prefix: Vec<u8>,
+    prefix_position: usize,
+    read_cipher: Option<Rc4>,
+    write_cipher: Option<Rc4>,
+    /// Encrypted bytes accepted from the caller but not yet written
+    pending: Vec<u8>,
+    pending_position: usize,
+}
+
+impl<S> CryptoStream<S> {
+    pub fn plaintext(inner: S) -> Self {
+        Self::with_prefix(inner, Vec::new())
+    }
+
+    fn with_prefix(inner: S, prefix: Vec<u8>) -> Self {
+        Self {
+            inner,
+            prefix,
+            prefix_position: 0,
+            read_cipher: None,
+            write_cipher: None,
+            pending: Vec::new(),
+            pending_position: 0,
+        }
+    }
+
+    fn encrypted(inner: S, prefix: Vec<u8>, read_cipher: Rc4, write_cipher: Rc4) -> Self {
+        Self {
+            read_cipher: Some(read_cipher),
+            write_cipher: Some(write_cipher),
+            ..Self::with_prefix(inner, prefix)
+        }
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.write_cipher.is_some()
+    }
+
+    /// The underlying transport
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl<S: AsyncWrite + Unpin> CryptoStream<S> {
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.pending_position < self.pending.len() {
+            let written = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.pending[self.pending_position..]))?;
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.pending_position += written;
+        }
+        self.pending.clear();
+        self.pending_position = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for CryptoStream<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        if this.prefix_position < this.prefix.len() {
+            let remaining = &this.prefix[this.prefix_position..];
+            let count = remaining.len().min(buf.remaining());
+            buf.put_slice(&remaining[..count]);
+            this.prefix_position += count;
+            return Poll::Ready(Ok(()));
+        }
+
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        if let Some(cipher) = this.read_cipher.as_mut() {
+            cipher.apply(&mut buf.filled_mut()[before..]);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for CryptoStream<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.write_cipher.is_none() {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        }
+
+        // The keystream advances as soon as bytes are encrypted, so accepted bytes
+        // are kept until written instead of being re-encrypted on retry
+        ready!(this.poll_drain(cx))?;
+        this.pending.extend_from_slice(buf);
+        if let Some(cipher) = this.write_cipher.as_mut() {
+            cipher.apply(&mut this.pending);
+        }
+        if let Poll::Ready(Err(e)) = this.poll_drain(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+/// One side's Diffie-Hellman key pair
+struct KeyPair {
+    private: BigUint,
+    public: [u8; KEY_LENGTH],
+}
+
+impl KeyPair {
+    fn generate() -> Self {
+        let prime = prime();
+        // 160 bits of private key, as the spec recommends
+        let private = BigUint::from_bytes_be(&rand::random::<[u8; 20]>());
+        let public = BigUint::from(GENERATOR).modpow(&private, &prime);
+        Self {
+            private,
+            public: to_key_bytes(&public),
+        }
+    }
+
+    fn shared_secret(&self, remote_public: &[u8; KEY_LENGTH]) -> Result<[u8; KEY_LENGTH], DomainError> {
+        let prime = prime();
+        let remote = BigUint::from_bytes_be(remote_public);
+        if remote <= BigUint::from(1u32) || remote >= &prime - 1u32 {
+            return Err(handshake_error("Invalid public key"));
+        }
+        Ok(to_key_bytes(&remote.modpow(&self.private, &prime)))
+    }
+}
+
+fn prime() -> BigUint {
+    BigUint::parse_bytes(PRIME.as_bytes(), 16).expect("MSE prime is valid hex")
+}
+
+/// Big-endian, left-padded to `KEY_LENGTH`
+fn to_key_bytes(value: &BigUint) -> [u8; KEY_LENGTH] {
+    let bytes = value.to_bytes_be();
+    let mut key = [0u8; KEY_LENGTH];
+    key[KEY_LENGTH - bytes.len()..].copy_from_slice(&bytes);
+    key
+}
+
+fn hash(parts: &[&[u8]]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+fn cipher(name: &[u8], secret: &[u8; KEY_LENGTH], info_hash: &[u8; 20]) -> Rc4 {
+    let mut rc4 = Rc4::new(&hash(&[name, secret, info_hash]));
+    rc4.discard(RC4_DISCARD);
+    rc4
+}
+
+fn random_padding() -> Vec<u8> {
+    let length = rand::random::<usize>() % (MAX_PADDING + 1);
+    (0..length).map(|_| rand::random::<u8>()).collect()
+}
+
+fn handshake_error(reason: impl fmt::Display) -> DomainError {
+    DomainError::PeerConnectionError(format!("Encryption handshake failed: {}", reason))
+}
+
+async fn read_bytes<S: AsyncRead + Unpin>(stream: &mut S, length: usize) -> Result<Vec<u8>, DomainError> {
+    let mut bytes = vec![0u8; length];
+    stream.read_exact(&mut bytes).await.map_err(handshake_error)?;
+    Ok(bytes)
+}
+
+/// Read until the last bytes equal `marker`, giving up after `limit` bytes.
+/// Reads one byte at a time so nothing past the marker is consumed.
+async fn synchronize<S: AsyncRead + Unpin>(stream: &mut S, marker: &[u8], limit: usize) -> Result<(), DomainError> {
+    let mut window = Vec::with_capacity(limit);
+    while window.len() < limit {
+        window.push(read_bytes(stream, 1).await?[0]);
+        if window.ends_with(marker) {
+            return Ok(());
+        }
+    }
+    Err(handshake_error("Could not find the start of the encrypted stream"))
+}
+
+async fn write_bytes<S: AsyncWrite + Unpin>(stream: &mut S, bytes: &[u8]) -> Result<(), DomainError> {
+    stream.write_all(bytes).await.map_err(handshake_error)?;
+    stream.flush().await.map_err(handshake_error)
+}
+
+/// Negotiate encryption as the connecting side, before the BitTorrent handshake.
+///
+/// With `Disabled` the stream is returned untouched. Otherwise the DH exchange
+/// runs and the peer picks one of the methods the policy provides.
+pub async fn initiate<S>(
+    mut stream: S,
+    info_hash: [u8; 20],
+    policy: EncryptionPolicy,
+) -> Result<CryptoStream<S>, DomainError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    if policy == EncryptionPolicy::Disabled {
+        return Ok(CryptoStream::plaintext(stream));
+    }
+
+    let keys = KeyPair::generate();
+    let mut message = keys.public.to_vec();
+    message.extend(random_padding());
+    write_bytes(&mut stream, &message).await?;
+
+    let mut remote_public = [0u8; KEY_LENGTH];
+    remote_public.copy_from_slice(&read_bytes(&mut stream, KEY_LENGTH).await?);
+    let secret = keys.shared_secret(&remote_public)?;
+
+    let mut outgoing = cipher(b"keyA", &secret, &info_hash);
+    let mut incoming = cipher(b"keyB", &secret, &info_hash);
+
+    let mut message = hash(&[b"req1", &secret]).to_vec();
+    let skey = hash(&[b"req2", &info_hash]);
+    let req3 = hash(&[b"req3", &secret]);
+    message.extend(skey.iter().zip(req3.iter()).map(|(a, b)| a ^ b));
+
+    // VC, crypto_provide, len(PadC) = 0, len(IA) = 0
+    let mut negotiation = VC.to_vec();
+    negotiation.extend_from_slice(&policy.crypto_provide().to_be_bytes());
+    negotiation.extend_from_slice(&0u16.to_be_bytes());
+    negotiation.extend_from_slice(&0u16.to_be_bytes());
+    outgoing.apply(&mut negotiation);
+    message.extend(negotiation);
+    write_bytes(&mut stream, &message).await?;
+
+    // The reply starts with VC encrypted under keyB, somewhere after PadB
+    let mut encrypted_vc = VC;
+    incoming.clone().apply(&mut encrypted_vc);
+    synchronize(&mut stream, &encrypted_vc, MAX_PADDING + VC.len()).await?;
+    incoming.apply(&mut [0u8; 8]);
+
+    let mut reply = read_bytes(&mut stream, 6).await?;
+    incoming.apply(&mut reply);
+    let selected = u32::from_be_bytes([reply[0], reply[1], reply[2], reply[3]]);
+    let padding_length = u16::from_be_bytes([reply[4], reply[5]]) as usize;
+    if padding_length > MAX_PADDING {
+        return Err(handshake_error("Padding too long"));
+    }
+    let mut padding = read_bytes(&mut stream, padding_length).await?;
+    incoming.apply(&mut padding);
+
+    match selected {
+        CRYPTO_RC4 if policy.crypto_provide() & CRYPTO_RC4 != 0 => {
+            Ok(CryptoStream::encrypted(stream, Vec::new(), incoming, outgoing))
+        }
+        CRYPTO_PLAINTEXT if policy.crypto_provide() & CRYPTO_PLAINTEXT != 0 => Ok(CryptoStream::plaintext(stream)),
+        other => Err(handshake_error(format!("Peer selected unsupported method {:#x}", other))),
+    }
+}
+
+/// Negotiate encryption as the accepting side.
+///
+/// Plaintext BitTorrent handshakes are recognised by their first bytes and passed
+/// through unless the policy is `Forced`. For encrypted connections the torrent
+/// is identified among `info_hashes`, and it is returned so the caller can check
+/// the BitTorrent handshake that follows against it.
+pub async fn accept<S>(
+    mut stream: S,
+    info_hashes: &[[u8; 20]],
+    policy: EncryptionPolicy,
+) -> Result<(CryptoStream<S>, Option<[u8; 20]>), DomainError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let start = read_bytes(&mut stream, 1 + PROTOCOL_NAME.len()).await?;
+    if start[0] as usize == PROTOCOL_NAME.len() && &start[1..] == PROTOCOL_NAME {
+        if policy == EncryptionPolicy::Forced {
+            return Err(handshake_error("Plaintext connections are not accepted"));
+        }
+        return Ok((CryptoStream::with_prefix(stream, start), None));
+    }
+    if policy == EncryptionPolicy::Disabled {
+        return Err(handshake_error("Encrypted connections are not accepted"));
+    }
+
+    let mut remote_public = [0u8; KEY_LENGTH];
+    remote_public[..start.len()].copy_from_slice(&start);
+    remote_public[start.len()..].copy_from_slice(&read_bytes(&mut stream, KEY_LENGTH - start.len()).await?);
+
+    let keys = KeyPair::generate();
+    let secret = keys.shared_secret(&remote_public)?;
+    let mut message = keys.public.to_vec();
+    message.extend(random_padding());
+    write_bytes(&mut stream, &message).await?;
+
+    // PadA ends where HASH('req1', S) starts
+    let req1 = hash(&[b"req1", &secret]);
+    synchronize(&mut stream, &req1, MAX_PADDING + req1.len()).await?;
+
+    let obfuscated = read_bytes(&mut stream, 20).await?;
+    let req3 = hash(&[b"req3", &secret]);
+    let skey: Vec<u8> = obfuscated.iter().zip(req3.iter()).map(|(a, b)| a ^ b).collect();
+    let info_hash = *info_hashes
+        .iter()
+        .find(|info_hash| hash(&[b"req2", info_hash.as_slice()]).as_slice() == skey.as_slice())
+        .ok_or_else(|| handshake_error("Unknown torrent"))?;
+
+    let mut incoming = cipher(b"keyA", &secret, &info_hash);
+    let mut outgoing = cipher(b"keyB", &secret, &info_hash);
+
+    let mut negotiation = read_bytes(&mut stream, 14).await?;
+    incoming.apply(&mut negotiation);
+    if negotiation[..8] != VC {
+        return Err(handshake_error("Invalid verification constant"));
+    }
+    let provided = u32::from_be_bytes([negotiation[8], negotiation[9], negotiation[10], negotiation[11]]);
+    let padding_length = u16::from_be_bytes([negotiation[12], negotiation[13]]) as usize;
+    if padding_length > MAX_PADDING {
+        return Err(handshake_error("Padding too long"));
+    }
+    let mut padding = read_bytes(&mut stream, padding_length).await?;
+    incoming.apply(&mut padding);
+
+    let mut length = read_bytes(&mut stream, 2).await?;
+    incoming.apply(&mut length);
+    // The initial payload usually carries the BitTorrent handshake; replay it later
+    let mut initial_payload = read_bytes(&mut stream, u16::from_be_bytes([length[0], length[1]]) as usize).await?;
+    incoming.apply(&mut initial_payload);
+
+    let selected = policy
+        .crypto_select(provided)
+        .ok_or_else(|| handshake_error(format!("No acceptable method in {:#x}", provided)))?;
+
+    // VC, crypto_select, len(PadD) = 0
+    let mut reply = VC.to_vec();
+    reply.extend_from_slice(&selected.to_be_bytes());
+    reply.extend_from_slice(&0u16.to_be_bytes());
+    outgoing.apply(&mut reply);
+    write_bytes(&mut stream, &reply).await?;
+
+    let stream = if selected == CRYPTO_RC4 {
+        CryptoStream::encrypted(stream, initial_payload, incoming, outgoing)
+    } else {
+        CryptoStream::with_prefix(stream, initial_payload)
+    };
+    Ok((stream, Some(info_hash)))
+}
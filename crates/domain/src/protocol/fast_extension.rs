@@ -0,0 +1,39 @@
+use sha1::{Digest, Sha1};
+use std::net::Ipv4Addr;
+
+/// Reserved byte and bit advertising the fast extension (BEP 6)
+pub const FAST_EXTENSION_BYTE: usize = 7;
+pub const FAST_EXTENSION_BIT: u8 = 0x04;
+
+/// Number of allowed-fast pieces we grant each peer
+pub const ALLOWED_FAST_COUNT: usize = 10;
+
+/// The canonical allowed-fast set for a peer (BEP 6).
+///
+/// Derived from the peer's /24 network and the info hash, so every client
+/// computes the same set and a peer cannot collect more by reconnecting.
+pub fn allowed_fast_set(ip: Ipv4Addr, info_hash: &[u8; 20], piece_count: u32, count: usize) -> Vec<u32> {
+    let count = count.min(piece_count as usize);
+    let mut pieces = Vec::with_capacity(count);
+
+    let network = u32::from(ip) & 0xFFFF_FF00;
+    let mut x = Vec::with_capacity(24);
+    x.extend_from_slice(&network.to_be_bytes());
+    x.extend_from_slice(info_hash);
+
+    while pieces.len() < count {
+        x = Sha1::digest(&x).to_vec();
+        for chunk in x.chunks_exact(4) {
+            if pieces.len() >= count {
+                break;
+            }
+            let y = u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+            let index = y % piece_count;
+            if !pieces.contains(&index) {
+                pieces.push(index);
+            }
+        }
+    }
+
+    pieces
+}
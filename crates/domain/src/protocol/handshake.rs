@@ -0,0 +1,107 @@
+use crate::errors::DomainError;
+use crate::protocol::fast_extension::{FAST_EXTENSION_BIT, FAST_EXTENSION_BYTE};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+pub const PROTOCOL_NAME: &[u8; 19] = b"BitTorrent protocol";
+
+/// 1 + 19 + 8 + 20 + 20 bytes
+pub const HANDSHAKE_LENGTH: usize = 68;
+
+/// BitTorrent handshake: `<pstrlen><pstr><reserved><info_hash><peer_id>`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Handshake {
+    pub reserved: [u8; 8],
+    pub info_hash: [u8; 20],
+    pub peer_id: [u8; 20],
+}
+
+impl Handshake {
+    pub fn new(info_hash: [u8; 20], peer_id: [u8; 20]) -> Self {
+        Self {
+            reserved: [0u8; 8],
+            info_hash,
+            peer_id,
+        }
+    }
+
+    /// Advertise support for the fast extension (BEP 6)
+    pub fn with_fast_extension(mut self) -> Self {
+        self.reserved[FAST_EXTENSION_BYTE] |= FAST_EXTENSION_BIT;
+        self
+    }
+
+    pub fn supports_fast_extension(&self) -> bool {
+        self.reserved[FAST_EXTENSION_BYTE] & FAST_EXTENSION_BIT != 0
+    }
+
+    pub fn to_bytes(&self) -> [u8; HANDSHAKE_LENGTH] {
+        let mut bytes = [0u8; HANDSHAKE_LENGTH];
+        bytes[0] = PROTOCOL_NAME.len() as u8;
+        bytes[1..20].copy_from_slice(PROTOCOL_NAME);
+        bytes[20..28].copy_from_slice(&self.reserved);
+        bytes[28..48].copy_from_slice(&self.info_hash);
+        bytes[48..68].copy_from_slice(&self.peer_id);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, DomainError> {
+        if bytes.len() != HANDSHAKE_LENGTH {
+            return Err(DomainError::PeerConnectionError(format!(
+                "Handshake must be {} bytes, got {}",
+                HANDSHAKE_LENGTH,
+                bytes.len()
+            )));
+        }
+
+        if bytes[0] as usize != PROTOCOL_NAME.len() || &bytes[1..20] != PROTOCOL_NAME {
+            return Err(DomainError::PeerConnectionError("Invalid handshake response".to_string()));
+        }
+
+        let mut handshake = Self::new([0u8; 20], [0u8; 20]);
+        handshake.reserved.copy_from_slice(&bytes[20..28]);
+        handshake.info_hash.copy_from_slice(&bytes[28..48]);
+        handshake.peer_id.copy_from_slice(&bytes[48..68]);
+        Ok(handshake)
+    }
+
+    pub async fn write_to<W>(&self, writer: &mut W) -> Result<(), DomainError>
+    where
+        W: AsyncWrite + Unpin + ?Sized,
+    {
+        writer.write_all(&self.to_bytes()).await
+            .map_err(|e| DomainError::PeerConnectionError(format!("Failed to send handshake: {}", e)))?;
+        writer.flush().await
+            .map_err(|e| DomainError::PeerConnectionError(format!("Failed to send handshake: {}", e)))
+    }
+
+    pub async fn read_from<R>(reader: &mut R) -> Result<Self, DomainError>
+    where
+        R: AsyncRead + Unpin + ?Sized,
+    {
+        let mut bytes = [0u8; HANDSHAKE_LENGTH];
+        reader.read_exact(&mut bytes).await
+            .map_err(|e| DomainError::PeerConnectionError(format!("Failed to read handshake response: {}", e)))?;
+        Self::from_bytes(&bytes)
+    }
+}
+
+/// Decode a hex info hash as stored on `Torrent::info_hash`
+pub fn info_hash_bytes(info_hash: &str) -> Result<[u8; 20], DomainError> {
+    let bytes = hex::decode(info_hash)
+        .map_err(|e| DomainError::PeerConnectionError(format!("Invalid info hash: {}", e)))?;
+
+    bytes.try_into()
+        .map_err(|_| DomainError::PeerConnectionError("Info hash must be 20 bytes".to_string()))
+}
+
+/// Generate our peer_id (20 bytes) - format: -ST0001-xxxxxxxxxxxx
+pub fn generate_peer_id() -> [u8; 20] {
+    let mut peer_id = [0u8; 20];
+    peer_id[..8].copy_from_slice(b"-ST0001-");
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as u32;
+    peer_id[8..12].copy_from_slice(&timestamp.to_be_bytes());
+    peer_id[12..16].copy_from_slice(&rand::random::<u32>().to_be_bytes());
+    peer_id[16..20].copy_from_slice(&rand::random::<u32>().to_be_bytes());
+    peer_id
+}
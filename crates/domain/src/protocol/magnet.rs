@@ -0,0 +1,100 @@
+use crate::errors::DomainError;
+use url::Url;
+
+/// More file indices than any real torrent has; guards `so=` ranges
+const MAX_SELECTED_FILES: usize = 100_000;
+
+/// The parts of a magnet link (BEP 9) we use
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct MagnetLink {
+    /// Hex encoded info hash from `xt=urn:btih:`
+    pub info_hash: Option<String>,
+    pub display_name: Option<String>,
+    pub trackers: Vec<String>,
+    /// Where the .torrent file can be fetched (`xs`)
+    pub exact_source: Option<String>,
+    /// Indices of the files to download (BEP 53 `so`), sorted; every file when `None`
+    pub select_only: Option<Vec<usize>>,
+}
+
+impl MagnetLink {
+    pub fn is_magnet(uri: &str) -> bool {
+        uri.get(..7).is_some_and(|scheme| scheme.eq_ignore_ascii_case("magnet:"))
+    }
+
+    pub fn parse(uri: &str) -> Result<Self, DomainError> {
+        if !Self::is_magnet(uri) {
+            return Err(DomainError::ParseError(format!("Not a magnet link: {}", uri)));
+        }
+        let url = Url::parse(uri).map_err(|e| DomainError::ParseError(format!("Invalid magnet link: {}", e)))?;
+
+        let mut magnet = MagnetLink::default();
+        for (key, value) in url.query_pairs() {
+            match key.as_ref() {
+                "xt" => {
+                    if let Some(hash) = value.strip_prefix("urn:btih:") {
+                        magnet.info_hash = Some(parse_info_hash(hash)?);
+                    }
+                }
+                "dn" => magnet.display_name = Some(value.into_owned()),
+                "tr" => magnet.trackers.push(value.into_owned()),
+                "xs" => magnet.exact_source = Some(value.into_owned()),
+                "so" => magnet.select_only = Some(parse_select_only(&value)?),
+                _ => {}
+            }
+        }
+        Ok(magnet)
+    }
+}
+
+/// Parse a BEP 53 file selection such as `0,2,4-6` into sorted, unique indices
+pub fn parse_select_only(value: &str) -> Result<Vec<usize>, DomainError> {
+    let invalid = || DomainError::ParseError(format!("Invalid file selection: {}", value));
+    let mut indices = Vec::new();
+    for part in value.split(',').map(str::trim).filter(|part| !part.is_empty()) {
+        let (first, last) = match part.split_once('-') {
+            Some((first, last)) => (first.parse::<usize>().map_err(|_| invalid())?, last.parse::<usize>().map_err(|_| invalid())?),
+            None => {
+                let index = part.parse::<usize>().map_err(|_| invalid())?;
+                (index, index)
+            }
+        };
+        if last < first || last - first >= MAX_SELECTED_FILES || indices.len() + (last - first) >= MAX_SELECTED_FILES {
+            return Err(invalid());
+        }
+        indices.extend(first..=last);
+    }
+    indices.sort_unstable();
+    indices.dedup();
+    Ok(indices)
+}
+
+/// Info hashes come hex encoded (40 chars) or base32 encoded (32 chars)
+fn parse_info_hash(hash: &str) -> Result<String, DomainError> {
+    let invalid = || DomainError::ParseError(format!("Invalid info hash in magnet link: {}", hash));
+    match hash.len() {
+        40 => {
+            let bytes = hex::decode(hash).map_err(|_| invalid())?;
+            Ok(hex::encode(bytes))
+        }
+        32 => {
+            let mut bytes = Vec::with_capacity(20);
+            let (mut buffer, mut bits) = (0u64, 0u32);
+            for c in hash.bytes() {
+                let value = match c.to_ascii_uppercase() {
+                    c @ b'A'..=b'Z' => c - b'A',
+                    c @ b'2'..=b'7' => c - b'2' + 26,
+                    _ => return Err(invalid()),
+                };
+                buffer = (buffer << 5) | value as u64;
+                bits += 5;
+                if bits >= 8 {
+                    bits -= 8;
+                    bytes.push((buffer >> bits) as u8);
+                }
+            }
+            Ok(hex::encode(bytes))
+        }
+        _ => Err(invalid()),
+    }
+}
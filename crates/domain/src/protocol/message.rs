@@ -0,0 +1,356 @@
+use crate::errors::DomainError;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Default upper bound for a single peer wire frame (length prefix excluded).
+/// A 16 KiB block plus header is tiny, but bitfields of very large torrents and
+/// extended messages can legitimately grow past that.
+pub const DEFAULT_MAX_MESSAGE_LENGTH: usize = 1024 * 1024;
+
+/// Message IDs from BEP 3, BEP 5 (port), BEP 6 (fast extension) and BEP 10 (extended)
+pub mod message_id {
+    pub const CHOKE: u8 = 0;
+    pub const UNCHOKE: u8 = 1;
+    pub const INTERESTED: u8 = 2;
+    pub const NOT_INTERESTED: u8 = 3;
+    pub const HAVE: u8 = 4;
+    pub const BITFIELD: u8 = 5;
+    pub const REQUEST: u8 = 6;
+    pub const PIECE: u8 = 7;
+    pub const CANCEL: u8 = 8;
+    pub const PORT: u8 = 9;
+    pub const SUGGEST_PIECE: u8 = 0x0D;
+    pub const HAVE_ALL: u8 = 0x0E;
+    pub const HAVE_NONE: u8 = 0x0F;
+    pub const REJECT_REQUEST: u8 = 0x10;
+    pub const ALLOWED_FAST: u8 = 0x11;
+    pub const EXTENDED: u8 = 20;
+}
+
+/// A block within a piece, as carried by `request`, `cancel` and `reject_request`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BlockRequest {
+    pub piece_index: u32,
+    pub begin: u32,
+    pub length: u32,
+}
+
+impl BlockRequest {
+    pub fn new(piece_index: u32, begin: u32, length: u32) -> Self {
+        Self {
+            piece_index,
+            begin,
+            length,
+        }
+    }
+}
+
+/// Typed BitTorrent peer wire message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerMessage {
+    KeepAlive,
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    Have { piece_index: u32 },
+    Bitfield(Vec<u8>),
+    Request(BlockRequest),
+    Piece { piece_index: u32, begin: u32, data: Vec<u8> },
+    Cancel(BlockRequest),
+    Port(u16),
+    SuggestPiece { piece_index: u32 },
+    HaveAll,
+    HaveNone,
+    RejectRequest(BlockRequest),
+    AllowedFast { piece_index: u32 },
+    Extended { id: u8, payload: Vec<u8> },
+    /// A message we do not implement, kept so the connection survives it
+    Unknown { id: u8, payload: Vec<u8> },
+}
+
+impl PeerMessage {
+    /// Wire ID of this message, `None` for keep-alive
+    pub fn id(&self) -> Option<u8> {
+        use message_id::*;
+
+        match self {
+            PeerMessage::KeepAlive => None,
+            PeerMessage::Choke => Some(CHOKE),
+            PeerMessage::Unchoke => Some(UNCHOKE),
+            PeerMessage::Interested => Some(INTERESTED),
+            PeerMessage::NotInterested => Some(NOT_INTERESTED),
+            PeerMessage::Have { .. } => Some(HAVE),
+            PeerMessage::Bitfield(_) => Some(BITFIELD),
+            PeerMessage::Request(_) => Some(REQUEST),
+            PeerMessage::Piece { .. } => Some(PIECE),
+            PeerMessage::Cancel(_) => Some(CANCEL),
+            PeerMessage::Port(_) => Some(PORT),
+            PeerMessage::SuggestPiece { .. } => Some(SUGGEST_PIECE),
+            PeerMessage::HaveAll => Some(HAVE_ALL),
+            PeerMessage::HaveNone => Some(HAVE_NONE),
+            PeerMessage::RejectRequest(_) => Some(REJECT_REQUEST),
+            PeerMessage::AllowedFast { .. } => Some(ALLOWED_FAST),
+            PeerMessage::Extended { .. } => Some(EXTENDED),
+            PeerMessage::Unknown { id, .. } => Some(*id),
+        }
+    }
+
+    /// Length of the frame body (message ID plus payload), i.e. the value of the length prefix
+    pub fn body_len(&self) -> usize {
+        match self {
+            PeerMessage::KeepAlive => 0,
+            PeerMessage::Choke
+            | PeerMessage::Unchoke
+            | PeerMessage::Interested
+            | PeerMessage::NotInterested
+            | PeerMessage::HaveAll
+            | PeerMessage::HaveNone => 1,
+            PeerMessage::Have { .. }
+            | PeerMessage::SuggestPiece { .. }
+            | PeerMessage::AllowedFast { .. } => 5,
+            PeerMessage::Bitfield(bits) => 1 + bits.len(),
+            PeerMessage::Request(_) | PeerMessage::Cancel(_) | PeerMessage::RejectRequest(_) => 13,
+            PeerMessage::Piece { data, .. } => 9 + data.len(),
+            PeerMessage::Port(_) => 3,
+            PeerMessage::Extended { payload, .. } => 2 + payload.len(),
+            PeerMessage::Unknown { payload, .. } => 1 + payload.len(),
+        }
+    }
+
+    /// Append the length-prefixed frame for this message to `buf`
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        buf.reserve(4 + self.body_len());
+        buf.extend_from_slice(&(self.body_len() as u32).to_be_bytes());
+
+        let Some(id) = self.id() else {
+            return;
+        };
+        buf.push(id);
+
+        match self {
+            PeerMessage::Have { piece_index }
+            | PeerMessage::SuggestPiece { piece_index }
+            | PeerMessage::AllowedFast { piece_index } => {
+                buf.extend_from_slice(&piece_index.to_be_bytes());
+            }
+            PeerMessage::Bitfield(bits) => buf.extend_from_slice(bits),
+            PeerMessage::Request(block) | PeerMessage::Cancel(block) | PeerMessage::RejectRequest(block) => {
+                buf.extend_from_slice(&block.piece_index.to_be_bytes());
+                buf.extend_from_slice(&block.begin.to_be_bytes());
+                buf.extend_from_slice(&block.length.to_be_bytes());
+            }
+            PeerMessage::Piece { piece_index, begin, data } => {
+                buf.extend_from_slice(&piece_index.to_be_bytes());
+                buf.extend_from_slice(&begin.to_be_bytes());
+                buf.extend_from_slice(data);
+            }
+            PeerMessage::Port(port) => buf.extend_from_slice(&port.to_be_bytes()),
+            PeerMessage::Extended { id, payload } => {
+                buf.push(*id);
+                buf.extend_from_slice(payload);
+            }
+            PeerMessage::Unknown { payload, .. } => buf.extend_from_slice(payload),
+            _ => {}
+        }
+    }
+
+    /// Encode this message into a fresh buffer
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        self.encode(&mut buf);
+        buf
+    }
+
+    /// Parse a frame body (without the length prefix). An empty body is a keep-alive.
+    pub fn from_body(body: &[u8]) -> Result<Self, DomainError> {
+        use message_id::*;
+
+        let Some((&id, payload)) = body.split_first() else {
+            return Ok(PeerMessage::KeepAlive);
+        };
+
+        let message = match id {
+            CHOKE => expect_empty(id, payload, PeerMessage::Choke)?,
+            UNCHOKE => expect_empty(id, payload, PeerMessage::Unchoke)?,
+            INTERESTED => expect_empty(id, payload, PeerMessage::Interested)?,
+            NOT_INTERESTED => expect_empty(id, payload, PeerMessage::NotInterested)?,
+            HAVE_ALL => expect_empty(id, payload, PeerMessage::HaveAll)?,
+            HAVE_NONE => expect_empty(id, payload, PeerMessage::HaveNone)?,
+            HAVE => PeerMessage::Have {
+                piece_index: read_index(id, payload)?,
+            },
+            SUGGEST_PIECE => PeerMessage::SuggestPiece {
+                piece_index: read_index(id, payload)?,
+            },
+            ALLOWED_FAST => PeerMessage::AllowedFast {
+                piece_index: read_index(id, payload)?,
+            },
+            BITFIELD => PeerMessage::Bitfield(payload.to_vec()),
+            REQUEST => PeerMessage::Request(read_block(id, payload)?),
+            CANCEL => PeerMessage::Cancel(read_block(id, payload)?),
+            REJECT_REQUEST => PeerMessage::RejectRequest(read_block(id, payload)?),
+            PIECE => {
+                if payload.len() < 8 {
+                    return Err(invalid_length(id, payload.len()));
+                }
+                PeerMessage::Piece {
+                    piece_index: read_u32(&payload[0..4]),
+                    begin: read_u32(&payload[4..8]),
+                    data: payload[8..].to_vec(),
+                }
+            }
+            PORT => {
+                if payload.len() != 2 {
+                    return Err(invalid_length(id, payload.len()));
+                }
+                PeerMessage::Port(u16::from_be_bytes([payload[0], payload[1]]))
+            }
+            EXTENDED => {
+                let Some((&extended_id, extended_payload)) = payload.split_first() else {
+                    return Err(invalid_length(id, payload.len()));
+                };
+                PeerMessage::Extended {
+                    id: extended_id,
+                    payload: extended_payload.to_vec(),
+                }
+            }
+            unknown => PeerMessage::Unknown {
+                id: unknown,
+                payload: payload.to_vec(),
+            },
+        };
+
+        Ok(message)
+    }
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+fn invalid_length(id: u8, payload_len: usize) -> DomainError {
+    DomainError::ParseError(format!(
+        "Invalid payload length {} for peer message ID {}",
+        payload_len, id
+    ))
+}
+
+fn expect_empty(id: u8, payload: &[u8], message: PeerMessage) -> Result<PeerMessage, DomainError> {
+    if payload.is_empty() {
+        Ok(message)
+    } else {
+        Err(invalid_length(id, payload.len()))
+    }
+}
+
+fn read_index(id: u8, payload: &[u8]) -> Result<u32, DomainError> {
+    if payload.len() != 4 {
+        return Err(invalid_length(id, payload.len()));
+    }
+    Ok(read_u32(payload))
+}
+
+fn read_block(id: u8, payload: &[u8]) -> Result<BlockRequest, DomainError> {
+    if payload.len() != 12 {
+        return Err(invalid_length(id, payload.len()));
+    }
+    Ok(BlockRequest::new(
+        read_u32(&payload[0..4]),
+        read_u32(&payload[4..8]),
+        read_u32(&payload[8..12]),
+    ))
+}
+
+/// Length-prefixed framing for peer wire messages.
+///
+/// `decode` works on an accumulating byte buffer so callers can feed it partial
+/// reads; `read_message` / `write_message` drive it directly over async streams.
+#[derive(Debug, Clone, Copy)]
+pub struct MessageCodec {
+    max_message_length: usize,
+}
+
+impl Default for MessageCodec {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_MESSAGE_LENGTH)
+    }
+}
+
+impl MessageCodec {
+    pub fn new(max_message_length: usize) -> Self {
+        Self { max_message_length }
+    }
+
+    pub fn max_message_length(&self) -> usize {
+        self.max_message_length
+    }
+
+    /// Append the frame for `message` to `buf`
+    pub fn encode(&self, message: &PeerMessage, buf: &mut Vec<u8>) -> Result<(), DomainError> {
+        self.check_length(message.body_len())?;
+        message.encode(buf);
+        Ok(())
+    }
+
+    /// Try to take one complete frame off the front of `buf`.
+    /// Returns `Ok(None)` when more bytes are needed.
+    pub fn decode(&self, buf: &mut Vec<u8>) -> Result<Option<PeerMessage>, DomainError> {
+        if buf.len() < 4 {
+            return Ok(None);
+        }
+
+        let body_len = read_u32(&buf[..4]) as usize;
+        self.check_length(body_len)?;
+
+        if buf.len() < 4 + body_len {
+            return Ok(None);
+        }
+
+        let message = PeerMessage::from_body(&buf[4..4 + body_len]);
+        buf.drain(..4 + body_len);
+        message.map(Some)
+    }
+
+    /// Read exactly one message from `reader`
+    pub async fn read_message<R>(&self, reader: &mut R) -> Result<PeerMessage, DomainError>
+    where
+        R: AsyncRead + Unpin + ?Sized,
+    {
+        let mut length_bytes = [0u8; 4];
+        reader.read_exact(&mut length_bytes).await
+            .map_err(|e| DomainError::NetworkError(format!("Failed to read message length: {}", e)))?;
+
+        let body_len = u32::from_be_bytes(length_bytes) as usize;
+        self.check_length(body_len)?;
+
+        let mut body = vec![0u8; body_len];
+        reader.read_exact(&mut body).await
+            .map_err(|e| DomainError::NetworkError(format!("Failed to read message body: {}", e)))?;
+
+        PeerMessage::from_body(&body)
+    }
+
+    /// Write one message to `writer`
+    pub async fn write_message<W>(&self, writer: &mut W, message: &PeerMessage) -> Result<(), DomainError>
+    where
+        W: AsyncWrite + Unpin + ?Sized,
+    {
+        let mut buf = Vec::new();
+        self.encode(message, &mut buf)?;
+        writer.write_all(&buf).await
+            .map_err(|e| DomainError::NetworkError(format!("Failed to send peer message: {}", e)))?;
+        // Encrypted streams buffer what the socket did not take yet
+        writer.flush().await
+            .map_err(|e| DomainError::NetworkError(format!("Failed to send peer message: {}", e)))
+    }
+
+    fn check_length(&self, body_len: usize) -> Result<(), DomainError> {
+        if body_len > self.max_message_length {
+            return Err(DomainError::ParseError(format!(
+                "Peer message length {} exceeds maximum {}",
+                body_len, self.max_message_length
+            )));
+        }
+        Ok(())
+    }
+}
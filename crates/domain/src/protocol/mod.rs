@@ -0,0 +1,15 @@
+pub mod encryption;
+pub mod fast_extension;
+pub mod handshake;
+pub mod magnet;
+pub mod message;
+pub mod transport;
+pub mod utp;
+
+pub use encryption::{CryptoStream, EncryptionPolicy};
+pub use fast_extension::{allowed_fast_set, ALLOWED_FAST_COUNT};
+pub use handshake::{generate_peer_id, info_hash_bytes, Handshake, HANDSHAKE_LENGTH, PROTOCOL_NAME};
+pub use magnet::{parse_select_only, MagnetLink};
+pub use message::{BlockRequest, MessageCodec, PeerMessage, DEFAULT_MAX_MESSAGE_LENGTH};
+pub use transport::PeerStream;
+pub use utp::{UtpSocket, UtpStream};
@@ -0,0 +1,138 @@
+use super::connection::MAX_PAYLOAD;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Queuing delay LEDBAT aims for; above it we back off in favour of other traffic
+const TARGET_DELAY_MICROS: f64 = 100_000.0;
+
+/// Window growth per round trip when the delay is far below target
+const MAX_WINDOW_INCREASE_PER_RTT: f64 = 3000.0;
+
+const MIN_WINDOW: f64 = MAX_PAYLOAD as f64;
+const INITIAL_WINDOW: f64 = 3.0 * MAX_PAYLOAD as f64;
+const MAX_WINDOW: f64 = 1024.0 * 1024.0;
+
+/// Minutes of delay history the base delay is taken from
+const BASE_HISTORY_MINUTES: usize = 10;
+
+const INITIAL_RTO: Duration = Duration::from_secs(1);
+const MIN_RTO: Duration = Duration::from_millis(500);
+const MAX_RTO: Duration = Duration::from_secs(30);
+
+/// LEDBAT congestion window (RFC 6817).
+///
+/// The one-way delay reported by the peer is compared with the lowest delay
+/// seen recently; the difference is queuing delay we caused, and the window
+/// grows or shrinks in proportion to how far it is from the target.
+pub(super) struct Ledbat {
+    window: f64,
+    started_at: Instant,
+    /// Lowest delay per minute, newest last
+    base_delays: VecDeque<(u64, u32)>,
+    last_decrease: Option<Instant>,
+}
+
+impl Ledbat {
+    pub(super) fn new() -> Self {
+        Self {
+            window: INITIAL_WINDOW,
+            started_at: Instant::now(),
+            base_delays: VecDeque::new(),
+            last_decrease: None,
+        }
+    }
+
+    pub(super) fn window(&self) -> usize {
+        self.window as usize
+    }
+
+    /// `delay_micros` is the peer's measurement of our packets' one-way delay
+    pub(super) fn on_ack(&mut self, bytes_acked: usize, delay_micros: u32, now: Instant) {
+        if bytes_acked == 0 {
+            return;
+        }
+
+        let off_target = if delay_micros == 0 {
+            // No measurement yet
+            1.0
+        } else {
+            let base_delay = self.update_base_delay(delay_micros, now);
+            let queuing_delay = delay_micros.saturating_sub(base_delay) as f64;
+            ((TARGET_DELAY_MICROS - queuing_delay) / TARGET_DELAY_MICROS).clamp(-1.0, 1.0)
+        };
+
+        self.window += MAX_WINDOW_INCREASE_PER_RTT * off_target * bytes_acked as f64 / self.window;
+        self.window = self.window.clamp(MIN_WINDOW, MAX_WINDOW);
+    }
+
+    /// Halve the window, at most once per round trip
+    pub(super) fn on_loss(&mut self, now: Instant, rtt: Duration) {
+        if self.last_decrease.is_some_and(|at| now.duration_since(at) < rtt) {
+            return;
+        }
+        self.last_decrease = Some(now);
+        self.window = (self.window / 2.0).max(MIN_WINDOW);
+    }
+
+    pub(super) fn on_timeout(&mut self) {
+        self.window = MIN_WINDOW;
+    }
+
+    fn update_base_delay(&mut self, delay_micros: u32, now: Instant) -> u32 {
+        let minute = now.duration_since(self.started_at).as_secs() / 60;
+        match self.base_delays.back_mut() {
+            Some((bucket, lowest)) if *bucket == minute => *lowest = (*lowest).min(delay_micros),
+            _ => {
+                self.base_delays.push_back((minute, delay_micros));
+                if self.base_delays.len() > BASE_HISTORY_MINUTES {
+                    self.base_delays.pop_front();
+                }
+            }
+        }
+        self.base_delays.iter().map(|(_, lowest)| *lowest).min().unwrap_or(delay_micros)
+    }
+}
+
+/// Smoothed round-trip time and retransmission timeout (RFC 6298)
+pub(super) struct RttEstimator {
+    smoothed: Option<Duration>,
+    variance: Duration,
+    rto: Duration,
+}
+
+impl RttEstimator {
+    pub(super) fn new() -> Self {
+        Self {
+            smoothed: None,
+            variance: Duration::ZERO,
+            rto: INITIAL_RTO,
+        }
+    }
+
+    pub(super) fn rtt(&self) -> Duration {
+        self.smoothed.unwrap_or(INITIAL_RTO)
+    }
+
+    pub(super) fn rto(&self) -> Duration {
+        self.rto
+    }
+
+    pub(super) fn update(&mut self, sample: Duration) {
+        match self.smoothed {
+            None => {
+                self.smoothed = Some(sample);
+                self.variance = sample / 2;
+            }
+            Some(smoothed) => {
+                let deviation = smoothed.abs_diff(sample);
+                self.variance = self.variance * 3 / 4 + deviation / 4;
+                self.smoothed = Some(smoothed * 7 / 8 + sample / 8);
+            }
+        }
+        self.rto = (self.rtt() + self.variance * 4).clamp(MIN_RTO, MAX_RTO);
+    }
+
+    pub(super) fn backoff(&mut self) {
+        self.rto = (self.rto * 2).min(MAX_RTO);
+    }
+}
@@ -0,0 +1,577 @@
+use super::congestion::{Ledbat, RttEstimator};
+use super::packet::{Packet, PacketType};
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::OnceLock;
+use std::task::{Context, Poll, Waker};
+use std::time::Instant;
+use tokio::io::ReadBuf;
+use tokio::sync::oneshot;
+
+/// Payload per packet, leaving room for IP/UDP and uTP headers in a 1500-byte MTU
+pub(super) const MAX_PAYLOAD: usize = 1380;
+
+/// Bytes a writer may queue before `poll_write` waits
+const SEND_BUFFER: usize = 1024 * 1024;
+
+/// Bytes we accept ahead of the reader; advertised as our window
+const RECEIVE_BUFFER: usize = 1024 * 1024;
+
+/// Packets in flight, well inside the half of the sequence space that compares sanely
+const MAX_PACKETS_IN_FLIGHT: usize = 512;
+
+/// Consecutive timeouts before giving up on the peer
+const MAX_SYN_TIMEOUTS: u32 = 2;
+const MAX_TIMEOUTS: u32 = 6;
+
+/// Duplicate ACKs, or later packets selectively acknowledged, that mark a packet lost
+const DUPLICATE_ACK_THRESHOLD: u32 = 3;
+const SELECTIVE_ACK_THRESHOLD: usize = 3;
+
+/// Packets ahead of `ack_nr + 1` our selective ACKs describe
+const SELECTIVE_ACK_BITS: usize = 32;
+
+/// `a` comes before `b` in the wrapping sequence space
+pub(super) fn seq_before(a: u16, b: u16) -> bool {
+    (b.wrapping_sub(a) as i16) > 0
+}
+
+/// Microsecond clock for packet timestamps; only differences matter
+fn now_micros() -> u32 {
+    static EPOCH: OnceLock<Instant> = OnceLock::new();
+    EPOCH.get_or_init(Instant::now).elapsed().as_micros() as u32
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    SynSent,
+    Connected,
+    Closed,
+}
+
+/// A packet that needs an acknowledgement
+struct Outgoing {
+    seq_nr: u16,
+    packet_type: PacketType,
+    payload: Vec<u8>,
+    sent_at: Instant,
+    transmissions: u32,
+    needs_resend: bool,
+    fast_resent: bool,
+}
+
+/// State of one uTP connection.
+///
+/// Purely synchronous: packets and timer ticks go in, packets to send collect
+/// in an outbox that the socket drains after every call.
+pub(super) struct Connection {
+    remote: SocketAddr,
+    state: State,
+    recv_id: u16,
+    send_id: u16,
+    /// Next sequence number to send
+    seq_nr: u16,
+    /// Last sequence number received in order
+    ack_nr: u16,
+    /// Peer's delay measurement of our packets, echoed back to it
+    reply_micros: u32,
+
+    send_buffer: VecDeque<u8>,
+    in_flight: VecDeque<Outgoing>,
+    peer_window: usize,
+    congestion: Ledbat,
+    rtt: RttEstimator,
+    last_ack_nr: u16,
+    duplicate_acks: u32,
+    timeouts: u32,
+    fin_queued: bool,
+    fin_sent: bool,
+    fin_acked: bool,
+
+    received: VecDeque<u8>,
+    out_of_order: HashMap<u16, Packet>,
+    out_of_order_bytes: usize,
+    fin_seq_nr: Option<u16>,
+    eof: bool,
+
+    error: Option<io::ErrorKind>,
+    stream_dropped: bool,
+    connected: Option<oneshot::Sender<io::Result<()>>>,
+    read_waker: Option<Waker>,
+    write_waker: Option<Waker>,
+    close_waker: Option<Waker>,
+    outbox: Vec<Packet>,
+}
+
+impl Connection {
+    fn new(remote: SocketAddr, recv_id: u16, send_id: u16, state: State) -> Self {
+        Self {
+            remote,
+            state,
+            recv_id,
+            send_id,
+            seq_nr: 1,
+            ack_nr: 0,
+            reply_micros: 0,
+            send_buffer: VecDeque::new(),
+            in_flight: VecDeque::new(),
+            peer_window: RECEIVE_BUFFER,
+            congestion: Ledbat::new(),
+            rtt: RttEstimator::new(),
+            last_ack_nr: 0,
+            duplicate_acks: 0,
+            timeouts: 0,
+            fin_queued: false,
+            fin_sent: false,
+            fin_acked: false,
+            received: VecDeque::new(),
+            out_of_order: HashMap::new(),
+            out_of_order_bytes: 0,
+            fin_seq_nr: None,
+            eof: false,
+            error: None,
+            stream_dropped: false,
+            connected: None,
+            read_waker: None,
+            write_waker: None,
+            close_waker: None,
+            outbox: Vec::new(),
+        }
+    }
+
+    /// Outbound connection; `connected` fires once the peer answers our SYN
+    pub(super) fn connect(
+        remote: SocketAddr,
+        recv_id: u16,
+        connected: oneshot::Sender<io::Result<()>>,
+        now: Instant,
+    ) -> Self {
+        let mut connection = Self::new(remote, recv_id, recv_id.wrapping_add(1), State::SynSent);
+        connection.connected = Some(connected);
+        connection.queue(PacketType::Syn, Vec::new(), now);
+        connection
+    }
+
+    /// Inbound connection from a SYN; answered right away
+    pub(super) fn accept(remote: SocketAddr, syn: &Packet) -> Self {
+        let mut connection = Self::new(remote, syn.connection_id.wrapping_add(1), syn.connection_id, State::Connected);
+        connection.seq_nr = rand::random();
+        connection.ack_nr = syn.seq_nr;
+        connection.peer_window = syn.window_size as usize;
+        connection.reply_micros = now_micros().wrapping_sub(syn.timestamp);
+        connection.send_state();
+        connection
+    }
+
+    pub(super) fn remote(&self) -> SocketAddr {
+        self.remote
+    }
+
+    pub(super) fn send_id(&self) -> u16 {
+        self.send_id
+    }
+
+    pub(super) fn take_outbox(&mut self) -> Vec<Packet> {
+        std::mem::take(&mut self.outbox)
+    }
+
+    /// Nothing left to exchange; the socket can forget the connection
+    pub(super) fn is_finished(&self) -> bool {
+        self.state == State::Closed || (self.fin_acked && (self.eof || self.stream_dropped))
+    }
+
+    pub(super) fn handle_packet(&mut self, packet: Packet, now: Instant) {
+        if self.state == State::Closed {
+            return;
+        }
+        if packet.packet_type == PacketType::Reset {
+            self.fail(io::ErrorKind::ConnectionReset);
+            return;
+        }
+
+        self.peer_window = packet.window_size as usize;
+        self.reply_micros = now_micros().wrapping_sub(packet.timestamp);
+
+        if self.state == State::SynSent {
+            if packet.packet_type != PacketType::State {
+                return;
+            }
+            // The SYN-ACK does not consume a sequence number; the peer's first data packet does
+            self.state = State::Connected;
+            self.ack_nr = packet.seq_nr.wrapping_sub(1);
+            if let Some(connected) = self.connected.take() {
+                if connected.send(Ok(())).is_err() {
+                    // Whoever connected gave up waiting
+                    self.outbox.push(self.packet(PacketType::Reset, self.seq_nr));
+                    self.fail(io::ErrorKind::ConnectionAborted);
+                    return;
+                }
+            }
+        }
+
+        self.process_ack(&packet, now);
+
+        match packet.packet_type {
+            PacketType::Data | PacketType::Fin => self.receive(packet),
+            // Our SYN-ACK was lost
+            PacketType::Syn => self.send_state(),
+            _ => {}
+        }
+
+        self.send_pending(now);
+    }
+
+    /// Retransmit on timeout; called periodically by the socket
+    pub(super) fn tick(&mut self, now: Instant) {
+        if self.state == State::Closed {
+            return;
+        }
+
+        let oldest = self.in_flight.iter().filter(|packet| !packet.needs_resend).map(|packet| packet.sent_at).min();
+        let Some(oldest) = oldest else {
+            return;
+        };
+        if now.duration_since(oldest) < self.rtt.rto() {
+            return;
+        }
+
+        self.timeouts += 1;
+        let limit = if self.state == State::SynSent { MAX_SYN_TIMEOUTS } else { MAX_TIMEOUTS };
+        if self.timeouts > limit {
+            self.fail(io::ErrorKind::TimedOut);
+            return;
+        }
+
+        self.rtt.backoff();
+        self.congestion.on_timeout();
+        for packet in &mut self.in_flight {
+            packet.needs_resend = true;
+        }
+        self.send_pending(now);
+    }
+
+    pub(super) fn poll_read(&mut self, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        if !self.received.is_empty() {
+            let window_was_closed = self.receive_window() < MAX_PAYLOAD;
+            let count = buf.remaining().min(self.received.len());
+            let data: Vec<u8> = self.received.drain(..count).collect();
+            buf.put_slice(&data);
+
+            // Tell a sender stalled on our window that it may continue
+            if window_was_closed && self.receive_window() >= MAX_PAYLOAD && self.state == State::Connected {
+                self.send_state();
+            }
+            return Poll::Ready(Ok(()));
+        }
+
+        if self.eof {
+            return Poll::Ready(Ok(()));
+        }
+        if let Some(kind) = self.error {
+            return Poll::Ready(Err(kind.into()));
+        }
+
+        self.read_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+
+    pub(super) fn poll_write(&mut self, cx: &mut Context<'_>, data: &[u8]) -> Poll<io::Result<usize>> {
+        if let Some(kind) = self.error {
+            return Poll::Ready(Err(kind.into()));
+        }
+        if self.fin_queued {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+
+        let space = SEND_BUFFER.saturating_sub(self.send_buffer.len());
+        if space == 0 {
+            self.write_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+
+        let count = space.min(data.len());
+        self.send_buffer.extend(&data[..count]);
+        self.send_pending(Instant::now());
+        Poll::Ready(Ok(count))
+    }
+
+    /// Send FIN once everything queued is out, and wait until it is acknowledged
+    pub(super) fn poll_shutdown(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if self.state == State::Closed || self.fin_acked {
+            return Poll::Ready(Ok(()));
+        }
+        if !self.fin_queued {
+            self.fin_queued = true;
+            self.send_pending(Instant::now());
+        }
+
+        self.close_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+
+    /// The stream is gone; close gracefully once queued data is delivered
+    pub(super) fn on_stream_dropped(&mut self) {
+        self.stream_dropped = true;
+        if !self.fin_queued && self.state != State::Closed {
+            self.fin_queued = true;
+            self.send_pending(Instant::now());
+        }
+    }
+
+    fn process_ack(&mut self, packet: &Packet, now: Instant) {
+        let ack_nr = packet.ack_nr;
+        if seq_before(self.seq_nr.wrapping_sub(1), ack_nr) {
+            // Acknowledges something we never sent
+            return;
+        }
+
+        let mut acked_packets = 0;
+        let mut acked_bytes = 0;
+        let mut rtt_sample = None;
+        let mut account = |acked: Outgoing| {
+            acked_packets += 1;
+            acked_bytes += acked.payload.len();
+            // Karn's algorithm: retransmitted packets give ambiguous samples
+            if acked.transmissions == 1 {
+                rtt_sample = Some(now.duration_since(acked.sent_at));
+            }
+        };
+
+        while self.in_flight.front().is_some_and(|front| !seq_before(ack_nr, front.seq_nr)) {
+            account(self.in_flight.pop_front().unwrap());
+        }
+
+        let selective_acks = packet.selective_acks();
+        if !selective_acks.is_empty() {
+            let mut remaining = VecDeque::with_capacity(self.in_flight.len());
+            for outgoing in self.in_flight.drain(..) {
+                if selective_acks.contains(&outgoing.seq_nr) {
+                    account(outgoing);
+                } else {
+                    remaining.push_back(outgoing);
+                }
+            }
+            self.in_flight = remaining;
+        }
+
+        let mut lost = false;
+
+        // A packet with enough later packets acknowledged is lost
+        for outgoing in &mut self.in_flight {
+            let later = selective_acks.iter().filter(|seq_nr| seq_before(outgoing.seq_nr, **seq_nr)).count();
+            if later >= SELECTIVE_ACK_THRESHOLD && !outgoing.fast_resent {
+                outgoing.needs_resend = true;
+                outgoing.fast_resent = true;
+                lost = true;
+            }
+        }
+
+        if acked_packets > 0 {
+            self.timeouts = 0;
+            self.duplicate_acks = 0;
+            if let Some(sample) = rtt_sample {
+                self.rtt.update(sample);
+            }
+            self.congestion.on_ack(acked_bytes, packet.timestamp_difference, now);
+        } else if packet.packet_type == PacketType::State && ack_nr == self.last_ack_nr {
+            self.duplicate_acks += 1;
+            if self.duplicate_acks == DUPLICATE_ACK_THRESHOLD {
+                if let Some(front) = self.in_flight.front_mut().filter(|front| !front.fast_resent) {
+                    front.needs_resend = true;
+                    front.fast_resent = true;
+                    lost = true;
+                }
+            }
+        }
+
+        if lost {
+            self.congestion.on_loss(now, self.rtt.rtt());
+        }
+        self.last_ack_nr = ack_nr;
+
+        if self.fin_sent && self.in_flight.is_empty() && !self.fin_acked {
+            self.fin_acked = true;
+            wake(&mut self.close_waker);
+        }
+        if acked_packets > 0 {
+            wake(&mut self.write_waker);
+        }
+    }
+
+    fn receive(&mut self, packet: Packet) {
+        let seq_nr = packet.seq_nr;
+        if !seq_before(self.ack_nr, seq_nr) {
+            // Already delivered; our ACK was probably lost
+            self.send_state();
+            return;
+        }
+
+        let buffered = self.received.len() + self.out_of_order_bytes + packet.payload.len();
+        if buffered > RECEIVE_BUFFER {
+            // No room; the sender retransmits once the reader catches up
+            return;
+        }
+
+        if packet.packet_type == PacketType::Fin {
+            self.fin_seq_nr = Some(seq_nr);
+        }
+
+        if seq_nr == self.ack_nr.wrapping_add(1) {
+            self.deliver(packet);
+            while let Some(next) = self.out_of_order.remove(&self.ack_nr.wrapping_add(1)) {
+                self.out_of_order_bytes -= next.payload.len();
+                self.deliver(next);
+            }
+        } else if !self.out_of_order.contains_key(&seq_nr) {
+            self.out_of_order_bytes += packet.payload.len();
+            self.out_of_order.insert(seq_nr, packet);
+        }
+
+        self.send_state();
+    }
+
+    fn deliver(&mut self, packet: Packet) {
+        self.ack_nr = packet.seq_nr;
+        self.received.extend(packet.payload);
+        if self.fin_seq_nr == Some(self.ack_nr) {
+            self.eof = true;
+        }
+        wake(&mut self.read_waker);
+    }
+
+    /// Retransmit what is marked lost, then packetize new data, within the window
+    fn send_pending(&mut self, now: Instant) {
+        if self.state == State::Closed {
+            return;
+        }
+
+        let window = self.congestion.window().min(self.peer_window);
+        let mut bytes_in_flight: usize = self
+            .in_flight
+            .iter()
+            .filter(|outgoing| !outgoing.needs_resend)
+            .map(|outgoing| outgoing.payload.len())
+            .sum();
+        // With nothing in flight one packet always goes, which also probes a closed window
+        let fits = |bytes_in_flight: usize, length: usize| bytes_in_flight == 0 || bytes_in_flight + length <= window;
+
+        for index in 0..self.in_flight.len() {
+            if !self.in_flight[index].needs_resend {
+                continue;
+            }
+            let length = self.in_flight[index].payload.len();
+            if !fits(bytes_in_flight, length) {
+                return;
+            }
+            self.transmit(index, now);
+            bytes_in_flight += length;
+        }
+
+        // Before the SYN is answered only the SYN itself goes out
+        if self.state != State::Connected {
+            return;
+        }
+
+        let mut drained = false;
+        while !self.send_buffer.is_empty() && self.in_flight.len() < MAX_PACKETS_IN_FLIGHT {
+            let length = self.send_buffer.len().min(MAX_PAYLOAD);
+            if !fits(bytes_in_flight, length) {
+                break;
+            }
+            let payload: Vec<u8> = self.send_buffer.drain(..length).collect();
+            self.queue(PacketType::Data, payload, now);
+            bytes_in_flight += length;
+            drained = true;
+        }
+        if drained {
+            wake(&mut self.write_waker);
+        }
+
+        if self.fin_queued && !self.fin_sent && self.send_buffer.is_empty() {
+            self.fin_sent = true;
+            self.queue(PacketType::Fin, Vec::new(), now);
+        }
+    }
+
+    /// Assign the next sequence number and send
+    fn queue(&mut self, packet_type: PacketType, payload: Vec<u8>, now: Instant) {
+        self.in_flight.push_back(Outgoing {
+            seq_nr: self.seq_nr,
+            packet_type,
+            payload,
+            sent_at: now,
+            transmissions: 0,
+            needs_resend: false,
+            fast_resent: false,
+        });
+        self.seq_nr = self.seq_nr.wrapping_add(1);
+        self.transmit(self.in_flight.len() - 1, now);
+    }
+
+    fn transmit(&mut self, index: usize, now: Instant) {
+        let outgoing = &mut self.in_flight[index];
+        outgoing.sent_at = now;
+        outgoing.transmissions += 1;
+        outgoing.needs_resend = false;
+        let (packet_type, seq_nr, payload) = (outgoing.packet_type, outgoing.seq_nr, outgoing.payload.clone());
+
+        let mut packet = self.packet(packet_type, seq_nr);
+        packet.payload = payload;
+        if packet_type == PacketType::Syn {
+            // The SYN carries the ID we want to receive on
+            packet.connection_id = self.recv_id;
+        }
+        self.outbox.push(packet);
+    }
+
+    fn send_state(&mut self) {
+        self.outbox.push(self.packet(PacketType::State, self.seq_nr));
+    }
+
+    fn packet(&self, packet_type: PacketType, seq_nr: u16) -> Packet {
+        let mut packet = Packet::new(packet_type, self.send_id, seq_nr, self.ack_nr);
+        packet.timestamp = now_micros();
+        packet.timestamp_difference = self.reply_micros;
+        packet.window_size = self.receive_window() as u32;
+        packet.selective_ack = self.selective_ack();
+        packet
+    }
+
+    fn receive_window(&self) -> usize {
+        RECEIVE_BUFFER.saturating_sub(self.received.len() + self.out_of_order_bytes)
+    }
+
+    fn selective_ack(&self) -> Option<Vec<u8>> {
+        if self.out_of_order.is_empty() {
+            return None;
+        }
+
+        let mut mask = vec![0u8; SELECTIVE_ACK_BITS / 8];
+        for bit in 0..SELECTIVE_ACK_BITS {
+            let seq_nr = self.ack_nr.wrapping_add(2 + bit as u16);
+            if self.out_of_order.contains_key(&seq_nr) {
+                mask[bit / 8] |= 1 << (bit % 8);
+            }
+        }
+        Some(mask)
+    }
+
+    fn fail(&mut self, kind: io::ErrorKind) {
+        self.state = State::Closed;
+        self.error = Some(kind);
+        self.in_flight.clear();
+        if let Some(connected) = self.connected.take() {
+            let _ = connected.send(Err(kind.into()));
+        }
+        wake(&mut self.read_waker);
+        wake(&mut self.write_waker);
+        wake(&mut self.close_waker);
+    }
+}
+
+fn wake(waker: &mut Option<Waker>) {
+    if let Some(waker) = waker.take() {
+        waker.wake();
+    }
+}
@@ -0,0 +1,7 @@
+mod congestion;
+mod connection;
+pub mod packet;
+mod socket;
+
+pub use packet::{Packet, PacketType};
+pub use socket::{UtpSocket, UtpStream};
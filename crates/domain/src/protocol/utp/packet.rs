@@ -0,0 +1,148 @@
+use crate::errors::DomainError;
+
+/// Fixed header: type/version, extension, connection_id, two timestamps, window, seq_nr, ack_nr
+pub const HEADER_LENGTH: usize = 20;
+pub const VERSION: u8 = 1;
+
+const EXTENSION_NONE: u8 = 0;
+const EXTENSION_SELECTIVE_ACK: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketType {
+    Data,
+    Fin,
+    State,
+    Reset,
+    Syn,
+}
+
+impl PacketType {
+    fn id(&self) -> u8 {
+        match self {
+            PacketType::Data => 0,
+            PacketType::Fin => 1,
+            PacketType::State => 2,
+            PacketType::Reset => 3,
+            PacketType::Syn => 4,
+        }
+    }
+
+    fn from_id(id: u8) -> Result<Self, DomainError> {
+        match id {
+            0 => Ok(PacketType::Data),
+            1 => Ok(PacketType::Fin),
+            2 => Ok(PacketType::State),
+            3 => Ok(PacketType::Reset),
+            4 => Ok(PacketType::Syn),
+            other => Err(DomainError::ParseError(format!("Unknown uTP packet type {}", other))),
+        }
+    }
+}
+
+/// A uTP packet (BEP 29)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Packet {
+    pub packet_type: PacketType,
+    pub connection_id: u16,
+    pub timestamp: u32,
+    pub timestamp_difference: u32,
+    pub window_size: u32,
+    pub seq_nr: u16,
+    pub ack_nr: u16,
+    /// Selective ACK bitmask; bit `i` (least significant first) acknowledges `ack_nr + 2 + i`
+    pub selective_ack: Option<Vec<u8>>,
+    pub payload: Vec<u8>,
+}
+
+impl Packet {
+    pub fn new(packet_type: PacketType, connection_id: u16, seq_nr: u16, ack_nr: u16) -> Self {
+        Self {
+            packet_type,
+            connection_id,
+            timestamp: 0,
+            timestamp_difference: 0,
+            window_size: 0,
+            seq_nr,
+            ack_nr,
+            selective_ack: None,
+            payload: Vec::new(),
+        }
+    }
+
+    /// Sequence numbers acknowledged by the selective ACK extension
+    pub fn selective_acks(&self) -> Vec<u16> {
+        let Some(mask) = &self.selective_ack else {
+            return Vec::new();
+        };
+
+        (0..mask.len() * 8)
+            .filter(|bit| mask[bit / 8] & (1 << (bit % 8)) != 0)
+            .map(|bit| self.ack_nr.wrapping_add(2 + bit as u16))
+            .collect()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let extension_length = self.selective_ack.as_ref().map_or(0, |mask| 2 + mask.len());
+        let mut bytes = Vec::with_capacity(HEADER_LENGTH + extension_length + self.payload.len());
+
+        bytes.push((self.packet_type.id() << 4) | VERSION);
+        bytes.push(if self.selective_ack.is_some() { EXTENSION_SELECTIVE_ACK } else { EXTENSION_NONE });
+        bytes.extend_from_slice(&self.connection_id.to_be_bytes());
+        bytes.extend_from_slice(&self.timestamp.to_be_bytes());
+        bytes.extend_from_slice(&self.timestamp_difference.to_be_bytes());
+        bytes.extend_from_slice(&self.window_size.to_be_bytes());
+        bytes.extend_from_slice(&self.seq_nr.to_be_bytes());
+        bytes.extend_from_slice(&self.ack_nr.to_be_bytes());
+
+        if let Some(mask) = &self.selective_ack {
+            bytes.push(EXTENSION_NONE);
+            bytes.push(mask.len() as u8);
+            bytes.extend_from_slice(mask);
+        }
+
+        bytes.extend_from_slice(&self.payload);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, DomainError> {
+        if bytes.len() < HEADER_LENGTH {
+            return Err(DomainError::ParseError(format!("uTP packet too short: {} bytes", bytes.len())));
+        }
+        if bytes[0] & 0x0F != VERSION {
+            return Err(DomainError::ParseError(format!("Unsupported uTP version {}", bytes[0] & 0x0F)));
+        }
+
+        let u16_at = |offset: usize| u16::from_be_bytes([bytes[offset], bytes[offset + 1]]);
+        let u32_at = |offset: usize| {
+            u32::from_be_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]])
+        };
+
+        let mut packet = Packet::new(PacketType::from_id(bytes[0] >> 4)?, u16_at(2), u16_at(16), u16_at(18));
+        packet.timestamp = u32_at(4);
+        packet.timestamp_difference = u32_at(8);
+        packet.window_size = u32_at(12);
+
+        // Walk the extension chain, keeping the ones we understand
+        let mut extension = bytes[1];
+        let mut offset = HEADER_LENGTH;
+        while extension != EXTENSION_NONE {
+            if offset + 2 > bytes.len() {
+                return Err(DomainError::ParseError("Truncated uTP extension header".to_string()));
+            }
+            let next = bytes[offset];
+            let length = bytes[offset + 1] as usize;
+            offset += 2;
+            if offset + length > bytes.len() {
+                return Err(DomainError::ParseError("Truncated uTP extension".to_string()));
+            }
+            if extension == EXTENSION_SELECTIVE_ACK {
+                packet.selective_ack = Some(bytes[offset..offset + length].to_vec());
+            }
+            offset += length;
+            extension = next;
+        }
+
+        packet.payload = bytes[offset..].to_vec();
+        Ok(packet)
+    }
+}
@@ -0,0 +1,293 @@
+use super::connection::Connection;
+use super::packet::{Packet, PacketType};
+use crate::errors::DomainError;
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, Weak};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+
+/// How often connections are checked for retransmission timeouts
+const TICK_INTERVAL: Duration = Duration::from_millis(50);
+
+const MAX_DATAGRAM: usize = 64 * 1024;
+
+/// Connections are keyed by remote address and the ID the remote sends to us
+type ConnectionKey = (SocketAddr, u16);
+
+struct Shared {
+    udp: UdpSocket,
+    /// Same socket, for sending from synchronous code. Tokio's `try_send_to`
+    /// reports `WouldBlock` until the reactor has seen the socket writable.
+    sender: std::net::UdpSocket,
+    connections: Mutex<HashMap<ConnectionKey, Arc<Mutex<Connection>>>>,
+    incoming: mpsc::UnboundedSender<UtpStream>,
+}
+
+/// Owns the receive and timer tasks; they stop once the socket and every
+/// stream opened on it are dropped
+struct Driver {
+    shared: Arc<Shared>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Drop for Driver {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+/// A UDP socket carrying uTP connections (BEP 29), both ones we open and ones
+/// we accept. LEDBAT congestion control keeps bulk transfers from crowding out
+/// interactive traffic on the same link.
+pub struct UtpSocket {
+    driver: Arc<Driver>,
+    incoming: tokio::sync::Mutex<mpsc::UnboundedReceiver<UtpStream>>,
+}
+
+impl UtpSocket {
+    pub async fn bind(address: SocketAddr) -> Result<Self, DomainError> {
+        let bind_error = |e: io::Error| DomainError::NetworkError(format!("Failed to bind uTP socket on {}: {}", address, e));
+        let socket = std::net::UdpSocket::bind(address).map_err(bind_error)?;
+        socket.set_nonblocking(true).map_err(bind_error)?;
+        let sender = socket.try_clone().map_err(bind_error)?;
+        let udp = UdpSocket::from_std(socket).map_err(bind_error)?;
+
+        let (incoming_tx, incoming) = mpsc::unbounded_channel();
+        let shared = Arc::new(Shared {
+            udp,
+            sender,
+            connections: Mutex::new(HashMap::new()),
+            incoming: incoming_tx,
+        });
+
+        let driver = Arc::new_cyclic(|driver: &Weak<Driver>| Driver {
+            tasks: vec![
+                tokio::spawn(Shared::receive_loop(shared.clone(), driver.clone())),
+                tokio::spawn(Shared::tick_loop(shared.clone())),
+            ],
+            shared,
+        });
+
+        Ok(Self {
+            driver,
+            incoming: tokio::sync::Mutex::new(incoming),
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, DomainError> {
+        self.driver.shared.udp.local_addr()
+            .map_err(|e| DomainError::NetworkError(format!("Failed to read uTP socket address: {}", e)))
+    }
+
+    /// Live connections on this socket, inbound and outbound
+    pub fn connection_count(&self) -> usize {
+        self.driver.shared.connections.lock().unwrap().len()
+    }
+
+    /// Open a connection; fails once the SYN has been retried without answer
+    pub async fn connect(&self, remote: SocketAddr) -> Result<UtpStream, DomainError> {
+        let (connected_tx, connected) = oneshot::channel();
+
+        let connection = {
+            let mut connections = self.driver.shared.connections.lock().unwrap();
+            let recv_id = loop {
+                let recv_id = rand::random::<u16>();
+                if !connections.contains_key(&(remote, recv_id)) {
+                    break recv_id;
+                }
+            };
+            let connection = Arc::new(Mutex::new(Connection::connect(remote, recv_id, connected_tx, Instant::now())));
+            connections.insert((remote, recv_id), connection.clone());
+            connection
+        };
+        self.driver.shared.flush(&mut connection.lock().unwrap());
+
+        match connected.await {
+            Ok(Ok(())) => Ok(UtpStream::new(self.driver.clone(), connection, remote)),
+            Ok(Err(e)) => Err(DomainError::NetworkError(format!("uTP connection to {} failed: {}", remote, e))),
+            Err(_) => Err(DomainError::NetworkError(format!("uTP connection to {} was dropped", remote))),
+        }
+    }
+
+    /// Wait for the next inbound connection
+    pub async fn accept(&self) -> Result<UtpStream, DomainError> {
+        self.incoming.lock().await.recv().await
+            .ok_or_else(|| DomainError::NetworkError("uTP socket closed".to_string()))
+    }
+}
+
+impl Shared {
+    async fn receive_loop(shared: Arc<Shared>, driver: Weak<Driver>) {
+        let mut buf = vec![0u8; MAX_DATAGRAM];
+        loop {
+            // ICMP errors from earlier sends surface here; they do not end the socket
+            if let Ok((length, from)) = shared.udp.recv_from(&mut buf).await {
+                shared.handle_datagram(&buf[..length], from, &driver);
+            }
+        }
+    }
+
+    async fn tick_loop(shared: Arc<Shared>) {
+        let mut interval = tokio::time::interval(TICK_INTERVAL);
+        loop {
+            interval.tick().await;
+            shared.tick();
+        }
+    }
+
+    fn handle_datagram(&self, bytes: &[u8], from: SocketAddr, driver: &Weak<Driver>) {
+        let Ok(packet) = Packet::from_bytes(bytes) else {
+            return;
+        };
+        let now = Instant::now();
+
+        let connection = if packet.packet_type == PacketType::Reset {
+            self.find_reset_target(from, packet.connection_id)
+        } else {
+            // A SYN carries the initiator's receive ID; it sends everything else with ID + 1
+            let recv_id = match packet.packet_type {
+                PacketType::Syn => packet.connection_id.wrapping_add(1),
+                _ => packet.connection_id,
+            };
+            self.connections.lock().unwrap().get(&(from, recv_id)).cloned()
+        };
+
+        if let Some(connection) = connection {
+            let mut connection = connection.lock().unwrap();
+            connection.handle_packet(packet, now);
+            self.flush(&mut connection);
+            return;
+        }
+
+        match packet.packet_type {
+            PacketType::Syn => {
+                let Some(driver) = driver.upgrade() else {
+                    return;
+                };
+                let mut connection = Connection::accept(from, &packet);
+                self.flush(&mut connection);
+
+                let connection = Arc::new(Mutex::new(connection));
+                self.connections.lock().unwrap().insert((from, packet.connection_id.wrapping_add(1)), connection.clone());
+                let _ = self.incoming.send(UtpStream::new(driver, connection, from));
+            }
+            PacketType::Reset => {}
+            // Unknown connection: tell the sender to stop
+            _ => {
+                let reset = Packet::new(PacketType::Reset, packet.connection_id, rand::random(), packet.seq_nr);
+                let _ = self.sender.send_to(&reset.to_bytes(), from);
+            }
+        }
+    }
+
+    /// Resets may carry either of the two connection IDs
+    fn find_reset_target(&self, from: SocketAddr, connection_id: u16) -> Option<Arc<Mutex<Connection>>> {
+        let connections = self.connections.lock().unwrap();
+        if let Some(connection) = connections.get(&(from, connection_id)) {
+            return Some(connection.clone());
+        }
+        connections
+            .values()
+            .find(|connection| {
+                let connection = connection.lock().unwrap();
+                connection.remote() == from && connection.send_id() == connection_id
+            })
+            .cloned()
+    }
+
+    fn tick(&self) {
+        let now = Instant::now();
+        let connections: Vec<(ConnectionKey, Arc<Mutex<Connection>>)> = self
+            .connections
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(key, connection)| (*key, connection.clone()))
+            .collect();
+
+        let mut finished = Vec::new();
+        for (key, connection) in connections {
+            let mut connection = connection.lock().unwrap();
+            connection.tick(now);
+            self.flush(&mut connection);
+            if connection.is_finished() {
+                finished.push(key);
+            }
+        }
+
+        if !finished.is_empty() {
+            let mut connections = self.connections.lock().unwrap();
+            for key in finished {
+                connections.remove(&key);
+            }
+        }
+    }
+
+    /// Send what the connection queued. A full socket buffer counts as loss.
+    fn flush(&self, connection: &mut Connection) {
+        let remote = connection.remote();
+        for packet in connection.take_outbox() {
+            let _ = self.sender.send_to(&packet.to_bytes(), remote);
+        }
+    }
+}
+
+/// A reliable, ordered byte stream over uTP
+pub struct UtpStream {
+    driver: Arc<Driver>,
+    connection: Arc<Mutex<Connection>>,
+    peer_addr: SocketAddr,
+}
+
+impl UtpStream {
+    fn new(driver: Arc<Driver>, connection: Arc<Mutex<Connection>>, peer_addr: SocketAddr) -> Self {
+        Self { driver, connection, peer_addr }
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+
+    fn with_connection<T>(&self, operation: impl FnOnce(&mut Connection) -> T) -> T {
+        let mut connection = self.connection.lock().unwrap();
+        let result = operation(&mut connection);
+        self.driver.shared.flush(&mut connection);
+        result
+    }
+}
+
+impl AsyncRead for UtpStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        self.with_connection(|connection| connection.poll_read(cx, buf))
+    }
+}
+
+impl AsyncWrite for UtpStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        self.with_connection(|connection| connection.poll_write(cx, buf))
+    }
+
+    /// Written bytes are owned by the connection and retransmitted as needed
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.with_connection(|connection| connection.poll_shutdown(cx))
+    }
+}
+
+impl Drop for UtpStream {
+    fn drop(&mut self) {
+        self.with_connection(|connection| connection.on_stream_dropped());
+    }
+}
@@ -0,0 +1,10 @@
+use crate::entities::FileSnapshot;
+use crate::errors::DomainError;
+use async_trait::async_trait;
+
+#[async_trait]
+pub trait FileSnapshotRepository: Send + Sync {
+    async fn find_by_torrent_id(&self, torrent_id: i32) -> Result<Vec<FileSnapshot>, DomainError>;
+    /// Replace every snapshot of a torrent with `snapshots`
+    async fn replace_for_torrent(&self, torrent_id: i32, snapshots: &[FileSnapshot]) -> Result<(), DomainError>;
+}
@@ -2,8 +2,12 @@ pub mod torrent_repository;
 pub mod piece_repository;
 pub mod peer_repository;
 pub mod tracker_repository;
+pub mod torrent_file_repository;
+pub mod file_snapshot_repository;
 
 pub use torrent_repository::TorrentRepository;
 pub use piece_repository::PieceRepository;
 pub use peer_repository::PeerRepository;
 pub use tracker_repository::TrackerRepository;
+pub use torrent_file_repository::TorrentFileRepository;
+pub use file_snapshot_repository::FileSnapshotRepository;
@@ -9,6 +9,8 @@ pub trait PieceRepository: Send + Sync {
     async fn save(&self, piece: &Piece) -> Result<Piece, DomainError>;
     async fn update(&self, piece: &Piece) -> Result<Piece, DomainError>;
     async fn save_batch(&self, pieces: &[Piece]) -> Result<Vec<Piece>, DomainError>;
+    /// Update the state of many pieces at once, e.g. after a recheck
+    async fn update_batch(&self, pieces: &[Piece]) -> Result<(), DomainError>;
     async fn count_downloaded(&self, torrent_id: i32) -> Result<i32, DomainError>;
     async fn find_next_needed(&self, torrent_id: i32, limit: i32) -> Result<Vec<Piece>, DomainError>;
 }
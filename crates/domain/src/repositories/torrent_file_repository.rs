@@ -0,0 +1,11 @@
+use crate::entities::TorrentFile;
+use crate::errors::DomainError;
+use async_trait::async_trait;
+
+#[async_trait]
+pub trait TorrentFileRepository: Send + Sync {
+    /// Files of a torrent in the order they appear in it
+    async fn find_by_torrent_id(&self, torrent_id: i32) -> Result<Vec<TorrentFile>, DomainError>;
+    async fn save_batch(&self, files: &[TorrentFile]) -> Result<Vec<TorrentFile>, DomainError>;
+    async fn update(&self, file: &TorrentFile) -> Result<TorrentFile, DomainError>;
+}
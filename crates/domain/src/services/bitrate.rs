@@ -0,0 +1,200 @@
+use std::time::{Duration, Instant};
+
+/// How much of a file's head is read to find its duration
+pub const PROBE_BYTES: u64 = 256 * 1024;
+
+/// Observed consumption is trusted after watching playback this long
+const MIN_OBSERVATION: Duration = Duration::from_secs(20);
+
+/// A read starting further than this past the furthest byte read is a seek
+const SEEK_GAP: u64 = 8 * 1024 * 1024;
+
+const EBML_HEADER: u32 = 0x1A45_DFA3;
+const MKV_SEGMENT: u32 = 0x1853_8067;
+const MKV_INFO: u32 = 0x1549_A966;
+const MKV_CLUSTER: u32 = 0x1F43_B675;
+const MKV_TIMECODE_SCALE: u32 = 0x2A_D7B1;
+const MKV_DURATION: u32 = 0x4489;
+
+/// Where a stream's bitrate came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BitrateSource {
+    /// Nothing known yet; `DEFAULT_BITRATE` is assumed
+    Default,
+    /// Measured from how fast the player reads
+    Observed,
+    /// File size over the duration in the container header
+    Container,
+}
+
+/// Average bytes per second of a file playing for `duration`
+pub fn bitrate_for(file_size: u64, duration: Duration) -> Option<u64> {
+    let seconds = duration.as_secs_f64();
+    if seconds < 1.0 || file_size == 0 {
+        return None;
+    }
+    Some((file_size as f64 / seconds).round() as u64)
+}
+
+/// Playback duration from the head of an MP4 (`moov/mvhd`) or Matroska
+/// (`Segment/Info`) file. `None` when the header is not there, e.g. an MP4
+/// whose `moov` box sits at the end of the file.
+pub fn container_duration(header: &[u8]) -> Option<Duration> {
+    mkv_duration(header).or_else(|| mp4_duration(header))
+}
+
+fn be_u32(data: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(data.get(at..at + 4)?.try_into().ok()?))
+}
+
+fn be_u64(data: &[u8], at: usize) -> Option<u64> {
+    Some(u64::from_be_bytes(data.get(at..at + 8)?.try_into().ok()?))
+}
+
+fn mp4_duration(data: &[u8]) -> Option<Duration> {
+    let moov = find_box(data, b"moov")?;
+    let mvhd = find_box(moov, b"mvhd")?;
+    let (timescale, duration) = match mvhd.first()? {
+        1 => (be_u32(mvhd, 20)?, be_u64(mvhd, 24)?),
+        _ => (be_u32(mvhd, 12)?, be_u32(mvhd, 16)? as u64),
+    };
+    if timescale == 0 || duration == 0 || duration == u32::MAX as u64 || duration == u64::MAX {
+        return None;
+    }
+    Some(Duration::from_secs_f64(duration as f64 / timescale as f64))
+}
+
+/// Body of the first box of type `kind` among the boxes in `data`. The body
+/// is cut short when the box runs past the data read so far.
+fn find_box<'a>(mut data: &'a [u8], kind: &[u8; 4]) -> Option<&'a [u8]> {
+    while data.len() >= 8 {
+        let (header, size) = match be_u32(data, 0)? {
+            1 => (16, be_u64(data, 8)?),
+            0 => (8, data.len() as u64),
+            size => (8, size as u64),
+        };
+        if size < header as u64 {
+            return None;
+        }
+        let end = size.min(data.len() as u64) as usize;
+        if &data[4..8] == kind {
+            return data.get(header..end);
+        }
+        if size >= data.len() as u64 {
+            return None;
+        }
+        data = &data[end..];
+    }
+    None
+}
+
+/// An EBML element ID, with its marker bits, and its length in bytes
+fn ebml_id(data: &[u8]) -> Option<(u32, usize)> {
+    let length = data.first()?.leading_zeros() as usize + 1;
+    if length > 4 {
+        return None;
+    }
+    let id = data.get(..length)?.iter().fold(0u32, |id, byte| (id << 8) | *byte as u32);
+    Some((id, length))
+}
+
+/// An EBML element size (`None` for unknown) and its length in bytes
+fn ebml_size(data: &[u8]) -> Option<(Option<u64>, usize)> {
+    let first = *data.first()?;
+    let length = first.leading_zeros() as usize + 1;
+    if length > 8 {
+        return None;
+    }
+    let mut size = (first as u64) & (0xFF >> length);
+    for byte in data.get(1..length)? {
+        size = (size << 8) | *byte as u64;
+    }
+    let unknown = size == (1u64 << (7 * length)) - 1;
+    Some((if unknown { None } else { Some(size) }, length))
+}
+
+/// The elements in `data` as (id, body) pairs; an element of unknown size,
+/// or one running past the data, gets the rest of it
+fn ebml_elements(mut data: &[u8]) -> impl Iterator<Item = (u32, &[u8])> {
+    std::iter::from_fn(move || {
+        let (id, id_length) = ebml_id(data)?;
+        let (size, size_length) = ebml_size(&data[id_length..])?;
+        let start = id_length + size_length;
+        let end = size.map_or(data.len() as u64, |size| (start as u64).saturating_add(size)).min(data.len() as u64) as usize;
+        let body = data.get(start..end)?;
+        data = &data[end..];
+        Some((id, body))
+    })
+}
+
+fn mkv_duration(data: &[u8]) -> Option<Duration> {
+    let mut top = ebml_elements(data);
+    if top.next()?.0 != EBML_HEADER {
+        return None;
+    }
+    let (_, segment) = top.find(|(id, _)| *id == MKV_SEGMENT)?;
+    let (_, info) = ebml_elements(segment)
+        .take_while(|(id, _)| *id != MKV_CLUSTER)
+        .find(|(id, _)| *id == MKV_INFO)?;
+
+    let mut timecode_scale = 1_000_000u64;
+    let mut duration = None;
+    for (id, body) in ebml_elements(info) {
+        match id {
+            MKV_TIMECODE_SCALE if !body.is_empty() && body.len() <= 8 => {
+                timecode_scale = body.iter().fold(0u64, |value, byte| (value << 8) | *byte as u64);
+            }
+            MKV_DURATION => {
+                duration = match body.len() {
+                    4 => Some(f32::from_be_bytes(body.try_into().ok()?) as f64),
+                    8 => Some(f64::from_be_bytes(body.try_into().ok()?)),
+                    _ => None,
+                };
+            }
+            _ => {}
+        }
+    }
+
+    let nanos = duration? * timecode_scale as f64;
+    if !nanos.is_finite() || nanos <= 0.0 {
+        return None;
+    }
+    Some(Duration::from_secs_f64(nanos / 1e9))
+}
+
+/// Estimates a stream's bitrate from how fast the player reads through it.
+///
+/// Players read ahead in bursts, so the estimate is only trusted after a
+/// while. A seek restarts the measurement.
+#[derive(Debug, Clone, Default)]
+pub struct ConsumptionRate {
+    /// Where and when the current run of sequential reads started
+    anchor: Option<(u64, Instant)>,
+    furthest: u64,
+}
+
+impl ConsumptionRate {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a read of `start..end` at `now`
+    pub fn observe(&mut self, start: u64, end: u64, now: Instant) {
+        let sequential = self.anchor.is_some_and(|(anchor, _)| start >= anchor && start <= self.furthest + SEEK_GAP);
+        if !sequential {
+            self.anchor = Some((start, now));
+            self.furthest = start;
+        }
+        self.furthest = self.furthest.max(end);
+    }
+
+    /// Bytes per second played, once playback has been watched long enough
+    pub fn bitrate(&self, now: Instant) -> Option<u64> {
+        let (anchor, started) = self.anchor?;
+        let elapsed = now.saturating_duration_since(started);
+        if elapsed < MIN_OBSERVATION {
+            return None;
+        }
+        Some(((self.furthest - anchor) as f64 / elapsed.as_secs_f64()).round() as u64).filter(|rate| *rate > 0)
+    }
+}
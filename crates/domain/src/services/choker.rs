@@ -0,0 +1,182 @@
+use crate::entities::TorrentStatus;
+use crate::protocol::PeerMessage;
+use crate::repositories::TorrentRepository;
+use crate::services::connection_manager::ConnectionManager;
+use crate::services::peer_connection::{PeerHandle, PeerStats};
+use rand::seq::SliceRandom;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How often upload slots are reassigned (BEP 3 suggests every 10 seconds)
+pub const RECHOKE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How often the optimistic unchoke moves to another peer
+pub const OPTIMISTIC_UNCHOKE_INTERVAL: Duration = Duration::from_secs(30);
+
+pub const DEFAULT_UPLOAD_SLOTS_PER_TORRENT: usize = 4;
+pub const DEFAULT_GLOBAL_UPLOAD_SLOTS: usize = 20;
+
+/// The peer currently holding the optimistic slot
+struct OptimisticUnchoke {
+    handle: Option<PeerHandle>,
+    rotated_at: Option<Instant>,
+}
+
+/// Tit-for-tat choker over every connection of the connection manager.
+///
+/// Interested peers are ranked by the rate they give us while we leech, or by the
+/// rate we achieve to them once we seed, and the best ones get the upload slots.
+/// Snubbed peers are ranked last. One extra slot rotates every 30 seconds to a
+/// random choked peer so newcomers get a chance to prove themselves.
+pub struct Choker {
+    connection_manager: Arc<ConnectionManager>,
+    torrent_repository: Arc<dyn TorrentRepository>,
+    upload_slots_per_torrent: usize,
+    global_upload_slots: usize,
+    optimistic: Mutex<OptimisticUnchoke>,
+}
+
+/// An unchoke candidate with the rate it is ranked by
+struct Candidate {
+    handle: PeerHandle,
+    stats: PeerStats,
+    rate: f64,
+}
+
+impl Choker {
+    pub fn new(
+        connection_manager: Arc<ConnectionManager>,
+        torrent_repository: Arc<dyn TorrentRepository>,
+    ) -> Self {
+        Self {
+            connection_manager,
+            torrent_repository,
+            upload_slots_per_torrent: DEFAULT_UPLOAD_SLOTS_PER_TORRENT,
+            global_upload_slots: DEFAULT_GLOBAL_UPLOAD_SLOTS,
+            optimistic: Mutex::new(OptimisticUnchoke {
+                handle: None,
+                rotated_at: None,
+            }),
+        }
+    }
+
+    /// Cap on regular unchokes, per torrent and across all torrents
+    pub fn with_upload_slots(mut self, per_torrent: usize, global: usize) -> Self {
+        self.upload_slots_per_torrent = per_torrent;
+        self.global_upload_slots = global;
+        self
+    }
+
+    /// Rechoke every `RECHOKE_INTERVAL` until the task is dropped
+    pub fn spawn(self: &Arc<Self>) -> tokio::task::JoinHandle<()> {
+        let choker = Arc::clone(self);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(RECHOKE_INTERVAL);
+            loop {
+                interval.tick().await;
+                choker.rechoke().await;
+            }
+        })
+    }
+
+    /// Reassign upload slots once
+    pub async fn rechoke(&self) {
+        let mut candidates = Vec::new();
+        let mut all_handles = Vec::new();
+
+        for torrent_id in self.connection_manager.torrent_ids() {
+            let seeding = self.is_seeding(torrent_id).await;
+            for handle in self.connection_manager.handles(torrent_id) {
+                let stats = handle.stats();
+                if stats.peer_interested {
+                    // Leeching: reward peers that upload to us. Seeding: prefer peers that take data fastest.
+                    let rate = if seeding { stats.upload_rate } else { stats.download_rate };
+                    candidates.push(Candidate { handle: handle.clone(), stats, rate });
+                }
+                all_handles.push(handle);
+            }
+        }
+
+        // Non-snubbed peers first, then by rate
+        candidates.sort_by(|a, b| {
+            a.stats.snubbed.cmp(&b.stats.snubbed)
+                .then(b.rate.partial_cmp(&a.rate).unwrap_or(std::cmp::Ordering::Equal))
+        });
+
+        let regular_slots = self.global_upload_slots.saturating_sub(1);
+        let mut unchoked_per_torrent: HashMap<i32, usize> = HashMap::new();
+        let mut unchoke: Vec<PeerHandle> = Vec::new();
+
+        for candidate in &candidates {
+            if unchoke.len() >= regular_slots {
+                break;
+            }
+            let torrent_slots = unchoked_per_torrent.entry(candidate.handle.torrent_id()).or_default();
+            if *torrent_slots < self.upload_slots_per_torrent {
+                *torrent_slots += 1;
+                unchoke.push(candidate.handle.clone());
+            }
+        }
+
+        if let Some(optimistic) = self.optimistic_unchoke(&candidates, &unchoke) {
+            unchoke.push(optimistic);
+        }
+
+        for handle in all_handles {
+            let should_unchoke = unchoke.iter().any(|selected| selected.same_connection(&handle));
+            let am_choking = handle.stats().am_choking;
+
+            if should_unchoke && am_choking {
+                let _ = handle.send(PeerMessage::Unchoke);
+            } else if !should_unchoke && !am_choking {
+                let _ = handle.send(PeerMessage::Choke);
+            }
+        }
+    }
+
+    /// Keep the current optimistic peer for 30 seconds, then move the slot to a
+    /// random interested peer that did not earn a regular slot
+    fn optimistic_unchoke(&self, candidates: &[Candidate], regular: &[PeerHandle]) -> Option<PeerHandle> {
+        let mut optimistic = self.optimistic.lock().unwrap();
+
+        let current_valid = optimistic.handle.as_ref().is_some_and(|current| {
+            candidates.iter().any(|candidate| candidate.handle.same_connection(current))
+        });
+        let due = optimistic
+            .rotated_at
+            .is_none_or(|rotated_at| rotated_at.elapsed() >= OPTIMISTIC_UNCHOKE_INTERVAL);
+
+        if !current_valid || due {
+            let choked: Vec<&PeerHandle> = candidates
+                .iter()
+                .map(|candidate| &candidate.handle)
+                .filter(|handle| !regular.iter().any(|selected| selected.same_connection(handle)))
+                .filter(|handle| {
+                    // Rotate away from the current holder when there is anyone else
+                    !optimistic.handle.as_ref().is_some_and(|current| current.same_connection(handle))
+                })
+                .collect();
+
+            let next = choked.choose(&mut rand::thread_rng()).map(|handle| (*handle).clone());
+            // Nobody else to try: keep the current holder if it is still eligible
+            if next.is_some() || !current_valid {
+                optimistic.handle = next;
+            }
+            optimistic.rotated_at = Some(Instant::now());
+        }
+
+        optimistic
+            .handle
+            .as_ref()
+            .filter(|current| !regular.iter().any(|selected| selected.same_connection(current)))
+            .cloned()
+    }
+
+    async fn is_seeding(&self, torrent_id: i32) -> bool {
+        match self.torrent_repository.find_by_id(torrent_id).await {
+            Ok(Some(torrent)) => torrent.is_complete() || torrent.status == TorrentStatus::Seeding,
+            _ => false,
+        }
+    }
+}
@@ -0,0 +1,429 @@
+use crate::entities::{Peer, PeerStatus};
+use crate::errors::DomainError;
+use crate::protocol::encryption;
+use crate::protocol::{
+    generate_peer_id, info_hash_bytes, CryptoStream, EncryptionPolicy, Handshake, MessageCodec, PeerMessage, PeerStream,
+    UtpSocket,
+};
+use crate::repositories::{PeerRepository, TorrentRepository};
+use crate::services::peer_connection::{PeerConnection, PeerHandle};
+use crate::services::piece_availability::PieceAvailability;
+use crate::services::seeder::Seeder;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+
+/// Default cap on live connections per torrent
+pub const DEFAULT_MAX_CONNECTIONS_PER_TORRENT: usize = 50;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long an outbound uTP attempt may take before we fall back to TCP
+const UTP_CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Held for the length of one outbound dial to a peer
+type DialLock = tokio::sync::Mutex<()>;
+
+/// Keeps live peer connections per torrent and reuses them across pieces.
+///
+/// Connection status is mirrored into `PeerRepository` so the rest of the system
+/// (and the API) sees which peers are actually connected.
+pub struct ConnectionManager {
+    peer_repository: Arc<dyn PeerRepository>,
+    torrent_repository: Arc<dyn TorrentRepository>,
+    connections: Arc<Mutex<HashMap<i32, HashMap<String, PeerHandle>>>>,
+    /// Outbound dials in progress by torrent and address; a second caller
+    /// waits for the first instead of dialing the same peer again
+    dials: Mutex<HashMap<(i32, String), Arc<DialLock>>>,
+    availability: Mutex<HashMap<i32, Arc<PieceAvailability>>>,
+    local_peer_id: [u8; 20],
+    codec: MessageCodec,
+    max_connections_per_torrent: usize,
+    seeder: Option<Arc<Seeder>>,
+    encryption: EncryptionPolicy,
+    utp: Mutex<Option<Arc<UtpSocket>>>,
+}
+
+impl ConnectionManager {
+    pub fn new(
+        peer_repository: Arc<dyn PeerRepository>,
+        torrent_repository: Arc<dyn TorrentRepository>,
+    ) -> Self {
+        Self {
+            peer_repository,
+            torrent_repository,
+            connections: Arc::new(Mutex::new(HashMap::new())),
+            dials: Mutex::new(HashMap::new()),
+            availability: Mutex::new(HashMap::new()),
+            local_peer_id: generate_peer_id(),
+            codec: MessageCodec::default(),
+            max_connections_per_torrent: DEFAULT_MAX_CONNECTIONS_PER_TORRENT,
+            seeder: None,
+            encryption: EncryptionPolicy::default(),
+            utp: Mutex::new(None),
+        }
+    }
+
+    pub fn with_max_connections_per_torrent(mut self, max_connections: usize) -> Self {
+        self.max_connections_per_torrent = max_connections;
+        self
+    }
+
+    /// Serve block requests and advertise our pieces on every connection
+    pub fn with_seeder(mut self, seeder: Arc<Seeder>) -> Self {
+        self.seeder = Some(seeder);
+        self
+    }
+
+    /// Message Stream Encryption policy for outbound and inbound connections
+    pub fn with_encryption(mut self, policy: EncryptionPolicy) -> Self {
+        self.encryption = policy;
+        self
+    }
+
+    pub fn encryption_policy(&self) -> EncryptionPolicy {
+        self.encryption
+    }
+
+    /// Try uTP on this socket before TCP for outbound connections. Usually the
+    /// socket the peer listener accepts uTP on, so peers see a single port.
+    pub fn set_utp_socket(&self, socket: Arc<UtpSocket>) {
+        *self.utp.lock().unwrap() = Some(socket);
+    }
+
+    /// Our peer_id, shared by every connection this client makes
+    pub fn local_peer_id(&self) -> [u8; 20] {
+        self.local_peer_id
+    }
+
+    /// The handshake we send for a torrent; we always offer the fast extension
+    pub fn local_handshake(&self, info_hash: [u8; 20]) -> Handshake {
+        Handshake::new(info_hash, self.local_peer_id).with_fast_extension()
+    }
+
+    /// Live connections for a torrent
+    pub fn handles(&self, torrent_id: i32) -> Vec<PeerHandle> {
+        let connections = self.connections.lock().unwrap();
+        connections
+            .get(&torrent_id)
+            .map(|peers| peers.values().filter(|handle| !handle.is_closed()).cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Live connections for a torrent, the ones we would rather download from first
+    pub fn ranked_handles(&self, torrent_id: i32) -> Vec<PeerHandle> {
+        let mut ranked: Vec<_> = self.handles(torrent_id)
+            .into_iter()
+            .map(|handle| (handle.stats(), handle))
+            .collect();
+        ranked.sort_by(|(a, _), (b, _)| a.cmp_performance(b));
+        ranked.into_iter().map(|(_, handle)| handle).collect()
+    }
+
+    /// How many connected peers have each piece of a torrent
+    pub fn availability(&self, torrent_id: i32) -> Arc<PieceAvailability> {
+        self.availability.lock().unwrap().entry(torrent_id).or_default().clone()
+    }
+
+    /// Live connection to a specific peer, if any
+    pub fn handle(&self, torrent_id: i32, address: &str) -> Option<PeerHandle> {
+        let connections = self.connections.lock().unwrap();
+        connections
+            .get(&torrent_id)
+            .and_then(|peers| peers.get(address))
+            .filter(|handle| !handle.is_closed())
+            .cloned()
+    }
+
+    /// Torrents that currently have connections
+    pub fn torrent_ids(&self) -> Vec<i32> {
+        let connections = self.connections.lock().unwrap();
+        connections
+            .iter()
+            .filter(|(_, peers)| !peers.is_empty())
+            .map(|(torrent_id, _)| *torrent_id)
+            .collect()
+    }
+
+    pub fn connection_count(&self, torrent_id: i32) -> usize {
+        self.handles(torrent_id).len()
+    }
+
+    /// Whether a torrent may take another connection, inbound or outbound
+    pub fn has_room(&self, torrent_id: i32) -> bool {
+        self.connection_count(torrent_id) < self.max_connections_per_torrent
+    }
+
+    /// Connect to known peers of a torrent until the connection cap is reached,
+    /// dialing as many at once as there is room for
+    pub async fn connect_to_peers(&self, torrent_id: i32) -> Result<Vec<PeerHandle>, DomainError> {
+        let torrent = self.torrent_repository.find_by_id(torrent_id).await?
+            .ok_or(DomainError::TorrentNotFound(torrent_id))?;
+
+        let peers = self.peer_repository.find_by_torrent_id(torrent_id).await?;
+        let mut candidates = peers.into_iter().filter(|peer| {
+            peer.status != PeerStatus::Banned
+                && !peer.inbound
+                && self.handle(torrent_id, &peer.socket_addr()).is_none()
+        });
+
+        loop {
+            let room = self.max_connections_per_torrent.saturating_sub(self.connection_count(torrent_id));
+            let batch: Vec<Peer> = candidates.by_ref().take(room).collect();
+            if batch.is_empty() {
+                break;
+            }
+
+            let attempts = batch.into_iter().map(|peer| async {
+                let socket_addr = peer.socket_addr();
+                if let Err(e) = self.connect(peer, &torrent.info_hash).await {
+                    eprintln!("Failed to connect to peer {}: {}", socket_addr, e);
+                }
+            });
+            futures::future::join_all(attempts).await;
+        }
+
+        Ok(self.handles(torrent_id))
+    }
+
+    /// Return the live connection to `peer`, opening one if needed
+    pub async fn connect(&self, peer: Peer, info_hash: &str) -> Result<PeerHandle, DomainError> {
+        let key = (peer.torrent_id, peer.socket_addr());
+        let dial = self.dials.lock().unwrap().entry(key.clone()).or_default().clone();
+
+        let result = {
+            let _dialing = dial.lock().await;
+            self.connect_once(peer, info_hash).await
+        };
+
+        // Forget the dial unless another caller is already waiting on it
+        let mut dials = self.dials.lock().unwrap();
+        if Arc::strong_count(&dial) == 2 {
+            dials.remove(&key);
+        }
+        result
+    }
+
+    async fn connect_once(&self, mut peer: Peer, info_hash: &str) -> Result<PeerHandle, DomainError> {
+        if let Some(handle) = self.handle(peer.torrent_id, &peer.socket_addr()) {
+            return Ok(handle);
+        }
+
+        let socket_addr = peer.socket_addr();
+        println!("🤝 Attempting to connect to peer: {}", socket_addr);
+
+        peer.set_status(PeerStatus::Connecting);
+        self.save_status(&peer).await;
+
+        let result = self.open_connection(&socket_addr, info_hash).await;
+        let (stream, remote) = match result {
+            Ok(connection) => connection,
+            Err(e) => {
+                peer.set_status(PeerStatus::Disconnected);
+                self.save_status(&peer).await;
+                return Err(e);
+            }
+        };
+
+        let encrypted = if stream.is_encrypted() { ", encrypted" } else { "" };
+        println!(
+            "✅ Successfully completed BitTorrent handshake with {} ({}{})",
+            socket_addr,
+            stream.get_ref().transport_name(),
+            encrypted
+        );
+        self.register(stream, peer, remote).await
+    }
+
+    async fn open_connection(
+        &self,
+        socket_addr: &str,
+        info_hash: &str,
+    ) -> Result<(CryptoStream<PeerStream>, Handshake), DomainError> {
+        let info_hash = info_hash_bytes(info_hash)?;
+
+        let stream = self.dial(socket_addr).await?;
+        match self.handshake(stream, socket_addr, info_hash, self.encryption).await {
+            // Plenty of peers only speak plaintext; retry on a fresh connection
+            Err(e) if self.encryption == EncryptionPolicy::Enabled => {
+                println!("🔓 Encrypted handshake with {} failed ({}), retrying in plaintext", socket_addr, e);
+                let stream = self.dial(socket_addr).await?;
+                self.handshake(stream, socket_addr, info_hash, EncryptionPolicy::Disabled).await
+            }
+            result => result,
+        }
+    }
+
+    /// Try uTP first when a socket is configured, then TCP
+    async fn dial(&self, socket_addr: &str) -> Result<PeerStream, DomainError> {
+        let utp = self.utp.lock().unwrap().clone();
+        if let (Some(utp), Ok(address)) = (utp, socket_addr.parse::<SocketAddr>()) {
+            match tokio::time::timeout(UTP_CONNECT_TIMEOUT, utp.connect(address)).await {
+                Ok(Ok(stream)) => return Ok(PeerStream::Utp(stream)),
+                Ok(Err(e)) => println!("🔁 uTP to {} failed ({}), falling back to TCP", socket_addr, e),
+                Err(_) => println!("🔁 uTP to {} timed out, falling back to TCP", socket_addr),
+            }
+        }
+
+        let stream = tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(socket_addr)).await
+            .map_err(|_| DomainError::PeerConnectionError(format!("Connection timeout to {}", socket_addr)))?
+            .map_err(|e| DomainError::PeerConnectionError(format!("Failed to connect to {}: {}", socket_addr, e)))?;
+        Ok(PeerStream::Tcp(stream))
+    }
+
+    /// Negotiate encryption, then exchange BitTorrent handshakes over the result
+    async fn handshake(
+        &self,
+        stream: PeerStream,
+        socket_addr: &str,
+        info_hash: [u8; 20],
+        policy: EncryptionPolicy,
+    ) -> Result<(CryptoStream<PeerStream>, Handshake), DomainError> {
+        let exchange = async {
+            let mut stream = encryption::initiate(stream, info_hash, policy).await?;
+            self.local_handshake(info_hash).write_to(&mut stream).await?;
+            let remote = Handshake::read_from(&mut stream).await?;
+            Ok::<_, DomainError>((stream, remote))
+        };
+
+        let (stream, remote) = tokio::time::timeout(CONNECT_TIMEOUT, exchange).await
+            .map_err(|_| DomainError::PeerConnectionError(format!("Handshake timeout with {}", socket_addr)))??;
+
+        if remote.info_hash != info_hash {
+            return Err(DomainError::PeerConnectionError("Info hash mismatch in handshake".to_string()));
+        }
+
+        Ok((stream, remote))
+    }
+
+    /// Take over a stream whose handshake has already been exchanged.
+    /// Both outbound connections and accepted inbound ones end up here.
+    pub async fn register<S>(&self, stream: S, mut peer: Peer, remote: Handshake) -> Result<PeerHandle, DomainError>
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let torrent_id = peer.torrent_id;
+        let address = peer.socket_addr();
+
+        // A peer we already know, e.g. one reconnecting or also returned by a
+        // tracker, keeps its row; the address is unique per torrent
+        if peer.id.is_none() {
+            let known = self.peer_repository.find_by_torrent_id(torrent_id).await?
+                .into_iter()
+                .find(|known| known.ip == peer.ip && known.port == peer.port);
+            if let Some(known) = known {
+                if known.status == PeerStatus::Banned {
+                    return Err(DomainError::PeerConnectionError(format!("Peer {} is banned", address)));
+                }
+                peer.id = known.id;
+                peer.inbound = peer.inbound && known.inbound;
+            }
+        }
+
+        peer.set_status(PeerStatus::Connected);
+        peer.peer_id = Some(hex::encode(remote.peer_id));
+        let peer = match peer.id {
+            Some(_) => self.peer_repository.update(&peer).await?,
+            None => self.peer_repository.save(&peer).await?,
+        };
+
+        // We always offer the fast extension, so the remote bit decides
+        let fast_extension = remote.supports_fast_extension();
+        let handle = PeerConnection::spawn(stream, torrent_id, peer, remote.peer_id, fast_extension, self.codec);
+        handle.track_availability(self.availability(torrent_id));
+
+        // The bitfield may only follow the handshake, so introduce ourselves before
+        // `announce_piece` can see this connection and queue a `have` ahead of it
+        match &self.seeder {
+            Some(seeder) => seeder.serve(handle.clone()).await,
+            // BEP 6 requires an availability message; without a seeder we offer nothing
+            None if fast_extension => handle.send(PeerMessage::HaveNone)?,
+            None => {}
+        }
+
+        let previous = {
+            let mut connections = self.connections.lock().unwrap();
+            connections.entry(torrent_id).or_default().insert(address, handle.clone())
+        };
+        if let Some(previous) = previous {
+            previous.close();
+        }
+
+        self.watch_connection(handle.clone());
+        Ok(handle)
+    }
+
+    /// Mark the peer disconnected and forget the handle once the connection ends
+    fn watch_connection(&self, handle: PeerHandle) {
+        let connections = Arc::clone(&self.connections);
+        let peer_repository = Arc::clone(&self.peer_repository);
+
+        tokio::spawn(async move {
+            handle.closed().await;
+
+            {
+                let mut connections = connections.lock().unwrap();
+                if let Some(peers) = connections.get_mut(&handle.torrent_id()) {
+                    let is_current = peers
+                        .get(&handle.address())
+                        .is_some_and(|current| current.same_connection(&handle));
+                    if is_current {
+                        peers.remove(&handle.address());
+                    }
+                }
+            }
+
+            let mut peer = handle.peer().clone();
+            if peer.status != PeerStatus::Banned {
+                peer.set_status(PeerStatus::Disconnected);
+            }
+            if let Err(e) = peer_repository.update(&peer).await {
+                eprintln!("Failed to update peer {} status: {}", peer.socket_addr(), e);
+            }
+        });
+    }
+
+    /// Tell every peer of the torrent that we now have a verified piece
+    pub fn announce_piece(&self, torrent_id: i32, piece_index: u32) {
+        for handle in self.handles(torrent_id) {
+            let _ = handle.send(PeerMessage::Have { piece_index });
+        }
+    }
+
+    /// Close the connection to a peer by its repository ID
+    pub fn disconnect(&self, peer_id: i32) -> bool {
+        let connections = self.connections.lock().unwrap();
+        let handle = connections
+            .values()
+            .flat_map(|peers| peers.values())
+            .find(|handle| handle.peer().id == Some(peer_id));
+
+        match handle {
+            Some(handle) => {
+                handle.close();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Close every connection of a torrent, e.g. when it is paused or removed
+    pub fn disconnect_torrent(&self, torrent_id: i32) {
+        let handles = self.connections.lock().unwrap().remove(&torrent_id).unwrap_or_default();
+        for handle in handles.values() {
+            handle.close();
+        }
+    }
+
+    async fn save_status(&self, peer: &Peer) {
+        if peer.id.is_none() {
+            return;
+        }
+        if let Err(e) = self.peer_repository.update(peer).await {
+            eprintln!("Failed to update peer {} status: {}", peer.socket_addr(), e);
+        }
+    }
+}
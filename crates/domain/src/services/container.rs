@@ -0,0 +1,67 @@
+use crate::entities::FileInfo;
+use std::ops::Range;
+
+const MIB: u64 = 1024 * 1024;
+
+/// Media container of a streamed file, as far as prefetching is concerned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContainerKind {
+    /// MP4 and QuickTime; the `moov` index may sit at either end
+    Mp4,
+    /// Matroska and WebM; the Cues seek index usually sits at the end
+    Matroska,
+    /// AVI; the `idx1` index sits at the end
+    Avi,
+    /// Anything players read front to back, e.g. MPEG-TS or MP3
+    Other,
+}
+
+impl ContainerKind {
+    pub fn from_file_name(file_name: &str) -> Self {
+        match FileInfo::detect_mime_type(file_name).as_str() {
+            "video/mp4" | "video/quicktime" | "video/x-m4v" | "video/3gpp" | "audio/x-m4a" => ContainerKind::Mp4,
+            "video/x-matroska" | "video/webm" => ContainerKind::Matroska,
+            "video/x-msvideo" => ContainerKind::Avi,
+            _ => ContainerKind::Other,
+        }
+    }
+
+    /// Bytes at the start of the file a player reads before playing:
+    /// headers, track descriptions and the first frames
+    pub fn head_bytes(&self) -> u64 {
+        2 * MIB
+    }
+
+    /// Bytes at the end of the file a player reads before playing. The `moov`
+    /// of a long MP4 grows with its duration, so it gets a share of the file.
+    pub fn tail_bytes(&self, file_size: u64) -> u64 {
+        match self {
+            ContainerKind::Mp4 => (file_size / 200).clamp(4 * MIB, 32 * MIB),
+            ContainerKind::Matroska => 4 * MIB,
+            ContainerKind::Avi => (file_size / 500).clamp(MIB, 8 * MIB),
+            ContainerKind::Other => 0,
+        }
+    }
+
+    /// Byte ranges of the file at `file_offset` in the torrent to fetch
+    /// before playback starts, head first. They never overlap.
+    pub fn prefetch_ranges(&self, file_offset: u64, file_size: u64) -> Vec<Range<u64>> {
+        let end = file_offset + file_size;
+        let head = file_offset..(file_offset + self.head_bytes()).min(end);
+        let tail_start = end.saturating_sub(self.tail_bytes(file_size)).max(head.end);
+        let mut ranges = vec![head];
+        if tail_start < end {
+            ranges.push(tail_start..end);
+        }
+        ranges.retain(|range| !range.is_empty());
+        ranges
+    }
+}
+
+/// Pieces of `piece_length` bytes holding any byte of `range`
+pub fn pieces_for_range(range: &Range<u64>, piece_length: u64) -> Range<usize> {
+    if range.is_empty() || piece_length == 0 {
+        return 0..0;
+    }
+    (range.start / piece_length) as usize..((range.end - 1) / piece_length) as usize + 1
+}
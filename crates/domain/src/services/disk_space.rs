@@ -0,0 +1,132 @@
+use crate::errors::DomainError;
+use std::path::Path;
+use std::str::FromStr;
+
+const MIB: u64 = 1024 * 1024;
+
+/// Free space below which downloading torrents are paused
+pub const DEFAULT_MIN_FREE_SPACE: u64 = 1024 * MIB;
+
+/// How the files of a torrent are allocated before it starts downloading
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Preallocation {
+    /// Files grow as pieces arrive
+    Off,
+    /// Files are created at full length without reserving blocks; cheap,
+    /// and keeps pieces written out of order from fragmenting metadata
+    #[default]
+    Sparse,
+    /// Every byte is written up front, reserving the space and laying the
+    /// file out contiguously
+    Full,
+}
+
+impl FromStr for Preallocation {
+    type Err = DomainError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "off" => Ok(Preallocation::Off),
+            "sparse" => Ok(Preallocation::Sparse),
+            "full" => Ok(Preallocation::Full),
+            other => Err(DomainError::ValidationError(format!(
+                "Unknown preallocation '{}', expected off, sparse or full",
+                other
+            ))),
+        }
+    }
+}
+
+/// What happens to a torrent added without room for it on disk
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DiskFullPolicy {
+    /// Adding it fails
+    #[default]
+    Refuse,
+    /// It is added as `Queued` and starts once there is room
+    Queue,
+}
+
+impl FromStr for DiskFullPolicy {
+    type Err = DomainError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "refuse" => Ok(DiskFullPolicy::Refuse),
+            "queue" => Ok(DiskFullPolicy::Queue),
+            other => Err(DomainError::ValidationError(format!(
+                "Unknown disk full policy '{}', expected refuse or queue",
+                other
+            ))),
+        }
+    }
+}
+
+/// How torrents kept on disk use it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DiskPolicy {
+    pub preallocation: Preallocation,
+    pub when_full: DiskFullPolicy,
+    /// Space to leave free: a torrent fits only if this much remains after
+    /// it, and downloads pause when less is left
+    pub min_free_space: u64,
+}
+
+impl Default for DiskPolicy {
+    fn default() -> Self {
+        Self {
+            preallocation: Preallocation::default(),
+            when_full: DiskFullPolicy::default(),
+            min_free_space: DEFAULT_MIN_FREE_SPACE,
+        }
+    }
+}
+
+impl DiskPolicy {
+    /// Whether `needed` more bytes fit in `available` with the reserve left over
+    pub fn fits(&self, needed: u64, available: u64) -> bool {
+        needed.saturating_add(self.min_free_space) <= available
+    }
+
+    /// Whether so little is left that downloads should stop
+    pub fn is_low(&self, available: u64) -> bool {
+        available < self.min_free_space
+    }
+}
+
+/// `bytes` for messages, in MiB
+pub fn mib(bytes: u64) -> u64 {
+    bytes / MIB
+}
+
+/// Bytes available to us on the filesystem holding `path`, or the closest
+/// ancestor that exists. `None` where the platform cannot tell.
+pub async fn available_space(path: &Path) -> Result<Option<u64>, DomainError> {
+    let Some(existing) = path.ancestors().find(|ancestor| ancestor.exists()).map(Path::to_path_buf) else {
+        return Ok(None);
+    };
+    tokio::task::spawn_blocking(move || statvfs_available(&existing))
+        .await
+        .map_err(|e| DomainError::IoError(e.to_string()))?
+}
+
+#[cfg(unix)]
+fn statvfs_available(path: &Path) -> Result<Option<u64>, DomainError> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = std::ffi::CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| DomainError::IoError(format!("Invalid path {}: {}", path.display(), e)))?;
+    let mut stats: libc::statvfs = unsafe { std::mem::zeroed() };
+    // SAFETY: `c_path` is a valid C string and `stats` a writable statvfs
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stats) } != 0 {
+        let e = std::io::Error::last_os_error();
+        return Err(DomainError::IoError(format!("Failed to read free space of {}: {}", path.display(), e)));
+    }
+    #[allow(clippy::unnecessary_cast)]
+    Ok(Some(stats.f_bavail as u64 * stats.f_frsize as u64))
+}
+
+#[cfg(not(unix))]
+fn statvfs_available(_path: &Path) -> Result<Option<u64>, DomainError> {
+    Ok(None)
+}
@@ -1,6 +1,7 @@
 use crate::entities::Piece;
 use crate::errors::DomainError;
 use crate::repositories::{PieceRepository, TorrentRepository};
+use crate::services::storage::TorrentStorage;
 use std::sync::Arc;
 
 /// Service for managing piece downloads and verification
@@ -8,16 +9,19 @@ use std::sync::Arc;
 pub struct DownloadService {
     piece_repository: Arc<dyn PieceRepository>,
     torrent_repository: Arc<dyn TorrentRepository>,
+    storage: Arc<TorrentStorage>,
 }
 
 impl DownloadService {
     pub fn new(
         piece_repository: Arc<dyn PieceRepository>,
         torrent_repository: Arc<dyn TorrentRepository>,
+        storage: Arc<TorrentStorage>,
     ) -> Self {
         Self {
             piece_repository,
             torrent_repository,
+            storage,
         }
     }
 
@@ -83,8 +87,7 @@ impl DownloadService {
         format!("{:x}", hasher.finalize())
     }
 
-    /// Write piece data to local file or stream buffer
-    /// This implements: write to local file or stream buffer
+    /// Write piece data at its place in the torrent's files
     async fn write_piece_data(
         &self,
         torrent_id: i32,
@@ -97,55 +100,8 @@ impl DownloadService {
             .await?
             .ok_or(DomainError::TorrentNotFound(torrent_id))?;
 
-        if let Some(file_path) = &torrent.file_path {
-            // Calculate byte offset for this piece
-            let offset = (piece_index as i64) * (torrent.piece_length as i64);
-
-            // Implement actual file writing with proper offset
-            use tokio::fs::OpenOptions;
-            use tokio::io::{AsyncSeekExt, AsyncWriteExt};
-
-            let mut file = OpenOptions::new()
-                .create(true)
-                .write(true)
-                .open(file_path)
-                .await
-                .map_err(|e| {
-                    DomainError::IoError(format!("Failed to open file {}: {}", file_path, e))
-                })?;
-
-            file.seek(std::io::SeekFrom::Start(offset as u64))
-                .await
-                .map_err(|e| {
-                    DomainError::IoError(format!("Failed to seek to offset {}: {}", offset, e))
-                })?;
-
-            file.write_all(&data)
-                .await
-                .map_err(|e| DomainError::IoError(format!("Failed to write piece data: {}", e)))?;
-
-            file.flush()
-                .await
-                .map_err(|e| DomainError::IoError(format!("Failed to flush file: {}", e)))?;
-        } else {
-            // Store in memory buffer for streaming
-            // For now, just create a temporary file
-            let temp_path = format!(
-                "./downloads/torrent_{}_piece_{}.tmp",
-                torrent_id, piece_index
-            );
-            if let Some(parent) = std::path::Path::new(&temp_path).parent() {
-                tokio::fs::create_dir_all(parent)
-                    .await
-                    .map_err(|e| DomainError::IoError(e.to_string()))?;
-            }
-
-            tokio::fs::write(&temp_path, data)
-                .await
-                .map_err(|e| DomainError::IoError(e.to_string()))?;
-        }
-
-        Ok(())
+        let offset = piece_index as u64 * torrent.piece_length as u64;
+        self.storage.write(torrent_id, offset, &data).await
     }
 
     /// Check if torrent download is complete
@@ -0,0 +1,62 @@
+use crate::entities::{FilePriority, Torrent, TorrentFile};
+use crate::services::piece_manager::PiecePriority;
+
+impl FilePriority {
+    /// The piece priority a file asks for, `None` when it is skipped
+    pub fn piece_priority(&self) -> Option<PiecePriority> {
+        match self {
+            FilePriority::Skip => None,
+            FilePriority::Low => Some(PiecePriority::Low),
+            FilePriority::Normal => Some(PiecePriority::Normal),
+            FilePriority::High => Some(PiecePriority::High),
+        }
+    }
+}
+
+/// Priority of every piece of a torrent, derived from the files it overlaps.
+///
+/// A piece shared by two files gets the higher of their priorities, so a
+/// wanted file never misses its first or last piece because a neighbouring
+/// file is skipped. Pieces only skipped files touch are not wanted.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PiecePriorities {
+    pieces: Vec<Option<PiecePriority>>,
+}
+
+impl PiecePriorities {
+    /// Every piece wanted at normal priority
+    pub fn all(piece_count: usize) -> Self {
+        Self { pieces: vec![Some(PiecePriority::Normal); piece_count] }
+    }
+
+    /// Torrents without a recorded file list want everything
+    pub fn from_files(torrent: &Torrent, files: &[TorrentFile]) -> Self {
+        let piece_count = torrent.piece_count.max(0) as usize;
+        if files.is_empty() {
+            return Self::all(piece_count);
+        }
+
+        let mut pieces = vec![None; piece_count];
+        for file in files {
+            let priority = file.priority.piece_priority();
+            let range = file.piece_range(torrent.piece_length as i64);
+            for piece in pieces.iter_mut().take(range.end).skip(range.start) {
+                *piece = (*piece).max(priority);
+            }
+        }
+        Self { pieces }
+    }
+
+    /// `None` when the piece is not wanted
+    pub fn get(&self, piece_index: usize) -> Option<PiecePriority> {
+        self.pieces.get(piece_index).copied().flatten()
+    }
+
+    pub fn is_wanted(&self, piece_index: usize) -> bool {
+        self.get(piece_index).is_some()
+    }
+
+    pub fn wanted_count(&self) -> usize {
+        self.pieces.iter().filter(|piece| piece.is_some()).count()
+    }
+}
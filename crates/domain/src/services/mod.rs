@@ -4,16 +4,48 @@ pub mod tracker_service;
 pub mod peer_service;
 pub mod streaming_service;
 pub mod piece_manager;
+pub mod priority_aggregator;
 pub mod stream_prioritizer;
 pub mod piece_downloader;
+pub mod request_pipeline;
+pub mod peer_connection;
+pub mod connection_manager;
+pub mod seeder;
+pub mod choker;
 pub mod streaming_buffer;
+pub mod piece_availability;
+pub mod piece_picker;
+pub mod piece_download;
+pub mod file_priorities;
+pub mod bitrate;
+pub mod container;
+pub mod storage;
+pub mod storage_backend;
+pub mod resume;
+pub mod disk_space;
 
-pub use torrent_service::TorrentService;
+pub use torrent_service::{Recheck, TorrentService};
 pub use download_service::DownloadService;
-pub use tracker_service::TrackerService;
+pub use tracker_service::{TrackerService, DEFAULT_LISTEN_PORT};
 pub use peer_service::PeerService;
 pub use piece_manager::PieceManager;
+pub use priority_aggregator::{PriorityAggregator, DEFAULT_WEIGHT};
 pub use streaming_service::{StreamingService, StreamingServiceImpl};
-pub use stream_prioritizer::{StreamPrioritizer, StreamingPattern};
+pub use stream_prioritizer::{piece_deadlines, read_ahead_bytes, StreamPrioritizer, DEFAULT_BITRATE, READ_AHEAD};
 pub use piece_downloader::PieceDownloader;
+pub use request_pipeline::{PieceAssembler, RequestQueue, TransferRate, BLOCK_SIZE};
+pub use peer_connection::{PeerConnection, PeerHandle, PeerStats};
+pub use connection_manager::ConnectionManager;
+pub use seeder::Seeder;
+pub use choker::{Choker, DEFAULT_GLOBAL_UPLOAD_SLOTS, DEFAULT_UPLOAD_SLOTS_PER_TORRENT};
 pub use streaming_buffer::StreamingBuffer;
+pub use piece_availability::{PeerPieces, PieceAvailability};
+pub use piece_picker::{rarest_first, PiecePick, PiecePicker};
+pub use piece_download::{BlockCheckpoint, PieceDownload};
+pub use file_priorities::PiecePriorities;
+pub use bitrate::{bitrate_for, container_duration, BitrateSource, ConsumptionRate};
+pub use container::{pieces_for_range, ContainerKind};
+pub use storage::{FileSpan, StorageLayout, StoredFile, TorrentStorage};
+pub use resume::{ResumeCheck, ResumeValidator};
+pub use storage_backend::{FilesystemBackend, MemoryBackend, StorageBackend, DEFAULT_MEMORY_CAPACITY};
+pub use disk_space::{DiskFullPolicy, DiskPolicy, Preallocation, DEFAULT_MIN_FREE_SPACE};
@@ -0,0 +1,693 @@
+use crate::entities::Peer;
+use crate::errors::DomainError;
+use crate::protocol::{BlockRequest, MessageCodec, PeerMessage};
+use crate::services::piece_availability::{PeerPieces, PieceAvailability};
+use crate::services::piece_download::PieceDownload;
+use crate::services::request_pipeline::{RequestQueue, TransferRate};
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::sync::{mpsc, watch};
+
+/// How long a piece download waits for any progress before giving up on the peer
+const BLOCK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Send a keep-alive when we have had nothing to say for this long
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(120);
+
+/// Drop peers that have not sent anything (not even a keep-alive) for this long
+const IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Suggestions kept per peer; older ones are dropped first
+const MAX_SUGGESTED_PIECES: usize = 32;
+
+/// A peer we want data from that has sent no block for this long is snubbing us
+pub const SNUB_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Choke/interest flags and transfer counters of one live connection
+#[derive(Debug)]
+struct ConnectionState {
+    am_choking: bool,
+    am_interested: bool,
+    peer_choking: bool,
+    peer_interested: bool,
+    download_rate: TransferRate,
+    upload_rate: TransferRate,
+    rtt: Option<Duration>,
+    /// Block bytes that another connection had already delivered
+    duplicate_bytes: u64,
+    connected_at: Instant,
+    last_received_at: Instant,
+    /// Last block received, or when we became interested if that is later
+    last_progress_at: Instant,
+}
+
+/// Point-in-time view of a connection, used by schedulers and the API
+#[derive(Debug, Clone)]
+pub struct PeerStats {
+    pub am_choking: bool,
+    pub am_interested: bool,
+    pub peer_choking: bool,
+    pub peer_interested: bool,
+    /// Bytes per second received from the peer
+    pub download_rate: f64,
+    /// Bytes per second sent to the peer
+    pub upload_rate: f64,
+    pub downloaded: u64,
+    pub uploaded: u64,
+    pub rtt: Option<Duration>,
+    /// Received block bytes we already had, mostly from endgame mode
+    pub duplicate_bytes: u64,
+    pub connected_for: Duration,
+    pub idle_for: Duration,
+    /// We are interested but the peer has sent no block for `SNUB_TIMEOUT`
+    pub snubbed: bool,
+}
+
+impl PeerStats {
+    /// Orders the peers we would rather download from first: snubbing peers
+    /// last, then by observed download rate, then by latency
+    pub fn cmp_performance(&self, other: &PeerStats) -> Ordering {
+        self.snubbed
+            .cmp(&other.snubbed)
+            .then_with(|| other.download_rate.partial_cmp(&self.download_rate).unwrap_or(Ordering::Equal))
+            .then_with(|| match (self.rtt, other.rtt) {
+                (Some(ours), Some(theirs)) => ours.cmp(&theirs),
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (None, None) => Ordering::Equal,
+            })
+    }
+}
+
+struct Shared {
+    torrent_id: i32,
+    peer: Peer,
+    remote_peer_id: [u8; 20],
+    /// Both sides set the fast extension bit in their handshakes
+    fast_extension: bool,
+    /// Pieces the peer lets us request while it chokes us
+    allowed_fast: Mutex<HashSet<u32>>,
+    /// Pieces the peer suggested, most recent last
+    suggested: Mutex<VecDeque<u32>>,
+    /// Pieces the peer announced it has
+    pieces: Mutex<PeerPieces>,
+    /// Bumped whenever `pieces` changes
+    pieces_changed: watch::Sender<u64>,
+    /// Torrent-wide counts this connection contributes its pieces to
+    availability: Mutex<Option<Arc<PieceAvailability>>>,
+    outgoing: mpsc::UnboundedSender<PeerMessage>,
+    /// `piece` messages sent but not yet written to the socket
+    queued_uploads: watch::Sender<usize>,
+    state: Mutex<ConnectionState>,
+    /// Piece index -> download waiting for blocks of that piece
+    routes: Mutex<HashMap<u32, mpsc::UnboundedSender<PeerMessage>>>,
+    peer_choking: watch::Sender<bool>,
+    closed: watch::Sender<bool>,
+    /// Serialises piece downloads on this connection and keeps the pipeline depth between pieces
+    download_queue: tokio::sync::Mutex<RequestQueue>,
+    /// Receiver of interest changes, requests and cancels, i.e. the upload side
+    uploads: Mutex<Option<mpsc::UnboundedSender<PeerMessage>>>,
+}
+
+/// Cheap, cloneable reference to a live peer connection.
+///
+/// The socket itself is owned by a reader and a writer task; everything else
+/// in the client talks to the peer through this handle.
+#[derive(Clone)]
+pub struct PeerHandle {
+    shared: Arc<Shared>,
+}
+
+impl std::fmt::Debug for PeerHandle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PeerHandle")
+            .field("torrent_id", &self.shared.torrent_id)
+            .field("address", &self.address())
+            .field("closed", &self.is_closed())
+            .finish()
+    }
+}
+
+/// Spawns the tasks that drive a connection after the handshake
+pub struct PeerConnection;
+
+impl PeerConnection {
+    pub fn spawn<S>(
+        stream: S,
+        torrent_id: i32,
+        peer: Peer,
+        remote_peer_id: [u8; 20],
+        fast_extension: bool,
+        codec: MessageCodec,
+    ) -> PeerHandle
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (reader, writer) = tokio::io::split(stream);
+        let (outgoing, outgoing_rx) = mpsc::unbounded_channel();
+        let now = Instant::now();
+
+        let handle = PeerHandle {
+            shared: Arc::new(Shared {
+                torrent_id,
+                peer,
+                remote_peer_id,
+                fast_extension,
+                allowed_fast: Mutex::new(HashSet::new()),
+                suggested: Mutex::new(VecDeque::new()),
+                pieces: Mutex::new(PeerPieces::default()),
+                pieces_changed: watch::Sender::new(0),
+                availability: Mutex::new(None),
+                outgoing,
+                queued_uploads: watch::Sender::new(0),
+                state: Mutex::new(ConnectionState {
+                    am_choking: true,
+                    am_interested: false,
+                    peer_choking: true,
+                    peer_interested: false,
+                    download_rate: TransferRate::new(),
+                    upload_rate: TransferRate::new(),
+                    rtt: None,
+                    duplicate_bytes: 0,
+                    connected_at: now,
+                    last_received_at: now,
+                    last_progress_at: now,
+                }),
+                routes: Mutex::new(HashMap::new()),
+                peer_choking: watch::Sender::new(true),
+                closed: watch::Sender::new(false),
+                download_queue: tokio::sync::Mutex::new(RequestQueue::new()),
+                uploads: Mutex::new(None),
+            }),
+        };
+
+        tokio::spawn(Self::run_writer(writer, outgoing_rx, handle.clone(), codec));
+        tokio::spawn(Self::run_reader(reader, handle.clone(), codec));
+
+        handle
+    }
+
+    async fn run_writer<S>(
+        mut writer: WriteHalf<S>,
+        mut outgoing: mpsc::UnboundedReceiver<PeerMessage>,
+        handle: PeerHandle,
+        codec: MessageCodec,
+    ) where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let mut closed = handle.shared.closed.subscribe();
+
+        loop {
+            let message = tokio::select! {
+                message = outgoing.recv() => match message {
+                    Some(message) => message,
+                    None => break,
+                },
+                _ = closed.wait_for(|closed| *closed) => break,
+                _ = tokio::time::sleep(KEEP_ALIVE_INTERVAL) => PeerMessage::KeepAlive,
+            };
+
+            let is_upload = matches!(message, PeerMessage::Piece { .. });
+            if let PeerMessage::Piece { data, .. } = &message {
+                handle.shared.state.lock().unwrap().upload_rate.record(data.len() as u64);
+            }
+
+            let written = codec.write_message(&mut writer, &message).await;
+            if is_upload {
+                handle.shared.queued_uploads.send_modify(|queued| *queued -= 1);
+            }
+            if let Err(e) = written {
+                eprintln!("Failed to write to peer {}: {}", handle.address(), e);
+                break;
+            }
+        }
+
+        let _ = writer.shutdown().await;
+        handle.close();
+    }
+
+    async fn run_reader<S>(mut reader: ReadHalf<S>, handle: PeerHandle, codec: MessageCodec)
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let mut closed = handle.shared.closed.subscribe();
+
+        loop {
+            let message = tokio::select! {
+                message = tokio::time::timeout(IDLE_TIMEOUT, codec.read_message(&mut reader)) => message,
+                _ = closed.wait_for(|closed| *closed) => break,
+            };
+
+            match message {
+                Ok(Ok(message)) => handle.dispatch(message),
+                Ok(Err(e)) => {
+                    eprintln!("Connection to peer {} ended: {}", handle.address(), e);
+                    break;
+                }
+                Err(_) => {
+                    eprintln!("Peer {} idle for {:?}, disconnecting", handle.address(), IDLE_TIMEOUT);
+                    break;
+                }
+            }
+        }
+
+        handle.close();
+    }
+}
+
+impl PeerHandle {
+    pub fn torrent_id(&self) -> i32 {
+        self.shared.torrent_id
+    }
+
+    /// The peer record this connection belongs to
+    pub fn peer(&self) -> &Peer {
+        &self.shared.peer
+    }
+
+    pub fn address(&self) -> String {
+        self.shared.peer.socket_addr()
+    }
+
+    pub fn remote_peer_id(&self) -> [u8; 20] {
+        self.shared.remote_peer_id
+    }
+
+    /// Whether the fast extension (BEP 6) was negotiated
+    pub fn supports_fast_extension(&self) -> bool {
+        self.shared.fast_extension
+    }
+
+    /// Whether the peer allows us to request `piece_index` while it chokes us
+    pub fn is_allowed_fast(&self, piece_index: u32) -> bool {
+        self.shared.allowed_fast.lock().unwrap().contains(&piece_index)
+    }
+
+    /// Whether a request for `piece_index` would be honoured right now
+    pub fn can_request(&self, piece_index: u32) -> bool {
+        !*self.shared.peer_choking.borrow() || self.is_allowed_fast(piece_index)
+    }
+
+    /// Pieces the peer suggested we download, most recent last
+    pub fn suggested_pieces(&self) -> Vec<u32> {
+        self.shared.suggested.lock().unwrap().iter().copied().collect()
+    }
+
+    /// Whether the peer announced it has `piece_index`
+    pub fn has_piece(&self, piece_index: u32) -> bool {
+        self.shared.pieces.lock().unwrap().has(piece_index)
+    }
+
+    /// Everything the peer announced so far
+    pub fn pieces(&self) -> PeerPieces {
+        self.shared.pieces.lock().unwrap().clone()
+    }
+
+    /// Resolves the next time the peer announces pieces, or once the connection
+    /// closes. Changes count from the call, not from the first poll.
+    pub fn pieces_changed(&self) -> impl std::future::Future<Output = ()> + Send + 'static {
+        let mut changed = self.shared.pieces_changed.subscribe();
+        let mut closed = self.shared.closed.subscribe();
+        async move {
+            tokio::select! {
+                _ = changed.changed() => {}
+                _ = closed.wait_for(|closed| *closed) => {}
+            }
+        }
+    }
+
+    /// Count this peer's pieces in `availability` until the connection closes
+    pub fn track_availability(&self, availability: Arc<PieceAvailability>) {
+        let pieces = self.shared.pieces.lock().unwrap();
+        if self.is_closed() {
+            return;
+        }
+        availability.add(&pieces);
+        if let Some(previous) = self.shared.availability.lock().unwrap().replace(availability) {
+            previous.remove(&pieces);
+        }
+    }
+
+    /// Whether both handles refer to the same underlying connection
+    pub fn same_connection(&self, other: &PeerHandle) -> bool {
+        Arc::ptr_eq(&self.shared, &other.shared)
+    }
+
+    pub fn is_closed(&self) -> bool {
+        *self.shared.closed.borrow()
+    }
+
+    /// Resolves once the connection has been torn down
+    pub async fn closed(&self) {
+        let mut closed = self.shared.closed.subscribe();
+        let _ = closed.wait_for(|closed| *closed).await;
+    }
+
+    /// Tear the connection down. Pending downloads fail and the socket is closed.
+    pub fn close(&self) {
+        self.shared.closed.send_replace(true);
+        // Dropping the senders wakes every download waiting on this peer
+        self.shared.routes.lock().unwrap().clear();
+        self.shared.uploads.lock().unwrap().take();
+
+        let pieces = self.shared.pieces.lock().unwrap();
+        if let Some(availability) = self.shared.availability.lock().unwrap().take() {
+            availability.remove(&pieces);
+        }
+    }
+
+    /// Receive the upload side of the conversation: interest changes, block
+    /// requests and cancels. Without a subscriber, requests are ignored.
+    pub fn subscribe_uploads(&self) -> mpsc::UnboundedReceiver<PeerMessage> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        if !self.is_closed() {
+            *self.shared.uploads.lock().unwrap() = Some(sender);
+        }
+        receiver
+    }
+
+    pub fn stats(&self) -> PeerStats {
+        let mut state = self.shared.state.lock().unwrap();
+        PeerStats {
+            am_choking: state.am_choking,
+            am_interested: state.am_interested,
+            peer_choking: state.peer_choking,
+            peer_interested: state.peer_interested,
+            download_rate: state.download_rate.bytes_per_second(),
+            upload_rate: state.upload_rate.bytes_per_second(),
+            downloaded: state.download_rate.total_bytes(),
+            uploaded: state.upload_rate.total_bytes(),
+            rtt: state.rtt,
+            duplicate_bytes: state.duplicate_bytes,
+            connected_for: state.connected_at.elapsed(),
+            idle_for: state.last_received_at.elapsed(),
+            snubbed: state.am_interested && state.last_progress_at.elapsed() > SNUB_TIMEOUT,
+        }
+    }
+
+    /// Queue a message for the peer
+    pub fn send(&self, message: PeerMessage) -> Result<(), DomainError> {
+        if self.is_closed() {
+            return Err(self.closed_error());
+        }
+
+        {
+            let mut state = self.shared.state.lock().unwrap();
+            match message {
+                PeerMessage::Choke => state.am_choking = true,
+                PeerMessage::Unchoke => state.am_choking = false,
+                PeerMessage::Interested => {
+                    if !state.am_interested {
+                        state.last_progress_at = Instant::now();
+                    }
+                    state.am_interested = true;
+                }
+                PeerMessage::NotInterested => state.am_interested = false,
+                _ => {}
+            }
+        }
+
+        if matches!(message, PeerMessage::Piece { .. }) {
+            self.shared.queued_uploads.send_modify(|queued| *queued += 1);
+        }
+        self.shared.outgoing.send(message).map_err(|_| self.closed_error())
+    }
+
+    /// `piece` messages waiting for the writer, including one being written
+    pub fn queued_uploads(&self) -> usize {
+        *self.shared.queued_uploads.borrow()
+    }
+
+    /// Wait until fewer than `limit` `piece` messages wait for the writer,
+    /// or the connection closes
+    pub async fn uploads_below(&self, limit: usize) {
+        let mut queued = self.shared.queued_uploads.subscribe();
+        let mut closed = self.shared.closed.subscribe();
+        tokio::select! {
+            _ = queued.wait_for(|queued| *queued < limit) => {}
+            _ = closed.wait_for(|closed| *closed) => {}
+        }
+    }
+
+    /// Download one piece over this connection, keeping several block requests
+    /// in flight and reassembling blocks in whatever order they arrive.
+    /// The caller is responsible for hash verification.
+    pub async fn download_piece(&self, piece_index: u32, piece_length: u32) -> Result<Vec<u8>, DomainError> {
+        let piece = PieceDownload::new(piece_index, piece_length);
+        self.download_shared_piece(&piece).await?;
+        piece.take_data().ok_or_else(|| DomainError::NetworkError(format!(
+            "Piece {} was taken before {} finished it",
+            piece_index,
+            self.address()
+        )))
+    }
+
+    /// Work on a piece that other connections may be fetching too (endgame).
+    /// Returns once the piece is complete, whoever delivered the last block,
+    /// or fails once it is abandoned; requests still in flight on this
+    /// connection are cancelled.
+    pub async fn download_shared_piece(&self, piece: &PieceDownload) -> Result<(), DomainError> {
+        let mut queue = self.shared.download_queue.lock().await;
+
+        let (blocks_tx, mut blocks) = mpsc::unbounded_channel();
+        self.shared.routes.lock().unwrap().insert(piece.piece_index(), blocks_tx);
+
+        let result = self.run_piece_download(&mut queue, &mut blocks, piece).await;
+
+        self.shared.routes.lock().unwrap().remove(&piece.piece_index());
+        // Whatever is still in flight is no longer wanted
+        for request in queue.clear() {
+            piece.on_request_finished(&request);
+            let _ = self.send(PeerMessage::Cancel(request));
+        }
+
+        result
+    }
+
+    async fn run_piece_download(
+        &self,
+        queue: &mut RequestQueue,
+        blocks: &mut mpsc::UnboundedReceiver<PeerMessage>,
+        piece: &PieceDownload,
+    ) -> Result<(), DomainError> {
+        if !self.shared.state.lock().unwrap().am_interested {
+            self.send(PeerMessage::Interested)?;
+        }
+
+        let piece_index = piece.piece_index();
+        let mut received = piece.subscribe();
+        let mut peer_choking = self.shared.peer_choking.subscribe();
+        let mut closed = self.shared.closed.subscribe();
+
+        while !piece.is_complete() {
+            if *closed.borrow_and_update() {
+                return Err(self.closed_error());
+            }
+            if piece.is_abandoned() {
+                return Err(DomainError::NetworkError(format!("Piece {} is no longer wanted", piece_index)));
+            }
+
+            // Blocks another connection delivered first are no longer wanted from this one
+            received.borrow_and_update();
+            for request in queue.outstanding_requests() {
+                if piece.has_block(&request) {
+                    queue.remove(&request);
+                    piece.on_request_finished(&request);
+                    self.send(PeerMessage::Cancel(request))?;
+                }
+            }
+
+            // Allowed-fast pieces may be requested even while the peer chokes us
+            if !*peer_choking.borrow_and_update() || self.is_allowed_fast(piece_index) {
+                for request in piece.missing_blocks() {
+                    if !queue.has_capacity() {
+                        break;
+                    }
+                    if queue.is_outstanding(&request) {
+                        continue;
+                    }
+                    self.send(PeerMessage::Request(request))?;
+                    queue.on_request_sent(request);
+                    piece.on_request_sent(&request);
+                }
+            }
+
+            tokio::select! {
+                message = blocks.recv() => match message {
+                    Some(PeerMessage::Piece { piece_index: index, begin, data }) => {
+                        let request = BlockRequest::new(index, begin, data.len() as u32);
+                        if queue.on_block_received(&request) {
+                            piece.on_request_finished(&request);
+                        }
+                        // Late blocks are still welcome if nobody else has delivered them
+                        if !piece.add_block(begin, &data)? {
+                            self.shared.state.lock().unwrap().duplicate_bytes += data.len() as u64;
+                        }
+                    }
+                    Some(PeerMessage::RejectRequest(request)) => {
+                        if queue.is_outstanding(&request) {
+                            queue.remove(&request);
+                            piece.on_request_finished(&request);
+                        }
+                    }
+                    // Without the fast extension a choke discards every pending request; ask again
+                    // after the next unchoke. It travels with the blocks so a quick choke/unchoke
+                    // pair is never missed. With it, the peer rejects each dropped request explicitly.
+                    Some(PeerMessage::Choke) if !self.shared.fast_extension => {
+                        for request in queue.clear() {
+                            piece.on_request_finished(&request);
+                        }
+                    }
+                    Some(_) => {}
+                    None => return Err(self.closed_error()),
+                },
+                // Another connection delivered a block, cancel our copy of the request;
+                // or the piece was abandoned
+                changed = received.changed() => {
+                    if changed.is_err() {
+                        return Err(self.closed_error());
+                    }
+                }
+                // Wakes us up to send requests again after an unchoke
+                changed = peer_choking.changed() => {
+                    if changed.is_err() {
+                        return Err(self.closed_error());
+                    }
+                }
+                _ = closed.changed() => return Err(self.closed_error()),
+                _ = tokio::time::sleep(BLOCK_TIMEOUT) => {
+                    return Err(DomainError::NetworkError(format!(
+                        "Timed out waiting for blocks of piece {} from {}",
+                        piece_index,
+                        self.address()
+                    )));
+                }
+            }
+        }
+
+        self.shared.state.lock().unwrap().rtt = queue.smoothed_rtt();
+        Ok(())
+    }
+
+    /// Handle a message read from the socket
+    fn dispatch(&self, message: PeerMessage) {
+        let fast_message = matches!(
+            message,
+            PeerMessage::SuggestPiece { .. }
+                | PeerMessage::HaveAll
+                | PeerMessage::HaveNone
+                | PeerMessage::RejectRequest(_)
+                | PeerMessage::AllowedFast { .. }
+        );
+        if fast_message && !self.shared.fast_extension {
+            // BEP 6: fast messages without a negotiated extension are a protocol violation
+            eprintln!("Peer {} sent a fast extension message without negotiating it", self.address());
+            self.close();
+            return;
+        }
+
+        let mut state = self.shared.state.lock().unwrap();
+        state.last_received_at = Instant::now();
+
+        match message {
+            PeerMessage::Choke => {
+                state.peer_choking = true;
+                self.shared.peer_choking.send_replace(true);
+                drop(state);
+                for route in self.shared.routes.lock().unwrap().values() {
+                    let _ = route.send(PeerMessage::Choke);
+                }
+            }
+            PeerMessage::Unchoke => {
+                state.peer_choking = false;
+                self.shared.peer_choking.send_replace(false);
+            }
+            PeerMessage::Interested | PeerMessage::NotInterested => {
+                state.peer_interested = matches!(message, PeerMessage::Interested);
+                drop(state);
+                self.forward_upload(message);
+            }
+            PeerMessage::Request(_) | PeerMessage::Cancel(_) => {
+                drop(state);
+                self.forward_upload(message);
+            }
+            PeerMessage::Piece { piece_index, ref data, .. } => {
+                state.download_rate.record(data.len() as u64);
+                state.last_progress_at = Instant::now();
+                drop(state);
+                self.route(piece_index, message);
+            }
+            PeerMessage::RejectRequest(request) => {
+                drop(state);
+                self.route(request.piece_index, message);
+            }
+            PeerMessage::AllowedFast { piece_index } => {
+                self.shared.allowed_fast.lock().unwrap().insert(piece_index);
+            }
+            PeerMessage::Bitfield(bits) => {
+                drop(state);
+                self.replace_pieces(PeerPieces::from_bitfield(bits));
+            }
+            PeerMessage::HaveAll => {
+                drop(state);
+                self.replace_pieces(PeerPieces::all());
+            }
+            PeerMessage::HaveNone => {
+                drop(state);
+                self.replace_pieces(PeerPieces::default());
+            }
+            PeerMessage::Have { piece_index } => {
+                drop(state);
+                let mut pieces = self.shared.pieces.lock().unwrap();
+                if pieces.insert(piece_index) {
+                    if let Some(availability) = self.shared.availability.lock().unwrap().as_ref() {
+                        availability.add_piece(piece_index);
+                    }
+                    drop(pieces);
+                    self.shared.pieces_changed.send_modify(|version| *version += 1);
+                }
+            }
+            PeerMessage::SuggestPiece { piece_index } => {
+                let mut suggested = self.shared.suggested.lock().unwrap();
+                suggested.retain(|index| *index != piece_index);
+                suggested.push_back(piece_index);
+                if suggested.len() > MAX_SUGGESTED_PIECES {
+                    suggested.pop_front();
+                }
+            }
+            // Extensions we do not speak are ignored rather than dropping the peer
+            PeerMessage::Unknown { .. } => {}
+            _ => {}
+        }
+    }
+
+    fn replace_pieces(&self, replacement: PeerPieces) {
+        let mut pieces = self.shared.pieces.lock().unwrap();
+        if let Some(availability) = self.shared.availability.lock().unwrap().as_ref() {
+            availability.remove(&pieces);
+            availability.add(&replacement);
+        }
+        *pieces = replacement;
+        drop(pieces);
+        self.shared.pieces_changed.send_modify(|version| *version += 1);
+    }
+
+    fn forward_upload(&self, message: PeerMessage) {
+        if let Some(uploads) = self.shared.uploads.lock().unwrap().as_ref() {
+            let _ = uploads.send(message);
+        }
+    }
+
+    fn route(&self, piece_index: u32, message: PeerMessage) {
+        if let Some(route) = self.shared.routes.lock().unwrap().get(&piece_index) {
+            let _ = route.send(message);
+        }
+    }
+
+    fn closed_error(&self) -> DomainError {
+        DomainError::PeerConnectionError(format!("Connection to {} is closed", self.address()))
+    }
+}
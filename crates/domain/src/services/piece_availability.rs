@@ -0,0 +1,125 @@
+use std::sync::Mutex;
+
+/// Pieces one peer has announced through `bitfield`, `have`, `have all` and `have none`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PeerPieces {
+    /// The peer sent `have all`; it is a seed whatever the piece count
+    all: bool,
+    /// Wire-format bitfield, high bit of byte 0 is piece 0
+    bits: Vec<u8>,
+}
+
+impl PeerPieces {
+    pub fn all() -> Self {
+        Self { all: true, bits: Vec::new() }
+    }
+
+    pub fn from_bitfield(bits: Vec<u8>) -> Self {
+        Self { all: false, bits }
+    }
+
+    pub fn is_seed(&self) -> bool {
+        self.all
+    }
+
+    pub fn has(&self, piece_index: u32) -> bool {
+        let index = piece_index as usize;
+        self.all || self.bits.get(index / 8).is_some_and(|byte| byte & (0x80 >> (index % 8)) != 0)
+    }
+
+    /// Record a `have`; returns whether the piece is new
+    pub fn insert(&mut self, piece_index: u32) -> bool {
+        if self.has(piece_index) {
+            return false;
+        }
+        let index = piece_index as usize;
+        if self.bits.len() <= index / 8 {
+            self.bits.resize(index / 8 + 1, 0);
+        }
+        self.bits[index / 8] |= 0x80 >> (index % 8);
+        true
+    }
+
+    /// Number of pieces announced individually; seeds report zero
+    pub fn len(&self) -> usize {
+        self.bits.iter().map(|byte| byte.count_ones() as usize).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        !self.all && self.len() == 0
+    }
+
+    fn indices(&self) -> impl Iterator<Item = usize> + '_ {
+        self.bits.iter().enumerate().flat_map(|(byte_index, byte)| {
+            (0..8).filter(move |bit| byte & (0x80 >> bit) != 0).map(move |bit| byte_index * 8 + bit)
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+struct Counts {
+    seeds: u32,
+    per_piece: Vec<u32>,
+}
+
+/// How many connected peers of one torrent have each piece.
+///
+/// Connections add their pieces when they register and take them away again
+/// when they close, so the counts only ever describe live peers.
+#[derive(Debug, Default)]
+pub struct PieceAvailability {
+    counts: Mutex<Counts>,
+}
+
+impl PieceAvailability {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Connected peers that have `piece_index`
+    pub fn count(&self, piece_index: u32) -> u32 {
+        let counts = self.counts.lock().unwrap();
+        counts.seeds + counts.per_piece.get(piece_index as usize).copied().unwrap_or(0)
+    }
+
+    /// Connected peers that have every piece
+    pub fn seeds(&self) -> u32 {
+        self.counts.lock().unwrap().seeds
+    }
+
+    pub fn add(&self, pieces: &PeerPieces) {
+        let mut counts = self.counts.lock().unwrap();
+        if pieces.all {
+            counts.seeds += 1;
+            return;
+        }
+        for index in pieces.indices() {
+            if counts.per_piece.len() <= index {
+                counts.per_piece.resize(index + 1, 0);
+            }
+            counts.per_piece[index] += 1;
+        }
+    }
+
+    pub fn remove(&self, pieces: &PeerPieces) {
+        let mut counts = self.counts.lock().unwrap();
+        if pieces.all {
+            counts.seeds = counts.seeds.saturating_sub(1);
+            return;
+        }
+        for index in pieces.indices() {
+            if let Some(count) = counts.per_piece.get_mut(index) {
+                *count = count.saturating_sub(1);
+            }
+        }
+    }
+
+    pub(crate) fn add_piece(&self, piece_index: u32) {
+        let mut counts = self.counts.lock().unwrap();
+        let index = piece_index as usize;
+        if counts.per_piece.len() <= index {
+            counts.per_piece.resize(index + 1, 0);
+        }
+        counts.per_piece[index] += 1;
+    }
+}
@@ -0,0 +1,278 @@
+use crate::errors::DomainError;
+use crate::protocol::BlockRequest;
+use crate::services::request_pipeline::{PieceAssembler, BLOCK_SIZE};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+#[derive(Debug)]
+struct DownloadState {
+    /// Taken by the first caller of `take_data` once the piece is complete
+    assembler: Option<PieceAssembler>,
+    complete: bool,
+    /// Outstanding requests per block, across every peer working on the piece
+    requests: Vec<usize>,
+    duplicate_bytes: u64,
+    received_bytes: u64,
+    missing_bytes: u64,
+    /// When playback needs the piece, if a stream is waiting for it
+    deadline: Option<Instant>,
+    /// Offsets of blocks received since the last checkpoint
+    unsaved: Vec<u32>,
+    /// Nobody wants the piece any more, e.g. playback seeked past it
+    abandoned: bool,
+}
+
+/// Blocks of an unfinished piece to persist, so a restart resumes the piece
+/// instead of starting over
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlockCheckpoint {
+    /// Every block received so far, one bit per block
+    pub bitmap: Vec<u8>,
+    /// Offset and data of the blocks not persisted yet
+    pub blocks: Vec<(u32, Vec<u8>)>,
+}
+
+/// One piece being assembled, possibly from several peers at once.
+///
+/// Normally a single connection fetches a piece. In endgame mode other
+/// connections join the same download: they request the blocks that are still
+/// missing, whoever delivers a block first wins, and the others cancel their
+/// copies of that request.
+#[derive(Debug)]
+pub struct PieceDownload {
+    piece_index: u32,
+    state: Mutex<DownloadState>,
+    /// Number of blocks received so far; lets every participant notice new blocks
+    received: watch::Sender<usize>,
+    participants: AtomicUsize,
+    started_at: Instant,
+}
+
+impl PieceDownload {
+    pub fn new(piece_index: u32, piece_length: u32) -> Self {
+        let assembler = PieceAssembler::new(piece_index, piece_length);
+        Self {
+            piece_index,
+            state: Mutex::new(DownloadState {
+                requests: vec![0; assembler.block_count()],
+                assembler: Some(assembler),
+                complete: false,
+                duplicate_bytes: 0,
+                received_bytes: 0,
+                missing_bytes: piece_length as u64,
+                deadline: None,
+                unsaved: Vec::new(),
+                abandoned: false,
+            }),
+            received: watch::Sender::new(0),
+            participants: AtomicUsize::new(0),
+            started_at: Instant::now(),
+        }
+    }
+
+    pub fn piece_index(&self) -> u32 {
+        self.piece_index
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.state.lock().unwrap().complete
+    }
+
+    /// Blocks not received yet, the ones with the fewest outstanding requests first
+    pub fn missing_blocks(&self) -> Vec<BlockRequest> {
+        let state = self.state.lock().unwrap();
+        let Some(assembler) = state.assembler.as_ref() else {
+            return Vec::new();
+        };
+        let mut missing = assembler.missing_blocks();
+        missing.sort_by_key(|request| state.requests[(request.begin / BLOCK_SIZE) as usize]);
+        missing
+    }
+
+    /// Whether every missing block has been requested from some peer
+    pub fn all_blocks_requested(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        let Some(assembler) = state.assembler.as_ref() else {
+            return true;
+        };
+        assembler
+            .missing_blocks()
+            .iter()
+            .all(|request| state.requests[(request.begin / BLOCK_SIZE) as usize] > 0)
+    }
+
+    /// Whether the block has arrived, from any peer
+    pub fn has_block(&self, request: &BlockRequest) -> bool {
+        let state = self.state.lock().unwrap();
+        match state.assembler.as_ref() {
+            Some(assembler) => assembler.has_block(request.begin),
+            None => state.complete,
+        }
+    }
+
+    pub fn on_request_sent(&self, request: &BlockRequest) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(count) = state.requests.get_mut((request.begin / BLOCK_SIZE) as usize) {
+            *count += 1;
+        }
+    }
+
+    /// A request was answered, cancelled, rejected or dropped by a choke
+    pub fn on_request_finished(&self, request: &BlockRequest) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(count) = state.requests.get_mut((request.begin / BLOCK_SIZE) as usize) {
+            *count = count.saturating_sub(1);
+        }
+    }
+
+    /// Store a block. Returns `false` when another peer delivered it first,
+    /// in which case its bytes are counted as duplicate.
+    pub fn add_block(&self, begin: u32, block: &[u8]) -> Result<bool, DomainError> {
+        let mut state = self.state.lock().unwrap();
+        let added = match state.assembler.as_mut() {
+            Some(assembler) => assembler.add_block(begin, block)?,
+            None => false,
+        };
+
+        if !added {
+            state.duplicate_bytes += block.len() as u64;
+            return Ok(false);
+        }
+
+        state.received_bytes += block.len() as u64;
+        state.missing_bytes = state.missing_bytes.saturating_sub(block.len() as u64);
+        state.complete = state.assembler.as_ref().is_some_and(|assembler| assembler.is_complete());
+        state.unsaved.push(begin);
+        drop(state);
+        self.received.send_modify(|received| *received += 1);
+        Ok(true)
+    }
+
+    /// Put back a block persisted before a restart. It is already saved and
+    /// does not count towards the transfer rate.
+    pub fn restore_block(&self, begin: u32, block: &[u8]) -> Result<bool, DomainError> {
+        let mut state = self.state.lock().unwrap();
+        let restored = match state.assembler.as_mut() {
+            Some(assembler) => assembler.add_block(begin, block)?,
+            None => false,
+        };
+        if restored {
+            state.missing_bytes = state.missing_bytes.saturating_sub(block.len() as u64);
+            state.complete = state.assembler.as_ref().is_some_and(|assembler| assembler.is_complete());
+        }
+        Ok(restored)
+    }
+
+    /// The blocks received since the last checkpoint, `None` when there are
+    /// none or the piece has been taken for verification
+    pub fn checkpoint(&self) -> Option<BlockCheckpoint> {
+        let state = self.state.lock().unwrap();
+        let assembler = state.assembler.as_ref()?;
+        if state.unsaved.is_empty() {
+            return None;
+        }
+        let blocks = state.unsaved
+            .iter()
+            .filter_map(|begin| assembler.block(*begin).map(|block| (*begin, block.to_vec())))
+            .collect();
+        Some(BlockCheckpoint { bitmap: assembler.received_bitmap(), blocks })
+    }
+
+    /// The blocks of a checkpoint were persisted
+    pub fn mark_saved(&self, checkpoint: &BlockCheckpoint) {
+        let mut state = self.state.lock().unwrap();
+        state.unsaved.retain(|begin| !checkpoint.blocks.iter().any(|(saved, _)| saved == begin));
+    }
+
+    /// The assembled piece; only the first caller after completion gets it
+    pub fn take_data(&self) -> Option<Vec<u8>> {
+        let mut state = self.state.lock().unwrap();
+        if !state.complete {
+            return None;
+        }
+        state.assembler.take().map(PieceAssembler::into_data)
+    }
+
+    /// Bytes that arrived after another peer had already delivered them
+    pub fn duplicate_bytes(&self) -> u64 {
+        self.state.lock().unwrap().duplicate_bytes
+    }
+
+    /// Bytes of the piece still to arrive
+    pub fn missing_bytes(&self) -> u64 {
+        self.state.lock().unwrap().missing_bytes
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        self.state.lock().unwrap().deadline
+    }
+
+    /// Record when playback needs the piece; the earliest deadline wins
+    pub fn set_deadline(&self, deadline: Instant) {
+        let mut state = self.state.lock().unwrap();
+        state.deadline = Some(state.deadline.map_or(deadline, |current| current.min(deadline)));
+    }
+
+    /// How long the rest of the piece takes at the rate blocks have been
+    /// arriving so far; `None` before anything arrived
+    pub fn estimated_time_left(&self, now: Instant) -> Option<Duration> {
+        let state = self.state.lock().unwrap();
+        if state.complete {
+            return Some(Duration::ZERO);
+        }
+        if state.received_bytes == 0 {
+            return None;
+        }
+        let elapsed = now.duration_since(self.started_at).as_secs_f64().max(0.001);
+        let rate = state.received_bytes as f64 / elapsed;
+        Some(Duration::from_secs_f64(state.missing_bytes as f64 / rate))
+    }
+
+    /// Whether the piece will likely miss its deadline at the current pace.
+    /// A download that has produced nothing for `stall` counts as at risk too.
+    pub fn is_at_risk(&self, now: Instant, stall: Duration) -> bool {
+        let Some(deadline) = self.deadline() else {
+            return false;
+        };
+        if self.is_complete() || self.is_abandoned() {
+            return false;
+        }
+        match self.estimated_time_left(now) {
+            Some(left) => now + left > deadline,
+            None => now >= deadline || now.duration_since(self.started_at) >= stall,
+        }
+    }
+
+    /// Stop working on the piece: every connection cancels its in-flight
+    /// requests and leaves. Blocks received so far are kept for a later attempt.
+    pub fn abandon(&self) {
+        self.state.lock().unwrap().abandoned = true;
+        // Wake the participants so they notice
+        self.received.send_modify(|_| {});
+    }
+
+    pub fn is_abandoned(&self) -> bool {
+        self.state.lock().unwrap().abandoned
+    }
+
+    /// Changes every time a new block arrives
+    pub fn subscribe(&self) -> watch::Receiver<usize> {
+        self.received.subscribe()
+    }
+
+    /// Register a connection working on this piece
+    pub fn join(&self) {
+        self.participants.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Unregister a connection; returns how many are still working on the piece
+    pub fn leave(&self) -> usize {
+        self.participants.fetch_sub(1, Ordering::SeqCst).saturating_sub(1)
+    }
+
+    pub fn participants(&self) -> usize {
+        self.participants.load(Ordering::SeqCst)
+    }
+}
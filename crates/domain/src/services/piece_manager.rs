@@ -1,18 +1,30 @@
-use crate::entities::Torrent;
 use crate::errors::DomainError;
 use crate::repositories::{PieceRepository, TorrentRepository};
-use std::collections::{HashMap, VecDeque};
+use crate::services::piece_download::{BlockCheckpoint, PieceDownload};
+use crate::services::priority_aggregator::PriorityAggregator;
+use crate::services::storage::TorrentStorage;
+use crate::services::request_pipeline::BLOCK_SIZE;
+use std::collections::{HashMap, HashSet};
 use std::sync::{Arc, Mutex};
-use tokio::fs::File;
-use tokio::io::{AsyncReadExt, AsyncSeekExt, SeekFrom};
-use std::path::PathBuf;
+use std::time::{Duration, Instant};
 use sha1::Digest;
 
+/// Pieces due within this much time are urgent
+pub const URGENT_DEADLINE: Duration = Duration::from_secs(5);
+
+/// Pieces due within this much time are high priority
+pub const HIGH_DEADLINE: Duration = Duration::from_secs(30);
+
+/// How often blocks of unfinished pieces are written out to survive a restart
+pub const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(10);
+
 #[derive(Debug, Clone)]
 pub struct PieceRequest {
     pub piece_index: usize,
     pub priority: PiecePriority,
     pub requester: String, // Session ID or download ID
+    /// When playback reaches the piece, for streaming requests
+    pub deadline: Option<Instant>,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
@@ -23,11 +35,39 @@ pub enum PiecePriority {
     Urgent = 3, // For streaming
 }
 
+impl PiecePriority {
+    /// Priority of a piece playback needs `deadline` from `now`
+    pub fn for_deadline(deadline: Instant, now: Instant) -> Self {
+        let slack = deadline.saturating_duration_since(now);
+        if slack <= URGENT_DEADLINE {
+            PiecePriority::Urgent
+        } else if slack <= HIGH_DEADLINE {
+            PiecePriority::High
+        } else {
+            PiecePriority::Normal
+        }
+    }
+}
+
+/// Pieces of one torrent that peers are working on
+#[derive(Debug, Default)]
+struct DownloadProgress {
+    /// Being downloaded from one peer, or several in endgame mode
+    in_progress: HashMap<usize, Arc<PieceDownload>>,
+    /// Started earlier but not completed, e.g. because the peer went away
+    partial: HashSet<usize>,
+    /// Whether pieces with blocks saved by an earlier session were added to `partial`
+    partial_restored: bool,
+    /// Block bytes received more than once, summed over finished downloads
+    duplicate_bytes: u64,
+}
+
 pub struct PieceManager {
     piece_repository: Arc<dyn PieceRepository>,
     torrent_repository: Arc<dyn TorrentRepository>,
-    pending_requests: Arc<Mutex<HashMap<i32, VecDeque<PieceRequest>>>>,
-    download_dir: String,
+    pending_requests: Arc<Mutex<HashMap<i32, PriorityAggregator>>>,
+    downloads: Mutex<HashMap<i32, DownloadProgress>>,
+    storage: Arc<TorrentStorage>,
 }
 
 impl PieceManager {
@@ -38,33 +78,89 @@ impl PieceManager {
     ) -> Self {
         Self {
             piece_repository,
-            torrent_repository,
             pending_requests: Arc::new(Mutex::new(HashMap::new())),
-            download_dir,
+            downloads: Mutex::new(HashMap::new()),
+            storage: Arc::new(TorrentStorage::new(torrent_repository.clone(), download_dir)),
+            torrent_repository,
         }
     }
 
-    /// Request a piece with specific priority
+    /// Store pieces through `storage`, shared with the other services touching
+    /// downloaded data
+    pub fn with_storage(mut self, storage: Arc<TorrentStorage>) -> Self {
+        self.storage = storage;
+        self
+    }
+
+    /// Request a piece with specific priority. Requesting a piece again
+    /// replaces the requester's earlier claim instead of queueing a duplicate.
     pub async fn request_piece(&self, torrent_id: i32, piece_index: usize, priority: PiecePriority, requester: String) -> Result<(), DomainError> {
-        let request = PieceRequest {
-            piece_index,
-            priority,
-            requester,
-        };
+        let mut requests = self.pending_requests.lock().unwrap();
+        requests.entry(torrent_id).or_default().claim(piece_index, requester, priority, None);
+        Ok(())
+    }
 
+    /// Request a piece playback needs by `deadline`. The priority follows from
+    /// how close the deadline is; a download already running learns the deadline
+    /// so it can be re-requested from a faster peer when it falls behind.
+    pub async fn request_piece_by(&self, torrent_id: i32, piece_index: usize, deadline: Instant, requester: String) -> Result<(), DomainError> {
+        if let Some(download) = self.active_download(torrent_id, piece_index) {
+            download.set_deadline(deadline);
+        }
+        let priority = PiecePriority::for_deadline(deadline, Instant::now());
         let mut requests = self.pending_requests.lock().unwrap();
-        let torrent_requests = requests.entry(torrent_id).or_insert_with(VecDeque::new);
-        
-        // Insert in priority order
-        let insert_pos = torrent_requests.iter()
-            .position(|r| r.priority < priority)
-            .unwrap_or(torrent_requests.len());
-        
-        torrent_requests.insert(insert_pos, request);
-        
+        requests.entry(torrent_id).or_default().claim(piece_index, requester, priority, Some(deadline));
         Ok(())
     }
 
+    /// Give `requester` `weight` times the bandwidth of other requesters of the
+    /// torrent when their pieces compete, e.g. a stream on the big screen
+    pub fn set_request_weight(&self, torrent_id: i32, requester: &str, weight: u32) {
+        let mut requests = self.pending_requests.lock().unwrap();
+        requests.entry(torrent_id).or_default().set_weight(requester, weight);
+    }
+
+    /// Drop every claim `requester` holds, e.g. when a stream session closes.
+    /// Pieces nobody else asked for are no longer requested.
+    pub fn cancel_requests(&self, requester: &str) {
+        let mut requests = self.pending_requests.lock().unwrap();
+        for pending in requests.values_mut() {
+            pending.remove_requester(requester);
+        }
+        requests.retain(|_, pending| !pending.is_empty());
+    }
+
+    /// Drop `requester`'s claims on pieces failing `keep`, e.g. the old window
+    /// after a stream seeks. Running downloads of pieces nobody else wants are
+    /// abandoned, which cancels their in-flight blocks. Returns how many
+    /// claims were dropped.
+    pub fn release_requests_except(&self, torrent_id: i32, requester: &str, keep: impl Fn(usize) -> bool) -> usize {
+        let (released, orphaned) = {
+            let mut requests = self.pending_requests.lock().unwrap();
+            let Some(pending) = requests.get_mut(&torrent_id) else {
+                return 0;
+            };
+            let released = pending.remove_requester_where(requester, keep);
+            if pending.is_empty() {
+                requests.remove(&torrent_id);
+            }
+            released
+        };
+
+        for piece_index in orphaned {
+            if let Some(download) = self.active_download(torrent_id, piece_index) {
+                download.abandon();
+            }
+        }
+        released
+    }
+
+    /// The combined request for a piece across requesters, if anyone wants it
+    pub fn pending_request(&self, torrent_id: i32, piece_index: usize) -> Option<PieceRequest> {
+        let requests = self.pending_requests.lock().unwrap();
+        requests.get(&torrent_id)?.request(piece_index)
+    }
+
     /// Check if a piece is already downloaded
     pub async fn is_piece_available(&self, torrent_id: i32, piece_index: usize) -> Result<bool, DomainError> {
         if let Some(piece) = self.piece_repository.find_by_torrent_and_index(torrent_id, piece_index as i32).await? {
@@ -74,6 +170,22 @@ impl PieceManager {
         }
     }
 
+    /// Wire-format bitfield of the pieces we have verified (high bit of byte 0 is piece 0)
+    pub async fn verified_bitfield(&self, torrent_id: i32) -> Result<Vec<u8>, DomainError> {
+        let torrent = self.torrent_repository.find_by_id(torrent_id).await?
+            .ok_or_else(|| DomainError::NotFound(format!("Torrent {} not found", torrent_id)))?;
+
+        let mut bitfield = vec![0u8; (torrent.piece_count as usize).div_ceil(8)];
+        for piece in self.piece_repository.find_by_torrent_id(torrent_id).await? {
+            let index = piece.piece_index as usize;
+            if piece.is_complete() && index < torrent.piece_count as usize {
+                bitfield[index / 8] |= 0x80 >> (index % 8);
+            }
+        }
+
+        Ok(bitfield)
+    }
+
     /// Read piece data from the downloaded file
     pub async fn read_piece_data(&self, torrent_id: i32, piece_index: usize) -> Result<Vec<u8>, DomainError> {
         // Check if piece is available
@@ -85,39 +197,26 @@ impl PieceManager {
         let torrent = self.torrent_repository.find_by_id(torrent_id).await?
             .ok_or_else(|| DomainError::NotFound(format!("Torrent {} not found", torrent_id)))?;
 
-        // Calculate file path
-        let file_path = self.get_torrent_file_path(&torrent)?;
-        
-        // Calculate piece offset and size
-        let piece_size = torrent.piece_length as u64;
-        let piece_offset = piece_index as u64 * piece_size;
-        
-        // Determine actual piece size (last piece might be smaller)
-        let total_pieces = torrent.piece_count as usize;
-        let actual_piece_size = if piece_index == total_pieces - 1 {
-            // Last piece - calculate remaining bytes
-            let remaining = torrent.total_size as u64 - piece_offset;
-            remaining.min(piece_size) as usize
-        } else {
-            piece_size as usize
-        };
-
-        // Read piece data from file
-        let mut file = File::open(&file_path).await
-            .map_err(|e| DomainError::IoError(format!("Failed to open file {}: {}", file_path.display(), e)))?;
-
-        file.seek(SeekFrom::Start(piece_offset)).await
-            .map_err(|e| DomainError::IoError(format!("Failed to seek to piece offset: {}", e)))?;
-
-        let mut buffer = vec![0u8; actual_piece_size];
-        let bytes_read = file.read_exact(&mut buffer).await
-            .map_err(|e| DomainError::IoError(format!("Failed to read piece data: {}", e)))?;
-
-        if bytes_read != actual_piece_size {
-            return Err(DomainError::IoError(format!("Expected {} bytes, read {}", actual_piece_size, bytes_read)));
+        let piece_offset = piece_index as u64 * torrent.piece_length as u64;
+        let piece_length = torrent.piece_size(piece_index as i32) as usize;
+        match self.storage.read(torrent_id, piece_offset, piece_length).await {
+            Err(DomainError::NotFound(reason)) => {
+                // Evicted from memory storage, or the file was removed behind our back
+                self.forget_piece(torrent_id, piece_index).await?;
+                Err(DomainError::NotFound(format!("Piece {} of torrent {} is gone: {}", piece_index, torrent_id, reason)))
+            }
+            result => result,
         }
+    }
 
-        Ok(buffer)
+    /// Mark a piece we no longer hold as missing, so it is downloaded again
+    async fn forget_piece(&self, torrent_id: i32, piece_index: usize) -> Result<(), DomainError> {
+        if let Some(mut piece) = self.piece_repository.find_by_torrent_and_index(torrent_id, piece_index as i32).await? {
+            piece.mark_verified(false);
+            self.piece_repository.update(&piece).await?;
+            println!("♻️  Piece {} of torrent {} is no longer stored, downloading it again", piece_index, torrent_id);
+        }
+        Ok(())
     }
 
     /// Read a range of data across multiple pieces
@@ -179,25 +278,237 @@ impl PieceManager {
         Ok(result)
     }
 
-    fn get_torrent_file_path(&self, torrent: &Torrent) -> Result<PathBuf, DomainError> {
-        let download_path = PathBuf::from(&self.download_dir);
-        let file_name = torrent.file_path.as_ref()
-            .map(|p| PathBuf::from(p).file_name().unwrap().to_string_lossy().to_string())
-            .unwrap_or_else(|| torrent.name.clone());
-        
-        Ok(download_path.join(file_name))
-    }
-
     /// Get next piece that should be downloaded for a torrent
     pub fn get_next_piece_request(&self, torrent_id: i32) -> Option<PieceRequest> {
-        let mut requests = self.pending_requests.lock().unwrap();
-        if let Some(torrent_requests) = requests.get_mut(&torrent_id) {
-            torrent_requests.pop_front()
+        self.next_piece_request_where(torrent_id, |_| true)
+    }
+
+    /// The most pressing request whose piece passes `filter` and is not being
+    /// downloaded already, e.g. one a particular peer can serve. Requests stay
+    /// pending until the piece completes or their requesters cancel them.
+    pub fn next_piece_request_where(&self, torrent_id: i32, filter: impl Fn(&PieceRequest) -> bool) -> Option<PieceRequest> {
+        let in_progress = self.in_progress_pieces(torrent_id);
+        let requests = self.pending_requests.lock().unwrap();
+        requests
+            .get(&torrent_id)?
+            .best_where(|request| !in_progress.contains(&request.piece_index) && filter(request))
+    }
+
+    /// Whether any piece of the torrent is still waiting to be downloaded
+    pub fn has_pending_requests(&self, torrent_id: i32) -> bool {
+        let requests = self.pending_requests.lock().unwrap();
+        requests.get(&torrent_id).is_some_and(|pending| pending.has_claims())
+    }
+
+    /// Claim a piece for download; `None` if some peer is already fetching it
+    /// Blocks stored by an earlier session are put back, so only the rest
+    /// has to be downloaded. Requesters waiting for the piece are charged for
+    /// it, which keeps concurrent streams of the torrent taking turns.
+    pub async fn begin_download(&self, torrent_id: i32, piece_index: usize, piece_length: u32) -> Option<Arc<PieceDownload>> {
+        let download = {
+            let mut downloads = self.downloads.lock().unwrap();
+            let progress = downloads.entry(torrent_id).or_default();
+            if progress.in_progress.contains_key(&piece_index) {
+                return None;
+            }
+            let download = Arc::new(PieceDownload::new(piece_index as u32, piece_length));
+            progress.in_progress.insert(piece_index, download.clone());
+            download
+        };
+        if let Some(pending) = self.pending_requests.lock().unwrap().get_mut(&torrent_id) {
+            pending.charge(piece_index, piece_length as u64);
+        }
+
+        match self.restore_blocks(torrent_id, &download).await {
+            Ok(0) => {}
+            Ok(restored) => println!("♻️  Resuming piece {} with {} stored blocks", piece_index, restored),
+            Err(e) => eprintln!("⚠️  Could not restore stored blocks of piece {}: {}", piece_index, e),
+        }
+        Some(download)
+    }
+
+    /// Load the blocks an earlier session persisted for the piece
+    async fn restore_blocks(&self, torrent_id: i32, download: &PieceDownload) -> Result<usize, DomainError> {
+        let piece_index = download.piece_index() as usize;
+        let Some(piece) = self.piece_repository.find_by_torrent_and_index(torrent_id, piece_index as i32).await? else {
+            return Ok(0);
+        };
+        if piece.received_blocks.is_none() || piece.is_complete() {
+            return Ok(0);
+        }
+
+        let torrent = self.torrent_repository.find_by_id(torrent_id).await?
+            .ok_or_else(|| DomainError::NotFound(format!("Torrent {} not found", torrent_id)))?;
+        let piece_length = torrent.piece_size(piece_index as i32);
+        let piece_offset = piece_index as u64 * torrent.piece_length as u64;
+
+        let mut restored = 0;
+        for block_index in 0..piece_length.div_ceil(BLOCK_SIZE) as usize {
+            if !piece.has_received_block(block_index) {
+                continue;
+            }
+            let begin = block_index as u32 * BLOCK_SIZE;
+            let length = BLOCK_SIZE.min(piece_length - begin) as usize;
+            // A block never written may lie past the end of its file
+            let Ok(block) = self.storage.read(torrent_id, piece_offset + begin as u64, length).await else {
+                continue;
+            };
+            if download.restore_block(begin, &block)? {
+                restored += 1;
+            }
+        }
+        Ok(restored)
+    }
+
+    /// Count pieces with blocks saved by an earlier session as partial, so
+    /// they are finished before new pieces are started. Only the first call
+    /// per torrent reads the saved state; returns how many pieces were added.
+    pub async fn restore_partial_pieces(&self, torrent_id: i32) -> Result<usize, DomainError> {
+        let restored = self.downloads.lock().unwrap().get(&torrent_id).is_some_and(|progress| progress.partial_restored);
+        if restored {
+            return Ok(0);
+        }
+
+        let saved: Vec<usize> = self.piece_repository.find_by_torrent_id(torrent_id).await?
+            .iter()
+            .filter(|piece| piece.received_blocks.is_some() && !piece.is_complete())
+            .map(|piece| piece.piece_index as usize)
+            .collect();
+
+        let mut downloads = self.downloads.lock().unwrap();
+        let progress = downloads.entry(torrent_id).or_default();
+        if progress.partial_restored {
+            return Ok(0);
+        }
+        progress.partial_restored = true;
+        progress.partial.extend(&saved);
+        Ok(saved.len())
+    }
+
+    /// Persist the blocks of an unfinished piece received since its last
+    /// checkpoint. Returns whether anything was written.
+    pub async fn checkpoint_download(&self, torrent_id: i32, download: &PieceDownload) -> Result<bool, DomainError> {
+        let Some(checkpoint) = download.checkpoint() else {
+            return Ok(false);
+        };
+        let piece_index = download.piece_index() as usize;
+
+        // Data first: the bitmap must never claim blocks that are not on disk
+        self.write_blocks(torrent_id, piece_index, &checkpoint).await?;
+
+        let mut piece = self.piece_repository.find_by_torrent_and_index(torrent_id, piece_index as i32).await?
+            .ok_or_else(|| DomainError::NotFound(format!("Piece {} not found", piece_index)))?;
+        if piece.is_complete() {
+            return Ok(false);
+        }
+        piece.received_blocks = Some(checkpoint.bitmap.clone());
+        self.piece_repository.update(&piece).await?;
+
+        download.mark_saved(&checkpoint);
+        Ok(true)
+    }
+
+    /// Checkpoint every download in progress; returns how many pieces were saved
+    pub async fn checkpoint_partial_pieces(&self) -> Result<usize, DomainError> {
+        let active: Vec<(i32, Arc<PieceDownload>)> = {
+            let downloads = self.downloads.lock().unwrap();
+            downloads
+                .iter()
+                .flat_map(|(torrent_id, progress)| progress.in_progress.values().map(|download| (*torrent_id, download.clone())))
+                .collect()
+        };
+
+        let mut saved = 0;
+        for (torrent_id, download) in active {
+            if self.checkpoint_download(torrent_id, &download).await? {
+                saved += 1;
+            }
+        }
+        Ok(saved)
+    }
+
+    /// Checkpoint partial pieces and snapshot the files written to every
+    /// `CHECKPOINT_INTERVAL` until the task is dropped
+    pub fn spawn_checkpoints(self: &Arc<Self>) -> tokio::task::JoinHandle<()> {
+        let piece_manager = Arc::clone(self);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(CHECKPOINT_INTERVAL);
+            loop {
+                interval.tick().await;
+                if let Err(e) = piece_manager.checkpoint_partial_pieces().await {
+                    eprintln!("⚠️  Failed to checkpoint partial pieces: {}", e);
+                }
+                // Only now does the saved piece state match the files
+                if let Err(e) = piece_manager.storage.save_dirty_snapshots().await {
+                    eprintln!("⚠️  Failed to snapshot stored files: {}", e);
+                }
+            }
+        })
+    }
+
+    /// The download of a piece in progress, for another peer to join in endgame mode
+    pub fn active_download(&self, torrent_id: i32, piece_index: usize) -> Option<Arc<PieceDownload>> {
+        let downloads = self.downloads.lock().unwrap();
+        downloads.get(&torrent_id)?.in_progress.get(&piece_index).cloned()
+    }
+
+    /// Every download of the torrent in progress
+    pub fn active_downloads(&self, torrent_id: i32) -> Vec<Arc<PieceDownload>> {
+        let downloads = self.downloads.lock().unwrap();
+        downloads
+            .get(&torrent_id)
+            .map(|progress| progress.in_progress.values().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Release a piece claimed with `begin_download`. Pieces that did not
+    /// complete are remembered as partial so they are finished first.
+    pub fn finish_download(&self, torrent_id: i32, piece_index: usize, completed: bool) {
+        let mut downloads = self.downloads.lock().unwrap();
+        let progress = downloads.entry(torrent_id).or_default();
+        if let Some(download) = progress.in_progress.remove(&piece_index) {
+            progress.duplicate_bytes += download.duplicate_bytes();
+        }
+        if completed {
+            progress.partial.remove(&piece_index);
         } else {
-            None
+            progress.partial.insert(piece_index);
         }
     }
 
+    /// Pieces currently claimed by a download
+    pub fn in_progress_pieces(&self, torrent_id: i32) -> HashSet<usize> {
+        let downloads = self.downloads.lock().unwrap();
+        downloads
+            .get(&torrent_id)
+            .map(|progress| progress.in_progress.keys().copied().collect())
+            .unwrap_or_default()
+    }
+
+    /// Pieces started earlier that are neither complete nor being downloaded
+    pub fn partial_pieces(&self, torrent_id: i32) -> HashSet<usize> {
+        let downloads = self.downloads.lock().unwrap();
+        downloads
+            .get(&torrent_id)
+            .map(|progress| {
+                progress.partial
+                    .iter()
+                    .filter(|piece_index| !progress.in_progress.contains_key(piece_index))
+                    .copied()
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Block bytes of the torrent that were downloaded more than once
+    pub fn duplicate_bytes(&self, torrent_id: i32) -> u64 {
+        let downloads = self.downloads.lock().unwrap();
+        downloads.get(&torrent_id).map_or(0, |progress| {
+            progress.duplicate_bytes
+                + progress.in_progress.values().map(|download| download.duplicate_bytes()).sum::<u64>()
+        })
+    }
+
     /// Mark a piece as completed
     pub async fn mark_piece_completed(&self, torrent_id: i32, piece_index: usize, data: Vec<u8>) -> Result<(), DomainError> {
         // Verify piece hash
@@ -209,15 +520,46 @@ impl PieceManager {
             .map_err(|e| DomainError::ParseError(format!("Invalid piece hash: {}", e)))?;
 
         if computed_hash.as_slice() != expected_hash.as_slice() {
+            // Some stored block is bad; resuming from them would fail again
+            if piece.received_blocks.is_some() {
+                let mut failed_piece = piece.clone();
+                failed_piece.mark_verified(false);
+                self.piece_repository.update(&failed_piece).await?;
+            }
             return Err(DomainError::ValidationError("Piece hash verification failed".to_string()));
         }
 
+        // Persist the data so the piece can be streamed and served to other peers
+        self.write_piece_data(torrent_id, piece_index, &data).await?;
+
         // Update piece status - we'll need to modify the piece and save it
         let mut updated_piece = piece.clone();
-        updated_piece.downloaded = true;
-        updated_piece.verified = true;
+        updated_piece.mark_downloaded();
+        updated_piece.mark_verified(true);
         self.piece_repository.update(&updated_piece).await?;
 
+        // Nobody needs to ask for it any more
+        if let Some(pending) = self.pending_requests.lock().unwrap().get_mut(&torrent_id) {
+            pending.remove_piece(piece_index);
+        }
+
+        Ok(())
+    }
+
+    async fn write_piece_data(&self, torrent_id: i32, piece_index: usize, data: &[u8]) -> Result<(), DomainError> {
+        let whole_piece = BlockCheckpoint { bitmap: Vec::new(), blocks: vec![(0, data.to_vec())] };
+        self.write_blocks(torrent_id, piece_index, &whole_piece).await
+    }
+
+    /// Write blocks at their place in the piece
+    async fn write_blocks(&self, torrent_id: i32, piece_index: usize, checkpoint: &BlockCheckpoint) -> Result<(), DomainError> {
+        let torrent = self.torrent_repository.find_by_id(torrent_id).await?
+            .ok_or_else(|| DomainError::NotFound(format!("Torrent {} not found", torrent_id)))?;
+
+        let piece_offset = piece_index as u64 * torrent.piece_length as u64;
+        for (begin, data) in &checkpoint.blocks {
+            self.storage.write(torrent_id, piece_offset + *begin as u64, data).await?;
+        }
         Ok(())
     }
 }
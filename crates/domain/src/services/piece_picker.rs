@@ -0,0 +1,310 @@
+use crate::entities::{Piece, Torrent};
+use crate::errors::DomainError;
+use crate::repositories::{PieceRepository, TorrentFileRepository, TorrentRepository};
+use crate::services::connection_manager::ConnectionManager;
+use crate::services::file_priorities::PiecePriorities;
+use crate::services::peer_connection::{PeerHandle, PeerStats};
+use crate::services::piece_availability::PieceAvailability;
+use crate::services::piece_download::PieceDownload;
+use crate::services::piece_manager::{PieceManager, PiecePriority, PieceRequest};
+use rand::seq::SliceRandom;
+use rand::Rng;
+use std::cmp::Reverse;
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Requester recorded on pieces the picker chooses by itself
+pub const BACKGROUND_REQUESTER: &str = "background";
+
+/// A streamed piece whose download produced nothing for this long is at risk
+const STALL_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// A piece chosen for one peer. The peer is counted as working on the
+/// download until the caller calls `leave` on it.
+pub struct PiecePick {
+    pub request: PieceRequest,
+    pub download: Arc<PieceDownload>,
+    /// Other peers are fetching the same piece
+    pub endgame: bool,
+}
+
+impl PiecePick {
+    fn new(request: PieceRequest, download: Arc<PieceDownload>, endgame: bool) -> Self {
+        download.join();
+        Self { request, download, endgame }
+    }
+}
+
+fn background_request(piece_index: u32) -> PieceRequest {
+    PieceRequest {
+        piece_index: piece_index as usize,
+        priority: PiecePriority::Normal,
+        requester: BACKGROUND_REQUESTER.to_string(),
+        deadline: None,
+    }
+}
+
+/// How long `bytes` take from a peer at its observed rate, `None` if unmeasured
+fn expected_fetch_time(stats: &PeerStats, bytes: u64) -> Option<Duration> {
+    if stats.download_rate <= 0.0 {
+        return None;
+    }
+    Some(Duration::from_secs_f64(bytes as f64 / stats.download_rate) + stats.rtt.unwrap_or_default())
+}
+
+/// Chooses which missing pieces to download outside streaming windows.
+///
+/// Pieces of skipped files are left out and pieces of high priority files
+/// come first. Within a priority, pieces already started come first so they
+/// can be verified and shared sooner, then the pieces fewest connected peers
+/// have. Ties are broken at random so our clients do not all chase the same
+/// pieces. Requests queued in `PieceManager` for streaming always take
+/// precedence.
+#[derive(Clone)]
+pub struct PiecePicker {
+    piece_repository: Arc<dyn PieceRepository>,
+    torrent_repository: Arc<dyn TorrentRepository>,
+    torrent_file_repository: Arc<dyn TorrentFileRepository>,
+    piece_manager: Arc<PieceManager>,
+    connection_manager: Arc<ConnectionManager>,
+}
+
+impl PiecePicker {
+    pub fn new(
+        piece_repository: Arc<dyn PieceRepository>,
+        torrent_repository: Arc<dyn TorrentRepository>,
+        torrent_file_repository: Arc<dyn TorrentFileRepository>,
+        piece_manager: Arc<PieceManager>,
+        connection_manager: Arc<ConnectionManager>,
+    ) -> Self {
+        Self {
+            piece_repository,
+            torrent_repository,
+            torrent_file_repository,
+            piece_manager,
+            connection_manager,
+        }
+    }
+
+    /// Up to `count` missing pieces that nobody is downloading, best first
+    pub async fn next_pieces(&self, torrent_id: i32, count: usize) -> Result<Vec<Piece>, DomainError> {
+        self.piece_manager.restore_partial_pieces(torrent_id).await?;
+        let priorities = self.piece_priorities(torrent_id).await?;
+        let mut missing = self.missing_pieces(torrent_id, &priorities).await?;
+        let order = self.rank(torrent_id, missing.iter().map(|piece| piece.piece_index as u32), &priorities);
+
+        let picked = order.into_iter().take(count).filter_map(|piece_index| {
+            let position = missing.iter().position(|piece| piece.piece_index as u32 == piece_index)?;
+            Some(missing.swap_remove(position))
+        });
+        Ok(picked.collect())
+    }
+
+    /// The next piece to download from `peer`, already joined: a streaming
+    /// request the peer can serve, else the best piece it has that nobody is
+    /// fetching. When every such piece is taken, endgame mode lets the peer
+    /// help with a download in progress.
+    pub async fn pick_for_peer(&self, torrent: &Torrent, peer: &PeerHandle) -> Result<Option<PiecePick>, DomainError> {
+        let torrent_id = torrent.id.unwrap_or_default();
+        let now = Instant::now();
+        let peer_stats = peer.stats();
+
+        // A streamed piece falling behind its deadline is worth fetching twice
+        if let Some(download) = self.at_risk_download(torrent_id, peer, &peer_stats, now) {
+            println!(
+                "⏰ Piece {} may miss its playback deadline, re-requesting from {}",
+                download.piece_index(),
+                peer.address()
+            );
+            let mut request = background_request(download.piece_index());
+            request.priority = PiecePriority::Urgent;
+            request.deadline = download.deadline();
+            return Ok(Some(PiecePick::new(request, download, true)));
+        }
+
+        let others: Vec<(PeerHandle, PeerStats)> = self.connection_manager.handles(torrent_id)
+            .into_iter()
+            .filter(|other| !other.same_connection(peer))
+            .map(|other| {
+                let stats = other.stats();
+                (other, stats)
+            })
+            .collect();
+        let can_serve = |request: &PieceRequest| {
+            let piece_index = request.piece_index as u32;
+            peer.has_piece(piece_index) && self.meets_deadline(torrent, request, &peer_stats, &others, now)
+        };
+
+        let streaming = peer.suggested_pieces()
+            .into_iter()
+            .rev()
+            .find_map(|piece_index| {
+                self.piece_manager.next_piece_request_where(torrent_id, |request| {
+                    request.piece_index == piece_index as usize && can_serve(request)
+                })
+            })
+            .or_else(|| self.piece_manager.next_piece_request_where(torrent_id, can_serve));
+        if let Some(request) = streaming {
+            let length = torrent.piece_size(request.piece_index as i32);
+            // Otherwise a running download already covers it
+            if let Some(download) = self.piece_manager.begin_download(torrent_id, request.piece_index, length).await {
+                if let Some(deadline) = request.deadline {
+                    download.set_deadline(deadline);
+                }
+                return Ok(Some(PiecePick::new(request, download, false)));
+            }
+        }
+
+        self.piece_manager.restore_partial_pieces(torrent_id).await?;
+        let priorities = self.piece_priorities(torrent_id).await?;
+        let missing = self.missing_pieces(torrent_id, &priorities).await?;
+        let candidates = missing.iter()
+            .map(|piece| piece.piece_index as u32)
+            .filter(|piece_index| peer.has_piece(*piece_index));
+
+        let mut picked = None;
+        for piece_index in self.rank(torrent_id, candidates, &priorities) {
+            let length = torrent.piece_size(piece_index as i32);
+            picked = self.piece_manager.begin_download(torrent_id, piece_index as usize, length).await;
+            if picked.is_some() {
+                break;
+            }
+        }
+        if let Some(download) = picked {
+            let mut request = background_request(download.piece_index());
+            request.priority = priorities.get(request.piece_index).unwrap_or(PiecePriority::Normal);
+            return Ok(Some(PiecePick::new(request, download, false)));
+        }
+
+        // Endgame: all that is left is in flight. Help where the fewest peers
+        // are working and every missing block is already requested.
+        let endgame = self.piece_manager.active_downloads(torrent_id)
+            .into_iter()
+            .filter(|download| {
+                peer.has_piece(download.piece_index())
+                    && !download.is_complete()
+                    && !download.is_abandoned()
+                    && download.all_blocks_requested()
+            })
+            .min_by_key(|download| download.participants());
+        Ok(endgame.map(|download| PiecePick::new(background_request(download.piece_index()), download, true)))
+    }
+
+    /// The most pressing download at risk of missing its deadline that `peer`
+    /// could finish sooner than the peers working on it now
+    fn at_risk_download(
+        &self,
+        torrent_id: i32,
+        peer: &PeerHandle,
+        peer_stats: &PeerStats,
+        now: Instant,
+    ) -> Option<Arc<PieceDownload>> {
+        self.piece_manager.active_downloads(torrent_id)
+            .into_iter()
+            .filter(|download| peer.has_piece(download.piece_index()) && download.is_at_risk(now, STALL_TIMEOUT))
+            .filter(|download| {
+                match (download.estimated_time_left(now), expected_fetch_time(peer_stats, download.missing_bytes())) {
+                    (Some(current), Some(ours)) => ours < current,
+                    // Nothing arrived yet: anyone who might be quicker helps
+                    (None, _) => true,
+                    (Some(_), None) => false,
+                }
+            })
+            .min_by_key(|download| download.deadline())
+    }
+
+    /// Whether `peer` should take a request. Pieces with a deadline go to
+    /// another peer instead when this one would be late and the other in time.
+    fn meets_deadline(
+        &self,
+        torrent: &Torrent,
+        request: &PieceRequest,
+        peer_stats: &PeerStats,
+        others: &[(PeerHandle, PeerStats)],
+        now: Instant,
+    ) -> bool {
+        let Some(deadline) = request.deadline else {
+            return true;
+        };
+        let slack = deadline.saturating_duration_since(now);
+        let length = torrent.piece_size(request.piece_index as i32) as u64;
+        if expected_fetch_time(peer_stats, length).is_some_and(|ours| ours <= slack) {
+            return true;
+        }
+
+        let ours = expected_fetch_time(peer_stats, length);
+        !others.iter().any(|(other, stats)| {
+            other.has_piece(request.piece_index as u32)
+                && other.can_request(request.piece_index as u32)
+                && expected_fetch_time(stats, length).is_some_and(|theirs| {
+                    theirs <= slack && ours.is_none_or(|ours| theirs < ours)
+                })
+        })
+    }
+
+    /// Whether anything is left to download, including pieces in flight
+    pub async fn has_missing_pieces(&self, torrent_id: i32) -> Result<bool, DomainError> {
+        if self.piece_manager.has_pending_requests(torrent_id) {
+            return Ok(true);
+        }
+        let priorities = self.piece_priorities(torrent_id).await?;
+        let pieces = self.piece_repository.find_by_torrent_id(torrent_id).await?;
+        Ok(pieces.iter().any(|piece| !piece.is_complete() && priorities.is_wanted(piece.piece_index as usize)))
+    }
+
+    /// Priorities of the torrent's pieces, following its file priorities
+    async fn piece_priorities(&self, torrent_id: i32) -> Result<PiecePriorities, DomainError> {
+        let torrent = self.torrent_repository.find_by_id(torrent_id).await?
+            .ok_or(DomainError::TorrentNotFound(torrent_id))?;
+        let files = self.torrent_file_repository.find_by_torrent_id(torrent_id).await?;
+        Ok(PiecePriorities::from_files(&torrent, &files))
+    }
+
+    /// Pieces of wanted files neither verified nor being downloaded right now
+    async fn missing_pieces(&self, torrent_id: i32, priorities: &PiecePriorities) -> Result<Vec<Piece>, DomainError> {
+        let in_progress = self.piece_manager.in_progress_pieces(torrent_id);
+        let mut pieces = self.piece_repository.find_by_torrent_id(torrent_id).await?;
+        pieces.retain(|piece| {
+            let piece_index = piece.piece_index as usize;
+            !piece.is_complete() && priorities.is_wanted(piece_index) && !in_progress.contains(&piece_index)
+        });
+        Ok(pieces)
+    }
+
+    fn rank(&self, torrent_id: i32, candidates: impl IntoIterator<Item = u32>, priorities: &PiecePriorities) -> Vec<u32> {
+        let partial = self.piece_manager.partial_pieces(torrent_id)
+            .into_iter()
+            .map(|piece_index| piece_index as u32)
+            .collect();
+        let mut order = rarest_first(
+            candidates,
+            &self.connection_manager.availability(torrent_id),
+            &partial,
+            &mut rand::thread_rng(),
+        );
+        // Stable, so rarest first still holds among pieces of equal priority
+        order.sort_by_key(|piece_index| Reverse(priorities.get(*piece_index as usize)));
+        order
+    }
+}
+
+/// Order `candidates` for download: partial pieces first, then by how many
+/// peers have them, fewest first, with pieces no peer has at the very end.
+/// Equally good pieces come out in random order.
+pub fn rarest_first<R: Rng>(
+    candidates: impl IntoIterator<Item = u32>,
+    availability: &PieceAvailability,
+    partial: &HashSet<u32>,
+    rng: &mut R,
+) -> Vec<u32> {
+    let mut pieces: Vec<(u32, bool, u32)> = candidates
+        .into_iter()
+        .map(|piece_index| (piece_index, partial.contains(&piece_index), availability.count(piece_index)))
+        .collect();
+
+    // Shuffle first; the stable sort keeps the random order among equals
+    pieces.shuffle(rng);
+    pieces.sort_by_key(|(_, partial, peers)| (!partial, *peers == 0, *peers));
+    pieces.into_iter().map(|(piece_index, _, _)| piece_index).collect()
+}
@@ -0,0 +1,189 @@
+use crate::services::piece_manager::{PieceRequest, PiecePriority};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
+use std::time::Instant;
+
+/// Bandwidth share of a requester nobody gave a weight
+pub const DEFAULT_WEIGHT: u32 = 1;
+
+/// What one requester wants from a piece
+#[derive(Debug, Clone, Copy)]
+struct Claim {
+    priority: PiecePriority,
+    deadline: Option<Instant>,
+}
+
+/// How much a requester has been served, relative to its weight
+#[derive(Debug, Clone, Copy)]
+struct Share {
+    weight: u32,
+    /// Bytes started on the requester's behalf divided by its weight
+    virtual_bytes: f64,
+}
+
+/// Wanted pieces of one torrent, combined across every requester, e.g. two
+/// clients streaming different episodes of one pack.
+///
+/// Each piece appears once, with a claim per requester; the highest priority
+/// and earliest deadline across claims win. When several requesters want
+/// pieces of the same priority, the one served least relative to its weight
+/// goes next, so concurrent streams split the bandwidth instead of the one
+/// with the earliest deadlines starving the others.
+#[derive(Debug, Default)]
+pub struct PriorityAggregator {
+    pieces: BTreeMap<usize, HashMap<String, Claim>>,
+    shares: HashMap<String, Share>,
+}
+
+impl PriorityAggregator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add or replace `requester`'s claim on a piece
+    pub fn claim(&mut self, piece_index: usize, requester: String, priority: PiecePriority, deadline: Option<Instant>) {
+        self.share_mut(&requester);
+        self.pieces.entry(piece_index).or_default().insert(requester, Claim { priority, deadline });
+    }
+
+    /// Give `requester` `weight` times the bandwidth of a default requester
+    pub fn set_weight(&mut self, requester: &str, weight: u32) {
+        self.share_mut(requester).weight = weight.max(1);
+    }
+
+    pub fn weight(&self, requester: &str) -> u32 {
+        self.shares.get(requester).map_or(DEFAULT_WEIGHT, |share| share.weight)
+    }
+
+    /// A requester joining late starts level with the others instead of
+    /// catching up on everything they were served before it arrived
+    fn share_mut(&mut self, requester: &str) -> &mut Share {
+        if !self.shares.contains_key(requester) {
+            let virtual_bytes = self.shares.values().map(|share| share.virtual_bytes).fold(None, |least: Option<f64>, bytes| {
+                Some(least.map_or(bytes, |least| least.min(bytes)))
+            });
+            let share = Share { weight: DEFAULT_WEIGHT, virtual_bytes: virtual_bytes.unwrap_or(0.0) };
+            self.shares.insert(requester.to_string(), share);
+        }
+        self.shares.get_mut(requester).unwrap()
+    }
+
+    /// A download of `bytes` started for a piece; its claimants split the cost
+    pub fn charge(&mut self, piece_index: usize, bytes: u64) {
+        let Some(claims) = self.pieces.get(&piece_index) else {
+            return;
+        };
+        let cost = bytes as f64 / claims.len() as f64;
+        for requester in claims.keys() {
+            if let Some(share) = self.shares.get_mut(requester) {
+                share.virtual_bytes += cost / share.weight as f64;
+            }
+        }
+    }
+
+    pub fn remove_piece(&mut self, piece_index: usize) {
+        self.pieces.remove(&piece_index);
+    }
+
+    /// Forget a requester: its claims and its share
+    pub fn remove_requester(&mut self, requester: &str) {
+        self.pieces.retain(|_, claims| {
+            claims.remove(requester);
+            !claims.is_empty()
+        });
+        self.shares.remove(requester);
+    }
+
+    /// Drop `requester`'s claims on pieces failing `keep`. Returns how many
+    /// claims were dropped and the pieces nobody claims any more.
+    pub fn remove_requester_where(&mut self, requester: &str, keep: impl Fn(usize) -> bool) -> (usize, Vec<usize>) {
+        let mut released = 0;
+        let mut orphaned = Vec::new();
+        self.pieces.retain(|piece_index, claims| {
+            if keep(*piece_index) || claims.remove(requester).is_none() {
+                return true;
+            }
+            released += 1;
+            if claims.is_empty() {
+                orphaned.push(*piece_index);
+            }
+            !claims.is_empty()
+        });
+        (released, orphaned)
+    }
+
+    /// Whether any piece is claimed
+    pub fn has_claims(&self) -> bool {
+        !self.pieces.is_empty()
+    }
+
+    /// Whether nothing is claimed and no requester is known
+    pub fn is_empty(&self) -> bool {
+        self.pieces.is_empty() && self.shares.is_empty()
+    }
+
+    /// The combined request for a piece across requesters
+    pub fn request(&self, piece_index: usize) -> Option<PieceRequest> {
+        self.pieces.get(&piece_index).and_then(|claims| Self::merge(piece_index, claims))
+    }
+
+    /// The next request passing `filter`. Each requester's most pressing
+    /// piece competes: highest priority first, then the requester served
+    /// least for its weight, then the earliest deadline. The request is
+    /// attributed to the requester that won.
+    pub fn best_where(&self, filter: impl Fn(&PieceRequest) -> bool) -> Option<PieceRequest> {
+        let mut best: HashMap<&str, (usize, Claim)> = HashMap::new();
+        for (piece_index, claims) in &self.pieces {
+            let Some(request) = Self::merge(*piece_index, claims) else {
+                continue;
+            };
+            if !filter(&request) {
+                continue;
+            }
+            for (requester, claim) in claims {
+                let better = best.get(requester.as_str()).is_none_or(|(_, current)| {
+                    Self::urgency(claim) < Self::urgency(current)
+                });
+                if better {
+                    best.insert(requester, (*piece_index, *claim));
+                }
+            }
+        }
+
+        let (requester, (piece_index, _)) = best.into_iter().min_by(|(a, (a_index, a_claim)), (b, (b_index, b_claim))| {
+            let a_served = self.virtual_bytes(a);
+            let b_served = self.virtual_bytes(b);
+            Reverse(a_claim.priority)
+                .cmp(&Reverse(b_claim.priority))
+                .then(a_served.total_cmp(&b_served))
+                .then(Self::urgency(a_claim).cmp(&Self::urgency(b_claim)))
+                .then(a_index.cmp(b_index))
+                .then(a.cmp(b))
+        })?;
+
+        let mut request = self.request(piece_index)?;
+        request.requester = requester.to_string();
+        Some(request)
+    }
+
+    fn virtual_bytes(&self, requester: &str) -> f64 {
+        self.shares.get(requester).map_or(0.0, |share| share.virtual_bytes)
+    }
+
+    /// Orders one requester's claims, most pressing first
+    fn urgency(claim: &Claim) -> (Reverse<PiecePriority>, bool, Option<Instant>) {
+        (Reverse(claim.priority), claim.deadline.is_none(), claim.deadline)
+    }
+
+    fn merge(piece_index: usize, claims: &HashMap<String, Claim>) -> Option<PieceRequest> {
+        let (requester, strongest) = claims
+            .iter()
+            .min_by_key(|(requester, claim)| (Self::urgency(claim), *requester))?;
+        Some(PieceRequest {
+            piece_index,
+            priority: strongest.priority,
+            requester: requester.clone(),
+            deadline: claims.values().filter_map(|claim| claim.deadline).min(),
+        })
+    }
+}
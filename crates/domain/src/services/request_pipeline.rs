@@ -0,0 +1,310 @@
+use crate::errors::DomainError;
+use crate::protocol::BlockRequest;
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+// Tokio's clock so tests can pause and advance time
+use tokio::time::Instant;
+
+/// Standard block size used for `request` messages (16 KiB)
+pub const BLOCK_SIZE: u32 = 16 * 1024;
+
+/// Requests kept in flight before we have any rate measurement
+pub const INITIAL_QUEUE_DEPTH: usize = 4;
+pub const MIN_QUEUE_DEPTH: usize = 2;
+pub const MAX_QUEUE_DEPTH: usize = 250;
+
+/// How many seconds worth of data we want queued at the peer (libtorrent's `request_queue_time`)
+const REQUEST_QUEUE_TIME: Duration = Duration::from_secs(3);
+
+/// Window over which transfer rates are averaged
+const RATE_WINDOW: Duration = Duration::from_secs(5);
+
+/// Sliding-window transfer rate estimator
+#[derive(Debug, Clone)]
+pub struct TransferRate {
+    samples: VecDeque<(Instant, u64)>,
+    total_bytes: u64,
+    started_at: Instant,
+}
+
+impl Default for TransferRate {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TransferRate {
+    pub fn new() -> Self {
+        Self {
+            samples: VecDeque::new(),
+            total_bytes: 0,
+            started_at: Instant::now(),
+        }
+    }
+
+    pub fn record(&mut self, bytes: u64) {
+        let now = Instant::now();
+        self.samples.push_back((now, bytes));
+        self.total_bytes += bytes;
+        self.expire(now);
+    }
+
+    /// Average bytes per second over the last few seconds
+    pub fn bytes_per_second(&mut self) -> f64 {
+        let now = Instant::now();
+        self.expire(now);
+
+        let window_bytes: u64 = self.samples.iter().map(|(_, bytes)| bytes).sum();
+        // Young connections have not filled the window yet, so don't dilute their rate
+        let elapsed = now.duration_since(self.started_at).min(RATE_WINDOW).as_secs_f64().max(0.5);
+        window_bytes as f64 / elapsed
+    }
+
+    /// Total bytes recorded over the lifetime of the estimator
+    pub fn total_bytes(&self) -> u64 {
+        self.total_bytes
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while let Some((at, _)) = self.samples.front() {
+            if now.duration_since(*at) > RATE_WINDOW {
+                self.samples.pop_front();
+            } else {
+                break;
+            }
+        }
+    }
+}
+
+/// Collects the blocks of one piece in whatever order they arrive
+#[derive(Debug, Clone)]
+pub struct PieceAssembler {
+    piece_index: u32,
+    piece_length: u32,
+    data: Vec<u8>,
+    received: Vec<bool>,
+    received_count: usize,
+}
+
+impl PieceAssembler {
+    pub fn new(piece_index: u32, piece_length: u32) -> Self {
+        let block_count = piece_length.div_ceil(BLOCK_SIZE) as usize;
+        Self {
+            piece_index,
+            piece_length,
+            data: vec![0u8; piece_length as usize],
+            received: vec![false; block_count],
+            received_count: 0,
+        }
+    }
+
+    pub fn piece_index(&self) -> u32 {
+        self.piece_index
+    }
+
+    pub fn block_count(&self) -> usize {
+        self.received.len()
+    }
+
+    /// The request covering block `block_index` (the last block may be short)
+    pub fn block_request(&self, block_index: usize) -> BlockRequest {
+        let begin = block_index as u32 * BLOCK_SIZE;
+        let length = BLOCK_SIZE.min(self.piece_length - begin);
+        BlockRequest::new(self.piece_index, begin, length)
+    }
+
+    /// Requests for every block not received yet, in offset order
+    pub fn missing_blocks(&self) -> Vec<BlockRequest> {
+        (0..self.block_count())
+            .filter(|&block_index| !self.received[block_index])
+            .map(|block_index| self.block_request(block_index))
+            .collect()
+    }
+
+    /// Whether the block starting at `begin` has been stored
+    pub fn has_block(&self, begin: u32) -> bool {
+        self.received.get((begin / BLOCK_SIZE) as usize).copied().unwrap_or(false)
+    }
+
+    /// Store a block. Returns `false` when the block was a duplicate.
+    pub fn add_block(&mut self, begin: u32, block: &[u8]) -> Result<bool, DomainError> {
+        if !begin.is_multiple_of(BLOCK_SIZE) || begin >= self.piece_length {
+            return Err(DomainError::ValidationError(format!(
+                "Invalid block offset {} for piece {}",
+                begin, self.piece_index
+            )));
+        }
+
+        let block_index = (begin / BLOCK_SIZE) as usize;
+        let expected = self.block_request(block_index).length as usize;
+        if block.len() != expected {
+            return Err(DomainError::ValidationError(format!(
+                "Block data length mismatch: expected {}, got {}",
+                expected, block.len()
+            )));
+        }
+
+        if self.received[block_index] {
+            return Ok(false);
+        }
+
+        let start = begin as usize;
+        self.data[start..start + block.len()].copy_from_slice(block);
+        self.received[block_index] = true;
+        self.received_count += 1;
+        Ok(true)
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.received_count == self.received.len()
+    }
+
+    /// The stored block starting at `begin`
+    pub fn block(&self, begin: u32) -> Option<&[u8]> {
+        if !self.has_block(begin) {
+            return None;
+        }
+        let request = self.block_request((begin / BLOCK_SIZE) as usize);
+        Some(&self.data[begin as usize..(begin + request.length) as usize])
+    }
+
+    /// One bit per block, set for blocks received, most significant bit first
+    pub fn received_bitmap(&self) -> Vec<u8> {
+        let mut bitmap = vec![0u8; self.received.len().div_ceil(8)];
+        for (block_index, _) in self.received.iter().enumerate().filter(|(_, received)| **received) {
+            bitmap[block_index / 8] |= 0x80 >> (block_index % 8);
+        }
+        bitmap
+    }
+
+    pub fn into_data(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+/// Outstanding block requests to one peer, with a queue depth that adapts to
+/// the measured download rate and round-trip time.
+///
+/// The target depth is the number of blocks needed to cover
+/// `max(REQUEST_QUEUE_TIME, 2 * RTT)` at the current rate, the same bandwidth-delay
+/// approach libtorrent and Transmission use. Until a rate is known the queue grows
+/// by one request per received block (slow start).
+#[derive(Debug)]
+pub struct RequestQueue {
+    outstanding: HashMap<BlockRequest, Instant>,
+    target_depth: usize,
+    slow_start: bool,
+    smoothed_rtt: Option<Duration>,
+    download_rate: TransferRate,
+}
+
+impl Default for RequestQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RequestQueue {
+    pub fn new() -> Self {
+        Self {
+            outstanding: HashMap::new(),
+            target_depth: INITIAL_QUEUE_DEPTH,
+            slow_start: true,
+            smoothed_rtt: None,
+            download_rate: TransferRate::new(),
+        }
+    }
+
+    pub fn target_depth(&self) -> usize {
+        self.target_depth
+    }
+
+    pub fn outstanding_count(&self) -> usize {
+        self.outstanding.len()
+    }
+
+    pub fn has_capacity(&self) -> bool {
+        self.outstanding.len() < self.target_depth
+    }
+
+    pub fn is_outstanding(&self, request: &BlockRequest) -> bool {
+        self.outstanding.contains_key(request)
+    }
+
+    /// Every request still waiting for a block
+    pub fn outstanding_requests(&self) -> Vec<BlockRequest> {
+        self.outstanding.keys().copied().collect()
+    }
+
+    pub fn smoothed_rtt(&self) -> Option<Duration> {
+        self.smoothed_rtt
+    }
+
+    pub fn download_rate(&mut self) -> f64 {
+        self.download_rate.bytes_per_second()
+    }
+
+    pub fn on_request_sent(&mut self, request: BlockRequest) {
+        self.outstanding.insert(request, Instant::now());
+    }
+
+    /// Record an arrived block. Returns `false` if we never asked for it.
+    pub fn on_block_received(&mut self, request: &BlockRequest) -> bool {
+        let Some(sent_at) = self.outstanding.remove(request) else {
+            return false;
+        };
+
+        let sample = sent_at.elapsed();
+        self.smoothed_rtt = Some(match self.smoothed_rtt {
+            // Same 7/8 smoothing TCP uses for SRTT
+            Some(rtt) => (rtt * 7 + sample) / 8,
+            None => sample,
+        });
+        self.download_rate.record(request.length as u64);
+        self.update_target_depth();
+        true
+    }
+
+    /// Forget a request without a block arriving (cancelled, rejected or choked)
+    pub fn remove(&mut self, request: &BlockRequest) {
+        self.outstanding.remove(request);
+    }
+
+    /// Drop every outstanding request, e.g. after the peer choked us
+    pub fn clear(&mut self) -> Vec<BlockRequest> {
+        self.outstanding.drain().map(|(request, _)| request).collect()
+    }
+
+    /// Requests that have been outstanding for longer than `timeout`
+    pub fn timed_out(&self, timeout: Duration) -> Vec<BlockRequest> {
+        self.outstanding
+            .iter()
+            .filter(|(_, sent_at)| sent_at.elapsed() > timeout)
+            .map(|(request, _)| *request)
+            .collect()
+    }
+
+    fn update_target_depth(&mut self) {
+        let rate = self.download_rate.bytes_per_second();
+        let queue_time = self
+            .smoothed_rtt
+            .map(|rtt| (rtt * 2).max(REQUEST_QUEUE_TIME))
+            .unwrap_or(REQUEST_QUEUE_TIME);
+        let rate_depth = (rate * queue_time.as_secs_f64() / BLOCK_SIZE as f64).ceil() as usize;
+
+        if self.slow_start {
+            // Leave slow start once the rate-based depth stops keeping up with the ramp
+            if rate_depth > 0 && rate_depth < self.target_depth {
+                self.slow_start = false;
+            } else {
+                self.target_depth += 1;
+            }
+        }
+
+        if !self.slow_start {
+            self.target_depth = rate_depth;
+        }
+
+        self.target_depth = self.target_depth.clamp(MIN_QUEUE_DEPTH, MAX_QUEUE_DEPTH);
+    }
+}
@@ -0,0 +1,63 @@
+use crate::entities::{StorageKind, Torrent};
+use crate::errors::DomainError;
+use crate::repositories::PieceRepository;
+use crate::services::storage::TorrentStorage;
+use std::sync::Arc;
+
+/// How the saved piece state of a torrent was checked before resuming it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResumeCheck {
+    /// Every file was as last snapshotted, so the piece state was trusted
+    Quick,
+    /// Files changed or were never snapshotted; every stored piece was
+    /// hashed again and `invalid` of them did not match
+    Full { invalid: usize },
+    /// The data lived in memory and is gone; `invalid` pieces were dropped
+    Cleared { invalid: usize },
+}
+
+/// Checks the piece state saved by an earlier run against the stored data,
+/// so a restarted torrent neither serves pieces it lost nor downloads pieces
+/// it still has
+pub struct ResumeValidator {
+    piece_repository: Arc<dyn PieceRepository>,
+    storage: Arc<TorrentStorage>,
+}
+
+impl ResumeValidator {
+    pub fn new(piece_repository: Arc<dyn PieceRepository>, storage: Arc<TorrentStorage>) -> Self {
+        Self { piece_repository, storage }
+    }
+
+    pub async fn validate(&self, torrent: &Torrent) -> Result<ResumeCheck, DomainError> {
+        let torrent_id = torrent.id.ok_or_else(|| DomainError::ValidationError("Torrent has no id".to_string()))?;
+
+        if torrent.storage == StorageKind::Memory {
+            let mut invalid = Vec::new();
+            for mut piece in self.piece_repository.find_by_torrent_id(torrent_id).await? {
+                if piece.is_complete() || piece.received_blocks.is_some() {
+                    piece.mark_verified(false);
+                    invalid.push(piece);
+                }
+            }
+            self.piece_repository.update_batch(&invalid).await?;
+            return Ok(ResumeCheck::Cleared { invalid: invalid.len() });
+        }
+
+        if self.storage.is_unchanged(torrent_id).await? {
+            return Ok(ResumeCheck::Quick);
+        }
+
+        let mut invalid = Vec::new();
+        for mut piece in self.piece_repository.find_by_torrent_id(torrent_id).await? {
+            if piece.is_complete() && !self.storage.holds_piece(torrent, &piece).await {
+                piece.mark_verified(false);
+                invalid.push(piece);
+            }
+        }
+        self.piece_repository.update_batch(&invalid).await?;
+
+        self.storage.save_snapshot(torrent_id).await?;
+        Ok(ResumeCheck::Full { invalid: invalid.len() })
+    }
+}
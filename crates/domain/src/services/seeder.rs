@@ -0,0 +1,243 @@
+use crate::entities::Torrent;
+use crate::errors::DomainError;
+use crate::protocol::{allowed_fast_set, info_hash_bytes, BlockRequest, PeerMessage, ALLOWED_FAST_COUNT};
+use crate::repositories::TorrentRepository;
+use crate::services::peer_connection::PeerHandle;
+use crate::services::piece_manager::PieceManager;
+use std::collections::{HashSet, VecDeque};
+use std::net::IpAddr;
+use std::sync::Arc;
+use tokio::sync::mpsc::error::TryRecvError;
+
+/// Largest block we serve; mainline clients drop peers asking for more than 128 KiB
+pub const MAX_REQUEST_LENGTH: u32 = 128 * 1024;
+
+/// Requests queued per peer beyond which new ones are ignored
+const MAX_QUEUED_REQUESTS: usize = 500;
+
+/// Blocks handed to the connection but not yet written. A peer that reads
+/// slowly holds up its own requests here instead of in memory, where a
+/// cancel can still reach them.
+pub const MAX_UNSENT_BLOCKS: usize = 2;
+
+/// Serves block requests from verified pieces.
+///
+/// Each connection gets a task that reads its upload messages, queues requests
+/// while the peer is unchoked and answers them with `piece` messages. Who gets
+/// unchoked is decided by the `Choker`. With the fast extension, requests we
+/// will not serve are rejected explicitly and allowed-fast pieces are served
+/// even while the peer is choked.
+pub struct Seeder {
+    piece_manager: Arc<PieceManager>,
+    torrent_repository: Arc<dyn TorrentRepository>,
+}
+
+impl Seeder {
+    pub fn new(piece_manager: Arc<PieceManager>, torrent_repository: Arc<dyn TorrentRepository>) -> Self {
+        Self { piece_manager, torrent_repository }
+    }
+
+    /// Tell the peer which pieces we have, grant allowed-fast pieces and start
+    /// answering its requests until the connection closes
+    pub async fn serve(self: &Arc<Self>, handle: PeerHandle) {
+        let uploads = handle.subscribe_uploads();
+
+        let granted = match self.introduce(&handle).await {
+            Ok(granted) => granted,
+            Err(e) => {
+                eprintln!("Failed to announce pieces to {}: {}", handle.address(), e);
+                HashSet::new()
+            }
+        };
+
+        let seeder = Arc::clone(self);
+        tokio::spawn(async move { seeder.run(handle, uploads, granted).await });
+    }
+
+    /// Send the availability message that must follow the handshake, then our
+    /// allowed-fast grants. Returns the pieces the peer may request while choked.
+    async fn introduce(&self, handle: &PeerHandle) -> Result<HashSet<u32>, DomainError> {
+        let torrent_id = handle.torrent_id();
+        let torrent = self.torrent_repository.find_by_id(torrent_id).await?
+            .ok_or(DomainError::TorrentNotFound(torrent_id))?;
+
+        let bitfield = self.piece_manager.verified_bitfield(torrent_id).await?;
+        let have = |index: u32| bitfield[index as usize / 8] & (0x80 >> (index % 8)) != 0;
+        let have_count = (0..torrent.piece_count as u32).filter(|index| have(*index)).count();
+        let fast = handle.supports_fast_extension();
+
+        if fast && have_count == torrent.piece_count as usize {
+            handle.send(PeerMessage::HaveAll)?;
+        } else if fast && have_count == 0 {
+            handle.send(PeerMessage::HaveNone)?;
+        } else if have_count > 0 {
+            handle.send(PeerMessage::Bitfield(bitfield.clone()))?;
+        }
+
+        let mut granted = HashSet::new();
+        if fast {
+            for piece_index in Self::allowed_fast_pieces(handle, &torrent)? {
+                if have(piece_index) {
+                    handle.send(PeerMessage::AllowedFast { piece_index })?;
+                    granted.insert(piece_index);
+                }
+            }
+        }
+
+        Ok(granted)
+    }
+
+    fn allowed_fast_pieces(handle: &PeerHandle, torrent: &Torrent) -> Result<Vec<u32>, DomainError> {
+        // The canonical set is only defined for IPv4 peers
+        let Ok(IpAddr::V4(ip)) = handle.peer().ip.parse::<IpAddr>() else {
+            return Ok(Vec::new());
+        };
+        let info_hash = info_hash_bytes(&torrent.info_hash)?;
+        Ok(allowed_fast_set(ip, &info_hash, torrent.piece_count as u32, ALLOWED_FAST_COUNT))
+    }
+
+    async fn run(
+        &self,
+        handle: PeerHandle,
+        mut uploads: tokio::sync::mpsc::UnboundedReceiver<PeerMessage>,
+        granted: HashSet<u32>,
+    ) {
+        let mut queue: VecDeque<BlockRequest> = VecDeque::new();
+        // Consecutive requests usually hit the same piece, so keep the last one read
+        let mut cached_piece: Option<(u32, Vec<u8>)> = None;
+
+        loop {
+            // Drain everything the peer said before serving the next block, so cancels win
+            let message = if queue.is_empty() {
+                match uploads.recv().await {
+                    Some(message) => Some(message),
+                    None => break,
+                }
+            } else {
+                match uploads.try_recv() {
+                    Ok(message) => Some(message),
+                    Err(TryRecvError::Empty) => None,
+                    Err(TryRecvError::Disconnected) => break,
+                }
+            };
+
+            if let Some(message) = message {
+                self.handle_message(&handle, &granted, &mut queue, message);
+                continue;
+            }
+
+            // Requests pending when we choke are discarded (BEP 3), or rejected one
+            // by one with the fast extension. Allowed-fast requests survive the choke.
+            if handle.stats().am_choking {
+                let (kept, dropped): (VecDeque<_>, VecDeque<_>) = queue
+                    .drain(..)
+                    .partition(|request| granted.contains(&request.piece_index));
+                for request in dropped {
+                    Self::reject(&handle, request);
+                }
+                queue = kept;
+            }
+
+            if queue.is_empty() {
+                continue;
+            }
+            // Let the writer catch up, then look at what the peer said meanwhile
+            if handle.queued_uploads() >= MAX_UNSENT_BLOCKS {
+                handle.uploads_below(MAX_UNSENT_BLOCKS).await;
+                if handle.is_closed() {
+                    break;
+                }
+                continue;
+            }
+
+            let Some(request) = queue.pop_front() else {
+                continue;
+            };
+
+            match self.read_block(handle.torrent_id(), &request, &mut cached_piece).await {
+                Ok(data) => {
+                    let piece = PeerMessage::Piece { piece_index: request.piece_index, begin: request.begin, data };
+                    if handle.send(piece).is_err() {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    eprintln!(
+                        "Cannot serve piece {} to {}: {}",
+                        request.piece_index,
+                        handle.address(),
+                        e
+                    );
+                    Self::reject(&handle, request);
+                }
+            }
+        }
+    }
+
+    fn handle_message(
+        &self,
+        handle: &PeerHandle,
+        granted: &HashSet<u32>,
+        queue: &mut VecDeque<BlockRequest>,
+        message: PeerMessage,
+    ) {
+        match message {
+            // A peer that lost interest has no use for what is still queued
+            PeerMessage::NotInterested => {
+                for request in queue.drain(..) {
+                    Self::reject(handle, request);
+                }
+            }
+            PeerMessage::Request(request) => {
+                if queue.contains(&request) {
+                    return;
+                }
+                let valid = request.length > 0 && request.length <= MAX_REQUEST_LENGTH;
+                let allowed = !handle.stats().am_choking || granted.contains(&request.piece_index);
+                if valid && allowed && queue.len() < MAX_QUEUED_REQUESTS {
+                    queue.push_back(request);
+                } else {
+                    Self::reject(handle, request);
+                }
+            }
+            PeerMessage::Cancel(request) => queue.retain(|queued| *queued != request),
+            _ => {}
+        }
+    }
+
+    /// Tell a fast-extension peer we will not serve a request; others just never get an answer
+    fn reject(handle: &PeerHandle, request: BlockRequest) {
+        if handle.supports_fast_extension() {
+            let _ = handle.send(PeerMessage::RejectRequest(request));
+        }
+    }
+
+    async fn read_block(
+        &self,
+        torrent_id: i32,
+        request: &BlockRequest,
+        cached_piece: &mut Option<(u32, Vec<u8>)>,
+    ) -> Result<Vec<u8>, DomainError> {
+        let cached = matches!(cached_piece, Some((index, _)) if *index == request.piece_index);
+        if !cached {
+            // read_piece_data only returns pieces that passed hash verification
+            let data = self.piece_manager.read_piece_data(torrent_id, request.piece_index as usize).await?;
+            *cached_piece = Some((request.piece_index, data));
+        }
+
+        let (_, data) = cached_piece.as_ref().unwrap();
+        let start = request.begin as usize;
+        let end = start + request.length as usize;
+        if end > data.len() {
+            return Err(DomainError::ValidationError(format!(
+                "Request {}+{} is outside piece {} ({} bytes)",
+                request.begin,
+                request.length,
+                request.piece_index,
+                data.len()
+            )));
+        }
+
+        Ok(data[start..end].to_vec())
+    }
+}
@@ -0,0 +1,393 @@
+use crate::entities::{FileSnapshot, Piece, StorageKind, Torrent, TorrentFile};
+use crate::errors::DomainError;
+use crate::repositories::{FileSnapshotRepository, TorrentFileRepository, TorrentRepository};
+use crate::services::disk_space::Preallocation;
+use crate::services::storage_backend::{FilesystemBackend, MemoryBackend, StorageBackend};
+use std::collections::{HashMap, HashSet};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use sha1::Digest;
+
+/// Bytes of zeros written at a time by full preallocation
+const PREALLOCATION_CHUNK: usize = 4 * 1024 * 1024;
+
+/// A file of a torrent on disk and the bytes of the torrent it holds
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredFile {
+    pub path: PathBuf,
+    /// Byte offset of the file within the torrent
+    pub offset: u64,
+    pub length: u64,
+}
+
+/// The part of a torrent byte range that lives in one file
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileSpan {
+    pub path: PathBuf,
+    /// Where the span starts within the file
+    pub file_offset: u64,
+    /// Where the span starts within the range
+    pub range_offset: usize,
+    pub length: usize,
+}
+
+/// How a torrent's bytes are laid out over files.
+///
+/// A single-file torrent is stored as `download_dir/<file>`; a multi-file
+/// torrent as the directory tree `download_dir/<torrent name>/<file path>`.
+/// A torrent without a file list is treated as one file named after it.
+/// Memory-backed torrents use the same paths as keys.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StorageLayout {
+    /// The single file, or the directory holding every file
+    pub root: PathBuf,
+    /// Ordered by offset
+    pub files: Vec<StoredFile>,
+    pub storage: StorageKind,
+}
+
+impl StorageLayout {
+    pub fn new(download_dir: &Path, torrent: &Torrent, files: &[TorrentFile]) -> Self {
+        match files {
+            [] => {
+                let name = torrent.file_path.as_deref()
+                    .and_then(|path| Path::new(path).file_name())
+                    .map(|name| name.to_string_lossy().to_string())
+                    .unwrap_or_else(|| torrent.name.clone());
+                let root = download_dir.join(relative_path(&name));
+                let file = StoredFile { path: root.clone(), offset: 0, length: torrent.total_size as u64 };
+                Self { root, files: vec![file], storage: torrent.storage }
+            }
+            [file] => {
+                let root = download_dir.join(relative_path(&file.path));
+                let file = StoredFile { path: root.clone(), offset: file.offset as u64, length: file.length as u64 };
+                Self { root, files: vec![file], storage: torrent.storage }
+            }
+            _ => {
+                let root = download_dir.join(relative_path(&torrent.name));
+                let mut files: Vec<StoredFile> = files
+                    .iter()
+                    .map(|file| StoredFile {
+                        path: root.join(relative_path(&file.path)),
+                        offset: file.offset as u64,
+                        length: file.length as u64,
+                    })
+                    .collect();
+                files.sort_by_key(|file| file.offset);
+                Self { root, files, storage: torrent.storage }
+            }
+        }
+    }
+
+    pub fn total_size(&self) -> u64 {
+        self.files.last().map_or(0, |file| file.offset + file.length)
+    }
+
+    /// The file spans holding `offset..offset + length` of the torrent, in order
+    pub fn spans(&self, offset: u64, length: usize) -> Result<Vec<FileSpan>, DomainError> {
+        let end = offset + length as u64;
+        if end > self.total_size() {
+            return Err(DomainError::ValidationError(format!(
+                "Range {}..{} is past the end of the torrent ({} bytes)", offset, end, self.total_size()
+            )));
+        }
+
+        let first = self.files.partition_point(|file| file.offset + file.length <= offset);
+        let spans = self.files[first..]
+            .iter()
+            .take_while(|file| file.offset < end)
+            .filter(|file| file.length > 0)
+            .map(|file| {
+                let start = offset.max(file.offset);
+                let stop = end.min(file.offset + file.length);
+                FileSpan {
+                    path: file.path.clone(),
+                    file_offset: start - file.offset,
+                    range_offset: (start - offset) as usize,
+                    length: (stop - start) as usize,
+                }
+            })
+            .collect();
+        Ok(spans)
+    }
+}
+
+/// `path` with everything that could leave the download directory removed,
+/// e.g. `..` or a leading `/` from a malicious .torrent
+fn relative_path(path: &str) -> PathBuf {
+    let relative: PathBuf = Path::new(path)
+        .components()
+        .filter_map(|component| match component {
+            Component::Normal(part) => Some(part),
+            _ => None,
+        })
+        .collect();
+    if relative.as_os_str().is_empty() {
+        PathBuf::from("unnamed")
+    } else {
+        relative
+    }
+}
+
+/// Reads and writes torrent data by byte offset, mapping pieces onto the
+/// files of the torrent and the files onto the backend the torrent is kept
+/// in. Every component touching downloaded data goes through it, so all of
+/// them agree on where the bytes live.
+pub struct TorrentStorage {
+    torrent_repository: Arc<dyn TorrentRepository>,
+    torrent_file_repository: Option<Arc<dyn TorrentFileRepository>>,
+    snapshot_repository: Option<Arc<dyn FileSnapshotRepository>>,
+    download_dir: PathBuf,
+    backends: HashMap<StorageKind, Arc<dyn StorageBackend>>,
+    layouts: Mutex<HashMap<i32, Arc<StorageLayout>>>,
+    /// Torrents written to since their files were last snapshotted
+    dirty: Mutex<HashSet<i32>>,
+}
+
+impl TorrentStorage {
+    pub fn new(torrent_repository: Arc<dyn TorrentRepository>, download_dir: impl Into<PathBuf>) -> Self {
+        let download_dir = download_dir.into();
+        let mut backends: HashMap<StorageKind, Arc<dyn StorageBackend>> = HashMap::new();
+        backends.insert(StorageKind::Disk, Arc::new(FilesystemBackend::new(download_dir.clone())));
+        backends.insert(StorageKind::Memory, Arc::new(MemoryBackend::default()));
+        Self {
+            torrent_repository,
+            torrent_file_repository: None,
+            snapshot_repository: None,
+            download_dir,
+            backends,
+            layouts: Mutex::new(HashMap::new()),
+            dirty: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Lay torrents out by their file lists; without one every torrent is a single file
+    pub fn with_file_repository(mut self, torrent_file_repository: Arc<dyn TorrentFileRepository>) -> Self {
+        self.torrent_file_repository = Some(torrent_file_repository);
+        self
+    }
+
+    /// Record file sizes and modification times so a restart can tell
+    /// whether files changed; without it every restart re-hashes
+    pub fn with_snapshot_repository(mut self, snapshot_repository: Arc<dyn FileSnapshotRepository>) -> Self {
+        self.snapshot_repository = Some(snapshot_repository);
+        self
+    }
+
+    /// Keep torrents of `kind` in `backend`
+    pub fn with_backend(mut self, kind: StorageKind, backend: Arc<dyn StorageBackend>) -> Self {
+        self.backends.insert(kind, backend);
+        self
+    }
+
+    pub fn download_dir(&self) -> &Path {
+        &self.download_dir
+    }
+
+    pub fn backend(&self, kind: StorageKind) -> Arc<dyn StorageBackend> {
+        self.backends[&kind].clone()
+    }
+
+    /// Where the files of a torrent live
+    pub async fn layout(&self, torrent_id: i32) -> Result<Arc<StorageLayout>, DomainError> {
+        if let Some(layout) = self.layouts.lock().unwrap().get(&torrent_id) {
+            return Ok(layout.clone());
+        }
+
+        let torrent = self.torrent_repository.find_by_id(torrent_id).await?
+            .ok_or(DomainError::TorrentNotFound(torrent_id))?;
+        let files = match &self.torrent_file_repository {
+            Some(repository) => repository.find_by_torrent_id(torrent_id).await?,
+            None => Vec::new(),
+        };
+
+        let layout = Arc::new(StorageLayout::new(&self.download_dir, &torrent, &files));
+        // A file list saved later would change the layout
+        if !files.is_empty() {
+            self.layouts.lock().unwrap().insert(torrent_id, layout.clone());
+        }
+        Ok(layout)
+    }
+
+    /// Read `length` bytes of the torrent starting at `offset`. Bytes a
+    /// memory-backed torrent no longer holds are `NotFound`.
+    pub async fn read(&self, torrent_id: i32, offset: u64, length: usize) -> Result<Vec<u8>, DomainError> {
+        let layout = self.layout(torrent_id).await?;
+        let backend = self.backend(layout.storage);
+        let mut data = vec![0u8; length];
+        for span in layout.spans(offset, length)? {
+            let bytes = backend.read_span(&span.path, span.file_offset, span.length).await?;
+            data[span.range_offset..span.range_offset + span.length].copy_from_slice(&bytes);
+        }
+        Ok(data)
+    }
+
+    /// Whether the stored bytes of `piece` match its hash; missing or short
+    /// data does not
+    pub async fn holds_piece(&self, torrent: &Torrent, piece: &Piece) -> bool {
+        let offset = piece.piece_index as u64 * torrent.piece_length as u64;
+        let length = torrent.piece_size(piece.piece_index) as usize;
+        match self.read(piece.torrent_id, offset, length).await {
+            Ok(data) => hex::encode(sha1::Sha1::digest(&data)) == piece.hash,
+            Err(_) => false,
+        }
+    }
+
+    /// Write `data` at `offset` of the torrent, creating files and
+    /// directories as needed
+    pub async fn write(&self, torrent_id: i32, offset: u64, data: &[u8]) -> Result<(), DomainError> {
+        let layout = self.layout(torrent_id).await?;
+        let backend = self.backend(layout.storage);
+        for span in layout.spans(offset, data.len())? {
+            backend.write_span(&span.path, span.file_offset, &data[span.range_offset..span.range_offset + span.length]).await?;
+        }
+        self.dirty.lock().unwrap().insert(torrent_id);
+        Ok(())
+    }
+
+    /// Make everything written to the files of a torrent durable and
+    /// snapshot them
+    pub async fn flush(&self, torrent_id: i32) -> Result<(), DomainError> {
+        let layout = self.layout(torrent_id).await?;
+        let backend = self.backend(layout.storage);
+        for file in &layout.files {
+            backend.flush(&file.path).await?;
+        }
+        self.save_snapshot(torrent_id).await
+    }
+
+    /// Stored files of a torrent that are wanted, i.e. not skipped
+    async fn wanted_files(&self, torrent_id: i32) -> Result<Vec<StoredFile>, DomainError> {
+        let layout = self.layout(torrent_id).await?;
+        let skipped: HashSet<u64> = match &self.torrent_file_repository {
+            Some(repository) => repository.find_by_torrent_id(torrent_id).await?
+                .into_iter()
+                .filter(|file| !file.priority.is_wanted() && file.length > 0)
+                .map(|file| file.offset as u64)
+                .collect(),
+            None => HashSet::new(),
+        };
+        Ok(layout.files.iter()
+            .filter(|file| file.length > 0 && !skipped.contains(&file.offset))
+            .cloned()
+            .collect())
+    }
+
+    /// Bytes the wanted files of a torrent take once downloaded
+    pub async fn wanted_size(&self, torrent_id: i32) -> Result<u64, DomainError> {
+        Ok(self.wanted_files(torrent_id).await?.iter().map(|file| file.length).sum())
+    }
+
+    /// Bytes of the wanted files of a torrent beyond their current length,
+    /// i.e. not written yet when files are written in full, not sparsely
+    pub async fn unwritten_size(&self, torrent_id: i32) -> Result<u64, DomainError> {
+        let layout = self.layout(torrent_id).await?;
+        let backend = self.backend(layout.storage);
+        let mut unwritten = 0;
+        for file in self.wanted_files(torrent_id).await? {
+            let current = backend.stat(&file.path).await?.map_or(0, |(size, _)| size);
+            unwritten += file.length.saturating_sub(current);
+        }
+        Ok(unwritten)
+    }
+
+    /// Bytes the backend of `kind` can still take, if it can tell
+    pub async fn available_space(&self, kind: StorageKind) -> Result<Option<u64>, DomainError> {
+        self.backend(kind).available_space().await
+    }
+
+    /// Create the wanted files of a torrent at full length before any piece
+    /// arrives. Memory-backed torrents are left alone.
+    pub async fn preallocate(&self, torrent_id: i32, mode: Preallocation) -> Result<(), DomainError> {
+        let layout = self.layout(torrent_id).await?;
+        if mode == Preallocation::Off || layout.storage == StorageKind::Memory {
+            return Ok(());
+        }
+
+        let backend = self.backend(layout.storage);
+        for file in self.wanted_files(torrent_id).await? {
+            let current = backend.stat(&file.path).await?.map_or(0, |(size, _)| size);
+            if current >= file.length {
+                continue;
+            }
+            match mode {
+                Preallocation::Off => {}
+                Preallocation::Sparse => backend.truncate(&file.path, file.length).await?,
+                Preallocation::Full => {
+                    let mut offset = current;
+                    while offset < file.length {
+                        let length = (file.length - offset).min(PREALLOCATION_CHUNK as u64) as usize;
+                        backend.write_span(&file.path, offset, &vec![0u8; length]).await?;
+                        offset += length as u64;
+                    }
+                }
+            }
+        }
+        // No piece is stored yet, so the piece state still matches the files
+        self.save_snapshot(torrent_id).await
+    }
+
+    /// Size and modification time of the files of a torrent that exist
+    pub async fn snapshot(&self, torrent_id: i32) -> Result<Vec<FileSnapshot>, DomainError> {
+        let layout = self.layout(torrent_id).await?;
+        let backend = self.backend(layout.storage);
+        let mut snapshots = Vec::new();
+        for file in &layout.files {
+            if let Some((size, modified_at)) = backend.stat(&file.path).await? {
+                let path = file.path.to_string_lossy().to_string();
+                snapshots.push(FileSnapshot::new(torrent_id, path, size as i64, modified_at));
+            }
+        }
+        Ok(snapshots)
+    }
+
+    /// Record the files of a torrent as they are now. Call it once the piece
+    /// state matching them is saved.
+    pub async fn save_snapshot(&self, torrent_id: i32) -> Result<(), DomainError> {
+        self.dirty.lock().unwrap().remove(&torrent_id);
+        if let Some(repository) = &self.snapshot_repository {
+            let snapshots = self.snapshot(torrent_id).await?;
+            repository.replace_for_torrent(torrent_id, &snapshots).await?;
+        }
+        Ok(())
+    }
+
+    /// Snapshot every torrent written to since its last snapshot. Returns
+    /// how many were saved.
+    pub async fn save_dirty_snapshots(&self) -> Result<usize, DomainError> {
+        let dirty: Vec<i32> = self.dirty.lock().unwrap().drain().collect();
+        for torrent_id in &dirty {
+            self.save_snapshot(*torrent_id).await?;
+        }
+        Ok(dirty.len())
+    }
+
+    /// Whether the files of a torrent are exactly as last snapshotted, so
+    /// the saved piece state still describes them
+    pub async fn is_unchanged(&self, torrent_id: i32) -> Result<bool, DomainError> {
+        let Some(repository) = &self.snapshot_repository else {
+            return Ok(false);
+        };
+        let saved = repository.find_by_torrent_id(torrent_id).await?;
+        let current = self.snapshot(torrent_id).await?;
+        Ok(saved.len() == current.len()
+            && current.iter().all(|file| saved.iter().any(|snapshot| snapshot.matches(file))))
+    }
+
+    /// Delete the stored files of a torrent. On disk the directories they
+    /// leave empty go too, up to the download directory, so the directory of
+    /// a multi-file torrent is removed unless something else was put in it.
+    pub async fn delete(&self, torrent_id: i32) -> Result<(), DomainError> {
+        let layout = self.layout(torrent_id).await?;
+        self.layouts.lock().unwrap().remove(&torrent_id);
+        self.dirty.lock().unwrap().remove(&torrent_id);
+
+        let backend = self.backend(layout.storage);
+        for file in &layout.files {
+            if let Err(e) = backend.delete(&file.path).await {
+                eprintln!("⚠️  {}", e);
+            }
+        }
+        Ok(())
+    }
+}
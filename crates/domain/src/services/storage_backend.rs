@@ -0,0 +1,348 @@
+use crate::errors::DomainError;
+use crate::services::disk_space;
+use async_trait::async_trait;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, SeekFrom};
+
+/// Default memory budget of torrents kept in memory
+pub const DEFAULT_MEMORY_CAPACITY: usize = 512 * 1024 * 1024;
+
+/// Where the bytes of torrent files end up. `TorrentStorage` maps torrent
+/// offsets onto file spans; a backend only reads and writes those spans.
+#[async_trait]
+pub trait StorageBackend: Send + Sync {
+    /// Read `length` bytes of `path` starting at `offset`. Missing bytes are
+    /// an error: `NotFound` when they were never written or were evicted.
+    async fn read_span(&self, path: &Path, offset: u64, length: usize) -> Result<Vec<u8>, DomainError>;
+
+    /// Write `data` at `offset` of `path`, creating it as needed
+    async fn write_span(&self, path: &Path, offset: u64, data: &[u8]) -> Result<(), DomainError>;
+
+    /// Make everything written to `path` durable
+    async fn flush(&self, path: &Path) -> Result<(), DomainError>;
+
+    /// Cut or extend `path` to `length` bytes
+    async fn truncate(&self, path: &Path, length: u64) -> Result<(), DomainError>;
+
+    /// Remove `path`; removing a file that does not exist is not an error
+    async fn delete(&self, path: &Path) -> Result<(), DomainError>;
+
+    /// Size and modification time of `path`, if it exists and outlives the process
+    async fn stat(&self, path: &Path) -> Result<Option<(u64, SystemTime)>, DomainError>;
+
+    /// Bytes that can still be written, or `None` when it cannot be told
+    async fn available_space(&self) -> Result<Option<u64>, DomainError>;
+}
+
+/// Files on disk
+pub struct FilesystemBackend {
+    /// Directories emptied by deletes are removed up to, not including, this one
+    root: PathBuf,
+}
+
+impl FilesystemBackend {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    async fn open_for_write(path: &Path) -> Result<tokio::fs::File, DomainError> {
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await
+                .map_err(|e| DomainError::IoError(format!("Failed to create {}: {}", parent.display(), e)))?;
+        }
+        tokio::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path)
+            .await
+            .map_err(|e| DomainError::IoError(format!("Failed to open file {}: {}", path.display(), e)))
+    }
+}
+
+#[async_trait]
+impl StorageBackend for FilesystemBackend {
+    async fn read_span(&self, path: &Path, offset: u64, length: usize) -> Result<Vec<u8>, DomainError> {
+        let mut file = match tokio::fs::File::open(path).await {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Err(DomainError::NotFound(format!("File {}", path.display())));
+            }
+            Err(e) => return Err(DomainError::IoError(format!("Failed to open file {}: {}", path.display(), e))),
+        };
+        file.seek(SeekFrom::Start(offset)).await
+            .map_err(|e| DomainError::IoError(format!("Failed to seek in {}: {}", path.display(), e)))?;
+        let mut data = vec![0u8; length];
+        file.read_exact(&mut data).await
+            .map_err(|e| DomainError::IoError(format!("Failed to read {}: {}", path.display(), e)))?;
+        Ok(data)
+    }
+
+    async fn write_span(&self, path: &Path, offset: u64, data: &[u8]) -> Result<(), DomainError> {
+        let mut file = Self::open_for_write(path).await?;
+        file.seek(SeekFrom::Start(offset)).await
+            .map_err(|e| DomainError::IoError(format!("Failed to seek in {}: {}", path.display(), e)))?;
+        file.write_all(data).await
+            .map_err(|e| DomainError::IoError(format!("Failed to write {}: {}", path.display(), e)))?;
+        file.flush().await
+            .map_err(|e| DomainError::IoError(format!("Failed to flush {}: {}", path.display(), e)))
+    }
+
+    async fn flush(&self, path: &Path) -> Result<(), DomainError> {
+        match tokio::fs::File::open(path).await {
+            Ok(file) => file.sync_all().await
+                .map_err(|e| DomainError::IoError(format!("Failed to sync {}: {}", path.display(), e))),
+            // Nothing was written to it
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(DomainError::IoError(format!("Failed to open file {}: {}", path.display(), e))),
+        }
+    }
+
+    async fn truncate(&self, path: &Path, length: u64) -> Result<(), DomainError> {
+        let file = Self::open_for_write(path).await?;
+        file.set_len(length).await
+            .map_err(|e| DomainError::IoError(format!("Failed to resize {}: {}", path.display(), e)))
+    }
+
+    async fn delete(&self, path: &Path) -> Result<(), DomainError> {
+        match tokio::fs::remove_file(path).await {
+            Ok(()) => println!("🗑️  Deleted file: {}", path.display()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(DomainError::IoError(format!("Failed to delete file {}: {}", path.display(), e))),
+        }
+
+        // Remove the directories the file leaves empty, deepest first
+        for directory in path.ancestors().skip(1) {
+            if directory == self.root || !directory.starts_with(&self.root) {
+                break;
+            }
+            // A directory that was never created, e.g. that of a skipped
+            // file, does not stop the walk; one still holding files does
+            if tokio::fs::remove_dir(directory).await.is_err() && directory.exists() {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    async fn stat(&self, path: &Path) -> Result<Option<(u64, SystemTime)>, DomainError> {
+        let metadata = match tokio::fs::metadata(path).await {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(DomainError::IoError(format!("Failed to stat {}: {}", path.display(), e))),
+        };
+        let modified = metadata.modified()
+            .map_err(|e| DomainError::IoError(format!("Failed to stat {}: {}", path.display(), e)))?;
+        Ok(Some((metadata.len(), modified)))
+    }
+
+    async fn available_space(&self) -> Result<Option<u64>, DomainError> {
+        disk_space::available_space(&self.root).await
+    }
+}
+
+/// Bytes of a file held in memory, keyed by offset
+struct Extent {
+    data: Vec<u8>,
+    /// Position in `MemoryState::recency`
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct MemoryState {
+    files: HashMap<PathBuf, BTreeMap<u64, Extent>>,
+    /// Every extent by when it was last used, oldest first
+    recency: BTreeMap<u64, (PathBuf, u64)>,
+    clock: u64,
+    used: usize,
+}
+
+impl MemoryState {
+    fn touch(&mut self, path: &Path, offset: u64) {
+        self.clock += 1;
+        let clock = self.clock;
+        if let Some(extent) = self.files.get_mut(path).and_then(|extents| extents.get_mut(&offset)) {
+            self.recency.remove(&extent.last_used);
+            extent.last_used = clock;
+            self.recency.insert(clock, (path.to_path_buf(), offset));
+        }
+    }
+
+    fn insert(&mut self, path: &Path, offset: u64, data: Vec<u8>) {
+        self.clock += 1;
+        self.used += data.len();
+        self.recency.insert(self.clock, (path.to_path_buf(), offset));
+        let extent = Extent { data, last_used: self.clock };
+        self.files.entry(path.to_path_buf()).or_default().insert(offset, extent);
+    }
+
+    fn remove(&mut self, path: &Path, offset: u64) -> Option<Extent> {
+        let extents = self.files.get_mut(path)?;
+        let extent = extents.remove(&offset)?;
+        if extents.is_empty() {
+            self.files.remove(path);
+        }
+        self.recency.remove(&extent.last_used);
+        self.used -= extent.data.len();
+        Some(extent)
+    }
+
+    /// Drop the bytes of `path` in `start..end`, keeping what sticks out of
+    /// the extents they were part of
+    fn clear(&mut self, path: &Path, start: u64, end: u64) {
+        let overlapping: Vec<u64> = match self.files.get(path) {
+            Some(extents) => extents
+                .range(..end)
+                .rev()
+                .take_while(|(offset, extent)| **offset + extent.data.len() as u64 > start)
+                .map(|(offset, _)| *offset)
+                .collect(),
+            None => return,
+        };
+        for offset in overlapping {
+            let Some(extent) = self.remove(path, offset) else {
+                continue;
+            };
+            let extent_end = offset + extent.data.len() as u64;
+            if offset < start {
+                self.insert(path, offset, extent.data[..(start - offset) as usize].to_vec());
+            }
+            if extent_end > end {
+                self.insert(path, end, extent.data[(end - offset) as usize..].to_vec());
+            }
+        }
+    }
+
+    /// Evict the least recently used extents until `capacity` is respected
+    fn evict(&mut self, capacity: usize) {
+        while self.used > capacity {
+            let Some((_, (path, offset))) = self.recency.pop_first() else {
+                break;
+            };
+            if let Some(extents) = self.files.get_mut(&path) {
+                if let Some(extent) = extents.remove(&offset) {
+                    self.used -= extent.data.len();
+                }
+                if extents.is_empty() {
+                    self.files.remove(&path);
+                }
+            }
+        }
+    }
+}
+
+/// Files in memory, up to a byte budget. Writes past the budget evict the
+/// least recently used bytes; reading them again fails with `NotFound` so
+/// the pieces they belonged to are downloaded again. Nothing survives a
+/// restart.
+pub struct MemoryBackend {
+    capacity: usize,
+    state: Mutex<MemoryState>,
+}
+
+impl MemoryBackend {
+    pub fn new(capacity: usize) -> Self {
+        Self { capacity, state: Mutex::new(MemoryState::default()) }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Bytes currently held
+    pub fn used(&self) -> usize {
+        self.state.lock().unwrap().used
+    }
+}
+
+impl Default for MemoryBackend {
+    fn default() -> Self {
+        Self::new(DEFAULT_MEMORY_CAPACITY)
+    }
+}
+
+#[async_trait]
+impl StorageBackend for MemoryBackend {
+    async fn read_span(&self, path: &Path, offset: u64, length: usize) -> Result<Vec<u8>, DomainError> {
+        let end = offset + length as u64;
+        let mut state = self.state.lock().unwrap();
+        let mut covering: Vec<u64> = state.files.get(path)
+            .map(|extents| {
+                extents
+                    .range(..end)
+                    .rev()
+                    .take_while(|(start, extent)| **start + extent.data.len() as u64 > offset)
+                    .map(|(start, _)| *start)
+                    .collect()
+            })
+            .unwrap_or_default();
+        covering.reverse();
+
+        let mut data = Vec::with_capacity(length);
+        let extents = state.files.get(path);
+        for start in &covering {
+            let extent = &extents.unwrap()[start];
+            let position = offset + data.len() as u64;
+            if *start > position {
+                break;
+            }
+            let from = (position - start) as usize;
+            let to = ((end - start) as usize).min(extent.data.len());
+            data.extend_from_slice(&extent.data[from..to]);
+        }
+        if data.len() < length {
+            return Err(DomainError::NotFound(format!(
+                "Bytes {}..{} of {} are not in memory", offset + data.len() as u64, end, path.display()
+            )));
+        }
+
+        for start in covering {
+            state.touch(path, start);
+        }
+        Ok(data)
+    }
+
+    async fn write_span(&self, path: &Path, offset: u64, data: &[u8]) -> Result<(), DomainError> {
+        if data.len() > self.capacity {
+            return Err(DomainError::IoError(format!(
+                "Write of {} bytes does not fit in {} bytes of memory storage", data.len(), self.capacity
+            )));
+        }
+        let mut state = self.state.lock().unwrap();
+        state.clear(path, offset, offset + data.len() as u64);
+        state.insert(path, offset, data.to_vec());
+        state.evict(self.capacity);
+        Ok(())
+    }
+
+    async fn flush(&self, _path: &Path) -> Result<(), DomainError> {
+        Ok(())
+    }
+
+    async fn truncate(&self, path: &Path, length: u64) -> Result<(), DomainError> {
+        // Nothing to extend: unwritten bytes are missing either way
+        self.state.lock().unwrap().clear(path, length, u64::MAX);
+        Ok(())
+    }
+
+    async fn delete(&self, path: &Path) -> Result<(), DomainError> {
+        let mut state = self.state.lock().unwrap();
+        let offsets: Vec<u64> = state.files.get(path).map(|extents| extents.keys().copied().collect()).unwrap_or_default();
+        for offset in offsets {
+            state.remove(path, offset);
+        }
+        Ok(())
+    }
+
+    async fn stat(&self, _path: &Path) -> Result<Option<(u64, SystemTime)>, DomainError> {
+        // Gone after a restart, so there is nothing to compare against
+        Ok(None)
+    }
+
+    async fn available_space(&self) -> Result<Option<u64>, DomainError> {
+        Ok(Some(self.capacity.saturating_sub(self.used()) as u64))
+    }
+}
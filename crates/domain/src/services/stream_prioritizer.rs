@@ -1,10 +1,53 @@
-use crate::entities::{StreamSession, Torrent};
+use crate::entities::StreamSession;
 use crate::repositories::TorrentRepository;
-use crate::services::piece_manager::{PieceManager, PiecePriority};
+use crate::services::piece_manager::PieceManager;
+use crate::services::container::{pieces_for_range, ContainerKind};
 use crate::errors::DomainError;
+use std::ops::Range;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-/// Service responsible for prioritizing pieces for streaming
+/// Playback rate assumed until a stream's bitrate is known (8 Mbit/s)
+pub const DEFAULT_BITRATE: u64 = 1_000_000;
+
+/// How much playback time is fetched ahead of the playhead
+pub const READ_AHEAD: Duration = Duration::from_secs(30);
+
+/// The read-ahead window never shrinks below this
+pub const MIN_READ_AHEAD_BYTES: u64 = 4 * 1024 * 1024;
+
+/// Bytes to fetch ahead of the playhead for a stream playing `bitrate`
+/// bytes per second, at most `max_bytes`
+pub fn read_ahead_bytes(bitrate: u64, max_bytes: u64) -> u64 {
+    let wanted = (bitrate as f64 * READ_AHEAD.as_secs_f64()) as u64;
+    wanted.clamp(MIN_READ_AHEAD_BYTES, max_bytes.max(MIN_READ_AHEAD_BYTES))
+}
+
+/// When playback reaches each piece overlapping `playhead..end`, playing
+/// `bitrate` bytes per second from `now`. The piece under the playhead is due
+/// immediately.
+pub fn piece_deadlines(piece_length: u64, playhead: u64, end: u64, bitrate: u64, now: Instant) -> Vec<(usize, Instant)> {
+    if piece_length == 0 || end <= playhead {
+        return Vec::new();
+    }
+
+    let bitrate = bitrate.max(1) as f64;
+    let first_piece = playhead / piece_length;
+    let last_piece = (end - 1) / piece_length;
+    (first_piece..=last_piece)
+        .map(|piece_index| {
+            let starts_at = (piece_index * piece_length).max(playhead);
+            let wait = Duration::from_secs_f64((starts_at - playhead) as f64 / bitrate);
+            (piece_index as usize, now + wait)
+        })
+        .collect()
+}
+
+/// Schedules the pieces a stream needs by when playback will reach them.
+///
+/// Every piece between the playhead and the end of the window gets a deadline
+/// derived from the stream's bitrate; `PieceManager` turns the deadline into a
+/// priority and the piece picker uses it to pick peers fast enough to make it.
 pub struct StreamPrioritizer {
     piece_manager: Arc<PieceManager>,
     torrent_repository: Arc<dyn TorrentRepository>,
@@ -21,88 +64,28 @@ impl StreamPrioritizer {
         }
     }
 
-    /// Prioritize pieces for streaming based on current position and buffer strategy
-    pub async fn prioritize_for_streaming(
-        &self,
-        session: &StreamSession,
-        current_position: u64,
-        buffer_ahead_mb: usize,
-    ) -> Result<(), DomainError> {
-        let torrent = self.torrent_repository.find_by_id(session.torrent_id).await?
-            .ok_or_else(|| DomainError::NotFound(format!("Torrent {} not found", session.torrent_id)))?;
-
-        let piece_size = torrent.piece_length as u64;
-        let buffer_size = (buffer_ahead_mb * 1024 * 1024) as u64;
-
-        // Calculate piece range for current streaming position
-        let start_piece = (current_position / piece_size) as usize;
-        let end_piece = ((current_position + buffer_size) / piece_size) as usize;
-        let total_pieces = torrent.piece_count as usize;
-
-        // Prioritize immediate pieces as urgent
-        let urgent_pieces = 5; // Next 5 pieces are urgent
-        for piece_index in start_piece..=std::cmp::min(start_piece + urgent_pieces, total_pieces - 1) {
-            if !self.piece_manager.is_piece_available(session.torrent_id, piece_index).await? {
-                self.piece_manager.request_piece(
-                    session.torrent_id,
-                    piece_index,
-                    PiecePriority::Urgent,
-                    session.id.clone(),
-                ).await?;
-            }
-        }
-
-        // Prioritize buffer pieces as high
-        for piece_index in (start_piece + urgent_pieces + 1)..=std::cmp::min(end_piece, total_pieces - 1) {
-            if !self.piece_manager.is_piece_available(session.torrent_id, piece_index).await? {
-                self.piece_manager.request_piece(
-                    session.torrent_id,
-                    piece_index,
-                    PiecePriority::High,
-                    session.id.clone(),
-                ).await?;
-            }
-        }
-
-        Ok(())
-    }
-
-    /// Sequential piece prioritization for typical video streaming
-    pub async fn prioritize_sequential(
+    /// Request the missing pieces of `playhead..end` with deadlines for
+    /// playback at `bitrate` bytes per second
+    pub async fn prioritize_by_deadline(
         &self,
         torrent_id: i32,
-        file_offset: u64,
-        file_size: u64,
-        session_id: String,
+        playhead: u64,
+        end: u64,
+        bitrate: u64,
+        session_id: &str,
     ) -> Result<(), DomainError> {
         let torrent = self.torrent_repository.find_by_id(torrent_id).await?
             .ok_or_else(|| DomainError::NotFound(format!("Torrent {} not found", torrent_id)))?;
 
-        let piece_size = torrent.piece_length as u64;
-        let start_piece = (file_offset / piece_size) as usize;
-        let end_piece = ((file_offset + file_size - 1) / piece_size) as usize;
-
-        // Prioritize the first part of the file as urgent (for quick playback start)
-        let critical_pieces = 10; // First 10 pieces are critical
-        for piece_index in start_piece..=std::cmp::min(start_piece + critical_pieces, end_piece) {
+        let end = end.min(torrent.total_size as u64);
+        let deadlines = piece_deadlines(torrent.piece_length as u64, playhead, end, bitrate, Instant::now());
+        for (piece_index, deadline) in deadlines {
             if !self.piece_manager.is_piece_available(torrent_id, piece_index).await? {
-                self.piece_manager.request_piece(
+                self.piece_manager.request_piece_by(
                     torrent_id,
                     piece_index,
-                    PiecePriority::Urgent,
-                    session_id.clone(),
-                ).await?;
-            }
-        }
-
-        // Rest of the file as normal priority
-        for piece_index in (start_piece + critical_pieces + 1)..=end_piece {
-            if !self.piece_manager.is_piece_available(torrent_id, piece_index).await? {
-                self.piece_manager.request_piece(
-                    torrent_id,
-                    piece_index,
-                    PiecePriority::Normal,
-                    session_id.clone(),
+                    deadline,
+                    session_id.to_string(),
                 ).await?;
             }
         }
@@ -110,98 +93,68 @@ impl StreamPrioritizer {
         Ok(())
     }
 
-    /// Calculate optimal piece request pattern for streaming
-    pub fn calculate_streaming_pattern(
+    /// Prioritize the read-ahead window of a stream playing `bitrate` bytes
+    /// per second, at most `max_read_ahead` bytes past the current position
+    pub async fn prioritize_for_streaming(
         &self,
-        torrent: &Torrent,
+        session: &StreamSession,
         current_position: u64,
-        bandwidth_kbps: u32,
-    ) -> StreamingPattern {
-        let piece_size = torrent.piece_length as u64;
-        let current_piece = (current_position / piece_size) as usize;
-        
-        // Calculate how many pieces we can buffer based on bandwidth
-        // Assume we want 30 seconds of buffer at current bandwidth
-        let buffer_seconds = 30;
-        let bytes_per_second = (bandwidth_kbps * 1024) / 8; // Convert to bytes per second
-        let buffer_bytes = bytes_per_second * buffer_seconds;
-        let buffer_pieces = (buffer_bytes as u64 / piece_size) as usize;
-
-        StreamingPattern {
-            urgent_start: current_piece,
-            urgent_count: 3, // Always keep 3 pieces urgent
-            high_start: current_piece + 3,
-            high_count: std::cmp::min(buffer_pieces, 20), // Max 20 pieces high priority
-            normal_start: current_piece + 3 + std::cmp::min(buffer_pieces, 20),
-            normal_count: 50, // Background download
-        }
+        bitrate: u64,
+        max_read_ahead: u64,
+    ) -> Result<(), DomainError> {
+        self.prioritize_by_deadline(
+            session.torrent_id,
+            current_position,
+            current_position + read_ahead_bytes(bitrate, max_read_ahead),
+            bitrate,
+            &session.id,
+        ).await
     }
-}
-
-#[derive(Debug, Clone)]
-pub struct StreamingPattern {
-    pub urgent_start: usize,
-    pub urgent_count: usize,
-    pub high_start: usize,
-    pub high_count: usize,
-    pub normal_start: usize,
-    pub normal_count: usize,
-}
 
-impl StreamingPattern {
-    /// Apply this pattern to piece requests
-    pub async fn apply_to_piece_manager(
+    /// Request what a player reads before it starts playing, e.g. the `moov`
+    /// of a non-faststart MP4 or the Cues of an MKV at the end of the file,
+    /// ahead of everything else. Returns the pieces requested, head first.
+    pub async fn prioritize_head_and_tail(
         &self,
-        piece_manager: &PieceManager,
         torrent_id: i32,
-        session_id: String,
-        total_pieces: usize,
-    ) -> Result<(), DomainError> {
-        // Request urgent pieces
-        for i in 0..self.urgent_count {
-            let piece_index = self.urgent_start + i;
-            if piece_index < total_pieces {
-                if !piece_manager.is_piece_available(torrent_id, piece_index).await? {
-                    piece_manager.request_piece(
-                        torrent_id,
-                        piece_index,
-                        PiecePriority::Urgent,
-                        session_id.clone(),
-                    ).await?;
-                }
-            }
-        }
+        container: ContainerKind,
+        file_offset: u64,
+        file_size: u64,
+        session_id: &str,
+    ) -> Result<Vec<Range<usize>>, DomainError> {
+        let torrent = self.torrent_repository.find_by_id(torrent_id).await?
+            .ok_or_else(|| DomainError::NotFound(format!("Torrent {} not found", torrent_id)))?;
 
-        // Request high priority pieces
-        for i in 0..self.high_count {
-            let piece_index = self.high_start + i;
-            if piece_index < total_pieces {
-                if !piece_manager.is_piece_available(torrent_id, piece_index).await? {
-                    piece_manager.request_piece(
-                        torrent_id,
-                        piece_index,
-                        PiecePriority::High,
-                        session_id.clone(),
-                    ).await?;
+        let now = Instant::now();
+        let mut pinned = Vec::new();
+        for range in container.prefetch_ranges(file_offset, file_size) {
+            let pieces = pieces_for_range(&range, torrent.piece_length as u64);
+            for piece_index in pieces.clone() {
+                if !self.piece_manager.is_piece_available(torrent_id, piece_index).await? {
+                    self.piece_manager.request_piece_by(torrent_id, piece_index, now, session_id.to_string()).await?;
                 }
             }
+            pinned.push(pieces);
         }
 
-        // Request normal priority pieces
-        for i in 0..self.normal_count {
-            let piece_index = self.normal_start + i;
-            if piece_index < total_pieces {
-                if !piece_manager.is_piece_available(torrent_id, piece_index).await? {
-                    piece_manager.request_piece(
-                        torrent_id,
-                        piece_index,
-                        PiecePriority::Normal,
-                        session_id.clone(),
-                    ).await?;
-                }
-            }
-        }
+        Ok(pinned)
+    }
 
-        Ok(())
+    /// Schedule a whole file for sequential playback from its start
+    pub async fn prioritize_sequential(
+        &self,
+        torrent_id: i32,
+        file_offset: u64,
+        file_size: u64,
+        bitrate: u64,
+        session_id: String,
+    ) -> Result<(), DomainError> {
+        self.prioritize_by_deadline(
+            torrent_id,
+            file_offset,
+            file_offset + file_size,
+            bitrate,
+            &session_id,
+        ).await
     }
 }
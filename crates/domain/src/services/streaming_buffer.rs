@@ -3,6 +3,10 @@ use crate::errors::DomainError;
 use crate::repositories::TorrentRepository;
 use crate::services::piece_manager::PieceManager;
 use crate::services::piece_downloader::PieceDownloader;
+use crate::services::stream_prioritizer::{piece_deadlines, read_ahead_bytes, DEFAULT_BITRATE};
+use crate::services::bitrate::{bitrate_for, container_duration, BitrateSource, ConsumptionRate, PROBE_BYTES};
+use crate::services::container::pieces_for_range;
+use std::ops::Range;
 use std::sync::{Arc, Mutex};
 use std::collections::{HashMap, VecDeque};
 use tokio::time::{Duration, Instant};
@@ -20,10 +24,45 @@ pub struct StreamingBuffer {
 struct SessionBuffer {
     _session_id: String,
     torrent_id: i32,
+    file_offset: u64,
+    file_size: u64,
+    piece_size: u64,
     current_position: u64,
     buffer_queue: VecDeque<BufferedPiece>,
     last_access: Instant,
-    buffer_ahead_pieces: usize,
+    /// Upper bound of the read-ahead window in bytes
+    max_read_ahead: u64,
+    bitrate: u64,
+    bitrate_source: BitrateSource,
+    consumption: ConsumptionRate,
+    /// Pieces the player needs wherever it is, e.g. the container index
+    pinned: Vec<Range<usize>>,
+}
+
+impl SessionBuffer {
+    /// End of the read-ahead window for playback at `position`
+    fn read_ahead_end(&self, position: u64) -> u64 {
+        (position + read_ahead_bytes(self.bitrate, self.max_read_ahead)).min(self.file_offset + self.file_size)
+    }
+
+    /// Whether a read at `position` jumps outside the current read-ahead window
+    fn is_seek(&self, position: u64) -> bool {
+        let first_piece = self.current_position / self.piece_size;
+        let last_piece = self.read_ahead_end(self.current_position).saturating_sub(1) / self.piece_size;
+        let piece = position / self.piece_size;
+        piece < first_piece || piece > last_piece.max(first_piece)
+    }
+
+    /// Fall back to the observed consumption rate until the container is probed
+    fn refresh_bitrate(&mut self, now: std::time::Instant) {
+        if self.bitrate_source == BitrateSource::Container {
+            return;
+        }
+        if let Some(observed) = self.consumption.bitrate(now) {
+            self.bitrate = observed;
+            self.bitrate_source = BitrateSource::Observed;
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -55,15 +94,28 @@ impl StreamingBuffer {
             .ok_or_else(|| DomainError::NotFound(format!("Torrent {} not found", session.torrent_id)))?;
 
         let piece_size = torrent.piece_length as u64;
-        let buffer_pieces = (self.buffer_size_mb * 1024 * 1024) / piece_size as usize;
+        let max_read_ahead = ((self.buffer_size_mb * 1024 * 1024) as u64).max(10 * piece_size); // At least 10 pieces ahead
+        let file_size = session.file_size as u64;
+
+        let (bitrate, bitrate_source) = match Self::probe_bitrate(&self.piece_manager, self.torrent_repository.as_ref(), session.torrent_id, file_offset, file_size).await {
+            Some(bitrate) => (bitrate, BitrateSource::Container),
+            None => (DEFAULT_BITRATE, BitrateSource::Default),
+        };
 
         let session_buffer = SessionBuffer {
             _session_id: session.id.clone(),
             torrent_id: session.torrent_id,
+            file_offset,
+            file_size,
+            piece_size,
             current_position: file_offset,
             buffer_queue: VecDeque::new(),
             last_access: Instant::now(),
-            buffer_ahead_pieces: buffer_pieces.max(10), // At least 10 pieces ahead
+            max_read_ahead,
+            bitrate,
+            bitrate_source,
+            consumption: ConsumptionRate::new(),
+            pinned: Vec::new(),
         };
 
         {
@@ -77,19 +129,106 @@ impl StreamingBuffer {
         Ok(())
     }
 
+    /// Keep requesting `pieces` for the session when it seeks, e.g. the head
+    /// and tail of its file that players read before anything else
+    pub fn pin_pieces(&self, session_id: &str, pieces: Vec<Range<usize>>) {
+        if let Some(buffer) = self.buffers.lock().unwrap().get_mut(session_id) {
+            buffer.pinned.extend(pieces);
+        }
+    }
+
+    /// Estimated bitrate of a session's file in bytes per second and where it came from
+    pub fn bitrate(&self, session_id: &str) -> Option<(u64, BitrateSource)> {
+        let buffers = self.buffers.lock().unwrap();
+        buffers.get(session_id).map(|buffer| (buffer.bitrate, buffer.bitrate_source))
+    }
+
+    /// Bitrate from the file size and the duration in the container header,
+    /// once the pieces holding the header are on disk
+    async fn probe_bitrate(
+        piece_manager: &PieceManager,
+        torrent_repository: &dyn TorrentRepository,
+        torrent_id: i32,
+        file_offset: u64,
+        file_size: u64,
+    ) -> Option<u64> {
+        let probe_length = PROBE_BYTES.min(file_size);
+        if probe_length == 0 {
+            return None;
+        }
+
+        // Reading a missing piece would request it urgently; the head is
+        // already first in line, so wait for it instead
+        let torrent = torrent_repository.find_by_id(torrent_id).await.ok()??;
+        let piece_size = torrent.piece_length as u64;
+        let first_piece = (file_offset / piece_size) as usize;
+        let last_piece = ((file_offset + probe_length - 1) / piece_size) as usize;
+        for piece_index in first_piece..=last_piece {
+            if !piece_manager.is_piece_available(torrent_id, piece_index).await.ok()? {
+                return None;
+            }
+        }
+
+        let header = piece_manager.read_range(torrent_id, file_offset, probe_length).await.ok()?;
+        bitrate_for(file_size, container_duration(&header)?)
+    }
+
+    /// Ask for the pieces of `start..end`, each due when playback at `bitrate`
+    /// reaches it
+    async fn request_window(
+        piece_manager: &PieceManager,
+        torrent_id: i32,
+        session_id: &str,
+        piece_size: u64,
+        start: u64,
+        end: u64,
+        bitrate: u64,
+    ) {
+        let deadlines = piece_deadlines(piece_size, start, end, bitrate, std::time::Instant::now());
+        for (piece_index, deadline) in deadlines {
+            // Check if piece is already available
+            if let Ok(false) = piece_manager.is_piece_available(torrent_id, piece_index).await {
+                let _ = piece_manager.request_piece_by(
+                    torrent_id,
+                    piece_index,
+                    deadline,
+                    session_id.to_string(),
+                ).await;
+            }
+        }
+    }
+
     /// Get buffered data for a range
     pub async fn get_buffered_data(&self, session_id: &str, start_offset: u64, length: u64) -> Result<Vec<u8>, DomainError> {
-        let torrent_id = {
+        let (torrent_id, seek) = {
             let mut buffers = self.buffers.lock().unwrap();
             let buffer = buffers.get_mut(session_id)
                 .ok_or_else(|| DomainError::NotFound(format!("Buffer for session {} not found", session_id)))?;
 
+            // Pieces buffered for the old position are of no use after a seek
+            let seek = buffer.is_seek(start_offset).then(|| {
+                buffer.buffer_queue.clear();
+                (buffer.piece_size, buffer.read_ahead_end(start_offset), buffer.bitrate, buffer.pinned.clone())
+            });
+
             buffer.last_access = Instant::now();
             buffer.current_position = start_offset;
+            buffer.consumption.observe(start_offset, start_offset + length, std::time::Instant::now());
 
-            buffer.torrent_id
+            (buffer.torrent_id, seek)
         };
-        
+
+        // Forget the old window and fetch the new one first
+        if let Some((piece_size, window_end, bitrate, pinned)) = seek {
+            let window = pieces_for_range(&(start_offset..window_end.max(start_offset + 1)), piece_size);
+            let keep = |piece_index: usize| {
+                window.contains(&piece_index) || pinned.iter().any(|pieces| pieces.contains(&piece_index))
+            };
+            let dropped = self.piece_manager.release_requests_except(torrent_id, session_id, keep);
+            println!("⏩ Stream {} seeked to byte {}, dropped {} stale piece requests", session_id, start_offset, dropped);
+            Self::request_window(&self.piece_manager, torrent_id, session_id, piece_size, start_offset, window_end, bitrate).await;
+        }
+
         let pieces_needed = self.calculate_pieces_for_range(torrent_id, start_offset, length).await?;
 
         // Check if we have the pieces in buffer
@@ -120,11 +259,11 @@ impl StreamingBuffer {
         // If we're missing pieces, try to download them immediately
         if !missing_pieces.is_empty() {
             for piece_index in missing_pieces {
-                // Priority download for missing pieces
-                self.piece_manager.request_piece(
+                // Playback is waiting for it right now
+                self.piece_manager.request_piece_by(
                     torrent_id,
                     piece_index,
-                    crate::services::piece_manager::PiecePriority::Urgent,
+                    std::time::Instant::now(),
                     session_id.to_string(),
                 ).await?;
 
@@ -191,49 +330,52 @@ impl StreamingBuffer {
         tokio::spawn(async move {
             loop {
                 // Check if session still exists and is active
-                let (torrent_id, current_pos, buffer_ahead) = {
+                let (torrent_id, file_offset, file_size, needs_probe) = {
                     let buffers_guard = buffers.lock().unwrap();
                     if let Some(buffer) = buffers_guard.get(&session_id) {
                         // Check if session is still active (accessed within last 5 minutes)
                         if buffer.last_access.elapsed() > Duration::from_secs(300) {
                             break; // Session inactive, stop buffering
                         }
-                        (buffer.torrent_id, buffer.current_position, buffer.buffer_ahead_pieces)
+                        (buffer.torrent_id, buffer.file_offset, buffer.file_size, buffer.bitrate_source != BitrateSource::Container)
                     } else {
                         break; // Session not found, stop buffering
                     }
                 };
 
-                // Get torrent info
-                if let Ok(Some(torrent)) = torrent_repository.find_by_id(torrent_id).await {
-                    let piece_size = torrent.piece_length as u64;
-                    let current_piece = (current_pos / piece_size) as usize;
-
-                    // Prefetch pieces ahead
-                    for i in 0..buffer_ahead {
-                        let piece_index = current_piece + i;
-                        if piece_index < torrent.piece_count as usize {
-                            // Check if piece is already available
-                            if let Ok(false) = piece_manager.is_piece_available(torrent_id, piece_index).await {
-                                // Request piece with high priority for buffering
-                                let _ = piece_manager.request_piece(
-                                    torrent_id,
-                                    piece_index,
-                                    crate::services::piece_manager::PiecePriority::High,
-                                    session_id.clone(),
-                                ).await;
-                            }
+                let probed = if needs_probe {
+                    Self::probe_bitrate(&piece_manager, torrent_repository.as_ref(), torrent_id, file_offset, file_size).await
+                } else {
+                    None
+                };
+
+                let (piece_size, current_pos, window_end, bitrate) = {
+                    let mut buffers_guard = buffers.lock().unwrap();
+                    let Some(buffer) = buffers_guard.get_mut(&session_id) else {
+                        break;
+                    };
+                    match probed {
+                        Some(bitrate) => {
+                            println!("🎞️ Stream {} plays at ~{} KB/s", session_id, bitrate / 1024);
+                            buffer.bitrate = bitrate;
+                            buffer.bitrate_source = BitrateSource::Container;
                         }
+                        None => buffer.refresh_bitrate(std::time::Instant::now()),
                     }
-                }
+                    let position = buffer.current_position;
+                    (buffer.piece_size, position, buffer.read_ahead_end(position), buffer.bitrate)
+                };
+
+                // Prefetch pieces ahead, each due when playback reaches it
+                Self::request_window(&piece_manager, torrent_id, &session_id, piece_size, current_pos, window_end, bitrate).await;
 
                 // Wait before next buffering cycle
                 tokio::time::sleep(Duration::from_secs(1)).await;
             }
 
-            // Clean up session buffer when done
-            let mut buffers_guard = buffers.lock().unwrap();
-            buffers_guard.remove(&session_id);
+            // Clean up session buffer when done; nobody is waiting for its pieces
+            buffers.lock().unwrap().remove(&session_id);
+            piece_manager.cancel_requests(&session_id);
         });
 
         Ok(())
@@ -262,6 +404,12 @@ impl StreamingBuffer {
         Ok(())
     }
 
+    /// Stop buffering for a session and release the pieces it requested
+    pub fn close_session(&self, session_id: &str) {
+        self.buffers.lock().unwrap().remove(session_id);
+        self.piece_manager.cancel_requests(session_id);
+    }
+
     /// Clean up inactive sessions
     pub async fn cleanup_inactive_sessions(&self) {
         let mut buffers = self.buffers.lock().unwrap();
@@ -2,7 +2,9 @@ use crate::entities::{FileInfo, StreamRange, StreamSession, Torrent};
 use crate::errors::DomainError;
 use crate::repositories::TorrentRepository;
 use crate::services::piece_manager::PieceManager;
-use crate::services::stream_prioritizer::StreamPrioritizer;
+use crate::services::container::ContainerKind;
+use crate::services::priority_aggregator::DEFAULT_WEIGHT;
+use crate::services::stream_prioritizer::{StreamPrioritizer, DEFAULT_BITRATE};
 use crate::services::streaming_buffer::StreamingBuffer;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
@@ -40,6 +42,9 @@ pub trait StreamingService: Send + Sync {
     /// Stream file content with range support
     async fn stream_content(&self, session_id: &str, range: Option<StreamRange>) -> Result<Vec<u8>, DomainError>;
     
+    /// Give a session `weight` times the bandwidth of the torrent's other streams
+    async fn set_session_weight(&self, session_id: &str, weight: u32) -> Result<StreamSession, DomainError>;
+
     /// Close streaming session
     async fn close_stream_session(&self, session_id: &str) -> Result<(), DomainError>;
     
@@ -153,19 +158,35 @@ impl StreamingService for StreamingServiceImpl {
             started_at: now,
             last_accessed: now,
             bytes_served: 0,
+            weight: DEFAULT_WEIGHT,
         };
 
         // Initialize streaming buffer for this session
         self.streaming_buffer.initialize_session_buffer(&session, file_info.offset as u64).await?;
 
-        // Start piece prioritization for streaming
+        // Start piece prioritization for streaming, paced by the file's bitrate
+        let bitrate = self.streaming_buffer.bitrate(&session_id)
+            .map_or(DEFAULT_BITRATE, |(bitrate, _)| bitrate);
         self.stream_prioritizer.prioritize_sequential(
             torrent_id,
             file_info.offset as u64,
             file_info.size as u64,
+            bitrate,
             session_id.clone(),
         ).await?;
 
+        // Players read the container's headers and index before playing;
+        // requested last so these claims replace the sequential ones
+        let container = ContainerKind::from_file_name(&file_info.name);
+        let pinned = self.stream_prioritizer.prioritize_head_and_tail(
+            torrent_id,
+            container,
+            file_info.offset as u64,
+            file_info.size as u64,
+            &session_id,
+        ).await?;
+        self.streaming_buffer.pin_pieces(&session_id, pinned);
+
         let mut sessions = self.sessions.lock().unwrap();
         sessions.insert(session_id.clone(), session.clone());
 
@@ -237,10 +258,25 @@ impl StreamingService for StreamingServiceImpl {
         Ok(content)
     }
 
+    async fn set_session_weight(&self, session_id: &str, weight: u32) -> Result<StreamSession, DomainError> {
+        if weight == 0 {
+            return Err(DomainError::ValidationError("Stream weight must be at least 1".to_string()));
+        }
+        let session = {
+            let mut sessions = self.sessions.lock().unwrap();
+            let session = sessions.get_mut(session_id)
+                .ok_or_else(|| DomainError::NotFound(format!("Session {} not found", session_id)))?;
+            session.weight = weight;
+            session.clone()
+        };
+        self.piece_manager.set_request_weight(session.torrent_id, session_id, weight);
+        Ok(session)
+    }
+
     async fn close_stream_session(&self, session_id: &str) -> Result<(), DomainError> {
-        let mut sessions = self.sessions.lock().unwrap();
-        sessions.remove(session_id)
+        self.sessions.lock().unwrap().remove(session_id)
             .ok_or_else(|| DomainError::NotFound(format!("Session {} not found", session_id)))?;
+        self.streaming_buffer.close_session(session_id);
         Ok(())
     }
 
@@ -1,13 +1,24 @@
-use crate::entities::{Piece, Torrent, TorrentStatus, Tracker};
+use crate::entities::{FilePriority, FileSelection, Piece, StorageKind, Torrent, TorrentFile, TorrentStatus, Tracker};
 use crate::errors::DomainError;
-use crate::repositories::{PieceRepository, TorrentRepository, TrackerRepository};
+use crate::repositories::{PieceRepository, TorrentFileRepository, TorrentRepository, TrackerRepository};
+use crate::services::connection_manager::ConnectionManager;
+use crate::services::disk_space::{mib, DiskFullPolicy, DiskPolicy, Preallocation};
+use crate::services::file_priorities::PiecePriorities;
+use crate::services::storage::TorrentStorage;
 use std::sync::Arc;
 
+/// How many pieces a recheck hashes between progress reports
+const RECHECK_REPORT_INTERVAL: usize = 64;
+
 /// Main torrent service that orchestrates the torrent flow
 pub struct TorrentService {
     torrent_repository: Arc<dyn TorrentRepository>,
     piece_repository: Arc<dyn PieceRepository>,
     tracker_repository: Arc<dyn TrackerRepository>,
+    torrent_file_repository: Arc<dyn TorrentFileRepository>,
+    storage: Arc<TorrentStorage>,
+    connection_manager: Option<Arc<ConnectionManager>>,
+    disk_policy: DiskPolicy,
 }
 
 impl TorrentService {
@@ -15,14 +26,32 @@ impl TorrentService {
         torrent_repository: Arc<dyn TorrentRepository>,
         piece_repository: Arc<dyn PieceRepository>,
         tracker_repository: Arc<dyn TrackerRepository>,
+        torrent_file_repository: Arc<dyn TorrentFileRepository>,
+        storage: Arc<TorrentStorage>,
     ) -> Self {
         Self {
             torrent_repository,
             piece_repository,
             tracker_repository,
+            torrent_file_repository,
+            storage,
+            connection_manager: None,
+            disk_policy: DiskPolicy::default(),
         }
     }
 
+    /// Close the peer connections of torrents that are paused or removed
+    pub fn with_connection_manager(mut self, connection_manager: Arc<ConnectionManager>) -> Self {
+        self.connection_manager = Some(connection_manager);
+        self
+    }
+
+    /// Preallocate, check free space and pause on low space per `disk_policy`
+    pub fn with_disk_policy(mut self, disk_policy: DiskPolicy) -> Self {
+        self.disk_policy = disk_policy;
+        self
+    }
+
     /// Parse .torrent file and create Torrent entity
     pub async fn parse_torrent_file(&self, torrent_data: Vec<u8>) -> Result<Torrent, DomainError> {
         use bip_metainfo::Metainfo;
@@ -47,8 +76,7 @@ impl TorrentService {
         
         let piece_length = info.piece_length() as i32;
         
-        // Calculate total length - simplified approach
-        let total_length = info.piece_length() as u64 * info.pieces().count() as u64;
+        let total_length: u64 = info.files().map(|file| file.length()).sum();
         
         let num_pieces = info.pieces().count() as i32;
 
@@ -94,22 +122,82 @@ impl TorrentService {
         Ok(tracker_urls)
     }
 
+    /// Extract the file list from torrent metainfo, every file at normal priority
+    pub fn extract_files(&self, torrent_id: i32, torrent_data: &[u8]) -> Result<Vec<TorrentFile>, DomainError> {
+        use bip_metainfo::Metainfo;
+
+        let metainfo = Metainfo::from_bytes(torrent_data)
+            .map_err(|e| DomainError::ValidationError(format!("Invalid torrent file: {}", e)))?;
+
+        let mut offset = 0i64;
+        let files = metainfo.info().files().enumerate().map(|(index, file)| {
+            let path = file.path().iter().map(|part| part.to_string_lossy()).collect::<Vec<_>>().join("/");
+            let torrent_file = TorrentFile::new(torrent_id, index as i32, path, file.length() as i64, offset);
+            offset += file.length() as i64;
+            torrent_file
+        });
+        Ok(files.collect())
+    }
+
     /// Add a new torrent from .torrent file data (includes parsing and tracker extraction)
     pub async fn add_torrent_from_file(&self, torrent_data: Vec<u8>) -> Result<Torrent, DomainError> {
+        self.add_torrent_from_file_with(torrent_data, &FileSelection::default(), StorageKind::Disk).await
+    }
+
+    /// Add a new torrent from .torrent file data, downloading only the files
+    /// `selection` wants and keeping the data in `storage`
+    pub async fn add_torrent_from_file_with(
+        &self,
+        torrent_data: Vec<u8>,
+        selection: &FileSelection,
+        storage: StorageKind,
+    ) -> Result<Torrent, DomainError> {
         // Parse the torrent file
-        let torrent = self.parse_torrent_file(torrent_data.clone()).await?;
+        let mut torrent = self.parse_torrent_file(torrent_data.clone()).await?;
+        torrent.storage = storage;
+
+        let mut files = self.extract_files(0, &torrent_data)?;
+        let out_of_range = selection.priorities.keys()
+            .chain(selection.only.iter().flatten())
+            .find(|file_index| **file_index >= files.len());
+        if let Some(file_index) = out_of_range {
+            return Err(DomainError::ValidationError(format!(
+                "Torrent has {} files, no file {}", files.len(), file_index
+            )));
+        }
         
         // Check if torrent already exists
-        if let Some(_) = self
+        if self
             .torrent_repository
             .find_by_info_hash(&torrent.info_hash)
             .await?
+            .is_some()
         {
             return Err(DomainError::ValidationError(
                 "Torrent already exists".to_string(),
             ));
         }
 
+        // Make sure the wanted files fit on disk; they are preallocated once
+        // the download starts, so adding stays quick
+        let fits = match storage {
+            StorageKind::Disk => {
+                let needed = if files.is_empty() {
+                    torrent.total_size as u64
+                } else {
+                    files.iter()
+                        .filter(|file| selection.priority_for(file.file_index as usize).is_wanted())
+                        .map(|file| file.length as u64)
+                        .sum()
+                };
+                self.check_space(&torrent.name, needed).await?
+            }
+            StorageKind::Memory => true,
+        };
+        if !fits {
+            torrent.set_status(TorrentStatus::Queued);
+        }
+
         // Save the torrent
         let saved_torrent = self.torrent_repository.save(&torrent).await?;
 
@@ -123,16 +211,167 @@ impl TorrentService {
         // Initialize pieces from torrent file
         self.initialize_pieces_from_torrent(saved_torrent.id.unwrap_or(0), &torrent_data).await?;
 
+        for file in &mut files {
+            file.torrent_id = saved_torrent.id.unwrap_or(0);
+            file.priority = selection.priority_for(file.file_index as usize);
+        }
+        self.torrent_file_repository.save_batch(&files).await?;
+        let skipped = files.iter().filter(|file| !file.priority.is_wanted()).count();
+        if skipped > 0 {
+            println!("⏭️  Skipping {} of {} files", skipped, files.len());
+        }
+
         Ok(saved_torrent)
     }
 
+    /// Whether `needed` more bytes leave the minimum free space on disk.
+    /// When they do not, the torrent is refused or, under
+    /// `DiskFullPolicy::Queue`, `false` tells the caller to queue it.
+    async fn check_space(&self, name: &str, needed: u64) -> Result<bool, DomainError> {
+        let Some(available) = self.uncommitted_space().await? else {
+            return Ok(true);
+        };
+        if self.disk_policy.fits(needed, available) {
+            return Ok(true);
+        }
+
+        let message = format!(
+            "{} needs {} MiB, {} MiB free once other downloads finish and {} MiB must stay free",
+            name, mib(needed), mib(available), mib(self.disk_policy.min_free_space)
+        );
+        match self.disk_policy.when_full {
+            DiskFullPolicy::Refuse => Err(DomainError::InsufficientSpace(message)),
+            DiskFullPolicy::Queue => {
+                println!("⏳ Queued: {}", message);
+                Ok(false)
+            }
+        }
+    }
+
+    /// Take queued torrents out of the queue, oldest first, as long as they
+    /// fit on disk. Returns the ids of those that can start downloading.
+    pub async fn start_queued(&self) -> Result<Vec<i32>, DomainError> {
+        let mut queued: Vec<Torrent> = self.torrent_repository.find_all().await?
+            .into_iter()
+            .filter(|torrent| torrent.status == TorrentStatus::Queued)
+            .collect();
+        queued.sort_by_key(|torrent| torrent.created_at);
+
+        let mut started = Vec::new();
+        for mut torrent in queued {
+            let Some(torrent_id) = torrent.id else {
+                continue;
+            };
+            let needed = self.storage.wanted_size(torrent_id).await?;
+            // Torrents started earlier in this loop already count as committed
+            let fits = match self.uncommitted_space().await? {
+                Some(available) => self.disk_policy.fits(needed, available),
+                None => true,
+            };
+            if !fits {
+                // Later torrents wait their turn too
+                break;
+            }
+
+            torrent.set_status(TorrentStatus::Connecting);
+            self.torrent_repository.update(&torrent).await?;
+            println!("▶️  Unqueued torrent: {} (ID: {})", torrent.name, torrent_id);
+            started.push(torrent_id);
+        }
+        Ok(started)
+    }
+
+    /// Free disk space minus what incomplete torrents on disk are still going
+    /// to write. Sparse or growing files only take their space as pieces
+    /// arrive, so free space alone would let two large torrents in where
+    /// only one fits. `None` when the disk cannot tell.
+    async fn uncommitted_space(&self) -> Result<Option<u64>, DomainError> {
+        let Some(available) = self.storage.available_space(StorageKind::Disk).await? else {
+            return Ok(None);
+        };
+
+        let mut committed = 0u64;
+        for torrent in self.torrent_repository.find_all().await? {
+            let Some(torrent_id) = torrent.id else {
+                continue;
+            };
+            if torrent.storage != StorageKind::Disk || torrent.status == TorrentStatus::Queued || torrent.is_complete() {
+                continue;
+            }
+            committed += match self.disk_policy.preallocation {
+                // Fully preallocated bytes are already gone from free space
+                Preallocation::Full => self.storage.unwritten_size(torrent_id).await?,
+                Preallocation::Sparse | Preallocation::Off => {
+                    let wanted = self.storage.wanted_size(torrent_id).await?;
+                    (wanted as f64 * (1.0 - torrent.progress as f64)) as u64
+                }
+            };
+        }
+        Ok(Some(available.saturating_sub(committed)))
+    }
+
+    /// Allocate the wanted files of a torrent per the disk policy. A failure
+    /// stops the torrent with an error status saying why.
+    pub async fn preallocate(&self, torrent_id: i32) -> Result<(), DomainError> {
+        let Err(e) = self.storage.preallocate(torrent_id, self.disk_policy.preallocation).await else {
+            return Ok(());
+        };
+
+        let mut torrent = self.get_torrent(torrent_id).await?;
+        torrent.set_status(TorrentStatus::Error(format!("Preallocation failed: {}", e)));
+        self.torrent_repository.update(&torrent).await?;
+        eprintln!("💾 Failed to preallocate {} (ID: {}): {}", torrent.name, torrent_id, e);
+        Err(e)
+    }
+
+    /// Whether free disk space is below the minimum of the disk policy
+    pub async fn is_low_on_space(&self) -> Result<bool, DomainError> {
+        Ok(match self.storage.available_space(StorageKind::Disk).await? {
+            Some(available) => self.disk_policy.is_low(available),
+            None => false,
+        })
+    }
+
+    /// Stop every incomplete torrent writing to disk when free space drops
+    /// below the minimum, leaving an error status that says why, and close
+    /// its peer connections. Returns the ids of the torrents stopped.
+    pub async fn pause_if_low_on_space(&self) -> Result<Vec<i32>, DomainError> {
+        let Some(available) = self.storage.available_space(StorageKind::Disk).await? else {
+            return Ok(Vec::new());
+        };
+        if !self.disk_policy.is_low(available) {
+            return Ok(Vec::new());
+        }
+
+        let message = format!(
+            "Paused: only {} MiB free on disk, below the {} MiB minimum",
+            mib(available), mib(self.disk_policy.min_free_space)
+        );
+        let mut paused = Vec::new();
+        for mut torrent in self.torrent_repository.find_all().await? {
+            let writing = matches!(torrent.status, TorrentStatus::Connecting | TorrentStatus::Downloading);
+            if !writing || torrent.storage != StorageKind::Disk || torrent.is_complete() {
+                continue;
+            }
+            torrent.set_status(TorrentStatus::Error(message.clone()));
+            self.torrent_repository.update(&torrent).await?;
+            eprintln!("💾 {} (ID: {:?}): {}", torrent.name, torrent.id, message);
+            if let Some(torrent_id) = torrent.id {
+                self.disconnect(torrent_id);
+                paused.push(torrent_id);
+            }
+        }
+        Ok(paused)
+    }
+
     /// Add a pre-parsed torrent to the system
     pub async fn add_torrent(&self, torrent: Torrent) -> Result<Torrent, DomainError> {
         // Check if torrent already exists
-        if let Some(_) = self
+        if self
             .torrent_repository
             .find_by_info_hash(&torrent.info_hash)
             .await?
+            .is_some()
         {
             return Err(DomainError::ValidationError(
                 "Torrent already exists".to_string(),
@@ -183,6 +422,9 @@ impl TorrentService {
 
         torrent.set_status(TorrentStatus::Connecting);
         self.torrent_repository.update(&torrent).await?;
+        if !torrent.is_complete() {
+            self.preallocate(torrent_id).await?;
+        }
 
         // Verify pieces exist
         let pieces = self.piece_repository.find_by_torrent_id(torrent_id).await?;
@@ -219,6 +461,7 @@ impl TorrentService {
 
         torrent.set_status(TorrentStatus::Paused);
         self.torrent_repository.update(&torrent).await?;
+        self.disconnect(torrent_id);
 
         Ok(())
     }
@@ -243,26 +486,15 @@ impl TorrentService {
         torrent_id: i32,
         delete_files: bool,
     ) -> Result<(), DomainError> {
-        let torrent = self
+        self
             .torrent_repository
             .find_by_id(torrent_id)
             .await?
             .ok_or(DomainError::TorrentNotFound(torrent_id))?;
+        self.disconnect(torrent_id);
 
         if delete_files {
-            if let Some(file_path) = &torrent.file_path {
-                // Delete the actual downloaded file
-                match tokio::fs::remove_file(file_path).await {
-                    Ok(_) => println!("🗑️  Deleted file: {}", file_path),
-                    Err(e) => eprintln!("⚠️  Failed to delete file {}: {}", file_path, e),
-                }
-
-                // Also try to delete any partial download files
-                let partial_path = format!("{}.part", file_path);
-                if tokio::fs::metadata(&partial_path).await.is_ok() {
-                    let _ = tokio::fs::remove_file(&partial_path).await;
-                }
-            }
+            self.storage.delete(torrent_id).await?;
         }
 
         self.torrent_repository.delete(torrent_id).await?;
@@ -270,6 +502,108 @@ impl TorrentService {
         Ok(())
     }
 
+    /// Drop every peer connection of a torrent so no more pieces move
+    fn disconnect(&self, torrent_id: i32) {
+        if let Some(connection_manager) = &self.connection_manager {
+            connection_manager.disconnect_torrent(torrent_id);
+        }
+    }
+
+    /// Hash every piece of a torrent from storage again, e.g. after its files
+    /// were changed or moved by hand, and make the piece state match. While
+    /// it runs the torrent is `Checking` and its progress is the share of
+    /// pieces checked; afterwards both are restored from the new piece state.
+    pub async fn recheck(&self, torrent_id: i32) -> Result<Torrent, DomainError> {
+        let recheck = self.begin_recheck(torrent_id).await?;
+        self.finish_recheck(recheck).await
+    }
+
+    /// Mark a torrent `Checking` with no progress, refusing one that already
+    /// is, so a caller can return before the pieces are hashed
+    pub async fn begin_recheck(&self, torrent_id: i32) -> Result<Recheck, DomainError> {
+        let mut torrent = self.get_torrent(torrent_id).await?;
+        if torrent.status == TorrentStatus::Checking {
+            return Err(DomainError::ValidationError(format!("Torrent {} is already being checked", torrent_id)));
+        }
+        let previous = torrent.status.clone();
+        torrent.set_status(TorrentStatus::Checking);
+        torrent.progress = 0.0;
+        let torrent = self.torrent_repository.update(&torrent).await?;
+        Ok(Recheck { torrent, previous })
+    }
+
+    /// Hash the pieces of a torrent marked by `begin_recheck` and give it
+    /// its status back, or keep one it was given meanwhile, e.g. by a pause
+    pub async fn finish_recheck(&self, recheck: Recheck) -> Result<Torrent, DomainError> {
+        let Recheck { torrent, previous } = recheck;
+        let torrent_id = torrent.id.ok_or_else(|| DomainError::ValidationError("Torrent has no id".to_string()))?;
+
+        let result = self.check_pieces(&torrent).await;
+        if result.is_ok() {
+            self.storage.save_snapshot(torrent_id).await?;
+        }
+
+        let mut torrent = self.get_torrent(torrent_id).await?;
+        let previous = match torrent.status {
+            TorrentStatus::Checking => previous,
+            _ => torrent.status.clone(),
+        };
+        let (downloaded, wanted) = self.piece_counts(&torrent).await?;
+        torrent.update_progress(downloaded, wanted);
+        let complete = torrent.progress >= 1.0;
+        let status = match previous {
+            // Stopped torrents stay stopped whatever was found
+            TorrentStatus::Paused | TorrentStatus::Queued | TorrentStatus::Error(_) => previous.clone(),
+            TorrentStatus::Completed | TorrentStatus::Seeding if complete => previous.clone(),
+            _ if complete => TorrentStatus::Completed,
+            _ => TorrentStatus::Downloading,
+        };
+        torrent.set_status(status);
+        let torrent = self.torrent_repository.update(&torrent).await?;
+        if complete && !matches!(previous, TorrentStatus::Completed | TorrentStatus::Seeding) {
+            self.storage.flush(torrent_id).await?;
+        }
+
+        let changed = result?;
+        println!("✅ Rechecked {}: {} pieces changed, {:.1}% complete", torrent.name, changed, torrent.progress * 100.0);
+        Ok(torrent)
+    }
+
+    /// Compare every piece with storage, saving the ones that changed in one
+    /// batch. Returns how many changed.
+    async fn check_pieces(&self, torrent: &Torrent) -> Result<usize, DomainError> {
+        let torrent_id = torrent.id.ok_or_else(|| DomainError::ValidationError("Torrent has no id".to_string()))?;
+        let mut pieces = self.piece_repository.find_by_torrent_id(torrent_id).await?;
+        pieces.sort_by_key(|piece| piece.piece_index);
+        let total = pieces.len();
+
+        let mut changed = Vec::new();
+        for (checked, mut piece) in pieces.into_iter().enumerate() {
+            let intact = self.storage.holds_piece(torrent, &piece).await;
+            if intact && !piece.is_complete() {
+                piece.mark_downloaded();
+                piece.mark_verified(true);
+                changed.push(piece);
+            } else if !intact && piece.is_complete() {
+                piece.mark_verified(false);
+                changed.push(piece);
+            }
+
+            if (checked + 1) % RECHECK_REPORT_INTERVAL == 0 {
+                // Only the progress is ours to change; the status may have moved on
+                let mut current = self.get_torrent(torrent_id).await?;
+                if current.status == TorrentStatus::Checking {
+                    current.progress = (checked + 1) as f32 / total as f32;
+                    self.torrent_repository.update(&current).await?;
+                }
+                println!("🔍 Checking {}: {}/{} pieces", torrent.name, checked + 1, total);
+            }
+        }
+
+        self.piece_repository.update_batch(&changed).await?;
+        Ok(changed.len())
+    }
+
     /// Get torrent by ID
     pub async fn get_torrent(&self, torrent_id: i32) -> Result<Torrent, DomainError> {
         self.torrent_repository
@@ -288,12 +622,64 @@ impl TorrentService {
         self.torrent_repository.find_active().await
     }
 
-    /// Update torrent progress based on downloaded pieces
+    /// Files of a torrent with their priorities
+    pub async fn get_files(&self, torrent_id: i32) -> Result<Vec<TorrentFile>, DomainError> {
+        self.get_torrent(torrent_id).await?;
+        self.torrent_file_repository.find_by_torrent_id(torrent_id).await
+    }
+
+    /// Change how much we want one file; skipped files are not downloaded
+    /// and do not count towards progress
+    pub async fn set_file_priority(
+        &self,
+        torrent_id: i32,
+        file_index: i32,
+        priority: FilePriority,
+    ) -> Result<TorrentFile, DomainError> {
+        let mut file = self.get_files(torrent_id).await?
+            .into_iter()
+            .find(|file| file.file_index == file_index)
+            .ok_or_else(|| DomainError::NotFound(format!("File {} of torrent {} not found", file_index, torrent_id)))?;
+
+        file.priority = priority;
+        let file = self.torrent_file_repository.update(&file).await?;
+        self.update_progress(torrent_id).await?;
+
+        Ok(file)
+    }
+
+    /// Update torrent progress based on the downloaded pieces of wanted files
     pub async fn update_progress(&self, torrent_id: i32) -> Result<Torrent, DomainError> {
         let mut torrent = self.get_torrent(torrent_id).await?;
-        let downloaded_pieces = self.piece_repository.count_downloaded(torrent_id).await?;
+        let (downloaded, wanted) = self.piece_counts(&torrent).await?;
+
+        let was_complete = torrent.is_complete();
+        torrent.update_progress(downloaded, wanted);
+        let torrent = self.torrent_repository.update(&torrent).await?;
+        if !was_complete && torrent.is_complete() {
+            self.storage.flush(torrent_id).await?;
+        }
+        Ok(torrent)
+    }
+
+    /// Downloaded and wanted piece counts of a torrent, leaving out pieces
+    /// that only belong to skipped files
+    async fn piece_counts(&self, torrent: &Torrent) -> Result<(i32, i32), DomainError> {
+        let torrent_id = torrent.id.ok_or_else(|| DomainError::ValidationError("Torrent has no id".to_string()))?;
+        let files = self.torrent_file_repository.find_by_torrent_id(torrent_id).await?;
+        let priorities = PiecePriorities::from_files(torrent, &files);
 
-        torrent.update_progress(downloaded_pieces);
-        self.torrent_repository.update(&torrent).await
+        let pieces = self.piece_repository.find_by_torrent_id(torrent_id).await?;
+        let downloaded_pieces = pieces.iter()
+            .filter(|piece| piece.is_complete() && priorities.is_wanted(piece.piece_index as usize))
+            .count();
+        Ok((downloaded_pieces as i32, priorities.wanted_count() as i32))
     }
 }
+
+/// A torrent marked `Checking` by `TorrentService::begin_recheck`, with the
+/// status it goes back to afterwards
+pub struct Recheck {
+    pub torrent: Torrent,
+    previous: TorrentStatus,
+}
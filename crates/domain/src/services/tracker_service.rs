@@ -1,9 +1,11 @@
 use crate::entities::{Peer, Tracker};
 use crate::errors::DomainError;
+use crate::protocol::generate_peer_id;
 use crate::repositories::{PeerRepository, TorrentRepository, TrackerRepository};
 use std::sync::Arc;
 use std::collections::HashMap;
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU16, Ordering};
 use tokio::net::UdpSocket;
 
 /// Bencoded value types for parsing tracker responses
@@ -114,12 +116,17 @@ impl<'a> BencodedParser<'a> {
     }
 }
 
+/// Port announced to trackers until the peer listener reports the one it bound
+pub const DEFAULT_LISTEN_PORT: u16 = 6881;
+
 /// Service for managing tracker communications
 /// Handles: connect to tracker(s) to get peers
 pub struct TrackerService {
     tracker_repository: Arc<dyn TrackerRepository>,
     peer_repository: Arc<dyn PeerRepository>,
     torrent_repository: Arc<dyn TorrentRepository>,
+    listen_port: AtomicU16,
+    peer_id: [u8; 20],
 }
 
 impl TrackerService {
@@ -132,9 +139,32 @@ impl TrackerService {
             tracker_repository,
             peer_repository,
             torrent_repository,
+            listen_port: AtomicU16::new(DEFAULT_LISTEN_PORT),
+            peer_id: generate_peer_id(),
         }
     }
 
+    /// Announce with the peer_id our handshakes carry, so trackers and peers
+    /// see the same client
+    pub fn with_peer_id(mut self, peer_id: [u8; 20]) -> Self {
+        self.peer_id = peer_id;
+        self
+    }
+
+    pub fn peer_id(&self) -> [u8; 20] {
+        self.peer_id
+    }
+
+    /// Port we accept peer connections on, as reported to trackers
+    pub fn listen_port(&self) -> u16 {
+        self.listen_port.load(Ordering::Relaxed)
+    }
+
+    /// Set once the peer listener is bound, so announces advertise the real port
+    pub fn set_listen_port(&self, port: u16) {
+        self.listen_port.store(port, Ordering::Relaxed);
+    }
+
     /// Announce to all trackers for a torrent
     /// This implements: connect to tracker(s) to get peers
     pub async fn announce_to_trackers(
@@ -146,7 +176,7 @@ impl TrackerService {
         let mut all_peers: Vec<Peer> = Vec::new();
 
         for mut tracker in trackers {
-            match self.announce_to_tracker(&mut tracker, info_hash).await {
+            match self.announce_to_tracker(&tracker, info_hash).await {
                 Ok(peers) => {
                     // Save the peers to the repository
                     let saved_peers: Vec<Peer> = self.peer_repository.save_batch(&peers).await?;
@@ -208,10 +238,8 @@ impl TrackerService {
         let info_hash_encoded =
             percent_encoding::percent_encode(&info_hash_bytes, percent_encoding::NON_ALPHANUMERIC);
 
-        // Generate peer_id (20 bytes)
-        let peer_id = format!("-RS0001-{:012}", rand::random::<u64>());
         let peer_id_encoded = percent_encoding::percent_encode(
-            peer_id.as_bytes(),
+            &self.peer_id,
             percent_encoding::NON_ALPHANUMERIC,
         );
 
@@ -234,7 +262,7 @@ impl TrackerService {
         url.query_pairs_mut()
             .append_pair("info_hash", &info_hash_encoded.to_string())
             .append_pair("peer_id", &peer_id_encoded.to_string())
-            .append_pair("port", "6881")
+            .append_pair("port", &self.listen_port().to_string())
             .append_pair("uploaded", "0")
             .append_pair("downloaded", "0")
             .append_pair("left", &remaining_bytes.to_string())
@@ -313,8 +341,8 @@ impl TrackerService {
         // 8       32-bit integer  action          0 (connect)
         // 12      32-bit integer  transaction_id  Random
         
-        let mut rng = rand::thread_rng();
-        let transaction_id: u32 = rng.gen();
+        // Not held across awaits, so announcing can run on a spawned task
+        let transaction_id: u32 = rand::thread_rng().gen();
         
         let mut connect_request = Vec::with_capacity(16);
         connect_request.extend_from_slice(&0x41727101980u64.to_be_bytes()); // protocol_id
@@ -373,8 +401,7 @@ impl TrackerService {
     ) -> Result<Vec<Peer>, DomainError> {
         use rand::Rng;
         
-        let mut rng = rand::thread_rng();
-        let transaction_id: u32 = rng.gen();
+        let transaction_id: u32 = rand::thread_rng().gen();
         
         // Convert info_hash from hex string to bytes
         let info_hash_bytes = hex::decode(info_hash)
@@ -384,8 +411,7 @@ impl TrackerService {
             return Err(DomainError::TrackerError("Info hash must be 20 bytes".to_string()));
         }
 
-        // Generate random peer_id (20 bytes)
-        let peer_id: [u8; 20] = rng.gen();
+        let peer_id = self.peer_id;
 
         // Announce request packet:
         // Offset  Size            Name            Value
@@ -414,9 +440,9 @@ impl TrackerService {
         announce_request.extend_from_slice(&0u64.to_be_bytes()); // uploaded
         announce_request.extend_from_slice(&2u32.to_be_bytes()); // event (2 = started)
         announce_request.extend_from_slice(&0u32.to_be_bytes()); // IP (0 = use sender)
-        announce_request.extend_from_slice(&rng.gen::<u32>().to_be_bytes()); // key
+        announce_request.extend_from_slice(&rand::thread_rng().gen::<u32>().to_be_bytes()); // key
         announce_request.extend_from_slice(&(-1i32 as u32).to_be_bytes()); // num_want (-1 = default)
-        announce_request.extend_from_slice(&6881u16.to_be_bytes()); // port
+        announce_request.extend_from_slice(&self.listen_port().to_be_bytes()); // port
 
         // Send announce request
         socket.send_to(&announce_request, tracker_addr).await
@@ -495,14 +521,14 @@ impl TrackerService {
         };
 
         // Check for failure message first
-        if let Some(BencodedValue::String(failure_reason)) = response_dict.get(&b"failure reason".to_vec()) {
+        if let Some(BencodedValue::String(failure_reason)) = response_dict.get(b"failure reason".as_slice()) {
             return Err(DomainError::TrackerError(
                 format!("Tracker error: {}", String::from_utf8_lossy(failure_reason))
             ));
         }
 
         // Extract peer list from the dictionary
-        let peers_value = response_dict.get(&b"peers".to_vec())
+        let peers_value = response_dict.get(b"peers".as_slice())
             .ok_or_else(|| DomainError::TrackerError("No peers found in tracker response".to_string()))?;
 
         let mut extracted_peers = Vec::new();
@@ -577,8 +603,8 @@ impl TrackerService {
         let torrents = self.torrent_repository.find_all().await?;
         
         for torrent in torrents {
-            // Skip completed or stopped torrents
-            if matches!(torrent.status, crate::entities::TorrentStatus::Completed | crate::entities::TorrentStatus::Paused) {
+            // Skip completed, stopped or queued torrents
+            if matches!(torrent.status, crate::entities::TorrentStatus::Completed | crate::entities::TorrentStatus::Paused | crate::entities::TorrentStatus::Queued) {
                 continue;
             }
 
@@ -0,0 +1,79 @@
+use domain::{bitrate_for, container_duration, read_ahead_bytes, ConsumptionRate};
+use std::time::{Duration, Instant};
+
+const GIB: u64 = 1024 * 1024 * 1024;
+
+fn mp4_box(kind: &[u8; 4], body: &[u8]) -> Vec<u8> {
+    let mut data = ((body.len() + 8) as u32).to_be_bytes().to_vec();
+    data.extend_from_slice(kind);
+    data.extend_from_slice(body);
+    data
+}
+
+/// `ftyp` followed by a `moov` whose version 0 `mvhd` runs `seconds` long
+fn mp4_header(seconds: u32) -> Vec<u8> {
+    let mut mvhd = vec![0u8; 12];
+    mvhd.extend_from_slice(&1000u32.to_be_bytes());
+    mvhd.extend_from_slice(&(seconds * 1000).to_be_bytes());
+    mvhd.extend_from_slice(&[0u8; 80]);
+
+    let mut header = mp4_box(b"ftyp", b"isom\0\0\0\0");
+    header.extend(mp4_box(b"moov", &mp4_box(b"mvhd", &mvhd)));
+    header.extend(mp4_box(b"mdat", &[0u8; 64]));
+    header
+}
+
+/// EBML header, then a Segment of unknown size holding Info with a
+/// millisecond TimecodeScale and a Duration in ticks
+fn mkv_header(milliseconds: f64) -> Vec<u8> {
+    let mut info = vec![0x2A, 0xD7, 0xB1, 0x83, 0x0F, 0x42, 0x40];
+    info.extend_from_slice(&[0x44, 0x89, 0x88]);
+    info.extend_from_slice(&milliseconds.to_be_bytes());
+
+    let mut header = vec![0x1A, 0x45, 0xDF, 0xA3, 0x80];
+    header.extend_from_slice(&[0x18, 0x53, 0x80, 0x67, 0x01, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]);
+    header.extend_from_slice(&[0x15, 0x49, 0xA9, 0x66, 0x80 | info.len() as u8]);
+    header.extend(info);
+    header.extend_from_slice(&[0x1F, 0x43, 0xB6, 0x75, 0x01, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]);
+    header
+}
+
+#[test]
+fn reads_duration_from_container_headers() {
+    assert_eq!(container_duration(&mp4_header(5400)), Some(Duration::from_secs(5400)));
+    assert_eq!(container_duration(&mkv_header(2_700_000.0)), Some(Duration::from_secs(2700)));
+
+    // moov at the end of the file, or a cut-off header, gives nothing
+    assert_eq!(container_duration(&mp4_box(b"ftyp", b"isom\0\0\0\0")), None);
+    assert_eq!(container_duration(&mkv_header(2_700_000.0)[..20]), None);
+    assert_eq!(container_duration(b"not a media file"), None);
+}
+
+#[test]
+fn higher_bitrates_buffer_further_ahead() {
+    let remux = bitrate_for(60 * GIB, Duration::from_secs(2 * 3600)).unwrap();
+    let episode = bitrate_for(GIB, Duration::from_secs(45 * 60)).unwrap();
+    assert!(remux > episode);
+
+    let cap = 512 * 1024 * 1024;
+    assert!(read_ahead_bytes(remux, cap) > read_ahead_bytes(episode, cap));
+    assert_eq!(read_ahead_bytes(remux, 64 * 1024 * 1024), 64 * 1024 * 1024);
+    assert_eq!(read_ahead_bytes(1, cap), 4 * 1024 * 1024);
+    assert_eq!(bitrate_for(GIB, Duration::ZERO), None);
+}
+
+#[test]
+fn consumption_rate_needs_time_and_restarts_on_seeks() {
+    let start = Instant::now();
+    let mut rate = ConsumptionRate::new();
+    rate.observe(0, 1_000_000, start);
+    rate.observe(1_000_000, 30_000_000, start + Duration::from_secs(10));
+    assert_eq!(rate.bitrate(start + Duration::from_secs(10)), None);
+
+    rate.observe(30_000_000, 40_000_000, start + Duration::from_secs(40));
+    assert_eq!(rate.bitrate(start + Duration::from_secs(40)), Some(1_000_000));
+
+    // Jumping far ahead starts a new measurement
+    rate.observe(GIB, GIB + 1_000_000, start + Duration::from_secs(41));
+    assert_eq!(rate.bitrate(start + Duration::from_secs(50)), None);
+}
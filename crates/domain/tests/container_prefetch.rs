@@ -0,0 +1,39 @@
+use domain::{pieces_for_range, ContainerKind};
+
+const MIB: u64 = 1024 * 1024;
+
+#[test]
+fn detects_containers_from_file_names() {
+    assert_eq!(ContainerKind::from_file_name("Movie.2019.1080p.MP4"), ContainerKind::Mp4);
+    assert_eq!(ContainerKind::from_file_name("clip.mov"), ContainerKind::Mp4);
+    assert_eq!(ContainerKind::from_file_name("Show/S01E01.mkv"), ContainerKind::Matroska);
+    assert_eq!(ContainerKind::from_file_name("talk.webm"), ContainerKind::Matroska);
+    assert_eq!(ContainerKind::from_file_name("old.avi"), ContainerKind::Avi);
+    assert_eq!(ContainerKind::from_file_name("broadcast.ts"), ContainerKind::Other);
+}
+
+#[test]
+fn prefetches_head_and_tail_by_container() {
+    // Second file of a torrent, 2 GiB at offset 100 MiB
+    let (offset, size) = (100 * MIB, 2048 * MIB);
+    let end = offset + size;
+
+    let mp4 = ContainerKind::Mp4.prefetch_ranges(offset, size);
+    assert_eq!(mp4, vec![offset..offset + 2 * MIB, end - size / 200..end]);
+
+    let mkv = ContainerKind::Matroska.prefetch_ranges(offset, size);
+    assert_eq!(mkv, vec![offset..offset + 2 * MIB, end - 4 * MIB..end]);
+
+    assert_eq!(ContainerKind::Other.prefetch_ranges(offset, size), vec![offset..offset + 2 * MIB]);
+
+    // A small file is fetched once, not twice
+    assert_eq!(ContainerKind::Mp4.prefetch_ranges(0, 3 * MIB), vec![0..2 * MIB, 2 * MIB..3 * MIB]);
+    assert_eq!(ContainerKind::Mp4.prefetch_ranges(0, MIB), vec![0..MIB]);
+}
+
+#[test]
+fn maps_byte_ranges_to_pieces() {
+    assert_eq!(pieces_for_range(&(0..MIB), MIB), 0..1);
+    assert_eq!(pieces_for_range(&(MIB - 1..MIB + 1), MIB), 0..2);
+    assert_eq!(pieces_for_range(&(5 * MIB..5 * MIB), MIB), 0..0);
+}
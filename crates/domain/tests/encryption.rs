@@ -0,0 +1,118 @@
+use domain::protocol::encryption::{self, Rc4};
+use domain::protocol::{EncryptionPolicy, Handshake, MessageCodec, PeerMessage};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+const INFO_HASH: [u8; 20] = [0x5Au8; 20];
+
+#[test]
+fn rc4_matches_reference_vector() {
+    let mut data = *b"Plaintext";
+    Rc4::new(b"Key").apply(&mut data);
+    assert_eq!(data, [0xBB, 0xF3, 0x16, 0xE8, 0xD9, 0x40, 0xAF, 0x0A, 0xD3]);
+}
+
+#[test]
+fn parses_policies() {
+    assert_eq!("Forced".parse::<EncryptionPolicy>().unwrap(), EncryptionPolicy::Forced);
+    assert_eq!("disabled".parse::<EncryptionPolicy>().unwrap(), EncryptionPolicy::Disabled);
+    assert!("sometimes".parse::<EncryptionPolicy>().is_err());
+    assert_eq!(EncryptionPolicy::default(), EncryptionPolicy::Enabled);
+}
+
+/// Two endpoints joined by a relay that records what the initiator puts on the wire
+fn recorded_pipe() -> (DuplexStream, DuplexStream, Arc<Mutex<Vec<u8>>>) {
+    let (initiator, relay_in) = tokio::io::duplex(1 << 16);
+    let (relay_out, responder) = tokio::io::duplex(1 << 16);
+    let wire = Arc::new(Mutex::new(Vec::new()));
+
+    let (mut in_read, mut in_write) = tokio::io::split(relay_in);
+    let (mut out_read, mut out_write) = tokio::io::split(relay_out);
+    let recorded = wire.clone();
+    tokio::spawn(async move {
+        let mut buf = [0u8; 4096];
+        while let Ok(n) = in_read.read(&mut buf).await {
+            if n == 0 || out_write.write_all(&buf[..n]).await.is_err() {
+                break;
+            }
+            recorded.lock().unwrap().extend_from_slice(&buf[..n]);
+        }
+    });
+    tokio::spawn(async move {
+        let _ = tokio::io::copy(&mut out_read, &mut in_write).await;
+    });
+
+    (initiator, responder, wire)
+}
+
+#[tokio::test]
+async fn encrypted_endpoints_exchange_handshake_and_messages() {
+    let (initiator, responder, wire) = recorded_pipe();
+
+    let (outbound, inbound) = tokio::join!(
+        encryption::initiate(initiator, INFO_HASH, EncryptionPolicy::Enabled),
+        encryption::accept(responder, &[[1u8; 20], INFO_HASH], EncryptionPolicy::Enabled),
+    );
+    let mut outbound = outbound.unwrap();
+    let (mut inbound, info_hash) = inbound.unwrap();
+    assert!(outbound.is_encrypted() && inbound.is_encrypted());
+    assert_eq!(info_hash, Some(INFO_HASH));
+
+    let codec = MessageCodec::default();
+    Handshake::new(INFO_HASH, [7u8; 20]).write_to(&mut outbound).await.unwrap();
+    codec.write_message(&mut outbound, &PeerMessage::Have { piece_index: 42 }).await.unwrap();
+    assert_eq!(Handshake::read_from(&mut inbound).await.unwrap().peer_id, [7u8; 20]);
+    assert_eq!(codec.read_message(&mut inbound).await.unwrap(), PeerMessage::Have { piece_index: 42 });
+
+    codec.write_message(&mut inbound, &PeerMessage::Unchoke).await.unwrap();
+    assert_eq!(codec.read_message(&mut outbound).await.unwrap(), PeerMessage::Unchoke);
+
+    // Neither the protocol name nor the info hash ever appears in the clear
+    let wire = wire.lock().unwrap();
+    assert!(!wire.windows(19).any(|window| window == b"BitTorrent protocol"));
+    assert!(!wire.windows(20).any(|window| window == INFO_HASH));
+}
+
+#[tokio::test]
+async fn plaintext_handshake_passes_through_enabled_responder() {
+    let (initiator, responder) = tokio::io::duplex(1 << 16);
+
+    let mut outbound = encryption::initiate(initiator, INFO_HASH, EncryptionPolicy::Disabled).await.unwrap();
+    assert!(!outbound.is_encrypted());
+    Handshake::new(INFO_HASH, [7u8; 20]).write_to(&mut outbound).await.unwrap();
+
+    let (mut inbound, info_hash) = encryption::accept(responder, &[INFO_HASH], EncryptionPolicy::Enabled)
+        .await
+        .unwrap();
+    assert!(!inbound.is_encrypted());
+    assert_eq!(info_hash, None);
+    // The bytes used to detect the plaintext handshake are replayed
+    assert_eq!(Handshake::read_from(&mut inbound).await.unwrap().info_hash, INFO_HASH);
+}
+
+#[tokio::test]
+async fn forced_responder_refuses_plaintext() {
+    let (mut initiator, responder) = tokio::io::duplex(1 << 16);
+    Handshake::new(INFO_HASH, [7u8; 20]).write_to(&mut initiator).await.unwrap();
+
+    assert!(encryption::accept(responder, &[INFO_HASH], EncryptionPolicy::Forced).await.is_err());
+}
+
+#[tokio::test]
+async fn refuses_encryption_when_disabled_or_torrent_unknown() {
+    let (initiator, responder) = tokio::io::duplex(1 << 16);
+    let (outbound, inbound) = tokio::join!(
+        encryption::initiate(initiator, INFO_HASH, EncryptionPolicy::Forced),
+        encryption::accept(responder, &[INFO_HASH], EncryptionPolicy::Disabled),
+    );
+    assert!(inbound.is_err());
+    assert!(outbound.is_err());
+
+    let (initiator, responder) = tokio::io::duplex(1 << 16);
+    let (outbound, inbound) = tokio::join!(
+        encryption::initiate(initiator, INFO_HASH, EncryptionPolicy::Forced),
+        encryption::accept(responder, &[[1u8; 20]], EncryptionPolicy::Forced),
+    );
+    assert!(inbound.is_err());
+    assert!(outbound.is_err());
+}
@@ -0,0 +1,116 @@
+use domain::protocol::{BlockRequest, MessageCodec, PeerMessage};
+use domain::services::{PeerConnection, PeerHandle, PieceDownload, BLOCK_SIZE};
+use domain::Peer;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::DuplexStream;
+
+const PIECE_LENGTH: u32 = 2 * BLOCK_SIZE;
+
+async fn unchoked_peer(port: u16) -> (PeerHandle, DuplexStream) {
+    let (local, mut remote) = tokio::io::duplex(1 << 20);
+    let peer = Peer::new(1, "127.0.0.1".to_string(), port);
+    let handle = PeerConnection::spawn(local, 1, peer, [7u8; 20], false, MessageCodec::default());
+    MessageCodec::default().write_message(&mut remote, &PeerMessage::Unchoke).await.unwrap();
+    (handle, remote)
+}
+
+/// Read until a message matching `wanted` arrives
+async fn expect(remote: &mut DuplexStream, wanted: impl Fn(&PeerMessage) -> bool) -> PeerMessage {
+    let read = async {
+        loop {
+            let message = MessageCodec::default().read_message(remote).await.unwrap();
+            if wanted(&message) {
+                return message;
+            }
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(5), read).await.unwrap()
+}
+
+async fn expect_requests(remote: &mut DuplexStream, count: usize) {
+    for _ in 0..count {
+        expect(remote, |message| matches!(message, PeerMessage::Request(_))).await;
+    }
+}
+
+fn block(begin: u32) -> PeerMessage {
+    PeerMessage::Piece { piece_index: 0, begin, data: vec![(begin / BLOCK_SIZE) as u8 + 1; BLOCK_SIZE as usize] }
+}
+
+#[tokio::test]
+async fn requests_blocks_from_several_peers_and_cancels_duplicates() {
+    let codec = MessageCodec::default();
+    let (slow, mut slow_remote) = unchoked_peer(6881).await;
+    let (fast, mut fast_remote) = unchoked_peer(6882).await;
+    let piece = Arc::new(PieceDownload::new(0, PIECE_LENGTH));
+
+    let slow_download = tokio::spawn({
+        let (slow, piece) = (slow.clone(), piece.clone());
+        async move { slow.download_shared_piece(&piece).await }
+    });
+    expect_requests(&mut slow_remote, 2).await;
+    assert!(piece.all_blocks_requested());
+
+    // Endgame: the second peer asks for the same blocks
+    let fast_download = tokio::spawn({
+        let (fast, piece) = (fast.clone(), piece.clone());
+        async move { fast.download_shared_piece(&piece).await }
+    });
+    expect_requests(&mut fast_remote, 2).await;
+
+    // The first copy of block 0 wins and the other request for it is cancelled
+    codec.write_message(&mut slow_remote, &block(0)).await.unwrap();
+    let cancel = expect(&mut fast_remote, |message| matches!(message, PeerMessage::Cancel(_))).await;
+    assert_eq!(cancel, PeerMessage::Cancel(BlockRequest::new(0, 0, BLOCK_SIZE)));
+
+    // A copy that crossed the cancel on the wire is counted as duplicate
+    codec.write_message(&mut fast_remote, &block(0)).await.unwrap();
+    codec.write_message(&mut fast_remote, &block(BLOCK_SIZE)).await.unwrap();
+
+    let cancel = expect(&mut slow_remote, |message| matches!(message, PeerMessage::Cancel(_))).await;
+    assert_eq!(cancel, PeerMessage::Cancel(BlockRequest::new(0, BLOCK_SIZE, BLOCK_SIZE)));
+
+    fast_download.await.unwrap().unwrap();
+    slow_download.await.unwrap().unwrap();
+
+    let mut expected = vec![1u8; BLOCK_SIZE as usize];
+    expected.extend(vec![2u8; BLOCK_SIZE as usize]);
+    assert_eq!(piece.take_data().unwrap(), expected);
+    assert!(piece.take_data().is_none(), "only one caller gets the piece");
+
+    assert_eq!(piece.duplicate_bytes(), BLOCK_SIZE as u64);
+    assert_eq!(fast.stats().duplicate_bytes, BLOCK_SIZE as u64);
+    assert_eq!(slow.stats().duplicate_bytes, 0);
+
+    slow.close();
+    fast.close();
+}
+
+#[tokio::test]
+async fn abandoning_a_piece_cancels_its_requests() {
+    let codec = MessageCodec::default();
+    let (peer, mut remote) = unchoked_peer(6883).await;
+    let piece = Arc::new(PieceDownload::new(0, PIECE_LENGTH));
+
+    let download = tokio::spawn({
+        let (peer, piece) = (peer.clone(), piece.clone());
+        async move { peer.download_shared_piece(&piece).await }
+    });
+    expect_requests(&mut remote, 2).await;
+    let mut received = piece.subscribe();
+    codec.write_message(&mut remote, &block(0)).await.unwrap();
+    tokio::time::timeout(Duration::from_secs(5), received.changed()).await.unwrap().unwrap();
+
+    // Playback seeks elsewhere before the second block arrives
+    piece.abandon();
+    let cancel = expect(&mut remote, |message| matches!(message, PeerMessage::Cancel(_))).await;
+    assert_eq!(cancel, PeerMessage::Cancel(BlockRequest::new(0, BLOCK_SIZE, BLOCK_SIZE)));
+    assert!(download.await.unwrap().is_err());
+
+    // The block that did arrive is kept for a later attempt
+    assert!(piece.has_block(&BlockRequest::new(0, 0, BLOCK_SIZE)));
+    assert_eq!(piece.missing_bytes(), BLOCK_SIZE as u64);
+
+    peer.close();
+}
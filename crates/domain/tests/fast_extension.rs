@@ -0,0 +1,110 @@
+use domain::protocol::{allowed_fast_set, BlockRequest, Handshake, MessageCodec, PeerMessage};
+use domain::services::PeerConnection;
+use domain::Peer;
+use std::net::Ipv4Addr;
+use std::time::Duration;
+
+#[test]
+fn allowed_fast_set_matches_bep6_vectors() {
+    let ip = Ipv4Addr::new(80, 4, 4, 200);
+    let info_hash = [0xAAu8; 20];
+
+    assert_eq!(allowed_fast_set(ip, &info_hash, 1313, 7), vec![1059, 431, 808, 1217, 287, 376, 1188]);
+    assert_eq!(
+        allowed_fast_set(ip, &info_hash, 1313, 9),
+        vec![1059, 431, 808, 1217, 287, 376, 1188, 353, 508]
+    );
+    // Never loops forever on tiny torrents
+    assert_eq!(allowed_fast_set(ip, &info_hash, 3, 10).len(), 3);
+}
+
+#[test]
+fn handshake_negotiates_fast_bit() {
+    let plain = Handshake::new([1u8; 20], [2u8; 20]);
+    assert!(!plain.supports_fast_extension());
+
+    let fast = plain.with_fast_extension();
+    assert_eq!(fast.reserved, [0, 0, 0, 0, 0, 0, 0, 0x04]);
+    assert!(Handshake::from_bytes(&fast.to_bytes()).unwrap().supports_fast_extension());
+}
+
+#[tokio::test]
+async fn downloads_allowed_fast_piece_while_choked() {
+    let (local, mut remote) = tokio::io::duplex(1 << 20);
+    let peer = Peer::new(1, "127.0.0.1".to_string(), 6881);
+    let handle = PeerConnection::spawn(local, 1, peer, [7u8; 20], true, MessageCodec::default());
+
+    // The peer keeps us choked but lets us fetch piece 3
+    let codec = MessageCodec::default();
+    codec.write_message(&mut remote, &PeerMessage::AllowedFast { piece_index: 3 }).await.unwrap();
+    codec.write_message(&mut remote, &PeerMessage::SuggestPiece { piece_index: 8 }).await.unwrap();
+    while !handle.is_allowed_fast(3) {
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+    assert!(handle.can_request(3));
+    assert!(!handle.can_request(4));
+    assert_eq!(handle.suggested_pieces(), vec![8]);
+
+    // Serve whatever is requested; anything but requests (and our interest) is unexpected
+    tokio::spawn(async move {
+        while let Ok(message) = codec.read_message(&mut remote).await {
+            match message {
+                PeerMessage::Request(request) => {
+                    assert_eq!(request.piece_index, 3);
+                    let data = vec![3u8; request.length as usize];
+                    let piece = PeerMessage::Piece { piece_index: request.piece_index, begin: request.begin, data };
+                    codec.write_message(&mut remote, &piece).await.unwrap();
+                }
+                PeerMessage::Interested | PeerMessage::KeepAlive => {}
+                other => panic!("unexpected message {:?}", other),
+            }
+        }
+    });
+
+    let data = tokio::time::timeout(Duration::from_secs(5), handle.download_piece(3, 20_000)).await.unwrap();
+    assert_eq!(data.unwrap(), vec![3u8; 20_000]);
+    handle.close();
+}
+
+#[tokio::test]
+async fn rerequests_rejected_blocks() {
+    let (local, mut remote) = tokio::io::duplex(1 << 20);
+    let peer = Peer::new(1, "127.0.0.1".to_string(), 6881);
+    let handle = PeerConnection::spawn(local, 1, peer, [7u8; 20], true, MessageCodec::default());
+
+    let codec = MessageCodec::default();
+    codec.write_message(&mut remote, &PeerMessage::Unchoke).await.unwrap();
+
+    let download = tokio::spawn({
+        let handle = handle.clone();
+        async move { handle.download_piece(0, 16384).await }
+    });
+
+    let mut rejected = false;
+    loop {
+        if let PeerMessage::Request(request) = codec.read_message(&mut remote).await.unwrap() {
+            assert_eq!(request, BlockRequest::new(0, 0, 16384));
+            if !rejected {
+                rejected = true;
+                codec.write_message(&mut remote, &PeerMessage::RejectRequest(request)).await.unwrap();
+                continue;
+            }
+            let piece = PeerMessage::Piece { piece_index: 0, begin: 0, data: vec![1u8; 16384] };
+            codec.write_message(&mut remote, &piece).await.unwrap();
+            break;
+        }
+    }
+
+    assert_eq!(download.await.unwrap().unwrap(), vec![1u8; 16384]);
+    handle.close();
+}
+
+#[tokio::test]
+async fn closes_on_fast_messages_without_negotiation() {
+    let (local, mut remote) = tokio::io::duplex(1 << 16);
+    let peer = Peer::new(1, "127.0.0.1".to_string(), 6881);
+    let handle = PeerConnection::spawn(local, 1, peer, [7u8; 20], false, MessageCodec::default());
+
+    MessageCodec::default().write_message(&mut remote, &PeerMessage::HaveAll).await.unwrap();
+    tokio::time::timeout(Duration::from_secs(5), handle.closed()).await.unwrap();
+}
@@ -0,0 +1,83 @@
+use domain::services::piece_manager::PiecePriority;
+use domain::{parse_select_only, FilePriority, FileSelection, MagnetLink, PiecePriorities, Torrent, TorrentFile, TorrentStatus};
+use std::collections::HashMap;
+
+/// Three files over ten 100-byte pieces: 0..250, 250..700 and 700..1000
+fn season_pack() -> (Torrent, Vec<TorrentFile>) {
+    let torrent = Torrent::new("ab".repeat(20), "season".to_string(), 1000, 100, 10);
+    let files = vec![
+        TorrentFile::new(1, 0, "season/e01.mkv".to_string(), 250, 0),
+        TorrentFile::new(1, 1, "season/e02.mkv".to_string(), 450, 250),
+        TorrentFile::new(1, 2, "season/e03.mkv".to_string(), 300, 700),
+    ];
+    (torrent, files)
+}
+
+#[test]
+fn pieces_follow_the_priorities_of_their_files() {
+    let (torrent, mut files) = season_pack();
+    files[0].priority = FilePriority::High;
+    files[1].priority = FilePriority::Skip;
+
+    let priorities = PiecePriorities::from_files(&torrent, &files);
+
+    assert_eq!(priorities.get(0), Some(PiecePriority::High));
+    // Piece 2 is shared with the skipped file and still wanted
+    assert_eq!(priorities.get(2), Some(PiecePriority::High));
+    assert!(!priorities.is_wanted(3));
+    assert!(!priorities.is_wanted(6));
+    assert_eq!(priorities.get(7), Some(PiecePriority::Normal));
+    assert_eq!(priorities.wanted_count(), 6);
+
+    // Without a file list everything is wanted
+    assert_eq!(PiecePriorities::from_files(&torrent, &[]).wanted_count(), 10);
+}
+
+#[test]
+fn selection_skips_files_left_out() {
+    let selection = FileSelection {
+        only: Some(vec![0, 2]),
+        priorities: HashMap::from([(2, FilePriority::High), (1, FilePriority::Low)]),
+    };
+
+    assert_eq!(selection.priority_for(0), FilePriority::Normal);
+    assert_eq!(selection.priority_for(1), FilePriority::Low);
+    assert_eq!(selection.priority_for(2), FilePriority::High);
+    assert_eq!(selection.priority_for(3), FilePriority::Skip);
+    assert_eq!(FileSelection::default().priority_for(3), FilePriority::Normal);
+}
+
+#[test]
+fn progress_counts_only_wanted_pieces() {
+    let (mut torrent, _) = season_pack();
+    torrent.update_progress(3, 6);
+    assert_eq!(torrent.progress, 0.5);
+
+    torrent.update_progress(6, 6);
+    assert_eq!(torrent.status, TorrentStatus::Completed);
+
+    // Selecting another file reopens the download
+    torrent.update_progress(6, 10);
+    assert_eq!(torrent.status, TorrentStatus::Downloading);
+}
+
+#[test]
+fn parses_select_only_from_magnet_links() {
+    assert_eq!(parse_select_only("0,2,4-6,2").unwrap(), vec![0, 2, 4, 5, 6]);
+    assert!(parse_select_only("3-1").is_err());
+    assert!(parse_select_only("0-999999999999").is_err());
+    assert!(parse_select_only("x").is_err());
+
+    let magnet = MagnetLink::parse(
+        "magnet:?xt=urn:btih:CIAQEAQGAQGQ4AQKBIGAQBAMCAEFJEQW&dn=Season&so=1,3-4\
+         &tr=http%3A%2F%2Ftracker.example%2Fannounce&xs=http%3A%2F%2Fexample.com%2Fseason.torrent",
+    )
+    .unwrap();
+    assert_eq!(magnet.info_hash.as_deref(), Some("1201020206040d0e020a0a0c08040c1008549216"));
+    assert_eq!(magnet.display_name.as_deref(), Some("Season"));
+    assert_eq!(magnet.trackers, vec!["http://tracker.example/announce"]);
+    assert_eq!(magnet.exact_source.as_deref(), Some("http://example.com/season.torrent"));
+    assert_eq!(magnet.select_only, Some(vec![1, 3, 4]));
+
+    assert!(MagnetLink::parse("http://example.com/season.torrent").is_err());
+}
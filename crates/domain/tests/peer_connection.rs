@@ -0,0 +1,75 @@
+use domain::protocol::{MessageCodec, PeerMessage};
+use domain::services::PeerConnection;
+use domain::Peer;
+use tokio::io::DuplexStream;
+
+const PIECE_LENGTH: u32 = 40_000;
+
+fn piece_data(piece_index: u32) -> Vec<u8> {
+    (0..PIECE_LENGTH).map(|i| (i as u8).wrapping_add(piece_index as u8)).collect()
+}
+
+/// A remote peer that unchokes us and serves every request from `piece_data`,
+/// choking once after the first block if `choke_once` is set
+async fn serve(mut stream: DuplexStream, choke_once: bool) -> usize {
+    let codec = MessageCodec::default();
+    let mut served = 0;
+    let mut choked = false;
+    codec.write_message(&mut stream, &PeerMessage::Unchoke).await.unwrap();
+
+    while let Ok(message) = codec.read_message(&mut stream).await {
+        if let PeerMessage::Request(request) = message {
+            if choke_once && served == 1 && !choked {
+                choked = true;
+                codec.write_message(&mut stream, &PeerMessage::Choke).await.unwrap();
+                codec.write_message(&mut stream, &PeerMessage::Unchoke).await.unwrap();
+                continue;
+            }
+
+            let start = request.begin as usize;
+            let data = piece_data(request.piece_index)[start..start + request.length as usize].to_vec();
+            let piece = PeerMessage::Piece { piece_index: request.piece_index, begin: request.begin, data };
+            codec.write_message(&mut stream, &piece).await.unwrap();
+            served += 1;
+        }
+    }
+    served
+}
+
+#[tokio::test]
+async fn reuses_one_connection_for_several_pieces() {
+    let (local, remote) = tokio::io::duplex(1 << 20);
+    let server = tokio::spawn(serve(remote, false));
+
+    let peer = Peer::new(1, "127.0.0.1".to_string(), 6881);
+    let handle = PeerConnection::spawn(local, 1, peer, [7u8; 20], false, MessageCodec::default());
+    handle.send(PeerMessage::Interested).unwrap();
+
+    for piece_index in 0..3 {
+        let data = handle.download_piece(piece_index, PIECE_LENGTH).await.unwrap();
+        assert_eq!(data, piece_data(piece_index));
+    }
+
+    let stats = handle.stats();
+    assert!(stats.am_interested);
+    assert!(!stats.peer_choking);
+    assert_eq!(stats.downloaded, 3 * PIECE_LENGTH as u64);
+
+    handle.close();
+    handle.closed().await;
+    assert!(handle.is_closed());
+    assert_eq!(server.await.unwrap(), 9);
+}
+
+#[tokio::test]
+async fn rerequests_blocks_dropped_by_a_choke() {
+    let (local, remote) = tokio::io::duplex(1 << 20);
+    tokio::spawn(serve(remote, true));
+
+    let peer = Peer::new(1, "127.0.0.1".to_string(), 6881);
+    let handle = PeerConnection::spawn(local, 1, peer, [7u8; 20], false, MessageCodec::default());
+
+    let data = handle.download_piece(4, PIECE_LENGTH).await.unwrap();
+    assert_eq!(data, piece_data(4));
+    handle.close();
+}
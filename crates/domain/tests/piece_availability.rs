@@ -0,0 +1,80 @@
+use domain::protocol::{MessageCodec, PeerMessage};
+use domain::services::{PeerConnection, PeerHandle, PeerPieces, PieceAvailability};
+use domain::Peer;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::DuplexStream;
+
+fn connect(fast_extension: bool) -> (PeerHandle, DuplexStream) {
+    let (local, remote) = tokio::io::duplex(1 << 16);
+    let peer = Peer::new(1, "127.0.0.1".to_string(), 6881);
+    let handle = PeerConnection::spawn(local, 1, peer, [7u8; 20], fast_extension, MessageCodec::default());
+    (handle, remote)
+}
+
+/// Send an announcement and wait until the connection has processed it
+async fn announce(handle: &PeerHandle, remote: &mut DuplexStream, message: PeerMessage) {
+    let expected = match &message {
+        PeerMessage::Bitfield(bits) => PeerPieces::from_bitfield(bits.clone()),
+        PeerMessage::HaveAll => PeerPieces::all(),
+        PeerMessage::HaveNone => PeerPieces::default(),
+        PeerMessage::Have { piece_index } => {
+            let mut pieces = handle.pieces();
+            pieces.insert(*piece_index);
+            pieces
+        }
+        other => panic!("not an announcement: {:?}", other),
+    };
+    MessageCodec::default().write_message(remote, &message).await.unwrap();
+
+    let processed = async {
+        while handle.pieces() != expected {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(5), processed).await.unwrap();
+}
+
+#[test]
+fn peer_pieces_follow_bitfield_and_haves() {
+    let mut pieces = PeerPieces::from_bitfield(vec![0b1010_0000]);
+    assert!(pieces.has(0) && !pieces.has(1) && pieces.has(2));
+    assert!(!pieces.has(100));
+
+    assert!(pieces.insert(17));
+    assert!(!pieces.insert(17));
+    assert!(pieces.has(17));
+    assert_eq!(pieces.len(), 3);
+
+    assert!(PeerPieces::all().has(12345));
+    assert!(PeerPieces::default().is_empty());
+}
+
+#[tokio::test]
+async fn counts_pieces_of_live_connections() {
+    let availability = Arc::new(PieceAvailability::new());
+    let (first, mut first_remote) = connect(false);
+    let (seed, mut seed_remote) = connect(true);
+    first.track_availability(availability.clone());
+    seed.track_availability(availability.clone());
+
+    announce(&first, &mut first_remote, PeerMessage::Bitfield(vec![0b1100_0000])).await;
+    announce(&first, &mut first_remote, PeerMessage::Have { piece_index: 5 }).await;
+    announce(&seed, &mut seed_remote, PeerMessage::HaveAll).await;
+
+    assert!(first.has_piece(5) && !first.has_piece(2));
+    assert_eq!(availability.count(0), 2);
+    assert_eq!(availability.count(2), 1);
+    assert_eq!(availability.count(5), 2);
+    assert_eq!(availability.seeds(), 1);
+
+    // A seed that takes back its announcement no longer counts
+    announce(&seed, &mut seed_remote, PeerMessage::HaveNone).await;
+    assert_eq!(availability.seeds(), 0);
+    assert_eq!(availability.count(2), 0);
+
+    first.close();
+    assert_eq!(availability.count(0), 0);
+    assert_eq!(availability.count(5), 0);
+    seed.close();
+}
@@ -0,0 +1,44 @@
+use domain::services::{rarest_first, PeerPieces, PieceAvailability};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use std::collections::HashSet;
+
+/// Piece 0 is on three peers, 1 and 2 on two, 3 on one and 4 on none
+fn availability() -> PieceAvailability {
+    let availability = PieceAvailability::new();
+    availability.add(&PeerPieces::from_bitfield(vec![0b1111_0000]));
+    availability.add(&PeerPieces::from_bitfield(vec![0b1110_0000]));
+    availability.add(&PeerPieces::from_bitfield(vec![0b1000_0000]));
+    availability
+}
+
+#[test]
+fn picks_rarest_pieces_first_and_unavailable_last() {
+    let order = rarest_first(0..5, &availability(), &HashSet::new(), &mut StdRng::seed_from_u64(7));
+
+    assert_eq!(order[0], 3);
+    assert_eq!(HashSet::from([order[1], order[2]]), HashSet::from([1, 2]));
+    assert_eq!(order[3], 0);
+    assert_eq!(order[4], 4);
+}
+
+#[test]
+fn finishes_partial_pieces_first() {
+    let partial = HashSet::from([0]);
+    let order = rarest_first(0..5, &availability(), &partial, &mut StdRng::seed_from_u64(7));
+
+    assert_eq!(order[0], 0);
+    assert_eq!(order[1], 3);
+}
+
+#[test]
+fn breaks_ties_at_random() {
+    let availability = PieceAvailability::new();
+    availability.add(&PeerPieces::all());
+
+    let orders: HashSet<Vec<u32>> = (0..20)
+        .map(|seed| rarest_first(0..8, &availability, &HashSet::new(), &mut StdRng::seed_from_u64(seed)))
+        .collect();
+    assert!(orders.len() > 1, "equally rare pieces always came out in the same order");
+    assert!(orders.iter().all(|order| order.iter().copied().collect::<HashSet<_>>() == (0..8).collect()));
+}
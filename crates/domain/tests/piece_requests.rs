@@ -0,0 +1,148 @@
+use async_trait::async_trait;
+use domain::services::piece_manager::{PieceManager, PiecePriority};
+use domain::{DomainError, Piece, PieceRepository, Torrent, TorrentRepository};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+struct NoPieces;
+
+#[async_trait]
+impl PieceRepository for NoPieces {
+    async fn find_by_torrent_id(&self, _torrent_id: i32) -> Result<Vec<Piece>, DomainError> {
+        Ok(Vec::new())
+    }
+    async fn find_by_torrent_and_index(&self, _torrent_id: i32, _piece_index: i32) -> Result<Option<Piece>, DomainError> {
+        Ok(None)
+    }
+    async fn save(&self, piece: &Piece) -> Result<Piece, DomainError> {
+        Ok(piece.clone())
+    }
+    async fn update(&self, piece: &Piece) -> Result<Piece, DomainError> {
+        Ok(piece.clone())
+    }
+    async fn save_batch(&self, pieces: &[Piece]) -> Result<Vec<Piece>, DomainError> {
+        Ok(pieces.to_vec())
+    }
+    async fn update_batch(&self, _pieces: &[Piece]) -> Result<(), DomainError> {
+        Ok(())
+    }
+    async fn count_downloaded(&self, _torrent_id: i32) -> Result<i32, DomainError> {
+        Ok(0)
+    }
+    async fn find_next_needed(&self, _torrent_id: i32, _limit: i32) -> Result<Vec<Piece>, DomainError> {
+        Ok(Vec::new())
+    }
+}
+
+struct NoTorrents;
+
+#[async_trait]
+impl TorrentRepository for NoTorrents {
+    async fn find_by_id(&self, _id: i32) -> Result<Option<Torrent>, DomainError> {
+        Ok(None)
+    }
+    async fn find_by_info_hash(&self, _info_hash: &str) -> Result<Option<Torrent>, DomainError> {
+        Ok(None)
+    }
+    async fn save(&self, torrent: &Torrent) -> Result<Torrent, DomainError> {
+        Ok(torrent.clone())
+    }
+    async fn update(&self, torrent: &Torrent) -> Result<Torrent, DomainError> {
+        Ok(torrent.clone())
+    }
+    async fn delete(&self, _id: i32) -> Result<(), DomainError> {
+        Ok(())
+    }
+    async fn find_all(&self) -> Result<Vec<Torrent>, DomainError> {
+        Ok(Vec::new())
+    }
+    async fn find_active(&self) -> Result<Vec<Torrent>, DomainError> {
+        Ok(Vec::new())
+    }
+}
+
+fn piece_manager() -> PieceManager {
+    PieceManager::new(Arc::new(NoPieces), Arc::new(NoTorrents), "downloads".to_string())
+}
+
+#[tokio::test]
+async fn repeated_requests_keep_one_entry_per_piece() {
+    let manager = piece_manager();
+    for _ in 0..10 {
+        manager.request_piece(1, 4, PiecePriority::Normal, "session-a".to_string()).await.unwrap();
+    }
+    manager.request_piece(1, 7, PiecePriority::Low, "session-a".to_string()).await.unwrap();
+
+    // Peeking does not consume the request
+    assert_eq!(manager.get_next_piece_request(1).unwrap().piece_index, 4);
+    assert_eq!(manager.get_next_piece_request(1).unwrap().piece_index, 4);
+
+    // Once a download runs, the next request is offered instead
+    manager.begin_download(1, 4, 1024).await.unwrap();
+    assert_eq!(manager.get_next_piece_request(1).unwrap().piece_index, 7);
+    assert!(manager.next_piece_request_where(1, |request| request.piece_index != 7).is_none());
+}
+
+#[tokio::test]
+async fn highest_priority_and_earliest_deadline_win() {
+    let manager = piece_manager();
+    let soon = Instant::now() + Duration::from_secs(3);
+    let later = Instant::now() + Duration::from_secs(120);
+
+    manager.request_piece_by(1, 2, later, "session-a".to_string()).await.unwrap();
+    manager.request_piece_by(1, 2, soon, "session-b".to_string()).await.unwrap();
+    manager.request_piece(1, 3, PiecePriority::High, "session-a".to_string()).await.unwrap();
+
+    let request = manager.pending_request(1, 2).unwrap();
+    assert_eq!(request.priority, PiecePriority::Urgent);
+    assert_eq!(request.deadline, Some(soon));
+    assert_eq!(request.requester, "session-b");
+    assert_eq!(manager.get_next_piece_request(1).unwrap().piece_index, 2);
+
+    // A requester's new claim replaces its old one
+    manager.request_piece_by(1, 2, later, "session-b".to_string()).await.unwrap();
+    assert_eq!(manager.pending_request(1, 2).unwrap().deadline, Some(later));
+    assert_eq!(manager.get_next_piece_request(1).unwrap().piece_index, 3);
+}
+
+#[tokio::test]
+async fn cancelling_a_requester_releases_only_its_pieces() {
+    let manager = piece_manager();
+    manager.request_piece(1, 0, PiecePriority::Urgent, "session-a".to_string()).await.unwrap();
+    manager.request_piece(1, 1, PiecePriority::Low, "session-a".to_string()).await.unwrap();
+    manager.request_piece(1, 1, PiecePriority::Normal, "session-b".to_string()).await.unwrap();
+
+    manager.cancel_requests("session-a");
+
+    assert!(manager.pending_request(1, 0).is_none());
+    let shared = manager.pending_request(1, 1).unwrap();
+    assert_eq!(shared.priority, PiecePriority::Normal);
+    assert_eq!(shared.requester, "session-b");
+
+    manager.cancel_requests("session-b");
+    assert!(!manager.has_pending_requests(1));
+}
+
+#[tokio::test]
+async fn seeking_releases_the_old_window() {
+    let manager = piece_manager();
+    let soon = Instant::now() + Duration::from_secs(3);
+    for piece_index in 0..4 {
+        manager.request_piece_by(1, piece_index, soon, "session-a".to_string()).await.unwrap();
+    }
+    manager.request_piece(1, 1, PiecePriority::Low, "session-b".to_string()).await.unwrap();
+    let running = manager.begin_download(1, 0, 1024).await.unwrap();
+    let shared = manager.begin_download(1, 1, 1024).await.unwrap();
+
+    // Playback jumps to pieces 3..6
+    assert_eq!(manager.release_requests_except(1, "session-a", |piece_index| (3..6).contains(&piece_index)), 3);
+
+    assert!(manager.pending_request(1, 0).is_none());
+    assert!(manager.pending_request(1, 2).is_none());
+    assert_eq!(manager.pending_request(1, 3).unwrap().requester, "session-a");
+    assert_eq!(manager.pending_request(1, 1).unwrap().requester, "session-b");
+
+    // Only the download nobody wants any more is stopped
+    assert!(running.is_abandoned());
+    assert!(!shared.is_abandoned());
+}
@@ -0,0 +1,75 @@
+use domain::services::piece_manager::PiecePriority;
+use domain::PriorityAggregator;
+use std::time::{Duration, Instant};
+
+const PIECE: u64 = 256 * 1024;
+
+/// Two sessions streaming different episodes: pieces 0..8 and 100..108, each
+/// due one second after the previous one. Session "b" started a bit later, so
+/// all of its deadlines are earlier than "a"'s and it goes first.
+fn two_streams() -> PriorityAggregator {
+    let now = Instant::now();
+    let mut aggregator = PriorityAggregator::new();
+    for i in 0..8 {
+        let deadline = now + Duration::from_secs(i as u64 + 10);
+        aggregator.claim(i, "a".to_string(), PiecePriority::Urgent, Some(deadline));
+        aggregator.claim(100 + i, "b".to_string(), PiecePriority::Urgent, Some(deadline - Duration::from_millis(500)));
+    }
+    aggregator
+}
+
+/// Start downloads in the order the aggregator hands them out
+fn schedule(aggregator: &mut PriorityAggregator, count: usize) -> Vec<String> {
+    (0..count)
+        .map(|_| {
+            let request = aggregator.best_where(|_| true).unwrap();
+            aggregator.charge(request.piece_index, PIECE);
+            aggregator.remove_piece(request.piece_index);
+            request.requester
+        })
+        .collect()
+}
+
+#[test]
+fn concurrent_streams_take_turns() {
+    let mut aggregator = two_streams();
+    assert_eq!(schedule(&mut aggregator, 6), ["b", "a", "b", "a", "b", "a"]);
+}
+
+#[test]
+fn weights_split_bandwidth_unevenly() {
+    let mut aggregator = two_streams();
+    aggregator.set_weight("a", 2);
+    let order = schedule(&mut aggregator, 9);
+    assert_eq!(order.iter().filter(|requester| *requester == "a").count(), 6);
+    assert_eq!(aggregator.weight("a"), 2);
+    assert_eq!(aggregator.weight("b"), 1);
+}
+
+#[test]
+fn higher_priority_still_goes_first() {
+    let mut aggregator = two_streams();
+    aggregator.claim(50, "a".to_string(), PiecePriority::High, None);
+    aggregator.charge(0, 10 * PIECE);
+
+    // "a" was served far more, but its urgent pieces still beat "c"'s high one
+    aggregator.claim(200, "c".to_string(), PiecePriority::High, None);
+    let request = aggregator.best_where(|request| request.piece_index >= 50).unwrap();
+    assert_eq!(request.requester, "b");
+    let request = aggregator.best_where(|request| request.priority < PiecePriority::Urgent).unwrap();
+    assert_eq!(request.requester, "c");
+}
+
+#[test]
+fn closing_a_session_releases_its_claims() {
+    let mut aggregator = two_streams();
+    aggregator.claim(3, "b".to_string(), PiecePriority::Low, None);
+    aggregator.remove_requester("a");
+
+    assert!(aggregator.request(0).is_none());
+    assert_eq!(aggregator.request(3).unwrap().requester, "b");
+    assert!(aggregator.best_where(|_| true).is_some_and(|request| request.requester == "b"));
+
+    aggregator.remove_requester("b");
+    assert!(aggregator.is_empty());
+}
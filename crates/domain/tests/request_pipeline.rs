@@ -0,0 +1,121 @@
+use domain::protocol::BlockRequest;
+use domain::services::request_pipeline::{INITIAL_QUEUE_DEPTH, MIN_QUEUE_DEPTH};
+use domain::services::{PieceAssembler, RequestQueue, TransferRate, BLOCK_SIZE};
+use std::time::Duration;
+
+/// Ask for a block and have it arrive straight away, so the round-trip
+/// time stays negligible and only the rate drives the queue depth
+fn receive_block(queue: &mut RequestQueue, block_index: u32) {
+    let request = BlockRequest::new(0, block_index * BLOCK_SIZE, BLOCK_SIZE);
+    queue.on_request_sent(request);
+    assert!(queue.on_block_received(&request));
+}
+
+#[test]
+fn slow_start_adds_one_request_per_block() {
+    let mut queue = RequestQueue::new();
+    assert_eq!(queue.target_depth(), INITIAL_QUEUE_DEPTH);
+
+    for block_index in 0..10 {
+        receive_block(&mut queue, block_index);
+    }
+    assert_eq!(queue.target_depth(), INITIAL_QUEUE_DEPTH + 10);
+}
+
+#[tokio::test(start_paused = true)]
+async fn depth_follows_the_rate_once_slow_start_ends() {
+    let mut queue = RequestQueue::new();
+    receive_block(&mut queue, 0);
+    let ramped = queue.target_depth();
+    assert_eq!(ramped, INITIAL_QUEUE_DEPTH + 1);
+
+    // A slow block: the rate no longer justifies the ramp, so slow start ends
+    // and the depth drops to what the rate needs
+    tokio::time::advance(Duration::from_secs(2)).await;
+    receive_block(&mut queue, 1);
+    let slow = queue.target_depth();
+    assert!(slow < ramped && slow >= MIN_QUEUE_DEPTH, "depth {} after a slow block", slow);
+
+    // A burst raises the rate and the depth with it, beyond one per block
+    for block_index in 2..22 {
+        receive_block(&mut queue, block_index);
+    }
+    let fast = queue.target_depth();
+    assert!(fast > slow + 20, "depth {} after a burst", fast);
+
+    // The rate falls off again and so does the depth
+    tokio::time::advance(Duration::from_secs(2)).await;
+    receive_block(&mut queue, 22);
+    let slowed = queue.target_depth();
+    assert!(slowed < fast && slowed > slow, "depth {} after slowing down", slowed);
+}
+
+#[test]
+fn queue_tracks_outstanding_requests() {
+    let mut queue = RequestQueue::new();
+    let first = BlockRequest::new(3, 0, BLOCK_SIZE);
+    let second = BlockRequest::new(3, BLOCK_SIZE, BLOCK_SIZE);
+    queue.on_request_sent(first);
+    queue.on_request_sent(second);
+    assert_eq!(queue.outstanding_count(), 2);
+
+    // Blocks we never asked for are not counted
+    assert!(!queue.on_block_received(&BlockRequest::new(4, 0, BLOCK_SIZE)));
+    assert!(queue.on_block_received(&first));
+    assert!(!queue.on_block_received(&first));
+    assert!(queue.smoothed_rtt().is_some());
+
+    assert_eq!(queue.clear(), [second]);
+    assert_eq!(queue.outstanding_count(), 0);
+}
+
+#[test]
+fn transfer_rate_counts_recent_bytes() {
+    let mut rate = TransferRate::new();
+    rate.record(16384);
+    rate.record(16384);
+
+    assert_eq!(rate.total_bytes(), 32768);
+    // A young estimator averages over half a second at least
+    assert_eq!(rate.bytes_per_second(), 65536.0);
+}
+
+#[test]
+fn blocks_assemble_in_any_order() {
+    let piece_length = 2 * BLOCK_SIZE + 100;
+    let data: Vec<u8> = (0..piece_length).map(|i| (i % 251) as u8).collect();
+    let block = |begin: u32, length: u32| &data[begin as usize..(begin + length) as usize];
+
+    let mut assembler = PieceAssembler::new(5, piece_length);
+    assert_eq!(assembler.block_count(), 3);
+    // The last block is short
+    assert_eq!(assembler.block_request(2), BlockRequest::new(5, 2 * BLOCK_SIZE, 100));
+
+    assert!(assembler.add_block(2 * BLOCK_SIZE, block(2 * BLOCK_SIZE, 100)).unwrap());
+    assert!(assembler.add_block(0, block(0, BLOCK_SIZE)).unwrap());
+    assert!(!assembler.is_complete());
+    assert_eq!(assembler.missing_blocks(), [BlockRequest::new(5, BLOCK_SIZE, BLOCK_SIZE)]);
+    assert_eq!(assembler.received_bitmap(), [0b1010_0000]);
+
+    assert!(assembler.add_block(BLOCK_SIZE, block(BLOCK_SIZE, BLOCK_SIZE)).unwrap());
+    assert!(assembler.is_complete());
+    assert_eq!(assembler.into_data(), data);
+}
+
+#[test]
+fn duplicate_and_malformed_blocks_are_not_stored() {
+    let mut assembler = PieceAssembler::new(0, 2 * BLOCK_SIZE);
+    let first = vec![1u8; BLOCK_SIZE as usize];
+
+    assert!(assembler.add_block(0, &first).unwrap());
+    // A duplicate is reported and does not overwrite what arrived first
+    assert!(!assembler.add_block(0, &vec![2u8; BLOCK_SIZE as usize]).unwrap());
+    assert_eq!(assembler.block(0), Some(first.as_slice()));
+    assert!(!assembler.is_complete());
+
+    // Offsets off the block grid or past the end, and blocks of the wrong length
+    assert!(assembler.add_block(100, &first).is_err());
+    assert!(assembler.add_block(2 * BLOCK_SIZE, &first).is_err());
+    assert!(assembler.add_block(BLOCK_SIZE, &first[..100]).is_err());
+    assert_eq!(assembler.block(BLOCK_SIZE), None);
+}
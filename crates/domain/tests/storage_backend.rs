@@ -0,0 +1,64 @@
+use domain::{DomainError, FilesystemBackend, MemoryBackend, StorageBackend};
+use std::path::Path;
+
+#[tokio::test]
+async fn memory_backend_overwrites_and_truncates() {
+    let backend = MemoryBackend::new(1024);
+    let path = Path::new("movie.mkv");
+    backend.write_span(path, 0, &[1; 100]).await.unwrap();
+    backend.write_span(path, 50, &[2; 100]).await.unwrap();
+
+    let data = backend.read_span(path, 40, 20).await.unwrap();
+    assert_eq!(data, [[1; 10], [2; 10]].concat());
+    assert_eq!(backend.used(), 150);
+
+    // Bytes never written are missing, not zero
+    assert!(matches!(backend.read_span(path, 140, 20).await, Err(DomainError::NotFound(_))));
+
+    backend.truncate(path, 75).await.unwrap();
+    assert_eq!(backend.used(), 75);
+    assert!(backend.read_span(path, 70, 10).await.is_err());
+
+    backend.delete(path).await.unwrap();
+    assert_eq!(backend.used(), 0);
+}
+
+#[tokio::test]
+async fn memory_backend_evicts_least_recently_used() {
+    let backend = MemoryBackend::new(300);
+    let path = Path::new("movie.mkv");
+    for (index, offset) in [0u64, 100, 200].into_iter().enumerate() {
+        backend.write_span(path, offset, &[index as u8; 100]).await.unwrap();
+    }
+    // Reading the first extent makes the second the oldest
+    backend.read_span(path, 0, 100).await.unwrap();
+    backend.write_span(path, 300, &[3; 100]).await.unwrap();
+
+    assert_eq!(backend.used(), 300);
+    assert!(backend.read_span(path, 0, 100).await.is_ok());
+    assert!(matches!(backend.read_span(path, 100, 100).await, Err(DomainError::NotFound(_))));
+
+    // A single write larger than the budget is refused
+    assert!(backend.write_span(path, 0, &[0; 301]).await.is_err());
+}
+
+#[tokio::test]
+async fn filesystem_backend_round_trips() {
+    let root = std::env::temp_dir().join(format!("fs-backend-{}", std::process::id()));
+    let path = root.join("Season 1/video/e01.mkv");
+    let backend = FilesystemBackend::new(&root);
+
+    backend.write_span(&path, 10, b"hello").await.unwrap();
+    backend.flush(&path).await.unwrap();
+    assert_eq!(backend.read_span(&path, 10, 5).await.unwrap(), b"hello");
+
+    backend.truncate(&path, 100).await.unwrap();
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), 100);
+
+    // Emptied directories go too, but not the root
+    backend.delete(&path).await.unwrap();
+    assert!(!root.join("Season 1").exists());
+    assert!(root.exists());
+    assert!(matches!(backend.read_span(&path, 0, 1).await, Err(DomainError::NotFound(_))));
+    std::fs::remove_dir(&root).unwrap();
+}
@@ -0,0 +1,62 @@
+use domain::services::piece_manager::PiecePriority;
+use domain::services::{piece_deadlines, PieceDownload, BLOCK_SIZE};
+use std::time::{Duration, Instant};
+
+const PIECE: u64 = 1_000_000;
+
+#[test]
+fn deadlines_follow_playhead_and_bitrate() {
+    let now = Instant::now();
+    // Playhead halfway through piece 2, playing 500 KB/s
+    let deadlines = piece_deadlines(PIECE, 2 * PIECE + PIECE / 2, 5 * PIECE, 500_000, now);
+
+    let pieces: Vec<usize> = deadlines.iter().map(|(piece_index, _)| *piece_index).collect();
+    assert_eq!(pieces, vec![2, 3, 4]);
+    assert_eq!(deadlines[0].1, now, "the piece under the playhead is due now");
+    assert_eq!(deadlines[1].1 - now, Duration::from_secs(1));
+    assert_eq!(deadlines[2].1 - now, Duration::from_secs(3));
+
+    // A faster stream needs the same pieces sooner
+    let faster = piece_deadlines(PIECE, 2 * PIECE + PIECE / 2, 5 * PIECE, 1_000_000, now);
+    assert!(faster[2].1 < deadlines[2].1);
+
+    assert!(piece_deadlines(PIECE, 5 * PIECE, 5 * PIECE, 500_000, now).is_empty());
+}
+
+#[test]
+fn priority_follows_deadline_slack() {
+    let now = Instant::now();
+    assert_eq!(PiecePriority::for_deadline(now, now), PiecePriority::Urgent);
+    assert_eq!(PiecePriority::for_deadline(now + Duration::from_secs(10), now), PiecePriority::High);
+    assert_eq!(PiecePriority::for_deadline(now + Duration::from_secs(120), now), PiecePriority::Normal);
+}
+
+#[test]
+fn downloads_behind_their_deadline_are_at_risk() {
+    let stall = Duration::from_secs(2);
+    let download = PieceDownload::new(0, 4 * BLOCK_SIZE);
+    let later = Instant::now() + Duration::from_secs(10);
+
+    // Without a stream waiting there is nothing to be late for
+    assert!(!download.is_at_risk(later, stall));
+
+    // Nothing arrived and the deadline passed
+    download.set_deadline(Instant::now());
+    assert!(download.is_at_risk(Instant::now(), stall));
+
+    // Half the piece arrived; at that pace the rest is not ready by the deadline
+    let download = PieceDownload::new(0, 4 * BLOCK_SIZE);
+    download.add_block(0, &vec![0u8; BLOCK_SIZE as usize]).unwrap();
+    download.add_block(BLOCK_SIZE, &vec![0u8; BLOCK_SIZE as usize]).unwrap();
+    let now = Instant::now() + Duration::from_secs(4);
+    download.set_deadline(now + Duration::from_secs(1));
+    assert!(download.is_at_risk(now, stall));
+
+    // A generous deadline is fine; the earliest deadline sticks
+    download.set_deadline(now + Duration::from_secs(60));
+    assert!(download.is_at_risk(now, stall));
+    let relaxed = PieceDownload::new(1, 4 * BLOCK_SIZE);
+    relaxed.add_block(0, &vec![0u8; BLOCK_SIZE as usize]).unwrap();
+    relaxed.set_deadline(now + Duration::from_secs(60));
+    assert!(!relaxed.is_at_risk(now, stall));
+}
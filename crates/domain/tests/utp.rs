@@ -0,0 +1,153 @@
+use domain::protocol::utp::{Packet, PacketType, UtpSocket, UtpStream};
+use rand::{Rng, SeedableRng};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UdpSocket;
+
+fn payload(length: usize) -> Vec<u8> {
+    (0..length).map(|index| (index * 31 % 251) as u8).collect()
+}
+
+async fn pair() -> (UtpStream, UtpStream, UtpSocket, UtpSocket) {
+    let server = UtpSocket::bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+    let client = UtpSocket::bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+
+    let connect = async { tokio::join!(client.connect(server.local_addr().unwrap()), server.accept()) };
+    let (outbound, inbound) = tokio::time::timeout(Duration::from_secs(10), connect).await.unwrap();
+    (outbound.unwrap(), inbound.unwrap(), client, server)
+}
+
+/// Send `data` one way, then read it back on the other side until EOF
+async fn transfer(mut from: UtpStream, mut to: UtpStream, data: Vec<u8>) -> Vec<u8> {
+    let writer = tokio::spawn(async move {
+        from.write_all(&data).await.unwrap();
+        from.shutdown().await.unwrap();
+    });
+
+    let mut received = Vec::new();
+    to.read_to_end(&mut received).await.unwrap();
+    writer.await.unwrap();
+    received
+}
+
+#[test]
+fn packet_round_trips_with_selective_ack() {
+    let mut packet = Packet::new(PacketType::Data, 4242, 17, 9);
+    packet.timestamp = 123_456;
+    packet.timestamp_difference = 789;
+    packet.window_size = 1 << 20;
+    packet.selective_ack = Some(vec![0b0000_0101, 0, 0, 0x80]);
+    packet.payload = b"hello".to_vec();
+
+    let bytes = packet.to_bytes();
+    assert_eq!(bytes[0], 0x01, "type 0, version 1");
+    assert_eq!(bytes[1], 1, "selective ack extension");
+
+    let parsed = Packet::from_bytes(&bytes).unwrap();
+    assert_eq!(parsed, packet);
+    // Bit 0 is ack_nr + 2, bit 2 is ack_nr + 4, bit 31 is ack_nr + 33
+    assert_eq!(parsed.selective_acks(), vec![11, 13, 42]);
+
+    assert!(Packet::from_bytes(&bytes[..10]).is_err());
+}
+
+#[tokio::test]
+async fn transfers_both_ways_over_loopback() {
+    let (client, server, _client_socket, server_socket) = pair().await;
+    assert_eq!(server_socket.connection_count(), 1);
+
+    let (mut client_read, mut client_write) = tokio::io::split(client);
+    let (mut server_read, mut server_write) = tokio::io::split(server);
+
+    let upload = payload(512 * 1024);
+    let expected = upload.clone();
+    let writer = tokio::spawn(async move { client_write.write_all(&upload).await.unwrap() });
+    let mut received = vec![0u8; expected.len()];
+    tokio::time::timeout(Duration::from_secs(10), server_read.read_exact(&mut received))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(received, expected);
+    writer.await.unwrap();
+
+    server_write.write_all(b"reply").await.unwrap();
+    let mut reply = [0u8; 5];
+    client_read.read_exact(&mut reply).await.unwrap();
+    assert_eq!(&reply, b"reply");
+}
+
+#[tokio::test]
+async fn closes_with_eof() {
+    let (client, server, _client_socket, _server_socket) = pair().await;
+
+    let received = tokio::time::timeout(Duration::from_secs(10), transfer(client, server, payload(10_000)))
+        .await
+        .unwrap();
+    assert_eq!(received, payload(10_000));
+}
+
+/// Forwards datagrams between a client and a server, dropping a share of them
+async fn lossy_relay(server: SocketAddr, loss: f64) -> SocketAddr {
+    let front = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+    let back = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+    let address = front.local_addr().unwrap();
+    let (client_tx, mut client_rx) = tokio::sync::watch::channel(None);
+
+    let (to_server_front, to_server_back) = (front.clone(), back.clone());
+    tokio::spawn(async move {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(1);
+        let mut buf = vec![0u8; 65536];
+        while let Ok((length, from)) = to_server_front.recv_from(&mut buf).await {
+            client_tx.send_replace(Some(from));
+            if !rng.gen_bool(loss) {
+                let _ = to_server_back.send_to(&buf[..length], server).await;
+            }
+        }
+    });
+    tokio::spawn(async move {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(2);
+        let mut buf = vec![0u8; 65536];
+        while let Ok((length, _)) = back.recv_from(&mut buf).await {
+            let client = *client_rx.borrow_and_update();
+            if let Some(client) = client {
+                if !rng.gen_bool(loss) {
+                    let _ = front.send_to(&buf[..length], client).await;
+                }
+            }
+        }
+    });
+
+    address
+}
+
+#[tokio::test]
+async fn delivers_intact_data_despite_packet_loss() {
+    let server = UtpSocket::bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+    let client = UtpSocket::bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+    let relay = lossy_relay(server.local_addr().unwrap(), 0.1).await;
+
+    // The SYN itself may be dropped; the connection retries it
+    let connect = async { tokio::join!(client.connect(relay), server.accept()) };
+    let (outbound, inbound) = tokio::time::timeout(Duration::from_secs(30), connect).await.unwrap();
+    let data = payload(256 * 1024);
+
+    let received = tokio::time::timeout(
+        Duration::from_secs(60),
+        transfer(outbound.unwrap(), inbound.unwrap(), data.clone()),
+    )
+    .await
+    .unwrap();
+    assert_eq!(received.len(), data.len());
+    assert!(received == data, "data corrupted in transit");
+}
+
+#[tokio::test]
+async fn connect_fails_without_a_listener() {
+    let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let client = UtpSocket::bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+
+    let result = tokio::time::timeout(Duration::from_secs(30), client.connect(silent.local_addr().unwrap())).await;
+    assert!(result.unwrap().is_err());
+}
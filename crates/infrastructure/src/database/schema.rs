@@ -12,6 +12,7 @@ diesel::table! {
         progress -> Float,         // Download progress (0.0 - 1.0)
         created_at -> Timestamp,
         updated_at -> Timestamp,
+        storage -> Text,           // disk or memory
     }
 }
 
@@ -19,9 +20,11 @@ diesel::table! {
     torrent_files (id) {
         id -> Integer,
         torrent_id -> Integer,
+        file_index -> Integer,     // Position in the torrent's file list
         path -> Text,              // File path within torrent
         length -> BigInt,          // File size in bytes
         offset -> BigInt,          // Byte offset in the torrent
+        priority -> Text,          // skip, low, normal, high
     }
 }
 
@@ -33,6 +36,7 @@ diesel::table! {
         hash -> Text,              // SHA1 hash of the piece
         downloaded -> Bool,        // Whether piece is downloaded
         verified -> Bool,          // Whether piece hash is verified
+        received_blocks -> Nullable<Binary>, // Bitmap of blocks stored for an unfinished piece
     }
 }
 
@@ -45,6 +49,7 @@ diesel::table! {
         peer_id -> Nullable<Text>,
         last_seen -> Timestamp,
         status -> Text,            // connected, disconnected, banned
+        inbound -> Bool,
     }
 }
 
@@ -62,9 +67,20 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    file_snapshots (id) {
+        id -> Integer,
+        torrent_id -> Integer,
+        path -> Text,              // Path of the stored file
+        size -> BigInt,            // Size on disk in bytes
+        modified_at -> Timestamp,  // Modification time when recorded
+    }
+}
+
 diesel::joinable!(torrent_files -> torrents (torrent_id));
 diesel::joinable!(pieces -> torrents (torrent_id));
 diesel::joinable!(peers -> torrents (torrent_id));
 diesel::joinable!(trackers -> torrents (torrent_id));
+diesel::joinable!(file_snapshots -> torrents (torrent_id));
 
-diesel::allow_tables_to_appear_in_same_query!(torrents, torrent_files, pieces, peers, trackers,);
+diesel::allow_tables_to_appear_in_same_query!(torrents, torrent_files, pieces, peers, trackers, file_snapshots,);
@@ -0,0 +1,99 @@
+use crate::database::{file_snapshots, SqlitePool};
+use async_trait::async_trait;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use diesel::prelude::*;
+use domain::{DomainError, FileSnapshot, FileSnapshotRepository};
+
+// Database model
+#[derive(Queryable, Selectable, Debug)]
+#[diesel(table_name = file_snapshots)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+struct FileSnapshotModel {
+    torrent_id: i32,
+    path: String,
+    size: i64,
+    modified_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = file_snapshots)]
+struct NewFileSnapshotModel {
+    torrent_id: i32,
+    path: String,
+    size: i64,
+    modified_at: NaiveDateTime,
+}
+
+impl From<FileSnapshotModel> for FileSnapshot {
+    fn from(model: FileSnapshotModel) -> Self {
+        FileSnapshot::new(model.torrent_id, model.path, model.size, model.modified_at.and_utc().into())
+    }
+}
+
+impl From<&FileSnapshot> for NewFileSnapshotModel {
+    fn from(snapshot: &FileSnapshot) -> Self {
+        NewFileSnapshotModel {
+            torrent_id: snapshot.torrent_id,
+            path: snapshot.path.clone(),
+            size: snapshot.size,
+            modified_at: DateTime::<Utc>::from(snapshot.modified_at).naive_utc(),
+        }
+    }
+}
+
+pub struct SqliteFileSnapshotRepository {
+    pool: SqlitePool,
+}
+
+impl SqliteFileSnapshotRepository {
+    pub fn new(pool: SqlitePool) -> Self {
+        Self { pool }
+    }
+}
+
+#[async_trait]
+impl FileSnapshotRepository for SqliteFileSnapshotRepository {
+    async fn find_by_torrent_id(&self, torrent_id: i32) -> Result<Vec<FileSnapshot>, DomainError> {
+        let mut conn = self
+            .pool
+            .get()
+            .map_err(|e| DomainError::RepositoryError(e.to_string()))?;
+
+        let result = tokio::task::spawn_blocking(move || {
+            file_snapshots::table
+                .filter(file_snapshots::torrent_id.eq(torrent_id))
+                .select(FileSnapshotModel::as_select())
+                .load::<FileSnapshotModel>(&mut conn)
+        })
+        .await
+        .map_err(|e| DomainError::RepositoryError(e.to_string()))?
+        .map_err(|e| DomainError::RepositoryError(e.to_string()))?;
+
+        Ok(result.into_iter().map(|model| model.into()).collect())
+    }
+
+    async fn replace_for_torrent(&self, torrent_id: i32, snapshots: &[FileSnapshot]) -> Result<(), DomainError> {
+        let mut conn = self
+            .pool
+            .get()
+            .map_err(|e| DomainError::RepositoryError(e.to_string()))?;
+
+        let new_snapshots: Vec<NewFileSnapshotModel> =
+            snapshots.iter().map(NewFileSnapshotModel::from).collect();
+
+        tokio::task::spawn_blocking(move || {
+            conn.transaction(|conn| {
+                diesel::delete(file_snapshots::table.filter(file_snapshots::torrent_id.eq(torrent_id)))
+                    .execute(conn)?;
+                diesel::insert_into(file_snapshots::table)
+                    .values(&new_snapshots)
+                    .execute(conn)
+            })
+        })
+        .await
+        .map_err(|e| DomainError::RepositoryError(e.to_string()))?
+        .map_err(|e: diesel::result::Error| DomainError::RepositoryError(e.to_string()))?;
+
+        Ok(())
+    }
+}
@@ -16,6 +16,7 @@ struct PeerModel {
     peer_id: Option<String>,
     last_seen: NaiveDateTime,
     status: String,
+    inbound: bool,
 }
 
 #[derive(Insertable)]
@@ -27,6 +28,7 @@ struct NewPeerModel {
     peer_id: Option<String>,
     last_seen: NaiveDateTime,
     status: String,
+    inbound: bool,
 }
 
 impl From<PeerModel> for Peer {
@@ -50,6 +52,7 @@ impl From<PeerModel> for Peer {
             peer_id: model.peer_id,
             last_seen,
             status,
+            inbound: model.inbound,
         }
     }
 }
@@ -72,6 +75,7 @@ impl From<&Peer> for NewPeerModel {
             peer_id: peer.peer_id.clone(),
             last_seen,
             status: status_str.to_string(),
+            inbound: peer.inbound,
         }
     }
 }
@@ -173,6 +177,7 @@ impl PeerRepository for SqlitePeerRepository {
 
         let last_seen = chrono::DateTime::<chrono::Utc>::from(peer.last_seen).naive_utc();
         let peer_id_opt = peer.peer_id.clone();
+        let inbound = peer.inbound;
 
         let result = tokio::task::spawn_blocking(move || {
             diesel::update(peers::table.filter(peers::id.eq(peer_id)))
@@ -180,6 +185,7 @@ impl PeerRepository for SqlitePeerRepository {
                     peers::status.eq(status_str),
                     peers::last_seen.eq(last_seen),
                     peers::peer_id.eq(peer_id_opt),
+                    peers::inbound.eq(inbound),
                 ))
                 .execute(&mut conn)?;
 
@@ -202,7 +208,7 @@ impl PeerRepository for SqlitePeerRepository {
             .get()
             .map_err(|e| DomainError::RepositoryError(e.to_string()))?;
 
-        let new_peers: Vec<NewPeerModel> = peers.iter().map(|p| NewPeerModel::from(p)).collect();
+        let new_peers: Vec<NewPeerModel> = peers.iter().map(NewPeerModel::from).collect();
 
         let result = tokio::task::spawn_blocking(move || {
             diesel::insert_into(peers::table)
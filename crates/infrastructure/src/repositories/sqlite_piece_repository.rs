@@ -14,6 +14,7 @@ struct PieceModel {
     hash: String,
     downloaded: bool,
     verified: bool,
+    received_blocks: Option<Vec<u8>>,
 }
 
 #[derive(Insertable)]
@@ -24,6 +25,7 @@ struct NewPieceModel {
     hash: String,
     downloaded: bool,
     verified: bool,
+    received_blocks: Option<Vec<u8>>,
 }
 
 impl From<PieceModel> for Piece {
@@ -35,6 +37,7 @@ impl From<PieceModel> for Piece {
             hash: model.hash,
             downloaded: model.downloaded,
             verified: model.verified,
+            received_blocks: model.received_blocks,
         }
     }
 }
@@ -47,6 +50,7 @@ impl From<&Piece> for NewPieceModel {
             hash: piece.hash.clone(),
             downloaded: piece.downloaded,
             verified: piece.verified,
+            received_blocks: piece.received_blocks.clone(),
         }
     }
 }
@@ -145,12 +149,14 @@ impl PieceRepository for SqlitePieceRepository {
 
         let downloaded = piece.downloaded;
         let verified = piece.verified;
+        let received_blocks = piece.received_blocks.clone();
 
         let result = tokio::task::spawn_blocking(move || {
             diesel::update(pieces::table.filter(pieces::id.eq(piece_id)))
                 .set((
                     pieces::downloaded.eq(downloaded),
                     pieces::verified.eq(verified),
+                    pieces::received_blocks.eq(received_blocks),
                 ))
                 .execute(&mut conn)?;
 
@@ -174,7 +180,7 @@ impl PieceRepository for SqlitePieceRepository {
             .map_err(|e| DomainError::RepositoryError(e.to_string()))?;
 
         let new_pieces: Vec<NewPieceModel> =
-            pieces.iter().map(|p| NewPieceModel::from(p)).collect();
+            pieces.iter().map(NewPieceModel::from).collect();
 
         let result = tokio::task::spawn_blocking(move || {
             diesel::insert_into(pieces::table)
@@ -196,6 +202,42 @@ impl PieceRepository for SqlitePieceRepository {
         Ok(result.into_iter().map(|model| model.into()).collect())
     }
 
+    async fn update_batch(&self, pieces: &[Piece]) -> Result<(), DomainError> {
+        let updates = pieces
+            .iter()
+            .map(|piece| {
+                let piece_id = piece.id.ok_or_else(|| {
+                    DomainError::ValidationError("Piece ID is required for updates".to_string())
+                })?;
+                Ok((piece_id, piece.downloaded, piece.verified, piece.received_blocks.clone()))
+            })
+            .collect::<Result<Vec<_>, DomainError>>()?;
+
+        let mut conn = self
+            .pool
+            .get()
+            .map_err(|e| DomainError::RepositoryError(e.to_string()))?;
+
+        // One transaction instead of a commit per piece
+        tokio::task::spawn_blocking(move || {
+            conn.transaction(|conn| {
+                for (piece_id, downloaded, verified, received_blocks) in updates {
+                    diesel::update(pieces::table.filter(pieces::id.eq(piece_id)))
+                        .set((
+                            pieces::downloaded.eq(downloaded),
+                            pieces::verified.eq(verified),
+                            pieces::received_blocks.eq(received_blocks),
+                        ))
+                        .execute(conn)?;
+                }
+                Ok(())
+            })
+        })
+        .await
+        .map_err(|e| DomainError::RepositoryError(e.to_string()))?
+        .map_err(|e: diesel::result::Error| DomainError::RepositoryError(e.to_string()))
+    }
+
     async fn count_downloaded(&self, torrent_id: i32) -> Result<i32, DomainError> {
         let mut conn = self
             .pool
@@ -0,0 +1,150 @@
+use crate::database::{torrent_files, SqlitePool};
+use async_trait::async_trait;
+use diesel::prelude::*;
+use domain::{DomainError, FilePriority, TorrentFile, TorrentFileRepository};
+
+// Database model
+#[derive(Queryable, Selectable, Debug)]
+#[diesel(table_name = torrent_files)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+struct TorrentFileModel {
+    id: i32,
+    torrent_id: i32,
+    file_index: i32,
+    path: String,
+    length: i64,
+    offset: i64,
+    priority: String,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = torrent_files)]
+struct NewTorrentFileModel {
+    torrent_id: i32,
+    file_index: i32,
+    path: String,
+    length: i64,
+    offset: i64,
+    priority: String,
+}
+
+impl From<TorrentFileModel> for TorrentFile {
+    fn from(model: TorrentFileModel) -> Self {
+        TorrentFile {
+            id: Some(model.id),
+            torrent_id: model.torrent_id,
+            file_index: model.file_index,
+            path: model.path,
+            length: model.length,
+            offset: model.offset,
+            priority: FilePriority::parse(&model.priority).unwrap_or_default(),
+        }
+    }
+}
+
+impl From<&TorrentFile> for NewTorrentFileModel {
+    fn from(file: &TorrentFile) -> Self {
+        NewTorrentFileModel {
+            torrent_id: file.torrent_id,
+            file_index: file.file_index,
+            path: file.path.clone(),
+            length: file.length,
+            offset: file.offset,
+            priority: file.priority.as_str().to_string(),
+        }
+    }
+}
+
+pub struct SqliteTorrentFileRepository {
+    pool: SqlitePool,
+}
+
+impl SqliteTorrentFileRepository {
+    pub fn new(pool: SqlitePool) -> Self {
+        Self { pool }
+    }
+}
+
+#[async_trait]
+impl TorrentFileRepository for SqliteTorrentFileRepository {
+    async fn find_by_torrent_id(&self, torrent_id: i32) -> Result<Vec<TorrentFile>, DomainError> {
+        let mut conn = self
+            .pool
+            .get()
+            .map_err(|e| DomainError::RepositoryError(e.to_string()))?;
+
+        let result = tokio::task::spawn_blocking(move || {
+            torrent_files::table
+                .filter(torrent_files::torrent_id.eq(torrent_id))
+                .order(torrent_files::file_index.asc())
+                .select(TorrentFileModel::as_select())
+                .load::<TorrentFileModel>(&mut conn)
+        })
+        .await
+        .map_err(|e| DomainError::RepositoryError(e.to_string()))?
+        .map_err(|e| DomainError::RepositoryError(e.to_string()))?;
+
+        Ok(result.into_iter().map(|model| model.into()).collect())
+    }
+
+    async fn save_batch(&self, files: &[TorrentFile]) -> Result<Vec<TorrentFile>, DomainError> {
+        let Some(torrent_id) = files.first().map(|file| file.torrent_id) else {
+            return Ok(Vec::new());
+        };
+
+        let mut conn = self
+            .pool
+            .get()
+            .map_err(|e| DomainError::RepositoryError(e.to_string()))?;
+
+        let new_files: Vec<NewTorrentFileModel> =
+            files.iter().map(NewTorrentFileModel::from).collect();
+
+        let result = tokio::task::spawn_blocking(move || {
+            diesel::insert_into(torrent_files::table)
+                .values(&new_files)
+                .execute(&mut conn)?;
+
+            torrent_files::table
+                .filter(torrent_files::torrent_id.eq(torrent_id))
+                .order(torrent_files::file_index.asc())
+                .select(TorrentFileModel::as_select())
+                .load::<TorrentFileModel>(&mut conn)
+        })
+        .await
+        .map_err(|e| DomainError::RepositoryError(e.to_string()))?
+        .map_err(|e| DomainError::RepositoryError(e.to_string()))?;
+
+        Ok(result.into_iter().map(|model| model.into()).collect())
+    }
+
+    async fn update(&self, file: &TorrentFile) -> Result<TorrentFile, DomainError> {
+        let file_id = file.id.ok_or_else(|| {
+            DomainError::ValidationError("File ID is required for updates".to_string())
+        })?;
+
+        let mut conn = self
+            .pool
+            .get()
+            .map_err(|e| DomainError::RepositoryError(e.to_string()))?;
+
+        let priority = file.priority.as_str().to_string();
+
+        let result = tokio::task::spawn_blocking(move || {
+            diesel::update(torrent_files::table.filter(torrent_files::id.eq(file_id)))
+                .set(torrent_files::priority.eq(priority))
+                .execute(&mut conn)?;
+
+            // Fetch the updated file
+            torrent_files::table
+                .filter(torrent_files::id.eq(file_id))
+                .select(TorrentFileModel::as_select())
+                .first::<TorrentFileModel>(&mut conn)
+        })
+        .await
+        .map_err(|e| DomainError::RepositoryError(e.to_string()))?
+        .map_err(|e| DomainError::RepositoryError(e.to_string()))?;
+
+        Ok(result.into())
+    }
+}
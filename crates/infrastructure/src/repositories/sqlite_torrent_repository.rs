@@ -2,7 +2,7 @@ use crate::database::{torrents, SqlitePool};
 use async_trait::async_trait;
 use chrono::NaiveDateTime;
 use diesel::prelude::*;
-use domain::{DomainError, Torrent, TorrentRepository, TorrentStatus};
+use domain::{DomainError, StorageKind, Torrent, TorrentRepository, TorrentStatus};
 
 // Database model - separate from domain entity
 #[derive(Queryable, Selectable, AsChangeset, Debug)]
@@ -20,6 +20,7 @@ struct TorrentModel {
     progress: f32,
     created_at: NaiveDateTime,
     updated_at: NaiveDateTime,
+    storage: String,
 }
 
 #[derive(Insertable)]
@@ -35,6 +36,7 @@ struct NewTorrentModel {
     progress: f32,
     created_at: NaiveDateTime,
     updated_at: NaiveDateTime,
+    storage: String,
 }
 
 // Convert between domain and database models
@@ -42,6 +44,8 @@ impl From<TorrentModel> for Torrent {
     fn from(model: TorrentModel) -> Self {
         let status = match model.status.as_str() {
             "parsing" => TorrentStatus::Parsing,
+            "queued" => TorrentStatus::Queued,
+            "checking" => TorrentStatus::Checking,
             "connecting" => TorrentStatus::Connecting,
             "downloading" => TorrentStatus::Downloading,
             "seeding" => TorrentStatus::Seeding,
@@ -58,6 +62,7 @@ impl From<TorrentModel> for Torrent {
             model.piece_length,
             model.piece_count,
             model.file_path,
+            StorageKind::parse(&model.storage).unwrap_or_default(),
             status,
             model.progress,
             std::time::SystemTime::UNIX_EPOCH
@@ -72,6 +77,8 @@ impl From<&Torrent> for NewTorrentModel {
     fn from(torrent: &Torrent) -> Self {
         let status_str = match &torrent.status {
             TorrentStatus::Parsing => "parsing",
+            TorrentStatus::Queued => "queued",
+            TorrentStatus::Checking => "checking",
             TorrentStatus::Connecting => "connecting",
             TorrentStatus::Downloading => "downloading",
             TorrentStatus::Seeding => "seeding",
@@ -93,6 +100,7 @@ impl From<&Torrent> for NewTorrentModel {
             progress: torrent.progress,
             created_at: now,
             updated_at: now,
+            storage: torrent.storage.as_str().to_string(),
         }
     }
 }
@@ -188,6 +196,8 @@ impl TorrentRepository for SqliteTorrentRepository {
 
         let status_str = match &torrent.status {
             TorrentStatus::Parsing => "parsing",
+            TorrentStatus::Queued => "queued",
+            TorrentStatus::Checking => "checking",
             TorrentStatus::Connecting => "connecting",
             TorrentStatus::Downloading => "downloading",
             TorrentStatus::Seeding => "seeding",
@@ -268,6 +278,7 @@ impl TorrentRepository for SqliteTorrentRepository {
             torrents::table
                 .filter(torrents::status.ne("completed"))
                 .filter(torrents::status.ne("paused"))
+                .filter(torrents::status.ne("queued"))
                 .select(TorrentModel::as_select())
                 .load::<TorrentModel>(&mut conn)
         })
@@ -233,7 +233,7 @@ impl TrackerRepository for SqliteTrackerRepository {
             .map_err(|e| DomainError::RepositoryError(e.to_string()))?;
 
         let new_trackers: Vec<NewTrackerModel> =
-            trackers.iter().map(|t| NewTrackerModel::from(t)).collect();
+            trackers.iter().map(NewTrackerModel::from).collect();
 
         let result = tokio::task::spawn_blocking(move || {
             diesel::insert_into(trackers::table)
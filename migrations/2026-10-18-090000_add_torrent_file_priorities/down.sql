@@ -0,0 +1,5 @@
+DROP INDEX IF EXISTS idx_torrent_files_torrent_file_index;
+
+ALTER TABLE torrent_files DROP COLUMN priority;
+ALTER TABLE torrent_files DROP COLUMN "offset";
+ALTER TABLE torrent_files DROP COLUMN file_index;
@@ -0,0 +1,6 @@
+-- Record where each file sits in its torrent and how much we want it
+ALTER TABLE torrent_files ADD COLUMN file_index INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE torrent_files ADD COLUMN "offset" BIGINT NOT NULL DEFAULT 0;
+ALTER TABLE torrent_files ADD COLUMN priority TEXT NOT NULL DEFAULT 'normal';
+
+CREATE UNIQUE INDEX idx_torrent_files_torrent_file_index ON torrent_files(torrent_id, file_index);
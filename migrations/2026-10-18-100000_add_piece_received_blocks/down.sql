@@ -0,0 +1 @@
+ALTER TABLE pieces DROP COLUMN received_blocks;
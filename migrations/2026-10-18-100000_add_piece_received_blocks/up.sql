@@ -0,0 +1,2 @@
+-- Blocks of unfinished pieces already on disk, one bit per block
+ALTER TABLE pieces ADD COLUMN received_blocks BLOB;
@@ -0,0 +1,2 @@
+-- Where a torrent's data is kept: disk or memory
+ALTER TABLE torrents ADD COLUMN storage TEXT NOT NULL DEFAULT 'disk';
@@ -0,0 +1,10 @@
+-- Size and modification time of stored files, to resume without re-hashing
+CREATE TABLE file_snapshots (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    torrent_id INTEGER NOT NULL,
+    path TEXT NOT NULL,
+    size BIGINT NOT NULL,
+    modified_at TIMESTAMP NOT NULL,
+    FOREIGN KEY (torrent_id) REFERENCES torrents(id) ON DELETE CASCADE,
+    UNIQUE(torrent_id, path)
+);
@@ -0,0 +1,2 @@
+-- Peers that reached us from a port we cannot dial back
+ALTER TABLE peers ADD COLUMN inbound BOOLEAN NOT NULL DEFAULT 0;